@@ -0,0 +1,206 @@
+use md5::Digest as _;
+use sha2::Digest;
+use std::fs::File;
+use std::io::Error;
+use std::io::Read;
+use std::path::Path;
+
+
+/// The hash functions confidence knows how to compute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+    Md5,
+
+    /// BLAKE2b-512, as b2sum computes by default
+    Blake2b,
+
+    /// Not a cryptographic hash at all, but it's what SFV files have
+    Crc32,
+}
+
+
+impl Algorithm {
+
+    /// What the algorithm is called on the command line and in xattrs,
+    /// caches, etc.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Md5 => "md5",
+            Algorithm::Blake2b => "blake2b",
+            Algorithm::Crc32 => "crc32",
+        }
+    }
+
+
+    /// What BSD style checksum files (and `sha256sum --tag`) call it
+    pub fn bsd_tag(&self) -> &'static str {
+        match self {
+            Algorithm::Sha1 => "SHA1",
+            Algorithm::Sha256 => "SHA256",
+            Algorithm::Sha512 => "SHA512",
+            Algorithm::Md5 => "MD5",
+            Algorithm::Blake2b => "BLAKE2b",
+            Algorithm::Crc32 => "CRC32",
+        }
+    }
+
+
+    /// Inverse of `bsd_tag`
+    pub fn from_bsd_tag(tag: &str) -> Option<Algorithm> {
+        [Algorithm::Sha1, Algorithm::Sha256, Algorithm::Sha512, Algorithm::Md5,
+                Algorithm::Blake2b, Algorithm::Crc32]
+                .iter().copied().find(|algorithm| algorithm.bsd_tag() == tag)
+    }
+
+
+    /// The algorithm whose hex digests are `num_hex_digits` long.  Plain
+    /// checksum files don't say, so this is all there is to go on.  (sha512
+    /// digests are as long as BLAKE2b ones, so they have to be asked for.)
+    pub fn from_hex_len(num_hex_digits: usize) -> Option<Algorithm> {
+        match num_hex_digits {
+            32 => Some(Algorithm::Md5),
+            40 => Some(Algorithm::Sha1),
+            64 => Some(Algorithm::Sha256),
+            128 => Some(Algorithm::Blake2b),
+            _ => None,
+        }
+    }
+
+
+    /// How long its hex digests are
+    pub fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha1 => 40,
+            Algorithm::Sha256 => 64,
+            Algorithm::Sha512 => 128,
+            Algorithm::Blake2b => 128,
+            Algorithm::Crc32 => 8,
+        }
+    }
+
+
+    /// Inverse of `name`
+    pub fn from_name(name: &str) -> Result<Algorithm, Error> {
+        match name {
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            "md5" => Ok(Algorithm::Md5),
+            "blake2b" => Ok(Algorithm::Blake2b),
+            "crc32" => Ok(Algorithm::Crc32),
+            _ => {
+                let err_s = "Unknown hash algorithm '".to_owned() + name + "'";
+                Err(Error::other(err_s))
+            }
+        }
+    }
+
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            Algorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            Algorithm::Blake2b => Hasher::Blake2b(blake2::Blake2b512::new()),
+            Algorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+}
+
+
+/// A hash in progress for one of the `Algorithm`s
+pub enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Md5(md5::Md5),
+    Blake2b(blake2::Blake2b512),
+    Crc32(crc32fast::Hasher),
+}
+
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(bytes),
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Sha512(hasher) => hasher.update(bytes),
+            Hasher::Md5(hasher) => hasher.update(bytes),
+            Hasher::Blake2b(hasher) => hasher.update(bytes),
+            Hasher::Crc32(hasher) => hasher.update(bytes),
+        }
+    }
+
+
+    /// The finished hash as lowercase hex
+    pub fn hex_digest(self) -> String {
+        match self {
+            Hasher::Sha1(hasher) => hasher.digest().to_string(),
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Md5(hasher) => hex_of(&hasher.finalize()),
+            Hasher::Blake2b(hasher) => hex_of(&hasher.finalize()),
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
+
+fn hex_of(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+
+/// Same as `hash_of_path`, but with any `algorithm`.  Returns the hash
+/// string and the number of bytes hashed.
+pub fn hash_of_path_with(path: &Path, algorithm: Algorithm)
+        -> Result<(String, usize), Error> {
+    let (mut hashes, num_bytes_hashed) = hashes_of_path_with(path,
+            &[algorithm])?;
+    Ok((hashes.remove(0), num_bytes_hashed))
+}
+
+
+/// Hash `path` with every one of `algorithms` at once, reading it only
+/// once.  Returns the hash strings (in the same order) and the number of
+/// bytes hashed.
+pub fn hashes_of_path_with(path: &Path, algorithms: &[Algorithm])
+        -> Result<(Vec<String>, usize), Error> {
+    if !path.is_file() {
+        match path.to_str() {
+            Some(path_s) => {
+                return Err(Error::other(path_s.to_owned() + " is not a regular file."));
+            },
+            None => {
+                return Err(Error::other("Empty path"));
+            }
+        }
+    }
+
+    let mut hashers = algorithms.iter().map(|algorithm| algorithm.hasher())
+            .collect::<Vec<_>>();
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut num_bytes_hashed: usize = 0;
+    loop {
+        let num_bytes_read = file.read(&mut buffer[..])?;
+        if num_bytes_read == 0 {
+            break;
+        }
+        for hasher in hashers.iter_mut() {
+            hasher.update(&buffer[..num_bytes_read]);
+        }
+        num_bytes_hashed += num_bytes_read;
+    }
+
+    Ok((hashers.into_iter().map(|hasher| hasher.hex_digest()).collect(),
+            num_bytes_hashed))
+}
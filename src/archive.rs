@@ -0,0 +1,875 @@
+use crate::algorithm::hashes_of_path_with;
+use crate::algorithm::Algorithm;
+use crate::algorithm::Hasher;
+use crate::cache;
+use crate::cache::HashCache;
+use crate::filter::Filter;
+use crate::iso9660;
+use crate::manifest::read_manifest;
+use crate::manifest::EntryKind;
+use crate::manifest::ManifestEntry;
+use crate::merkle::TreeHasher;
+use crate::output_progress;
+use crate::relative_path_string;
+use crate::special;
+use crate::special::SpecialKind;
+use crate::squashfs;
+use crate::BytesComparison;
+use indicatif::ProgressBar;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use tar::EntryType;
+use zip::ZipArchive;
+
+
+/// Kinds of archive (and disk image) that can stand in for a directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    TarXz,
+    Zip,
+    Iso9660,
+    Squashfs,
+}
+
+
+impl ArchiveFormat {
+
+    /// Going by the extension of `filename`
+    pub fn of(filename: &str) -> Option<ArchiveFormat> {
+        let lowercase = filename.to_ascii_lowercase();
+        [(".tar", ArchiveFormat::Tar), (".tar.gz", ArchiveFormat::TarGz),
+                (".tgz", ArchiveFormat::TarGz), (".tar.zst", ArchiveFormat::TarZst),
+                (".tzst", ArchiveFormat::TarZst), (".tar.xz", ArchiveFormat::TarXz),
+                (".txz", ArchiveFormat::TarXz), (".zip", ArchiveFormat::Zip),
+                (".iso", ArchiveFormat::Iso9660), (".squashfs", ArchiveFormat::Squashfs),
+                (".sqfs", ArchiveFormat::Squashfs), (".sqsh", ArchiveFormat::Squashfs)]
+                .iter()
+                .find(|(extension, _)| lowercase.ends_with(extension))
+                .map(|(_, format)| *format)
+    }
+}
+
+
+/// Whether `filename` is an archive to be read as a tree instead of a
+/// directory
+pub fn is_archive(filename: &str) -> bool {
+    Path::new(filename).is_file() && ArchiveFormat::of(filename).is_some()
+}
+
+
+/// A regular or special file in an archive.  Directories and symlinks
+/// aren't kept, the same as when walking a directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub path: String,
+    pub size: usize,
+    pub special: Option<SpecialKind>,
+
+    /// Stored in zips, and worked out on the way through tars.  Disk
+    /// images don't have one until the file's been read.
+    pub crc32: Option<u32>,
+
+    /// Known up front for tars.  Zip and disk image entries aren't read
+    /// until they're asked for (see `Archive::sha1_of`).
+    sha1: Option<String>,
+
+    /// Where it is in a zip or disk image
+    index: usize,
+}
+
+
+impl ArchiveEntry {
+
+    /// The `index`th file of a disk image, which hasn't been read yet
+    pub(crate) fn in_image(path: String, size: usize, special: Option<SpecialKind>,
+            index: usize) -> ArchiveEntry {
+        ArchiveEntry{path, size, special, crc32: None, sha1: None, index}
+    }
+}
+
+
+/// Where the contents of an archive's entries are read from, for those
+/// that aren't read up front
+enum Source {
+    Tar,
+    Zip(ZipArchive<BufReader<File>>),
+    Iso9660(Box<iso9660::Image>),
+    Squashfs(Box<squashfs::Image>),
+}
+
+
+/// Everything in an archive, read as a tree
+pub struct Archive {
+    pub filename: String,
+    pub entries: Vec<ArchiveEntry>,
+    source: Source,
+    indices: HashMap<String, usize>,
+}
+
+
+/// Works out a sha1 and crc32 of everything written to it
+struct Digester {
+    sha1: Hasher,
+    crc32: crc32fast::Hasher,
+}
+
+
+impl Digester {
+    fn new() -> Digester {
+        Digester{sha1: Algorithm::Sha1.hasher(), crc32: crc32fast::Hasher::new()}
+    }
+
+
+    fn finish(self) -> (String, u32) {
+        (self.sha1.hex_digest(), self.crc32.finalize())
+    }
+}
+
+
+impl Write for Digester {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.sha1.update(buf);
+        self.crc32.update(buf);
+        Ok(buf.len())
+    }
+
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+
+/// `path` with no leading `/` or `./`, as it'd be once extracted
+fn tidied(path: &Path) -> Result<String, Error> {
+    let components = path.components().filter_map(|component| {
+        match component {
+            Component::Normal(name) => Some(name.to_str()),
+            _ => None,
+        }
+    }).collect::<Option<Vec<_>>>();
+    match components {
+        Some(components) => Ok(components.join("/")),
+        None => {
+            let err_s = "Can't make a string of ".to_owned() +
+                    &path.to_string_lossy();
+            Err(Error::other(err_s))
+        }
+    }
+}
+
+
+/// The sha1 and crc32 of everything `reader` has
+fn digests_of(mut reader: impl Read) -> Result<(String, u32, usize), Error> {
+    let mut digester = Digester::new();
+    let num_bytes_read = std::io::copy(&mut reader, &mut digester)?;
+    let (sha1, crc32) = digester.finish();
+    Ok((sha1, crc32, num_bytes_read as usize))
+}
+
+
+/// Call `each` with every regular and special file in the tar `reader`
+/// has, hashing as it goes.  Hard links get what they're linked to.
+fn read_tar(reader: impl Read,
+        mut each: impl FnMut(ArchiveEntry) -> Result<(), Error>)
+                -> Result<(), Error> {
+    let mut linkable = HashMap::new();
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = tidied(&entry.path()?)?;
+        let header = entry.header().clone();
+        let device = || -> Result<(u32, u32), Error> {
+            Ok((header.device_major()?.unwrap_or(0),
+                    header.device_minor()?.unwrap_or(0)))
+        };
+        let special = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => None,
+            EntryType::Fifo => Some(SpecialKind::Fifo),
+            EntryType::Char => {
+                let (major, minor) = device()?;
+                Some(SpecialKind::CharDevice(major, minor))
+            },
+            EntryType::Block => {
+                let (major, minor) = device()?;
+                Some(SpecialKind::BlockDevice(major, minor))
+            },
+            EntryType::Link => {
+                let target = match entry.link_name()? {
+                    Some(target) => tidied(&target)?,
+                    None => continue,
+                };
+                if let Some(linked) = linkable.get(&target).cloned() {
+                    each(ArchiveEntry{path, ..linked})?;
+                }
+                continue;
+            },
+            _ => continue,
+        };
+        let archive_entry = if special.is_some() {
+            ArchiveEntry{path, size: 0, special, crc32: Some(0), sha1: None, index: 0}
+        }
+        else {
+            let (sha1, crc32, size) = digests_of(&mut entry)?;
+            ArchiveEntry{path, size, special: None, crc32: Some(crc32),
+                    sha1: Some(sha1), index: 0}
+        };
+        linkable.insert(archive_entry.path.clone(), archive_entry.clone());
+        each(archive_entry)?;
+    }
+    Ok(())
+}
+
+
+/// `read_tar`, collecting the entries
+fn tar_entries(reader: impl Read) -> Result<Vec<ArchiveEntry>, Error> {
+    let mut to_return = Vec::new();
+    read_tar(reader, |entry| {
+        to_return.push(entry);
+        Ok(())
+    })?;
+    Ok(to_return)
+}
+
+
+impl Archive {
+
+    /// Read the archive `filename`.  Tars are read (and hashed) all the
+    /// way through, but only a zip's central directory is, and only the
+    /// directories of a disk image.
+    pub fn open(filename: &str) -> Result<Archive, Error> {
+        let format = match ArchiveFormat::of(filename) {
+            Some(format) => format,
+            None => {
+                let err_s = "Don't know what kind of archive ".to_owned() +
+                        filename + " is.";
+                return Err(Error::other(err_s));
+            }
+        };
+        let file = BufReader::new(File::open(filename)?);
+        let mut source = Source::Tar;
+        let entries = match format {
+            ArchiveFormat::Tar => tar_entries(file)?,
+            ArchiveFormat::TarGz => tar_entries(flate2::read::MultiGzDecoder::new(file))?,
+            ArchiveFormat::TarZst => tar_entries(zstd::stream::read::Decoder::new(file)?)?,
+            ArchiveFormat::TarXz => tar_entries(xz2::read::XzDecoder::new_multi_decoder(file))?,
+            ArchiveFormat::Iso9660 => {
+                let mut image = iso9660::Image::open(filename)?;
+                let entries = std::mem::take(&mut image.entries);
+                source = Source::Iso9660(Box::new(image));
+                entries
+            },
+            ArchiveFormat::Squashfs => {
+                let mut image = squashfs::Image::open(filename)?;
+                let entries = std::mem::take(&mut image.entries);
+                source = Source::Squashfs(Box::new(image));
+                entries
+            },
+            ArchiveFormat::Zip => {
+                let mut archive = ZipArchive::new(file).map_err(Error::other)?;
+                let mut entries = Vec::new();
+                for index in 0..archive.len() {
+                    let file = archive.by_index_raw(index).map_err(Error::other)?;
+                    if !file.is_file() {
+                        continue;
+                    }
+                    entries.push(ArchiveEntry{path: tidied(Path::new(file.name()))?,
+                            size: file.size() as usize, special: None,
+                            crc32: Some(file.crc32()), sha1: None, index});
+                }
+                source = Source::Zip(archive);
+                entries
+            },
+        };
+
+        /* Whatever's last wins, like when extracting */
+        let mut to_return = Archive{filename: filename.to_owned(),
+                entries: Vec::new(), source, indices: HashMap::new()};
+        for entry in entries {
+            match to_return.indices.get(&entry.path) {
+                Some(&i) => to_return.entries[i] = entry,
+                None => {
+                    to_return.indices.insert(entry.path.clone(),
+                            to_return.entries.len());
+                    to_return.entries.push(entry);
+                },
+            }
+        }
+        Ok(to_return)
+    }
+
+
+    /// The directory everything in the archive is in, if there's just one
+    pub fn top(&self) -> Option<String> {
+        let (top, _) = self.entries.first()?.path.split_once('/')?;
+        let prefix = top.to_owned() + "/";
+        if self.entries.iter().all(|entry| entry.path.starts_with(&prefix)) {
+            Some(top.to_owned())
+        }
+        else {
+            None
+        }
+    }
+
+
+    /// If everything in the archive is in a directory named `name`, as
+    /// when `tar czf backup.tgz name` made it, take that off every path
+    pub fn strip_top(&mut self, name: &str) {
+        if self.top().as_deref() != Some(name) {
+            return;
+        }
+        let prefix = name.to_owned() + "/";
+        self.indices.clear();
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            entry.path = entry.path[prefix.len()..].to_owned();
+            self.indices.insert(entry.path.clone(), i);
+        }
+    }
+
+
+    /// Which of `entries` is at `relative`, if any
+    pub fn find(&self, relative: &str) -> Option<usize> {
+        self.indices.get(relative).copied()
+    }
+
+
+    /// The sha1 of the `i`th entry, reading it first if it's in a zip or
+    /// disk image.  Reading a zip entry whose crc32 doesn't match is an
+    /// error.
+    pub fn sha1_of(&mut self, i: usize) -> Result<String, Error> {
+        if let Some(ref sha1) = self.entries[i].sha1 {
+            return Ok(sha1.clone());
+        }
+        let index = self.entries[i].index;
+        let (sha1, crc32) = match self.source {
+            Source::Tar => return Err(Error::other("Special files have no sha1")),
+            Source::Zip(ref mut zip) => {
+                let file = zip.by_index(index).map_err(Error::other)?;
+                let (sha1, crc32, _) = digests_of(file)?;
+                (sha1, crc32)
+            },
+            Source::Iso9660(ref mut image) => {
+                let mut digester = Digester::new();
+                image.copy_contents(index, &mut digester)?;
+                digester.finish()
+            },
+            Source::Squashfs(ref mut image) => {
+                let mut digester = Digester::new();
+                image.copy_contents(index, &mut digester)?;
+                digester.finish()
+            },
+        };
+        self.entries[i].sha1 = Some(sha1.clone());
+        self.entries[i].crc32 = Some(crc32);
+        Ok(sha1)
+    }
+
+
+    /// What `hash_path` would write for the `i`th entry
+    pub fn manifest_entry(&mut self, i: usize) -> Result<ManifestEntry, Error> {
+        let kind = match self.entries[i].special {
+            Some(special_kind) => EntryKind::Special(special_kind),
+            None => EntryKind::File(self.sha1_of(i)?),
+        };
+        Ok(ManifestEntry{kind, path: self.entries[i].path.clone(),
+                size: self.entries[i].size, stat: None})
+    }
+}
+
+
+/// Write out a manifest line for everything in the archive `filename` that
+/// `filter` wants, the same as `hash_path` would for a directory.  Returns
+/// the number of bytes hashed.
+pub fn hash_archive(filename: &str, writable: &mut impl Write, num_vs: u8,
+        progress_bar: &Option<ProgressBar>, filter: &Filter,
+        tree_hasher: &mut Option<TreeHasher>) -> Result<usize, Error> {
+    let mut archive = Archive::open(filename)?;
+
+    /* In the order a walk would have found them */
+    let mut order = (0..archive.entries.len()).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| archive.entries[i].path.split('/')
+            .cmp(archive.entries[j].path.split('/')));
+    let mut num_bytes_hashed = 0;
+    for i in order {
+        if !filter.wants_manifest_entry(filename, &archive.entries[i].path,
+                archive.entries[i].size) {
+            continue;
+        }
+        if num_vs > 1 {
+            eprintln!("Output hash of {}", archive.entries[i].path);
+        }
+        let entry = archive.manifest_entry(i)?;
+        writeln!(writable, "{}", entry.to_line())?;
+        output_progress(entry.size as u64, progress_bar);
+        num_bytes_hashed += entry.size;
+        if let Some(ref mut tree_hasher) = tree_hasher {
+            tree_hasher.add_entry(&entry);
+        }
+    }
+    Ok(num_bytes_hashed)
+}
+
+
+/// Write out a manifest line for every entry of a tar stream (which can be
+/// gzipped, zstd'd or xz'd) as it's read, in one pass, so the stream never
+/// has to be on disk.  Paths are as they are in the tar, less any leading
+/// `./`.  Returns the number of bytes hashed.
+pub fn hash_tar_stream(reader: impl Read, mut writable: impl Write, num_vs: u8,
+        filter: &Filter) -> Result<usize, Error> {
+    let mut reader = BufReader::new(reader);
+    let magic = reader.fill_buf()?.to_vec();
+    let reader: Box<dyn Read> = if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::MultiGzDecoder::new(reader))
+    }
+    else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)
+    }
+    else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Box::new(xz2::read::XzDecoder::new_multi_decoder(reader))
+    }
+    else {
+        Box::new(reader)
+    };
+
+    let mut num_bytes_hashed = 0;
+    read_tar(reader, |entry| {
+        if !filter.wants_manifest_entry("-", &entry.path, entry.size) {
+            return Ok(());
+        }
+        if num_vs > 1 {
+            eprintln!("Output hash of {}", entry.path);
+        }
+        let kind = match (entry.special, entry.sha1) {
+            (Some(special_kind), _) => EntryKind::Special(special_kind),
+            (None, Some(sha1)) => EntryKind::File(sha1),
+            (None, None) => return Ok(()),
+        };
+        writeln!(writable, "{}", ManifestEntry{kind, path: entry.path,
+                size: entry.size, stat: None}.to_line())?;
+        num_bytes_hashed += entry.size;
+        Ok(())
+    })?;
+    writeln!(writable, "{} bytes hashed", num_bytes_hashed)?;
+    Ok(num_bytes_hashed)
+}
+
+
+/// Check the archive `filename` against the manifest `hashes_filename`,
+/// the way `compare_hashes` checks a directory.  A manifest of the tree
+/// the archive was made from lines up with it even when everything in the
+/// archive is in one top directory.
+pub fn verify_archive(hashes_filename: &str, filename: &str,
+        mut writable: impl Write, num_vs: u8,
+        progress_bar: &Option<ProgressBar>, filter: &Filter)
+                -> Result<BytesComparison, Error> {
+    let (entries, num_bytes_hashed) = read_manifest(hashes_filename)?;
+    let mut archive = Archive::open(filename)?;
+    if let Some(top) = archive.top() {
+        let prefix = top.clone() + "/";
+        if !entries.iter().any(|entry| entry.path.starts_with(&prefix)) {
+            archive.strip_top(&top);
+        }
+    }
+
+    let mut to_return = BytesComparison::default();
+    let mut num_bytes_filtered_out: usize = 0;
+    for entry in entries {
+        if let EntryKind::Directory(_) = entry.kind {
+            continue;
+        }
+        let display = Path::new(filename).join(&entry.path).display().to_string();
+        if num_vs > 1 {
+            writeln!(writable, "Examining {}", display)?;
+        }
+        output_progress(entry.size as u64, progress_bar);
+        if !filter.wants_manifest_entry(filename, &entry.path, entry.size) {
+            num_bytes_filtered_out += entry.size;
+            continue;
+        }
+
+        let i = match archive.find(&entry.path) {
+            Some(i) => i,
+            None => {
+                writeln!(writable, "Disagreement ({} bytes): {} isn't in {}.",
+                        entry.size, entry.path, filename)?;
+                to_return += if entry.size == 0 {
+                    BytesComparison{entry_disagreement: 1, ..Default::default()}
+                }
+                else {
+                    BytesComparison{disagreement: entry.size, ..Default::default()}
+                };
+                continue;
+            }
+        };
+        let (size, special) = (archive.entries[i].size, archive.entries[i].special);
+        let sha1 = match (&entry.kind, special) {
+            (EntryKind::Special(kind), Some(special)) if *kind == special => {
+                continue;
+            },
+            (EntryKind::Special(kind), _) => {
+                writeln!(writable, "Disagreement (0 bytes): {} is {} and {} isn't.",
+                        entry.path, kind, display)?;
+                to_return += BytesComparison{entry_disagreement: 1,
+                        ..Default::default()};
+                continue;
+            },
+            (EntryKind::File(_), Some(special)) => {
+                writeln!(writable, "Disagreement ({} bytes): {} exists and {} is {}.",
+                        entry.size, entry.path, display, special)?;
+                to_return += BytesComparison{disagreement: entry.size,
+                        ..Default::default()};
+                continue;
+            },
+            (EntryKind::File(sha1), None) => sha1,
+            (EntryKind::Directory(_), _) => continue,
+        };
+
+        let max_bytes_compared = cmp::max(size, entry.size);
+        if size != entry.size {
+            writeln!(writable, "Disagreement ({} bytes): {} and {} are different sizes.",
+                    max_bytes_compared, entry.path, display)?;
+            to_return += BytesComparison{disagreement: max_bytes_compared,
+                    ..Default::default()};
+            continue;
+        }
+        match archive.sha1_of(i) {
+            Ok(ref actual) if actual == sha1 => {
+                to_return += BytesComparison{agreement: size, ..Default::default()};
+            },
+            Ok(_) => {
+                writeln!(writable, "Disagreement ({} bytes): {} and {} have different hashes.",
+                        size, entry.path, display)?;
+                to_return += BytesComparison{disagreement: size, ..Default::default()};
+            },
+            Err(error) => {
+                writeln!(writable, "Disagreement ({} bytes): Couldn't hash {}: {}",
+                        size, display, error)?;
+                to_return += BytesComparison{disagreement: size, ..Default::default()};
+            },
+        }
+    }
+
+    let num_bytes_hashed = num_bytes_hashed.saturating_sub(num_bytes_filtered_out);
+    to_return.write_summary(&mut writable, num_bytes_hashed)?;
+    if to_return.entry_disagreement > 0 {
+        writeln!(writable, "Disagreed on {} special files.",
+                to_return.entry_disagreement)?;
+    }
+    Ok(to_return)
+}
+
+
+/// Total size of everything in the archive `filename` that `filter` wants
+pub fn size_of_archive(filename: &str, filter: &Filter) -> Result<usize, Error> {
+    Ok(Archive::open(filename)?.entries.iter()
+            .filter(|entry| filter.wants_manifest_entry(filename, &entry.path,
+                    entry.size))
+            .map(|entry| entry.size)
+            .sum())
+}
+
+
+/// One side of a comparison
+enum Side {
+
+    /// The last file hashed is kept, since its crc32 and sha1 are worked
+    /// out together but asked for separately
+    Directory(String, Option<(String, String, u32)>),
+    Archive(Archive),
+}
+
+
+impl Side {
+    fn open(filename: &str) -> Result<Side, Error> {
+        if is_archive(filename) {
+            Ok(Side::Archive(Archive::open(filename)?))
+        }
+        else {
+            Ok(Side::Directory(filename.to_owned(), None))
+        }
+    }
+
+
+    /// Take the top directory off an archive's paths when it's named the
+    /// same as the directory it's compared to.  Two archives that both
+    /// have one (e.g. `name-1.0/` and `name-1.1/`) both lose it.
+    fn line_up(&mut self, other: &mut Side) {
+        match (self, other) {
+            (Side::Archive(archive), Side::Directory(directory, _)) |
+                    (Side::Directory(directory, _), Side::Archive(archive)) => {
+                if let Some(name) = Path::new(directory).file_name() {
+                    archive.strip_top(&name.to_string_lossy());
+                }
+            },
+            (Side::Archive(archive_l), Side::Archive(archive_r)) => {
+                if let (Some(top_l), Some(top_r)) = (archive_l.top(), archive_r.top()) {
+                    archive_l.strip_top(&top_l);
+                    archive_r.strip_top(&top_r);
+                }
+            },
+            _ => {},
+        }
+    }
+
+
+    /// How to refer to `relative` in messages
+    fn display(&self, relative: &str) -> String {
+        let top = match self {
+            Side::Directory(directory, _) => directory,
+            Side::Archive(archive) => &archive.filename,
+        };
+        Path::new(top).join(relative).display().to_string()
+    }
+
+
+    /// The size of the regular file at `relative`, or what kind of special
+    /// file it is.  `None` if there's neither.
+    fn stat(&self, relative: &str) -> Option<Result<usize, SpecialKind>> {
+        match self {
+            Side::Directory(directory, _) => {
+                let path = Path::new(directory).join(relative);
+                if let Some(kind) = special::special_kind_of(&path) {
+                    return Some(Err(kind));
+                }
+                path.metadata().ok().filter(|metadata| metadata.is_file())
+                        .map(|metadata| Ok(metadata.len() as usize))
+            },
+            Side::Archive(archive) => {
+                archive.find(relative).map(|i| {
+                    let entry = &archive.entries[i];
+                    entry.special.map_or(Ok(entry.size), Err)
+                })
+            },
+        }
+    }
+
+
+    /// The crc32 of `relative`, if it's known without reading anything
+    fn known_crc32(&self, relative: &str) -> Option<u32> {
+        match self {
+            Side::Directory(_, _) => None,
+            Side::Archive(archive) => {
+                archive.find(relative).and_then(|i| archive.entries[i].crc32)
+            },
+        }
+    }
+
+
+    /// Whether a trusted `cache` knows the sha1 of the file at `relative`,
+    /// so there's no need to read it for a crc32
+    fn cached(&self, relative: &str, cache: &Option<HashCache>) -> bool {
+        match (self, cache) {
+            (Side::Directory(directory, _), Some(cache))
+                    if cache.mode() == cache::CacheMode::Trust => {
+                fs::metadata(Path::new(directory).join(relative)).ok()
+                        .and_then(|metadata| cache.lookup(&metadata, "sha1").ok())
+                        .flatten().is_some()
+            },
+            _ => false,
+        }
+    }
+
+
+    /// The sha1 of the regular file at `relative`, and how many bytes a
+    /// verified `cache` had wrong for it (see `hash_of_path_cached`).
+    /// Whatever's hashed in a directory is remembered in `cache`.
+    fn sha1(&mut self, relative: &str, cache: &Option<HashCache>,
+            writable: &mut impl Write) -> Result<(String, usize), Error> {
+        let path = match self {
+            Side::Directory(directory, _) => Path::new(directory).join(relative),
+            Side::Archive(_) => return Ok((self.digests(relative)?.0, 0)),
+        };
+
+        /* Already read for its crc32 */
+        if let (Side::Directory(_, Some((ref last, ref sha1, _))), Some(cache)) =
+                (&self, cache) {
+            if last == relative {
+                let metadata = fs::metadata(&path)?;
+                let num_bytes_wrong = cache.check(&path, &metadata, "sha1", sha1,
+                        writable)?;
+                cache.store(&metadata, "sha1", sha1)?;
+                return Ok((sha1.clone(), num_bytes_wrong));
+            }
+        }
+        let (sha1, _, num_bytes_wrong) = cache::hash_of_path_cached(&path,
+                cache, writable)?;
+        Ok((sha1, num_bytes_wrong))
+    }
+
+
+    /// The sha1 and crc32 of the regular file at `relative`
+    fn digests(&mut self, relative: &str) -> Result<(String, u32), Error> {
+        match self {
+            Side::Directory(directory, last) => {
+                if let Some((ref path, ref sha1, crc32)) = last {
+                    if path == relative {
+                        return Ok((sha1.clone(), *crc32));
+                    }
+                }
+                let (digests, _) = hashes_of_path_with(
+                        &Path::new(directory).join(relative),
+                        &[Algorithm::Sha1, Algorithm::Crc32])?;
+                let crc32 = u32::from_str_radix(&digests[1], 16)
+                        .map_err(Error::other)?;
+                *last = Some((relative.to_owned(), digests[0].clone(), crc32));
+                Ok((digests[0].clone(), crc32))
+            },
+            Side::Archive(archive) => {
+                let i = archive.find(relative)
+                        .ok_or_else(|| Error::other(relative.to_owned() + " isn't there"))?;
+                let sha1 = archive.sha1_of(i)?;
+                Ok((sha1, archive.entries[i].crc32.unwrap_or(0)))
+            },
+        }
+    }
+}
+
+
+/// Compare one file that's on the left with whatever's on the right
+fn compare_relative(relative: &str, left: &mut Side, right: &mut Side,
+        writable: &mut impl Write, num_vs: u8, cache: &Option<HashCache>)
+                -> Result<BytesComparison, Error> {
+    let (display_l, display_r) = (left.display(relative), right.display(relative));
+    if num_vs > 1 {
+        writeln!(writable, "Compare {} to {}", display_l, display_r)?;
+    }
+    let num_bytes_l = match (left.stat(relative), right.stat(relative)) {
+        (Some(Err(kind_l)), stat_r) => {
+            if stat_r == Some(Err(kind_l)) {
+                return Ok(BytesComparison::default());
+            }
+            writeln!(writable, "'{}' is {}, but '{}' isn't.", display_l,
+                    kind_l, display_r)?;
+            return Ok(BytesComparison{entry_disagreement: 1, ..Default::default()});
+        },
+        (Some(Ok(num_bytes_l)), Some(Ok(num_bytes_r))) => {
+            if num_bytes_l != num_bytes_r {
+                writeln!(writable, "'{}' and '{}' aren't the same size.",
+                        display_l, display_r)?;
+                return Ok(BytesComparison{
+                        disagreement: cmp::max(num_bytes_l, num_bytes_r),
+                        ..Default::default()});
+            }
+            num_bytes_l
+        },
+        (Some(Ok(num_bytes_l)), _) => {
+            writeln!(writable, "'{}' isn't a regular file, but '{}' is.",
+                    display_r, display_l)?;
+            return Ok(BytesComparison{disagreement: num_bytes_l, ..Default::default()});
+        },
+        (None, _) => return Ok(BytesComparison::default()),
+    };
+
+    /* A crc32 that's stored (or already worked out) can say they differ
+     * before anything's decompressed, unless a trusted cache can say
+     * without reading anything */
+    let use_crc32s = !left.cached(relative, cache) && !right.cached(relative, cache);
+    let known_l = left.known_crc32(relative).filter(|_| use_crc32s);
+    let known_r = right.known_crc32(relative).filter(|_| use_crc32s);
+    let read_error = |side: &Side, error: Error| {
+        "Couldn't read '".to_owned() + &side.display(relative) + "': " +
+                &error.to_string()
+    };
+    let crc32 = |side: &mut Side| {
+        side.digests(relative).map(|(_, crc32)| crc32)
+                .map_err(|error| read_error(side, error))
+    };
+    let (mut num_bytes_wrong, mut cache_output) = (0, Vec::new());
+    let mut sha1 = |side: &mut Side| {
+        match side.sha1(relative, cache, &mut cache_output) {
+            Ok((sha1, wrong)) => {
+                num_bytes_wrong += wrong;
+                Ok(sha1)
+            },
+            Err(error) => Err(read_error(side, error)),
+        }
+    };
+    let equal = if known_l.is_some() || known_r.is_some() {
+        let crc32_l = known_l.map_or_else(|| crc32(left), Ok);
+        let crc32_r = known_r.map_or_else(|| crc32(right), Ok);
+        match (crc32_l, crc32_r) {
+            (Ok(crc32_l), Ok(crc32_r)) if crc32_l != crc32_r => Ok(false),
+            (Err(error_s), _) | (_, Err(error_s)) => Err(error_s),
+            _ => sha1(left).and_then(|sha1_l| sha1(right).map(|sha1_r| sha1_l == sha1_r)),
+        }
+    }
+    else {
+        sha1(left).and_then(|sha1_l| sha1(right).map(|sha1_r| sha1_l == sha1_r))
+    };
+    writable.write_all(&cache_output)?;
+
+    match equal {
+        Ok(true) if num_bytes_wrong > 0 => {
+            Ok(BytesComparison{disagreement: num_bytes_wrong, ..Default::default()})
+        },
+        Ok(true) => Ok(BytesComparison{agreement: num_bytes_l, ..Default::default()}),
+        Ok(false) => {
+            writeln!(writable, "'{}' and '{}' aren't equal.", display_l,
+                    display_r)?;
+            Ok(BytesComparison{disagreement: num_bytes_l, ..Default::default()})
+        },
+        Err(error_s) => {
+            writeln!(writable, "{}", error_s)?;
+            Ok(BytesComparison{disagreement: num_bytes_l, ..Default::default()})
+        },
+    }
+}
+
+
+/// `compare_paths` for every file on the left when either side is an
+/// archive or disk image.  Files are compared by sha1 rather than byte by
+/// byte, but a zip's stored crc32s are checked first.  `cache` is used for
+/// the files in a directory.  Paths are lined up as
+/// `Side::line_up` says, so `tar czf backup.tgz name` can be compared to
+/// `name`.
+#[allow(clippy::too_many_arguments)]
+pub fn compare_with_archives(filename_l: &str, filename_r: &str,
+        mut writable: impl Write, num_vs: u8,
+        progress_bar: &Option<ProgressBar>, filter: &Filter,
+        ignore_perm_errors_flag: bool, cache: &Option<HashCache>)
+                -> Result<BytesComparison, Error> {
+    let mut left = Side::open(filename_l)?;
+    let mut right = Side::open(filename_r)?;
+    left.line_up(&mut right);
+    let mut relatives = Vec::new();
+    match left {
+        Side::Archive(ref archive) => {
+            for entry in &archive.entries {
+                if filter.wants_manifest_entry(filename_l, &entry.path, entry.size) {
+                    relatives.push(entry.path.clone());
+                }
+            }
+        },
+        Side::Directory(_, _) => {
+            for entry in filter.walk_entries(filename_l, ignore_perm_errors_flag) {
+                let entry = entry?;
+                if entry.path().is_file() ||
+                        special::special_kind_of(entry.path()).is_some() {
+                    relatives.push(relative_path_string(entry.path(), filename_l)?);
+                }
+            }
+        },
+    }
+
+    let mut to_return = BytesComparison::default();
+    for relative in relatives {
+        let comparison = compare_relative(&relative, &mut left, &mut right,
+                &mut writable, num_vs, cache)?;
+        output_progress((comparison.agreement + comparison.disagreement) as u64,
+                progress_bar);
+        to_return += comparison;
+    }
+    Ok(to_return)
+}
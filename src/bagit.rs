@@ -0,0 +1,380 @@
+use crate::algorithm::hashes_of_path_with;
+use crate::algorithm::Algorithm;
+use crate::copy::copy_tree;
+use crate::filter::civil_from_days;
+use crate::filter::Filter;
+use crate::history::now_seconds;
+use crate::relative_path_string;
+use crate::tidied;
+use crate::unhashable;
+use crate::BytesComparison;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::path::Path;
+
+
+/// The payload directory, relative to the top of a bag
+const DATA: &str = "data";
+
+
+const BAGIT_TXT: &str = "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n";
+
+
+/// Paths in manifests can't have line breaks in them, so those (and `%`)
+/// are percent encoded (RFC 8493, section 2.1.3)
+fn encoded(path: &str) -> String {
+    path.replace('%', "%25").replace('\r', "%0D").replace('\n', "%0A")
+}
+
+
+/// Inverse of `encoded`.  Other percent encodings are left alone, since
+/// they could be in real filenames.
+fn decoded(path: &str) -> String {
+    path.replace("%0D", "\r").replace("%0d", "\r").replace("%0A", "\n")
+            .replace("%0a", "\n").replace("%25", "%")
+}
+
+
+/// Every regular file under `directory`, relative to `top`, in order
+fn files_under(directory: &Path, top: &Path) -> Result<Vec<String>, Error> {
+    let mut to_return = Vec::new();
+    if !directory.is_dir() {
+        return Ok(to_return);
+    }
+    for entry in walkdir::WalkDir::new(directory)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = entry?;
+        if entry.path().is_file() {
+            to_return.push(relative_path_string(entry.path(),
+                    &top.to_string_lossy())?);
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// Write `manifest-<algorithm>.txt` style files at the top of `bag`
+/// listing each of `paths` (relative to `bag`).  Returns the number of
+/// bytes in them.
+fn write_manifests(bag: &Path, prefix: &str, paths: &[String],
+        algorithms: &[Algorithm]) -> Result<usize, Error> {
+    let mut manifests = Vec::new();
+    for algorithm in algorithms {
+        let filename = format!("{}-{}.txt", prefix, algorithm.name());
+        manifests.push(fs::File::create(bag.join(filename))?);
+    }
+    let mut num_bytes = 0;
+    for path in paths {
+        let (hashes, num_bytes_hashed) = hashes_of_path_with(&bag.join(path),
+                algorithms)?;
+        for (manifest, hash) in manifests.iter_mut().zip(hashes) {
+            writeln!(manifest, "{}  {}", hash, encoded(path))?;
+        }
+        num_bytes += num_bytes_hashed;
+    }
+    for manifest in manifests {
+        manifest.sync_all()?;
+    }
+    Ok(num_bytes)
+}
+
+
+/// Move everything in `bag` into `temporary` (which is in it) and then
+/// make that `data`.  If anything goes wrong, whatever was moved is moved
+/// back, so `bag` isn't left half bagged.
+fn move_into_data(bag: &Path, temporary: &Path) -> Result<(), Error> {
+    let mut moved = Vec::new();
+    let mut move_all = || {
+        for entry in fs::read_dir(bag)? {
+            let entry = entry?;
+            if entry.path() != temporary {
+                fs::rename(entry.path(), temporary.join(entry.file_name()))?;
+                moved.push(entry.file_name());
+            }
+        }
+        fs::rename(temporary, bag.join(DATA))
+    };
+    let result = move_all();
+    if result.is_err() {
+
+        /* The first error is the one worth reporting */
+        for name in moved.iter().rev() {
+            let _ = fs::rename(temporary.join(name), bag.join(name));
+        }
+        let _ = fs::remove_dir(temporary);
+    }
+    result
+}
+
+
+/// Make a bag (RFC 8493) of `source`.  With a `destination`, the payload
+/// is copied there (through `filter`, each copy checked like `copy_tree`
+/// does) and `source` is left alone.  Without one, everything in `source`
+/// is moved into `source/data` and `source` becomes the bag, or put back
+/// where it was if that fails partway.
+///
+/// The bag gets `bagit.txt`, a `bag-info.txt` with a Payload-Oxum, and a
+/// payload manifest and tag manifest for each of `algorithms`.
+#[allow(clippy::too_many_arguments)]
+pub fn create_bag(source: &str, destination: Option<&str>,
+        mut writable: impl Write, num_vs: u8, algorithms: &[Algorithm],
+        filter: &Filter, ignore_perm_errors_flag: bool)
+                -> Result<BytesComparison, Error> {
+    let (bag, mut to_return) = match destination {
+        Some(destination) => {
+            let data = Path::new(destination).join(DATA);
+            let comparison = copy_tree(source, &data.to_string_lossy(),
+                    std::io::sink(), &mut writable, num_vs, false, filter,
+                    ignore_perm_errors_flag, false)?;
+            (Path::new(destination).to_owned(), comparison)
+        },
+        None => {
+            let bag = Path::new(source);
+            if bag.join("bagit.txt").exists() {
+                let err_s = source.to_owned() + " is already a bag.";
+                return Err(Error::other(err_s));
+            }
+
+            /* Move everything aside first, in case there's already
+             * something called data */
+            let temporary = bag.join(".confidence-bagging");
+            if temporary.symlink_metadata().is_ok() {
+                let err_s = temporary.display().to_string() +
+                        " is left over from bagging that didn't finish.  Move what's in it back first.";
+                return Err(Error::other(err_s));
+            }
+            fs::create_dir(&temporary)?;
+            move_into_data(bag, &temporary)?;
+            if num_vs > 0 {
+                writeln!(writable, "Moved the contents of {} into {}", source,
+                        bag.join(DATA).display())?;
+            }
+            (bag.to_owned(), BytesComparison::default())
+        }
+    };
+
+    let payload = files_under(&bag.join(DATA), &bag)?;
+    let num_bytes = write_manifests(&bag, "manifest", &payload, algorithms)?;
+    if destination.is_none() {
+        to_return += BytesComparison{agreement: num_bytes, ..Default::default()};
+    }
+
+    fs::write(bag.join("bagit.txt"), BAGIT_TXT)?;
+    let (year, month, day) = civil_from_days(now_seconds() / (24 * 60 * 60));
+    fs::write(bag.join("bag-info.txt"), format!(
+            "Bag-Software-Agent: confidence {} ({})\nBagging-Date: {:04}-{:02}-{:02}\nPayload-Oxum: {}.{}\n",
+            env!("CARGO_PKG_VERSION"), env!("CARGO_PKG_HOMEPAGE"), year, month,
+            day, num_bytes, payload.len()))?;
+
+    let mut tag_files = vec!["bagit.txt".to_owned(), "bag-info.txt".to_owned()];
+    tag_files.extend(algorithms.iter()
+            .map(|algorithm| format!("manifest-{}.txt", algorithm.name())));
+    write_manifests(&bag, "tagmanifest", &tag_files, algorithms)?;
+
+    writeln!(writable, "Bagged {} files ({} bytes) in {}", payload.len(),
+            num_bytes, bag.display())?;
+    Ok(to_return)
+}
+
+
+/// The `Label: value` lines of a tag file like `bag-info.txt`.  Values
+/// can be continued on lines starting with whitespace.
+fn tag_values(contents: &str) -> Vec<(String, String)> {
+    let mut to_return: Vec<(String, String)> = Vec::new();
+    for line in contents.lines() {
+        if line.starts_with(&[' ', '\t'][..]) {
+            if let Some((_, value)) = to_return.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some((label, value)) = line.split_once(':') {
+            to_return.push((label.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    to_return
+}
+
+
+/// `(path, hash)` for each line of the manifest `filename`
+fn read_bag_manifest(filename: &Path) -> Result<Vec<(String, String)>, Error> {
+    let mut to_return = Vec::new();
+    for line in fs::read_to_string(filename)?.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.split_once(|c: char| c.is_whitespace()) {
+            Some((hash, path)) => {
+                to_return.push((decoded(path.trim_start()),
+                        hash.to_ascii_lowercase()));
+            },
+            None => {
+                let err_s = format!("Corrupt line in {}: {}", filename.display(),
+                        line);
+                return Err(Error::other(err_s));
+            }
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// Validate the bag `bag`: it has to be complete (every file in every
+/// manifest exists, every payload file is in every payload manifest and
+/// the Payload-Oxum adds up) and every hash in every manifest has to
+/// match.
+///
+/// Bytes in payload and tag files whose hashes all match agree.  Bytes in
+/// files with a wrong hash, that can't be read or that are missing from a
+/// manifest disagree.  Listed
+/// files that don't exist (so have no size) are entry disagreements, as
+/// are paths that are absolute or lead out of the bag.
+pub fn validate_bag(bag: &str, mut writable: impl Write, num_vs: u8)
+        -> Result<BytesComparison, Error> {
+    let bag = Path::new(bag);
+    let bagit_txt = fs::read_to_string(bag.join("bagit.txt")).map_err(|_| {
+        Error::other(bag.display().to_string() + " isn't a bag: there's no bagit.txt")
+    })?;
+    if !tag_values(&bagit_txt).iter().any(|(label, _)| label == "BagIt-Version") {
+        return Err(Error::other("bagit.txt has no BagIt-Version"));
+    }
+
+    /* Every manifest-<algorithm>.txt and tagmanifest-<algorithm>.txt, by
+     * the file each hash is of */
+    let mut payload_hashes: BTreeMap<String, Vec<(Algorithm, String)>> = BTreeMap::new();
+    let mut tag_hashes: BTreeMap<String, Vec<(Algorithm, String)>> = BTreeMap::new();
+    let mut num_payload_manifests = 0;
+    let mut num_outside = 0;
+    let mut names = fs::read_dir(bag)?.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect::<Vec<_>>();
+    names.sort();
+    for name in &names {
+        let (hashes, algorithm_s) = if let Some(rest) = name.strip_prefix("tagmanifest-") {
+            (&mut tag_hashes, rest)
+        }
+        else if let Some(rest) = name.strip_prefix("manifest-") {
+            (&mut payload_hashes, rest)
+        }
+        else {
+            continue;
+        };
+        let algorithm = match algorithm_s.strip_suffix(".txt")
+                .map(Algorithm::from_name) {
+            Some(Ok(algorithm)) => algorithm,
+            _ => {
+                writeln!(writable, "Can't check {}, which uses a hash confidence doesn't know.",
+                        name)?;
+                continue;
+            }
+        };
+        if name.starts_with("manifest-") {
+            num_payload_manifests += 1;
+        }
+        if num_vs > 1 {
+            writeln!(writable, "Reading {}", name)?;
+        }
+        for (path, hash) in read_bag_manifest(&bag.join(name))? {
+
+            /* An absolute path or one that climbs out is never in the bag */
+            match tidied(Path::new(&path)) {
+                Some(relative) => {
+                    hashes.entry(relative.to_string_lossy().into_owned())
+                            .or_default().push((algorithm, hash));
+                },
+                None => {
+                    writeln!(writable, "Disagreement (0 bytes): {} lists {}, which isn't in the bag.",
+                            name, path)?;
+                    num_outside += 1;
+                }
+            }
+        }
+    }
+    if num_payload_manifests == 0 {
+        return Err(Error::other(bag.display().to_string() +
+                " has no payload manifest confidence can check."));
+    }
+
+    let mut to_return = BytesComparison{entry_disagreement: num_outside,
+            ..Default::default()};
+
+    /* Complete: every payload file is in every payload manifest */
+    let payload = files_under(&bag.join(DATA), bag)?;
+    for path in &payload {
+        let num_manifests = payload_hashes.get(path).map_or(0, |hashes| hashes.len());
+        if num_manifests < num_payload_manifests {
+            let size = fs::metadata(bag.join(path))?.len() as usize;
+            writeln!(writable, "Disagreement ({} bytes): {} isn't in every payload manifest.",
+                    size, path)?;
+            to_return += BytesComparison{disagreement: size, ..Default::default()};
+            payload_hashes.remove(path);
+        }
+    }
+
+    /* Valid: every hash of every listed file matches */
+    for (path, hashes) in payload_hashes.iter().chain(&tag_hashes) {
+        let full_path = bag.join(path);
+        if !full_path.is_file() {
+            writeln!(writable, "Disagreement (0 bytes): {} is in a manifest but isn't in the bag.",
+                    path)?;
+            to_return += BytesComparison{entry_disagreement: 1, ..Default::default()};
+            continue;
+        }
+        let algorithms = hashes.iter().map(|(algorithm, _)| *algorithm)
+                .collect::<Vec<_>>();
+        let (actual, num_bytes_hashed) = match hashes_of_path_with(&full_path,
+                &algorithms) {
+            Ok(hashes_and_size) => hashes_and_size,
+            Err(error) => {
+                to_return += unhashable(&full_path, &error, &mut writable)?;
+                continue;
+            }
+        };
+        let wrong = hashes.iter().zip(&actual)
+                .filter(|((_, expected), actual)| expected != *actual)
+                .map(|((algorithm, _), _)| algorithm.name())
+                .collect::<BTreeSet<_>>();
+        if wrong.is_empty() {
+            if num_vs > 1 {
+                writeln!(writable, "{}: OK", path)?;
+            }
+            to_return += BytesComparison{agreement: num_bytes_hashed,
+                    ..Default::default()};
+        }
+        else {
+            writeln!(writable, "Disagreement ({} bytes): {} has the wrong {}.",
+                    num_bytes_hashed, path,
+                    wrong.into_iter().collect::<Vec<_>>().join(" and "))?;
+            to_return += BytesComparison{disagreement: num_bytes_hashed,
+                    ..Default::default()};
+        }
+    }
+
+    /* Payload-Oxum is a quick check for completeness, but it has to add
+     * up too */
+    if let Ok(bag_info) = fs::read_to_string(bag.join("bag-info.txt")) {
+        if let Some((_, oxum)) = tag_values(&bag_info).into_iter()
+                .find(|(label, _)| label == "Payload-Oxum") {
+            let num_bytes = payload.iter()
+                    .filter_map(|path| fs::metadata(bag.join(path)).ok())
+                    .map(|metadata| metadata.len()).sum::<u64>();
+            let actual = format!("{}.{}", num_bytes, payload.len());
+            if oxum != actual {
+                writeln!(writable, "Disagreement (0 bytes): the Payload-Oxum is {}, but the payload is {}.",
+                        oxum, actual)?;
+                to_return += BytesComparison{entry_disagreement: 1,
+                        ..Default::default()};
+            }
+        }
+    }
+
+    let num_bytes = to_return.agreement + to_return.disagreement;
+    to_return.write_summary(&mut writable, num_bytes)?;
+    writeln!(writable, "{} is {}.", bag.display(),
+            if to_return.disagrees() { "not a valid bag" } else { "a valid bag" })?;
+    Ok(to_return)
+}
@@ -1,20 +1,24 @@
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, SubCommand};
 
 fn main() {
     let matches = App::new("confidence").version("0.1.0")
             .author("John Baber-Lucero <cargo@frundle.com>")
             .about("Given <directory-two>, compare the files in both directories.  Given only <directory-one>, output a file full of hashes to compare with some directory in future.")
+            .setting(AppSettings::SubcommandsNegateReqs)
             .arg(Arg::with_name("ignore-permission-errors")
+                    .global(true)
                     .short("i")
                     .long("ignore-permission-errors")
                     .help("Ignore errors so you can skip files you don't have permission to read. Useful for examining everything on a drive that your non-root user can see.")
                     .takes_value(false)
             ).arg(Arg::with_name("progress")
+                    .global(true)
                     .short("p")
                     .long("progress")
                     .help("Print progress bar to stderr")
                     .takes_value(false)
             ).arg(Arg::with_name("verbosity")
+                    .global(true)
                     .short("v")
                     .multiple(true)
                     .help("verbosity level (0 - 3 v's)")
@@ -38,14 +42,388 @@ fn main() {
                     .short("S")
                     .long("find-size")
                     .takes_value(false)
+            ).arg(Arg::with_name("tree-hashes")
+                    .short("t")
+                    .long("tree-hashes")
+                    .takes_value(false)
+                    .conflicts_with("directory-two")
+                    .help("When outputting hashes, also output a hash for every directory built from the hashes of its contents, and a fingerprint of the whole tree before the \"bytes hashed\" line.  Only manifest-diff uses these to skip directories that agree; verifying still checks every file.")
+            ).arg(Arg::with_name("stat-columns")
+                    .long("stat-columns")
+                    .takes_value(false)
+                    .conflicts_with("directory-two")
+                    .help("When outputting hashes, also output each file's mtime and inode, so `update` can tell which files haven't changed.  Manifests with these columns can't be read by confidence 1.0.")
+            ).arg(Arg::with_name("include")
+                    .global(true)
+                    .long("include")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Only look at files whose path (relative to <directory-one>) matches this glob.  May be repeated.")
+            ).arg(Arg::with_name("exclude")
+                    .global(true)
+                    .long("exclude")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Skip files and whole directories whose path (relative to <directory-one>) matches this glob.  May be repeated.")
+            ).arg(Arg::with_name("min-size")
+                    .global(true)
+                    .long("min-size")
+                    .takes_value(true)
+                    .help("Skip files smaller than this many bytes.  Accepts K, M, G and T suffixes.")
+            ).arg(Arg::with_name("max-size")
+                    .global(true)
+                    .long("max-size")
+                    .takes_value(true)
+                    .help("Skip files larger than this many bytes.  Accepts K, M, G and T suffixes.")
+            ).arg(Arg::with_name("newer-than")
+                    .global(true)
+                    .long("newer-than")
+                    .takes_value(true)
+                    .help("Skip files modified before this time: YYYY-MM-DD, @seconds-since-epoch or a length of time ago like 7d")
+            ).arg(Arg::with_name("older-than")
+                    .global(true)
+                    .long("older-than")
+                    .takes_value(true)
+                    .help("Skip files modified at or after this time: YYYY-MM-DD, @seconds-since-epoch or a length of time ago like 7d")
+            ).arg(Arg::with_name("max-depth")
+                    .global(true)
+                    .long("max-depth")
+                    .takes_value(true)
+                    .help("Don't descend more than this many directories below <directory-one>")
+            ).arg(Arg::with_name("one-file-system")
+                    .global(true)
+                    .long("one-file-system")
+                    .takes_value(false)
+                    .help("Don't cross filesystem boundaries")
+            ).arg(Arg::with_name("gitignore")
+                    .global(true)
+                    .long("gitignore")
+                    .takes_value(false)
+                    .help("Honor .gitignore files as well as .confidenceignore files")
+            ).arg(Arg::with_name("no-ignore-files")
+                    .global(true)
+                    .long("no-ignore-files")
+                    .takes_value(false)
+                    .conflicts_with("gitignore")
+                    .help("Don't honor .confidenceignore (or .gitignore) files")
+            ).arg(Arg::with_name("cache")
+                    .global(true)
+                    .long("cache")
+                    .takes_value(true)
+                    .help("SQLite file remembering hashes between runs.  A file's cached hash is used as long as its device, inode, size, mtime and ctime haven't changed.")
+            ).arg(Arg::with_name("verify-cache")
+                    .global(true)
+                    .long("verify-cache")
+                    .takes_value(false)
+                    .help("Read files anyway, and count a cached hash that turns out to be wrong as a disagreement.  Otherwise cached hashes are trusted.")
+            ).arg(Arg::with_name("time-budget")
+                    .long("time-budget")
+                    .takes_value(true)
+                    .requires_all(&["input", "history"])
+                    .help("When comparing to a file full of hashes, stop after about this long (e.g. 4h), verifying the files checked least recently first.  Needs --history to remember what was checked.")
+            ).arg(Arg::with_name("history")
+                    .long("history")
+                    .takes_value(true)
+                    .help("SQLite file remembering when each file was last verified, for --time-budget")
+            ).arg(Arg::with_name("coverage-days")
+                    .long("coverage-days")
+                    .takes_value(true)
+                    .requires("time-budget")
+                    .help("With --time-budget, report how much has been verified within this many days (default 30)")
+            ).arg(Arg::with_name("repair")
+                    .long("repair")
+                    .takes_value(false)
+                    .requires("directory-two")
+                    .help("List what it would take to make <directory-two> a copy of <directory-one>: copying missing and differing files (and deleting extra ones, with --delete-extra).  Nothing is changed without --execute.")
+            ).arg(Arg::with_name("execute")
+                    .long("execute")
+                    .takes_value(false)
+                    .requires("repair")
+                    .help("With --repair, actually copy (and delete).  Each copy is read back before it replaces anything.")
+            ).arg(Arg::with_name("delete-extra")
+                    .long("delete-extra")
+                    .takes_value(false)
+                    .requires("repair")
+                    .help("With --repair, also delete whatever is only in <directory-two>")
+            ).arg(Arg::with_name("parity")
+                    .long("parity")
+                    .takes_value(true)
+                    .conflicts_with_all(&["directory-two", "input", "find-size"])
+                    .help("When outputting hashes, also write Reed-Solomon recovery data for every file to this file, for the parity-repair subcommand")
+            ).arg(Arg::with_name("redundancy")
+                    .long("redundancy")
+                    .takes_value(true)
+                    .requires("parity")
+                    .help("Parity blocks per hundred blocks of data for --parity (default 10)")
+            ).arg(Arg::with_name("fixup")
+                    .long("fixup")
+                    .takes_value(true)
+                    .help("When comparing, write what it would take to fix whatever is missing, different or extra to this file: a shell script of cp commands to review, or (with --fixup-format rsync) lists for rsync.  Not for archives or disk images")
+            ).arg(Arg::with_name("fixup-format")
+                    .long("fixup-format")
+                    .takes_value(true)
+                    .possible_values(&["script", "rsync"])
+                    .requires("fixup")
+                    .help("'script' (the default) or 'rsync', which writes NUL separated lists for `rsync --files-from=FILE -0` to FILE.missing, FILE.differing and FILE.extra")
             ).arg(Arg::with_name("directory-one")
+                    .help("A directory, a .tar, .tar.gz, .tar.zst, .tar.xz or .zip archive, or an .iso (ISO9660, with Rock Ridge or Joliet names) or .squashfs/.sqfs image, to read as one")
                     .required(true)
                     .index(1)
             ).arg(Arg::with_name("directory-two")
-                    .help("If present, we'll just directly compare <directory-one> and <directory-two>")
+                    .help("If present, we'll just directly compare <directory-one> and <directory-two>.  Either can be an archive or disk image; one whose contents are all in a directory named after the other side (as `tar czf backup.tgz name` makes) is compared to what's in it.")
                     .required(false)
                     .index(2)
                     .conflicts_with("output")
+            ).subcommand(SubCommand::with_name("manifest-diff")
+                    .about("Compare two files full of hashes without looking at the directories they came from.  Reports added, removed, modified and moved files.")
+                    .arg(Arg::with_name("old-manifest")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("new-manifest")
+                            .required(true)
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("update")
+                    .about("Output a fresh file full of hashes for <directory>, only re-hashing files whose size, mtime or inode changed since <old-manifest> was made.  That needs <old-manifest> to have mtime and inode columns (see --stat-columns); without them every file is hashed again")
+                    .arg(Arg::with_name("output")
+                            .short("o")
+                            .long("output-filename")
+                            .takes_value(true)
+                            .help("File to output hashes to.  Defaults to STDOUT")
+                    ).arg(Arg::with_name("rehash-fraction")
+                            .long("rehash-fraction")
+                            .takes_value(true)
+                            .help("Fraction (0 to 1) of unchanged files to re-hash anyway, chosen at random, to catch bit rot")
+                    ).arg(Arg::with_name("stat-columns")
+                            .long("stat-columns")
+                            .takes_value(false)
+                            .help("Output each file's mtime and inode even if <old-manifest> didn't have them, so the next update can reuse hashes")
+                    ).arg(Arg::with_name("old-manifest")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("tag")
+                    .about("Store the sha256 of every file in <directory> in its extended attributes, along with its mtime, the way shatag does")
+                    .arg(Arg::with_name("namespace")
+                            .long("namespace")
+                            .takes_value(true)
+                            .possible_values(&["shatag", "confidence"])
+                            .default_value("shatag")
+                            .help("Use user.shatag.* attributes (readable by shatag) or user.confidence.* ones")
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("check-tags")
+                    .about("Re-hash every file in <directory> and compare with the hash stored in its extended attributes by `tag` (or shatag)")
+                    .arg(Arg::with_name("namespace")
+                            .long("namespace")
+                            .takes_value(true)
+                            .possible_values(&["shatag", "confidence"])
+                            .default_value("shatag")
+                            .help("Read user.shatag.* attributes or user.confidence.* ones")
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("scrub")
+                    .about("Re-hash every file in <directory> and compare with the previous scrub, remembered in a history file.  Tells files that were edited (mtime changed) from ones that silently rotted.")
+                    .arg(Arg::with_name("history")
+                            .long("history")
+                            .takes_value(true)
+                            .required(true)
+                            .help("SQLite file holding every file's hash, size, mtime and when it was last verified")
+                    ).arg(Arg::with_name("forget-missing")
+                            .long("forget-missing")
+                            .takes_value(false)
+                            .help("Drop files that are gone from the history instead of reporting them")
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("copy")
+                    .about("Copy <source> to <destination>, hashing every file on the way and again once it's safely on disk, so the source only has to be read once")
+                    .arg(Arg::with_name("output")
+                            .short("o")
+                            .long("output-filename")
+                            .takes_value(true)
+                            .help("File to output hashes of <source> to, for comparing to later")
+                    ).arg(Arg::with_name("bypass-page-cache")
+                            .long("bypass-page-cache")
+                            .takes_value(false)
+                            .help("Have the kernel drop each copy from its page cache before reading it back, so it's read from the disk")
+                    ).arg(Arg::with_name("source")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("destination")
+                            .required(true)
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("vote")
+                    .about("Compare three or more replicas (directories, or files full of hashes) path by path.  Where most of them agree, the rest are named as likely corrupt.")
+                    .arg(Arg::with_name("replicas")
+                            .required(true)
+                            .multiple(true)
+                            .min_values(3)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("parity-repair")
+                    .about("Find damaged blocks of the files in <directory> using a file written with --parity, rebuild them in place and check the result")
+                    .arg(Arg::with_name("force")
+                            .long("force")
+                            .takes_value(false)
+                            .help("Repair files even if they were modified after the parity file was made (which undoes the modifications)")
+                    ).arg(Arg::with_name("parity-file")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("check-sums")
+                    .about("Check the files listed in a sha256sum/md5sum/b2sum style checksum file (plain or --tag style).  Paths are relative to <directory>, or else to wherever <sum-file> is.")
+                    .arg(Arg::with_name("algorithm")
+                            .long("algorithm")
+                            .takes_value(true)
+                            .possible_values(&["md5", "sha1", "sha256", "sha512", "blake2b"])
+                            .help("What plain lines were made with.  By default, it's guessed from the length of each hash.")
+                    ).arg(Arg::with_name("sum-file")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("directory")
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("check-sidecars")
+                    .about("Find every .sfv, .md5, .sha1, .sha256, .sha512, .b2 and MD5SUMS style file under <directory> and check the files each one lists, relative to its own directory.  Files no checksum file lists are reported too.")
+                    .arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("export-sums")
+                    .about("Output a sha256sum/md5sum/b2sum style checksum file for <source>, which is a directory or a file full of hashes (sha1 only)")
+                    .arg(Arg::with_name("algorithm")
+                            .long("algorithm")
+                            .takes_value(true)
+                            .possible_values(&["md5", "sha1", "sha256", "sha512", "blake2b"])
+                            .help("Defaults to sha256 for a directory and sha1 for a file full of hashes")
+                    ).arg(Arg::with_name("tag")
+                            .long("tag")
+                            .takes_value(false)
+                            .help("Write BSD style lines, e.g. 'SHA256 (path) = ...'")
+                    ).arg(Arg::with_name("output")
+                            .short("o")
+                            .long("output-filename")
+                            .takes_value(true)
+                            .help("File to output to instead of stdout")
+                    ).arg(Arg::with_name("source")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("hash")
+                    .about("Output a file full of hashes of everything in a tar stream, read in one pass, to compare with the directory it's restored to later (with -f)")
+                    .arg(Arg::with_name("tar")
+                            .long("tar")
+                            .takes_value(true)
+                            .required(true)
+                            .value_name("FILE")
+                            .help("The tar to read, or - for stdin.  It can be gzipped, zstd'd or xz'd.")
+                    ).arg(Arg::with_name("output")
+                            .short("o")
+                            .long("output-filename")
+                            .takes_value(true)
+                            .help("File to output to instead of stdout")
+                    )
+            ).subcommand(SubCommand::with_name("audit")
+                    .about("Audit <directory> against a hashdeep (or md5deep) manifest, the way hashdeep -a does: every file is matched, moved or new, and known files that weren't found are listed")
+                    .arg(Arg::with_name("hashdeep-file")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("export-hashdeep")
+                    .about("Output a hashdeep manifest (size, md5 and sha256 of every file) of <directory>")
+                    .arg(Arg::with_name("output")
+                            .short("o")
+                            .long("output-filename")
+                            .takes_value(true)
+                            .help("File to output to instead of stdout")
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("bag")
+                    .about("Make a BagIt bag (RFC 8493) of <source>.  With a <destination>, the payload is copied there and checked.  Without one, <source> is turned into a bag in place.")
+                    .arg(Arg::with_name("algorithm")
+                            .long("algorithm")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .possible_values(&["md5", "sha1", "sha256", "sha512"])
+                            .help("Write a manifest for this hash.  Can be given more than once.  Defaults to sha256.")
+                    ).arg(Arg::with_name("source")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("destination")
+                            .index(2)
+                    )
+            ).subcommand(SubCommand::with_name("check-bag")
+                    .about("Check that a BagIt bag is complete and every hash in its manifests matches")
+                    .arg(Arg::with_name("bag")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("mtree")
+                    .about("Output a BSD mtree spec (type, mode, uid, gid, size, sha256digest and link) of <directory>, or check <directory> against one with --verify")
+                    .arg(Arg::with_name("verify")
+                            .long("verify")
+                            .takes_value(true)
+                            .value_name("SPEC")
+                            .conflicts_with("output")
+                            .help("Check <directory> against this mtree spec, reporting every keyword that doesn't match")
+                    ).arg(Arg::with_name("output")
+                            .short("o")
+                            .long("output-filename")
+                            .takes_value(true)
+                            .help("File to output the spec to instead of stdout")
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("verify")
+                    .about("Check downloaded files in <directory> against the piece hashes of a .torrent file (v1, v2 or hybrid), without any network access")
+                    .arg(Arg::with_name("torrent")
+                            .long("torrent")
+                            .takes_value(true)
+                            .required(true)
+                            .value_name("FILE")
+                            .help("The .torrent file")
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(1)
+                    )
+            ).subcommand(SubCommand::with_name("compare-git")
+                    .about("Check that <directory> is exactly <rev> of the git repository <repo>: every file has to have the same blob hash, and none can be missing or extra")
+                    .arg(Arg::with_name("eol")
+                            .long("eol")
+                            .takes_value(false)
+                            .help("Undo the CRLFs git would have put in files checked out with .gitattributes text or eol set")
+                    ).arg(Arg::with_name("repo")
+                            .required(true)
+                            .index(1)
+                    ).arg(Arg::with_name("rev")
+                            .required(true)
+                            .index(2)
+                    ).arg(Arg::with_name("directory")
+                            .required(true)
+                            .index(3)
+                    )
             ).get_matches();
 
 
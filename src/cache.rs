@@ -0,0 +1,164 @@
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use std::fs;
+use std::fs::Metadata;
+use std::io::Error;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+
+/// How much to believe what's in a `HashCache`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Use a cached hash instead of reading the file
+    Trust,
+
+    /// Read the file anyway, and count it as a disagreement if the cached
+    /// hash is wrong
+    Verify,
+}
+
+
+/// Hashes of files remembered between runs, so unchanged files needn't be
+/// read again.  Kept in a SQLite file, keyed by device and inode.
+///
+/// A cached hash only counts if the file's size, mtime and ctime are the
+/// same as when it was hashed.  Anything that writes to a file changes
+/// its ctime, so a stale entry is noticed (and replaced) automatically.
+pub struct HashCache {
+    connection: Connection,
+    mode: CacheMode,
+}
+
+
+/// Wrap anything SQLite complains about as an io::Error
+pub fn sqlite_error(error: rusqlite::Error) -> Error {
+    Error::other(error)
+}
+
+
+impl HashCache {
+
+    /// Open (or create) the cache in `filename`
+    pub fn open(filename: &str, mode: CacheMode) -> Result<HashCache, Error> {
+        let connection = Connection::open(filename).map_err(sqlite_error)?;
+        connection.execute_batch("
+                PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = NORMAL;
+                CREATE TABLE IF NOT EXISTS hashes (
+                    device INTEGER NOT NULL,
+                    inode INTEGER NOT NULL,
+                    algorithm TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    mtime INTEGER NOT NULL,
+                    mtime_nsec INTEGER NOT NULL,
+                    ctime INTEGER NOT NULL,
+                    ctime_nsec INTEGER NOT NULL,
+                    digest TEXT NOT NULL,
+                    PRIMARY KEY (device, inode, algorithm)
+                );").map_err(sqlite_error)?;
+        Ok(HashCache{connection, mode})
+    }
+
+
+    pub fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+
+    /// The cached `algorithm` hash of the file described by `metadata`,
+    /// if there is one and the file hasn't changed since.
+    pub fn lookup(&self, metadata: &Metadata, algorithm: &str)
+            -> Result<Option<String>, Error> {
+        self.connection.query_row("
+                SELECT digest FROM hashes
+                WHERE device = ?1 AND inode = ?2 AND algorithm = ?3
+                        AND size = ?4 AND mtime = ?5 AND mtime_nsec = ?6
+                        AND ctime = ?7 AND ctime_nsec = ?8",
+                params![metadata.dev() as i64, metadata.ino() as i64,
+                        algorithm, metadata.len() as i64, metadata.mtime(),
+                        metadata.mtime_nsec(), metadata.ctime(),
+                        metadata.ctime_nsec()],
+                |row| row.get(0)).optional().map_err(sqlite_error)
+    }
+
+
+    /// Remember that the file described by `metadata` (taken before it
+    /// was read) hashes to `digest`
+    pub fn store(&self, metadata: &Metadata, algorithm: &str, digest: &str)
+            -> Result<(), Error> {
+        self.connection.execute("
+                INSERT OR REPLACE INTO hashes (device, inode, algorithm,
+                        size, mtime, mtime_nsec, ctime, ctime_nsec, digest)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![metadata.dev() as i64, metadata.ino() as i64,
+                        algorithm, metadata.len() as i64, metadata.mtime(),
+                        metadata.mtime_nsec(), metadata.ctime(),
+                        metadata.ctime_nsec(), digest])
+                .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+
+    /// `store` each of `hashes` (metadata and digest) at once, so either
+    /// all of them are remembered or none are
+    pub fn store_all(&self, algorithm: &str, hashes: &[(&Metadata, &str)])
+            -> Result<(), Error> {
+        let transaction = self.connection.unchecked_transaction()
+                .map_err(sqlite_error)?;
+        for (metadata, digest) in hashes {
+            self.store(metadata, algorithm, digest)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+
+
+    /// Write out a disagreement if what's cached for the file at `path`
+    /// isn't the `digest` it was just found to have, which means it
+    /// changed without its size, mtime or ctime changing.  Returns how
+    /// many bytes disagree.
+    pub fn check(&self, path: &Path, metadata: &Metadata, algorithm: &str,
+            digest: &str, writable: &mut impl Write) -> Result<usize, Error> {
+        match self.lookup(metadata, algorithm)? {
+            Some(ref cached) if cached != digest => {
+                writeln!(writable, "Disagreement ({} bytes): The cached hash of {} is wrong.  It changed without its size, mtime or ctime changing.",
+                        metadata.len(), path.display())?;
+                Ok(metadata.len() as usize)
+            },
+            _ => Ok(0),
+        }
+    }
+}
+
+
+/// Same as `hash_of_path`, but consults `cache` first (if there is one)
+/// and remembers whatever had to be hashed.  Also returns how many bytes
+/// disagree because a verified cache turned out to be wrong (see `check`),
+/// which is written to `writable`.
+pub fn hash_of_path_cached(path: &Path, cache: &Option<HashCache>,
+        writable: &mut impl Write) -> Result<(String, usize, usize), Error> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => {
+            let (digest, num_bytes_hashed) = crate::hash_of_path(path)?;
+            return Ok((digest, num_bytes_hashed, 0));
+        }
+    };
+
+    /* Stat first, so a file that changes while it's being hashed doesn't
+     * get cached as unchanged */
+    let metadata = fs::metadata(path)?;
+    if cache.mode == CacheMode::Trust {
+        if let Some(digest) = cache.lookup(&metadata, "sha1")? {
+            return Ok((digest, metadata.len() as usize, 0));
+        }
+    }
+
+    let (digest, num_bytes_hashed) = crate::hash_of_path(path)?;
+    let num_bytes_wrong = cache.check(path, &metadata, "sha1", &digest,
+            writable)?;
+    cache.store(&metadata, "sha1", &digest)?;
+    Ok((digest, num_bytes_hashed, num_bytes_wrong))
+}
@@ -0,0 +1,234 @@
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::hash_path_entry;
+use crate::manifest::EntryKind;
+use crate::manifest::ManifestEntry;
+use crate::output_progress;
+use crate::relative_path_string;
+use crate::special;
+use crate::BytesComparison;
+use indicatif::ProgressBar;
+use std::fs;
+use std::fs::File;
+use std::fs::FileType;
+use std::fs::OpenOptions;
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+
+/// Copy `source` to `destination`, hashing as it goes, and the result
+/// back out of `destination`, as a plain sha1 hex string.  `destination`
+/// has to be new, so nothing is written into a file (or through a
+/// symlink) that's already there.  It's fsynced once it has its
+/// permissions and mtime.
+pub(crate) fn copy_and_hash(source: &Path, destination: &Path,
+        progress_bar: &Option<ProgressBar>) -> Result<(String, usize), Error> {
+    let mut hasher = Algorithm::Sha1.hasher();
+    let mut file_l = File::open(source)?;
+    let mut file_r = OpenOptions::new().write(true).create_new(true)
+            .custom_flags(libc::O_NOFOLLOW).open(destination)?;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut num_bytes_copied = 0;
+    loop {
+        let num_bytes_read = file_l.read(&mut buffer[..])?;
+        if num_bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..num_bytes_read]);
+        file_r.write_all(&buffer[..num_bytes_read])?;
+        num_bytes_copied += num_bytes_read;
+        output_progress(num_bytes_read as u64, progress_bar);
+    }
+
+    let metadata_l = file_l.metadata()?;
+    file_r.set_permissions(metadata_l.permissions())?;
+    file_r.set_modified(metadata_l.modified()?)?;
+    file_r.sync_all()?;
+    Ok((hasher.hex_digest(), num_bytes_copied))
+}
+
+
+/// Somewhere next to `path` to copy to before renaming over it, with
+/// whatever was left there last time out of the way
+pub(crate) fn temporary_for(path: &Path, suffix: &str) -> Result<PathBuf, Error> {
+    let mut temporary_name = path.file_name().unwrap_or_default().to_owned();
+    temporary_name.push(suffix);
+    let temporary = path.with_file_name(temporary_name);
+    make_way(&temporary, |_| false)?;
+    Ok(temporary)
+}
+
+
+/// `copy_and_hash` to `temporary`, which is removed if that fails partway
+pub(crate) fn copy_to_temporary(source: &Path, temporary: &Path,
+        progress_bar: &Option<ProgressBar>) -> Result<(String, usize), Error> {
+    copy_and_hash(source, temporary, progress_bar).inspect_err(|_| {
+        let _ = fs::remove_file(temporary);
+    })
+}
+
+
+/// Remove whatever is at `path` unless `keep` says it's already the right
+/// type of thing, so nothing new is written through a symlink or refused
+/// because of a directory
+pub(crate) fn make_way(path: &Path, keep: impl Fn(&FileType) -> bool)
+        -> Result<(), Error> {
+    let file_type = match path.symlink_metadata() {
+        Ok(metadata) => metadata.file_type(),
+        Err(_) => {
+            return Ok(());
+        }
+    };
+    if keep(&file_type) {
+        Ok(())
+    }
+    else if file_type.is_dir() {
+        fs::remove_dir_all(path)
+    }
+    else {
+        fs::remove_file(path)
+    }
+}
+
+
+/// fsync the directory `path` is in, so a file created or renamed there
+/// survives a crash along with its contents
+pub(crate) fn sync_parent(path: &Path) -> Result<(), Error> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            File::open(parent)?.sync_all()
+        },
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+
+/// Hash `path` from the disk rather than whatever the page cache kept
+/// from writing it, as far as the kernel lets us
+fn hash_bypassing_cache(path: &Path) -> Result<(String, usize), Error> {
+    let file = File::open(path)?;
+
+    /* The copy was fsynced, so its pages are clean and can be dropped */
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    crate::algorithm::hash_of_path_with(path, Algorithm::Sha1)
+}
+
+
+/// Copy everything under `source` to `destination` (creating it if need
+/// be), hashing each file on the way, fsyncing it, and hashing it again
+/// out of `destination` to be sure the copy is good.  With
+/// `bypass_page_cache`, the page cache is asked to forget the copy first,
+/// so it's really read back from the disk.
+///
+/// Symlinks are recreated, not followed, and whatever's in the way of
+/// the copy is replaced.  Special files can't be copied, so each is an
+/// entry disagreement.  A manifest of `source`, as `hash_path`
+/// would write it, goes to `manifest` and the results to `writable`.
+#[allow(clippy::too_many_arguments)]
+pub fn copy_tree(source: &str, destination: &str, mut manifest: impl Write,
+        mut writable: impl Write, num_vs: u8, progress: bool, filter: &Filter,
+        ignore_perm_errors_flag: bool, bypass_page_cache: bool)
+                -> Result<BytesComparison, Error> {
+    let progress_bar = if progress {
+        let num_bytes = filter.walk(source).filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_file())
+                .filter_map(|entry| entry.metadata().ok())
+                .map(|metadata| metadata.len()).sum();
+        Some(ProgressBar::new(num_bytes))
+    }
+    else {
+        None
+    };
+
+    let mut to_return = BytesComparison::default();
+    let (mut num_files, mut num_bytes, mut num_bytes_listed) = (0, 0, 0);
+    for entry in filter.walk_entries(source, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let path_l = entry.path();
+        let relative = relative_path_string(path_l, source)?;
+        let path_r = Path::new(destination).join(&relative);
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+
+            /* `destination` itself may well be a symlink to a directory */
+            if !relative.is_empty() {
+                make_way(&path_r, |file_type| file_type.is_dir())?;
+            }
+            fs::create_dir_all(&path_r)?;
+            sync_parent(&path_r)?;
+            continue;
+        }
+        if file_type.is_symlink() {
+            make_way(&path_r, |_| false)?;
+            std::os::unix::fs::symlink(fs::read_link(path_l)?, &path_r)?;
+            sync_parent(&path_r)?;
+            if num_vs > 0 {
+                writeln!(writable, "Linked {}", path_r.display())?;
+            }
+
+            /* Hashing follows symlinks, so the manifest does too */
+            if let Some(manifest_entry) = hash_path_entry(path_l, source,
+                    &mut manifest, 0, &None, &None, false)? {
+                num_bytes_listed += manifest_entry.size;
+            }
+            continue;
+        }
+        if let Some(kind) = special::special_kind_of(path_l) {
+            writeln!(writable, "Disagreement (0 bytes): Didn't copy {}, which is {}.",
+                    path_l.display(), kind)?;
+            to_return += BytesComparison{entry_disagreement: 1,
+                    ..Default::default()};
+            writeln!(manifest, "{}", ManifestEntry{kind: EntryKind::Special(kind),
+                    path: relative, size: 0, stat: None}.to_line())?;
+            continue;
+        }
+
+        /* Copied alongside and renamed into place, so a file that's there
+         * (and whatever it's hard linked with) is never written into, and
+         * a failed copy leaves it as it was */
+        let temporary = temporary_for(&path_r, ".confidence-copy")?;
+        let (hash_l, num_bytes_copied) = copy_to_temporary(path_l, &temporary,
+                &progress_bar)?;
+        make_way(&path_r, |file_type| !file_type.is_dir())?;
+        fs::rename(&temporary, &path_r)?;
+        sync_parent(&path_r)?;
+        let (hash_r, _) = if bypass_page_cache {
+            hash_bypassing_cache(&path_r)?
+        }
+        else {
+            crate::hash_of_path(&path_r)?
+        };
+        if hash_l == hash_r {
+            if num_vs > 0 {
+                writeln!(writable, "Copied {}", path_r.display())?;
+            }
+            to_return += BytesComparison{agreement: num_bytes_copied,
+                    ..Default::default()};
+        }
+        else {
+            writeln!(writable, "Disagreement ({} bytes): {} doesn't match {}, which it was copied from.",
+                    num_bytes_copied, path_r.display(), path_l.display())?;
+            to_return += BytesComparison{disagreement: num_bytes_copied,
+                    ..Default::default()};
+        }
+        writeln!(manifest, "{}", ManifestEntry{kind: EntryKind::File(hash_l),
+                path: relative, size: num_bytes_copied, stat: None}
+                .to_line())?;
+        num_files += 1;
+        num_bytes += num_bytes_copied;
+        num_bytes_listed += num_bytes_copied;
+    }
+    writeln!(manifest, "{} bytes hashed", num_bytes_listed)?;
+
+    writeln!(writable, "Copied {} files ({} bytes).", num_files, num_bytes)?;
+    to_return.write_summary(&mut writable, num_bytes)?;
+    Ok(to_return)
+}
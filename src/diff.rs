@@ -0,0 +1,214 @@
+use crate::manifest::read_manifest;
+use crate::manifest::EntryKind;
+use crate::manifest::ManifestEntry;
+use crate::merkle;
+use crate::BytesComparison;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Error;
+use std::io::Write;
+
+
+/// How one manifest differs from another, entry by entry.  Directories
+/// only show up indirectly, through what's in them.
+#[derive(Debug, Default)]
+pub struct ManifestDiff {
+    pub added: Vec<ManifestEntry>,
+    pub removed: Vec<ManifestEntry>,
+
+    /// Same path, different contents: (old, new)
+    pub modified: Vec<(ManifestEntry, ManifestEntry)>,
+
+    /// Same contents, different path: (old, new)
+    pub moved: Vec<(ManifestEntry, ManifestEntry)>,
+
+    /// Bytes in files that are the same in both
+    pub unchanged_bytes: usize,
+}
+
+
+/// Work out what changed between two manifests.  If both have tree hashes
+/// (see `merkle`), subtrees whose directory hashes agree aren't looked at
+/// any further.
+pub fn diff_manifests(old: &[ManifestEntry], new: &[ManifestEntry])
+        -> ManifestDiff {
+
+    /* Directories that are the same in both, so everything under them is */
+    let old_directories = old.iter().filter_map(|entry| match entry.kind {
+        EntryKind::Directory(ref hash) => Some((entry.path.as_str(), hash)),
+        _ => None,
+    }).collect::<HashMap<_, _>>();
+    let identical = new.iter().filter_map(|entry| match entry.kind {
+        EntryKind::Directory(ref hash) => {
+            if old_directories.get(entry.path.as_str()) == Some(&hash) {
+                Some((entry.path.as_str(), entry.size))
+            }
+            else {
+                None
+            }
+        },
+        _ => None,
+    }).collect::<HashMap<_, _>>();
+
+    let mut to_return = ManifestDiff::default();
+    if let Some(size) = identical.get(merkle::ROOT) {
+        to_return.unchanged_bytes = *size;
+        return to_return;
+    }
+    let in_identical = |entry: &&ManifestEntry| {
+        let mut cur = entry.path.as_str();
+        while !cur.is_empty() {
+            cur = merkle::split_parent(cur).0;
+            if identical.contains_key(cur) {
+                return true;
+            }
+        }
+        false
+    };
+    to_return.unchanged_bytes = identical.iter()
+            .filter(|(path, _)| {
+                let (parent, _) = merkle::split_parent(path);
+                !identical.contains_key(parent)
+            })
+            .map(|(_, size)| size).sum();
+
+    let not_directory = |entry: &&ManifestEntry| {
+        !matches!(entry.kind, EntryKind::Directory(_))
+    };
+    let old_entries = old.iter().filter(not_directory)
+            .filter(|entry| !in_identical(entry))
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect::<BTreeMap<_, _>>();
+    let new_entries = new.iter().filter(not_directory)
+            .filter(|entry| !in_identical(entry))
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect::<BTreeMap<_, _>>();
+
+    let mut removed = Vec::new();
+    for (path, old_entry) in &old_entries {
+        match new_entries.get(path) {
+            Some(new_entry) => {
+                if old_entry.kind == new_entry.kind &&
+                        old_entry.size == new_entry.size {
+                    to_return.unchanged_bytes += old_entry.size;
+                }
+                else {
+                    to_return.modified.push(((*old_entry).clone(),
+                            (*new_entry).clone()));
+                }
+            },
+            None => removed.push(*old_entry),
+        }
+    }
+    let added = new_entries.iter()
+            .filter(|(path, _)| !old_entries.contains_key(*path))
+            .map(|(_, entry)| *entry)
+            .collect::<Vec<_>>();
+
+    /* A removed file and an added file with the same contents were moved.
+     * Empty files all have the same contents, so they're never moves. */
+    let mut removed_by_contents = HashMap::new();
+    for old_entry in &removed {
+        if let EntryKind::File(ref hash) = old_entry.kind {
+            if old_entry.size > 0 {
+                removed_by_contents.entry((hash, old_entry.size))
+                        .or_insert_with(Vec::new).push(*old_entry);
+            }
+        }
+    }
+    let mut moved_from = HashSet::new();
+    for new_entry in added {
+        let moved = match new_entry.kind {
+            EntryKind::File(ref hash) => {
+                removed_by_contents.get_mut(&(hash, new_entry.size))
+                        .and_then(|candidates| candidates.pop())
+            },
+            _ => None,
+        };
+        match moved {
+            Some(old_entry) => {
+                moved_from.insert(old_entry.path.as_str());
+                to_return.moved.push((old_entry.clone(), new_entry.clone()));
+            },
+            None => to_return.added.push(new_entry.clone()),
+        }
+    }
+    to_return.removed = removed.into_iter()
+            .filter(|entry| !moved_from.contains(entry.path.as_str()))
+            .cloned().collect();
+    to_return.moved.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+
+    to_return
+}
+
+
+/// Compare two manifests produced by `hash_path` without looking at the
+/// trees they describe.  Writes out every added, removed, modified and
+/// moved entry and totals for each.
+pub fn manifest_diff(old_filename: &str, new_filename: &str,
+        mut writable: impl Write, num_vs: u8) -> Result<BytesComparison, Error> {
+    if num_vs > 1 {
+        writeln!(writable, "Reading {} and {}", old_filename, new_filename)?;
+    }
+    let (old, _) = read_manifest(old_filename)?;
+    let (new, _) = read_manifest(new_filename)?;
+    let diff = diff_manifests(&old, &new);
+
+    for entry in &diff.removed {
+        writeln!(writable, "Removed ({} bytes): {}", entry.size, entry.path)?;
+    }
+    for entry in &diff.added {
+        writeln!(writable, "Added ({} bytes): {}", entry.size, entry.path)?;
+    }
+    for (old_entry, new_entry) in &diff.modified {
+        match (&old_entry.kind, &new_entry.kind) {
+            (EntryKind::File(_), EntryKind::File(_)) => {
+                writeln!(writable, "Modified ({} -> {} bytes): {}",
+                        old_entry.size, new_entry.size, new_entry.path)?;
+            },
+            _ => {
+                writeln!(writable, "Modified ({} -> {} bytes): {} was {} and is {}",
+                        old_entry.size, new_entry.size, new_entry.path,
+                        describe(old_entry), describe(new_entry))?;
+            }
+        }
+    }
+    for (old_entry, new_entry) in &diff.moved {
+        writeln!(writable, "Moved ({} bytes): {} -> {}", new_entry.size,
+                old_entry.path, new_entry.path)?;
+    }
+
+    let removed_bytes = diff.removed.iter().map(|entry| entry.size).sum::<usize>();
+    let added_bytes = diff.added.iter().map(|entry| entry.size).sum::<usize>();
+    let modified_bytes = diff.modified.iter()
+            .map(|(old_entry, new_entry)| std::cmp::max(old_entry.size, new_entry.size))
+            .sum::<usize>();
+    let moved_bytes = diff.moved.iter().map(|(_, entry)| entry.size).sum::<usize>();
+    writeln!(writable, "{} removed ({} bytes)", diff.removed.len(), removed_bytes)?;
+    writeln!(writable, "{} added ({} bytes)", diff.added.len(), added_bytes)?;
+    writeln!(writable, "{} modified ({} bytes)", diff.modified.len(),
+            modified_bytes)?;
+    writeln!(writable, "{} moved ({} bytes)", diff.moved.len(), moved_bytes)?;
+    writeln!(writable, "{} bytes unchanged", diff.unchanged_bytes)?;
+
+    Ok(BytesComparison{
+        agreement: diff.unchanged_bytes + moved_bytes,
+        disagreement: removed_bytes + added_bytes + modified_bytes,
+        entry_disagreement: diff.moved.len() +
+                diff.modified.iter().filter(|(old_entry, new_entry)| {
+                    old_entry.size == 0 && new_entry.size == 0
+                }).count() +
+                diff.added.iter().chain(diff.removed.iter())
+                        .filter(|entry| entry.size == 0).count(),
+    })
+}
+
+
+fn describe(entry: &ManifestEntry) -> String {
+    match entry.kind {
+        EntryKind::File(_) => "a regular file".to_owned(),
+        EntryKind::Special(kind) => kind.to_string(),
+        EntryKind::Directory(_) => "a directory".to_owned(),
+    }
+}
@@ -0,0 +1,401 @@
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use ignore::gitignore::Gitignore;
+use ignore::gitignore::GitignoreBuilder;
+use ignore::Match;
+use crate::walk_error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::Metadata;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+use walkdir::DirEntry;
+use walkdir::WalkDir;
+
+
+/// Narrows down which entries of a tree are looked at.  The same filter
+/// is applied when hashing, when verifying a manifest and when comparing
+/// two trees so that all three agree on what the tree is.
+///
+/// Globs are matched against paths relative to the top of the tree, and
+/// `*` matches across `/`, so `*.o` excludes object files at any depth.
+/// Excluding a directory skips everything under it.  Includes, sizes and
+/// times only narrow down non-directories.
+///
+/// Any file named in `ignore_filenames` (`.confidenceignore`, maybe
+/// `.gitignore`) is read as gitignore rules for the directory it's in and
+/// everything below it, with deeper files taking precedence.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub includes: Option<GlobSet>,
+    pub excludes: Option<GlobSet>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub newer_than: Option<SystemTime>,
+    pub older_than: Option<SystemTime>,
+    pub max_depth: Option<usize>,
+    pub one_file_system: bool,
+    pub ignore_filenames: Vec<String>,
+
+    /// Rules from the ignore files of each directory seen so far
+    ignore_rules: RefCell<HashMap<PathBuf, Option<Gitignore>>>,
+}
+
+
+impl Filter {
+
+    /// `walk`, with the errors `walk_error` says to move past left out.
+    pub fn walk_entries<'a>(&'a self, root: &'a str,
+            ignore_perm_errors_flag: bool)
+            -> impl Iterator<Item=Result<DirEntry, Error>> + 'a {
+        self.walk(root).filter_map(move |entry| match entry {
+            Ok(entry) => Some(Ok(entry)),
+            Err(error) => walk_error(error, ignore_perm_errors_flag).map(Err),
+        })
+    }
+
+    /// Walk `root` in sorted order, skipping whatever this filter doesn't
+    /// want and never descending into excluded directories.
+    pub fn walk<'a>(&'a self, root: &'a str)
+            -> impl Iterator<Item=walkdir::Result<DirEntry>> + 'a {
+        let mut walker = WalkDir::new(root)
+                .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+                .same_file_system(self.one_file_system);
+        if let Some(max_depth) = self.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        walker.into_iter().filter_entry(move |entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            match entry.path().strip_prefix(root) {
+                Ok(relative) => {
+                    let is_dir = entry.file_type().is_dir();
+                    if self.is_ignored(Path::new(root), relative, is_dir) {
+                        return false;
+                    }
+                    let metadata = if self.looks_at_metadata() {
+                        entry.metadata().ok()
+                    }
+                    else {
+                        None
+                    };
+                    self.wants_relative(relative, is_dir, metadata.as_ref())
+                },
+                Err(_) => true,
+            }
+        })
+    }
+
+
+    /// Whether the entry `relative` (to the top of the tree) should be
+    /// looked at.  `metadata` is what the entry looks like now, if it
+    /// exists.  Used directly for walks, where parents are already
+    /// accounted for.
+    pub fn wants_relative(&self, relative: &Path, is_dir: bool,
+            metadata: Option<&Metadata>) -> bool {
+        if let Some(ref excludes) = self.excludes {
+            if excludes.is_match(relative) {
+                return false;
+            }
+        }
+        if is_dir {
+            return true;
+        }
+
+        if let Some(ref includes) = self.includes {
+            if !includes.is_match(relative) {
+                return false;
+            }
+        }
+
+        match metadata {
+            Some(metadata) if metadata.is_file() => {
+                self.wants_size(metadata.len()) && self.wants_mtime(metadata)
+            },
+            _ => true,
+        }
+    }
+
+
+    /// Whether a manifest entry of `size` bytes at `relative` should be
+    /// verified against `directory`.  Unlike a walk, nothing's been pruned
+    /// yet, so excluded parents, depth and the filesystem are checked too.
+    pub fn wants_manifest_entry(&self, directory: &str, relative: &str,
+            size: usize) -> bool {
+        self.wants_listed_entry(directory, relative, Some(size))
+    }
+
+
+    /// Same as `wants_manifest_entry`, for lists that don't say how big
+    /// their entries are (like checksum files).  Without a `size`, it's
+    /// judged by what's there now.
+    pub fn wants_listed_entry(&self, directory: &str, relative: &str,
+            size: Option<usize>) -> bool {
+        let relative = Path::new(relative);
+        if let Some(max_depth) = self.max_depth {
+            if relative.components().count() > max_depth {
+                return false;
+            }
+        }
+        if let Some(ref excludes) = self.excludes {
+            if relative.ancestors()
+                    .filter(|parent| parent.as_os_str() != "")
+                    .any(|parent| excludes.is_match(parent)) {
+                return false;
+            }
+        }
+        if let Some(ref includes) = self.includes {
+            if !includes.is_match(relative) {
+                return false;
+            }
+        }
+        if size.is_some_and(|size| !self.wants_size(size as u64)) {
+            return false;
+        }
+        let top = Path::new(directory);
+        if self.is_ignored(top, relative, false) || relative.ancestors()
+                .skip(1)
+                .filter(|parent| parent.as_os_str() != "")
+                .any(|parent| self.is_ignored(top, parent, true)) {
+            return false;
+        }
+
+        /* Times and devices can only be judged by what's there now.  A
+         * missing file is kept so it gets reported. */
+        let path = Path::new(directory).join(relative);
+        if let Ok(metadata) = fs::metadata(&path) {
+            if !self.wants_mtime(&metadata) ||
+                    (size.is_none() && !self.wants_size(metadata.len())) {
+                return false;
+            }
+            if self.one_file_system {
+                if let Ok(top) = fs::metadata(directory) {
+                    if top.dev() != metadata.dev() {
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+
+    /// Whether the ignore files in `top` and the directories between it
+    /// and `relative` say to ignore `relative`.  The closest ignore file
+    /// with an opinion wins, so it can re-include what a parent ignored.
+    pub fn is_ignored(&self, top: &Path, relative: &Path, is_dir: bool) -> bool {
+        if self.ignore_filenames.is_empty() {
+            return false;
+        }
+        let path = top.join(relative);
+        for parent in relative.ancestors().skip(1) {
+            let directory = top.join(parent);
+            let mut ignore_rules = self.ignore_rules.borrow_mut();
+            let rules = ignore_rules.entry(directory.clone())
+                    .or_insert_with(|| self.read_ignore_files(&directory));
+            if let Some(rules) = rules {
+                match rules.matched(&path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        false
+    }
+
+
+    /// Gitignore rules from whichever ignore files `directory` has
+    fn read_ignore_files(&self, directory: &Path) -> Option<Gitignore> {
+        let mut builder = GitignoreBuilder::new(directory);
+        let mut found_any = false;
+        for ignore_filename in &self.ignore_filenames {
+            let ignore_path = directory.join(ignore_filename);
+            if ignore_path.is_file() {
+                if let Some(error) = builder.add(&ignore_path) {
+                    eprintln!("Problem reading {}: {}", ignore_path.display(),
+                            error);
+                }
+                found_any = true;
+            }
+        }
+        if !found_any {
+            return None;
+        }
+        match builder.build() {
+            Ok(rules) => Some(rules),
+            Err(error) => {
+                eprintln!("Problem with rules in {}: {}", directory.display(),
+                        error);
+                None
+            }
+        }
+    }
+
+
+    fn looks_at_metadata(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() ||
+                self.newer_than.is_some() || self.older_than.is_some()
+    }
+
+
+    fn wants_size(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min_size| size >= min_size) &&
+                self.max_size.is_none_or(|max_size| size <= max_size)
+    }
+
+
+    fn wants_mtime(&self, metadata: &Metadata) -> bool {
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+        match metadata.modified() {
+            Ok(mtime) => {
+                self.newer_than.is_none_or(|newer_than| mtime >= newer_than) &&
+                        self.older_than.is_none_or(|older_than| mtime < older_than)
+            },
+            Err(_) => true,
+        }
+    }
+}
+
+
+/// Build a `GlobSet` out of `patterns`, or `None` if there aren't any
+pub fn glob_set(patterns: &[&str]) -> Result<Option<GlobSet>, Error> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            },
+            Err(error) => {
+                let err_s = "Bad glob '".to_owned() + pattern + "': " +
+                        &error.to_string();
+                return Err(Error::other(err_s));
+            }
+        }
+    }
+    match builder.build() {
+        Ok(glob_set) => Ok(Some(glob_set)),
+        Err(error) => Err(Error::other(error)),
+    }
+}
+
+
+/// Parse a number of bytes with an optional binary suffix: `10K`, `3M`,
+/// `2G`, `1T`
+pub fn parse_size(size_s: &str) -> Result<u64, Error> {
+    let (number, multiplier) = match size_s.chars().last() {
+        Some('K') | Some('k') => (&size_s[..size_s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&size_s[..size_s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&size_s[..size_s.len() - 1], 1 << 30),
+        Some('T') | Some('t') => (&size_s[..size_s.len() - 1], 1 << 40),
+        _ => (size_s, 1),
+    };
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(multiplier)) {
+        Some(num_bytes) => Ok(num_bytes),
+        None => {
+            let err_s = "Couldn't interpret '".to_owned() + size_s +
+                    "' as a number of bytes.";
+            Err(Error::other(err_s))
+        }
+    }
+}
+
+
+/// Parse a length of time like `90s`, `15m`, `4h`, `7d` or `2w`
+pub fn parse_duration(duration_s: &str) -> Result<Duration, Error> {
+    let (number, seconds_per) = match duration_s.chars().last() {
+        Some('s') => (&duration_s[..duration_s.len() - 1], 1),
+        Some('m') => (&duration_s[..duration_s.len() - 1], 60),
+        Some('h') => (&duration_s[..duration_s.len() - 1], 60 * 60),
+        Some('d') => (&duration_s[..duration_s.len() - 1], 24 * 60 * 60),
+        Some('w') => (&duration_s[..duration_s.len() - 1], 7 * 24 * 60 * 60),
+        _ => (duration_s, 1),
+    };
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(seconds_per)) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => {
+            let err_s = "Couldn't interpret '".to_owned() + duration_s +
+                    "' as a length of time.";
+            Err(Error::other(err_s))
+        }
+    }
+}
+
+
+/// Parse a point in time: a `YYYY-MM-DD` date (midnight UTC), `@` and
+/// seconds since the epoch, or a length of time ago like `7d`.
+pub fn parse_time(time_s: &str) -> Result<SystemTime, Error> {
+    if let Some(seconds) = time_s.strip_prefix('@') {
+        if let Some(time) = seconds.parse::<u64>().ok()
+                .and_then(|seconds| UNIX_EPOCH.checked_add(Duration::from_secs(seconds))) {
+            return Ok(time);
+        }
+    }
+
+    let pieces = time_s.split('-').collect::<Vec<_>>();
+    if pieces.len() == 3 {
+        if let (Ok(year), Ok(month), Ok(day)) = (pieces[0].parse::<i64>(),
+                pieces[1].parse::<i64>(), pieces[2].parse::<i64>()) {
+            if (1970..=9999).contains(&year) && (1..=12).contains(&month) &&
+                    (1..=31).contains(&day) {
+                let days = days_from_civil(year, month, day);
+                return Ok(UNIX_EPOCH +
+                        Duration::from_secs(days as u64 * 24 * 60 * 60));
+            }
+        }
+    }
+
+    /* Too long ago is as bad as not a length of time at all */
+    match parse_duration(time_s).ok().and_then(|ago| SystemTime::now().checked_sub(ago)) {
+        Some(time) => Ok(time),
+        None => {
+            let err_s = "Couldn't interpret '".to_owned() + time_s +
+                    "' as a date, @seconds or a length of time ago.";
+            Err(Error::other(err_s))
+        }
+    }
+}
+
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 +
+            day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+
+/// Inverse of `days_from_civil`: the year, month and day `days` after
+/// 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+            day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 -
+            year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
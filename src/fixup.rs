@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::Error;
+use std::io::Write;
+
+
+/// How to write out what it would take to fix disagreements
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FixupFormat {
+    /// A shell script of `cp --preserve=all` commands, to review and run
+    Script,
+
+    /// NUL separated lists of paths for `rsync --files-from=... -0`, one
+    /// file per section
+    Rsync,
+}
+
+
+/// Paths (relative to the tops of the trees) that disagreed, sorted into
+/// what needs copying over and what shouldn't be there at all
+#[derive(Clone, Debug)]
+pub struct Fixup {
+    /// Where good copies are, if known.  There's no such thing when
+    /// checking against a manifest.
+    pub source: Option<String>,
+    pub destination: String,
+    pub missing: Vec<String>,
+    pub differing: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+
+/// `string` in single quotes, safe to paste into sh
+fn quoted(string: &str) -> String {
+    "'".to_owned() + &string.replace('\'', "'\\''") + "'"
+}
+
+
+impl Fixup {
+    pub fn new(source: Option<&str>, destination: &str) -> Fixup {
+        Fixup{source: source.map(|source| source.to_owned()),
+                destination: destination.to_owned(), missing: Vec::new(),
+                differing: Vec::new(), extra: Vec::new()}
+    }
+
+
+    /// A shell script copying what's missing or different from `$SRC` to
+    /// `$DST`.  Deleting what's extra is left commented out.
+    pub fn write_script(&self, mut writable: impl Write) -> Result<(), Error> {
+        writeln!(writable, "#!/bin/sh")?;
+        writeln!(writable, "# Written by confidence.  Review before running.")?;
+        writeln!(writable, "set -e")?;
+        match self.source {
+            Some(ref source) => writeln!(writable, "SRC={}", quoted(source))?,
+
+            /* Nothing from the destination goes anywhere it'd be expanded */
+            None => writeln!(writable, "[ -n \"$SRC\" ] || {{ echo 'Set SRC to a good copy of '{} >&2; exit 1; }}",
+                    quoted(&self.destination))?,
+        }
+        writeln!(writable, "DST={}", quoted(&self.destination))?;
+
+        for (heading, paths) in &[("Missing", &self.missing),
+                ("Differing", &self.differing)] {
+            writeln!(writable)?;
+            writeln!(writable, "# {} ({})", heading, paths.len())?;
+            for path in paths.iter() {
+                if let Some((parent, _)) = path.rsplit_once('/') {
+                    writeln!(writable, "mkdir -p -- \"$DST\"/{}", quoted(parent))?;
+                }
+                writeln!(writable, "cp --preserve=all -- \"$SRC\"/{} \"$DST\"/{}",
+                        quoted(path), quoted(path))?;
+            }
+        }
+
+        writeln!(writable)?;
+        writeln!(writable, "# Extra ({}).  Uncomment to delete.", self.extra.len())?;
+        for path in &self.extra {
+            writeln!(writable, "# rm -r -- \"$DST\"/{}",
+                    quoted(path).replace('\n', "\n# "))?;
+        }
+        Ok(())
+    }
+
+
+    /// `<prefix>.missing`, `<prefix>.differing` and `<prefix>.extra`, each
+    /// a NUL separated list of paths
+    pub fn write_rsync_lists(&self, prefix: &str) -> Result<(), Error> {
+        for (suffix, paths) in &[("missing", &self.missing),
+                ("differing", &self.differing), ("extra", &self.extra)] {
+            let mut file = File::create(format!("{}.{}", prefix, suffix))?;
+            for path in paths.iter() {
+                file.write_all(path.as_bytes())?;
+                file.write_all(b"\0")?;
+            }
+        }
+        Ok(())
+    }
+
+
+    pub fn write(&self, filename: &str, format: FixupFormat)
+            -> Result<(), Error> {
+        match format {
+            FixupFormat::Script => self.write_script(File::create(filename)?),
+            FixupFormat::Rsync => self.write_rsync_lists(filename),
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::relative_path_string;
+use crate::special;
+use crate::unhashable;
+use crate::BytesComparison;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+use std::cmp;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+
+/// One line of `git ls-tree -r --long`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GitEntry {
+
+    /// e.g. 100644, 100755, 120000 (a symlink) or 160000 (a submodule)
+    pub mode: String,
+
+    /// The blob's hash
+    pub object: String,
+
+    /// 0 for submodules, which git doesn't give a size for
+    pub size: usize,
+    pub path: String,
+}
+
+
+impl GitEntry {
+    pub fn is_symlink(&self) -> bool {
+        self.mode == "120000"
+    }
+
+
+    pub fn is_submodule(&self) -> bool {
+        self.mode == "160000"
+    }
+
+
+    pub fn is_executable(&self) -> bool {
+        self.mode == "100755"
+    }
+}
+
+
+/// Run git in `repo` and return what it wrote to stdout
+fn git_output(repo: &str, args: &[&str]) -> Result<Vec<u8>, Error> {
+    let output = Command::new("git").arg("-C").arg(repo).args(args).output()?;
+    if !output.status.success() {
+        let err_s = "git ".to_owned() + &args.join(" ") + " failed: " +
+                String::from_utf8_lossy(&output.stderr).trim();
+        return Err(Error::other(err_s));
+    }
+    Ok(output.stdout)
+}
+
+
+/// Every blob (and submodule) in the tree of `rev` in the repository at
+/// `repo`.  `rev` can't be taken for an option, even if it starts with `-`.
+pub fn git_tree(repo: &str, rev: &str) -> Result<Vec<GitEntry>, Error> {
+    let output = git_output(repo, &["ls-tree", "-r", "-z", "--long",
+            "--full-tree", "--end-of-options", rev])?;
+    let mut to_return = Vec::new();
+    for record in output.split(|byte| *byte == 0).filter(|record| !record.is_empty()) {
+        let record = String::from_utf8(record.to_vec()).map_err(|_| {
+            Error::other("git ls-tree gave a path that isn't UTF-8")
+        })?;
+        let corrupt = || Error::other("Unexpected git ls-tree line: ".to_owned() + &record);
+        let (meta, path) = record.split_once('\t').ok_or_else(corrupt)?;
+        let fields = meta.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 4 {
+            return Err(corrupt());
+        }
+        to_return.push(GitEntry{mode: fields[0].to_owned(),
+                object: fields[2].to_owned(),
+                size: fields[3].parse().unwrap_or(0),
+                path: path.to_owned()});
+    }
+    Ok(to_return)
+}
+
+
+/// What git would call `contents` if it were committed
+pub fn blob_hash_of(contents: &[u8]) -> String {
+    let mut hasher = Algorithm::Sha1.hasher();
+    hasher.update(format!("blob {}\0", contents.len()).as_bytes());
+    hasher.update(contents);
+    hasher.hex_digest()
+}
+
+
+/// What a `.gitattributes` line says about `text`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Text {
+    Set,
+    Unset,
+    Auto,
+    Unspecified,
+}
+
+
+/// One line of a `.gitattributes` file, as far as line endings go
+struct AttributeRule {
+    directory: String,
+    matcher: GlobMatcher,
+    match_name_only: bool,
+    text: Option<Text>,
+    eol: Option<bool>,
+}
+
+
+impl AttributeRule {
+    fn matches(&self, path: &str) -> bool {
+        let relative = if self.directory.is_empty() {
+            path
+        }
+        else {
+            match path.strip_prefix(&self.directory)
+                    .and_then(|rest| rest.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => return false,
+            }
+        };
+        if self.match_name_only {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            self.matcher.is_match(name)
+        }
+        else {
+            self.matcher.is_match(relative)
+        }
+    }
+}
+
+
+/// Every rule from the `.gitattributes` files in `entries`, shallowest
+/// first so later rules win.  Only `text`, `eol` and `binary` are kept,
+/// and `[attr]` macros are ignored.
+fn attribute_rules(repo: &str, entries: &[GitEntry])
+        -> Result<Vec<AttributeRule>, Error> {
+    let mut attributes_files = entries.iter()
+            .filter(|entry| entry.path == ".gitattributes" ||
+                    entry.path.ends_with("/.gitattributes"))
+            .collect::<Vec<_>>();
+    attributes_files.sort_by_key(|entry| entry.path.matches('/').count());
+
+    let mut to_return = Vec::new();
+    for entry in attributes_files {
+        let directory = entry.path.strip_suffix(".gitattributes").unwrap_or("")
+                .trim_end_matches('/').to_owned();
+        let contents = git_output(repo, &["cat-file", "blob", &entry.object])?;
+        for line in String::from_utf8_lossy(&contents).lines() {
+            let mut fields = line.split_whitespace();
+            let pattern = match fields.next() {
+                Some(pattern) if !pattern.starts_with('#') &&
+                        !pattern.starts_with("[attr]") &&
+                        !pattern.starts_with('"') && !pattern.ends_with('/') => pattern,
+                _ => continue,
+            };
+            let (text, eol) = fields.fold((None, None), |(text, eol), field| {
+                match field {
+                    "text" => (Some(Text::Set), eol),
+                    "-text" | "binary" => (Some(Text::Unset), eol),
+                    "!text" => (Some(Text::Unspecified), eol),
+                    "text=auto" => (Some(Text::Auto), eol),
+                    "-eol" | "!eol" => (text, Some(false)),
+                    _ if field.starts_with("eol=") => (text, Some(true)),
+                    _ => (text, eol),
+                }
+            });
+            if text.is_none() && eol.is_none() {
+                continue;
+            }
+            let match_name_only = !pattern.contains('/');
+            let glob = GlobBuilder::new(pattern.trim_start_matches('/'))
+                    .literal_separator(true).build()
+                    .map_err(|error| Error::other(error.to_string()))?;
+            to_return.push(AttributeRule{directory: directory.clone(),
+                    matcher: glob.compile_matcher(), match_name_only, text,
+                    eol});
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// Whether git would turn CRLFs in `contents` (or at least the start of
+/// them) into LFs when committing `path`, going by `rules`
+fn is_normalized(rules: &[AttributeRule], path: &str, contents: &[u8]) -> bool {
+    let mut text = Text::Unspecified;
+    let mut eol = false;
+    for rule in rules.iter().filter(|rule| rule.matches(path)) {
+        text = rule.text.unwrap_or(text);
+        eol = rule.eol.unwrap_or(eol);
+    }
+    match text {
+        Text::Set => true,
+        Text::Unset => false,
+
+        /* git's own test for binary files */
+        Text::Auto => !contents[..cmp::min(contents.len(), 8000)].contains(&0),
+        Text::Unspecified => eol,
+    }
+}
+
+
+/// Read from `file` until `buffer` is full or there's nothing left
+fn fill(file: &mut File, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut num_bytes_read = 0;
+    while num_bytes_read < buffer.len() {
+        let num_bytes = file.read(&mut buffer[num_bytes_read..])?;
+        if num_bytes == 0 {
+            break;
+        }
+        num_bytes_read += num_bytes;
+    }
+    Ok(num_bytes_read)
+}
+
+
+/// Stream the file at `path` through what git would call it, taking it
+/// to be `size` bytes long (git has to be told up front).  CRLFs are
+/// turned into LFs on the way if `normalized` says so, given the start of
+/// the file.  Also returns how long it turned out to be, since the hash
+/// only means anything if that's `size`.
+fn blob_hash_of_file(path: &Path, size: usize,
+        normalized: impl Fn(&[u8]) -> bool) -> Result<(String, usize), Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Algorithm::Sha1.hasher();
+    hasher.update(format!("blob {}\0", size).as_bytes());
+    let mut buffer = vec![0; 64 * 1024];
+    let mut output = Vec::with_capacity(buffer.len());
+    let mut normalizing = None;
+
+    /* A CR at the end of one buffer might start a CRLF in the next */
+    let mut pending_cr = false;
+    let mut num_bytes = 0;
+    loop {
+        let num_bytes_read = fill(&mut file, &mut buffer)?;
+        if num_bytes_read == 0 {
+            break;
+        }
+        let chunk = &buffer[..num_bytes_read];
+        if !*normalizing.get_or_insert_with(|| normalized(chunk)) {
+            hasher.update(chunk);
+            num_bytes += chunk.len();
+            continue;
+        }
+
+        output.clear();
+        if pending_cr && chunk[0] != b'\n' {
+            output.push(b'\r');
+        }
+        pending_cr = false;
+        for (i, byte) in chunk.iter().enumerate() {
+            if *byte == b'\r' {
+                match chunk.get(i + 1) {
+                    Some(b'\n') => continue,
+                    None => {
+                        pending_cr = true;
+                        continue;
+                    },
+                    _ => {},
+                }
+            }
+            output.push(*byte);
+        }
+        hasher.update(&output);
+        num_bytes += output.len();
+    }
+    if pending_cr {
+        hasher.update(b"\r");
+        num_bytes += 1;
+    }
+    Ok((hasher.hex_digest(), num_bytes))
+}
+
+
+/// Missing and extra files are what disagrees, or the entry itself if it's
+/// empty
+fn missing_or_extra(size: usize) -> BytesComparison {
+    if size == 0 {
+        BytesComparison{entry_disagreement: 1, ..Default::default()}
+    }
+    else {
+        BytesComparison{disagreement: size, ..Default::default()}
+    }
+}
+
+
+/// Check one file of the tree against what's in `directory` now, writing
+/// out a line for any disagreement
+fn verify_git_entry(entry: &GitEntry, rev: &str, directory: &str,
+        writable: &mut impl Write, rules: &[AttributeRule])
+                -> Result<BytesComparison, Error> {
+    let path = Path::new(directory).join(&entry.path);
+    if entry.is_symlink() {
+        return match fs::read_link(&path) {
+            Ok(target) if blob_hash_of(target.as_os_str().as_bytes()) == entry.object => {
+                Ok(BytesComparison{agreement: entry.size, ..Default::default()})
+            },
+            Ok(_) => {
+                writeln!(writable, "Disagreement ({} bytes): {} and {} are symlinks to different places.",
+                        entry.size, entry.path, path.display())?;
+                Ok(BytesComparison{disagreement: entry.size, ..Default::default()})
+            },
+            Err(_) => {
+                writeln!(writable, "Disagreement ({} bytes): {} is a symlink in {} and {} {}.",
+                        entry.size, entry.path, rev, path.display(),
+                        special::describe_path(&path))?;
+                Ok(missing_or_extra(entry.size))
+            },
+        };
+    }
+
+    if path.symlink_metadata().map_or(true, |metadata| !metadata.is_file()) {
+        writeln!(writable, "Disagreement ({} bytes): {} exists in {} and {} {}.",
+                entry.size, entry.path, rev, path.display(),
+                special::describe_path(&path))?;
+        return Ok(missing_or_extra(entry.size));
+    }
+
+    let (hash, num_bytes_hashed) = match blob_hash_of_file(&path, entry.size,
+            |start| is_normalized(rules, &entry.path, start)) {
+        Ok(hash_and_size) => hash_and_size,
+        Err(error) => {
+            return unhashable(&path, &error, writable);
+        }
+    };
+    let mut to_return = if num_bytes_hashed != entry.size {
+        let max_bytes_compared = cmp::max(num_bytes_hashed, entry.size);
+        writeln!(writable, "Disagreement ({} bytes): {} and {} are different sizes.",
+                max_bytes_compared, entry.path, path.display())?;
+        BytesComparison{disagreement: max_bytes_compared, ..Default::default()}
+    }
+    else if hash == entry.object {
+        BytesComparison{agreement: num_bytes_hashed, ..Default::default()}
+    }
+    else {
+        writeln!(writable, "Disagreement ({} bytes): {} and {} have different hashes.",
+                entry.size, entry.path, path.display())?;
+        BytesComparison{disagreement: entry.size, ..Default::default()}
+    };
+
+    /* git only keeps track of whether a file is executable */
+    let is_executable = fs::metadata(&path)?.permissions().mode() & 0o100 != 0;
+    if is_executable != entry.is_executable() {
+        writeln!(writable, "Disagreement (0 bytes): {} is {}executable in {} and {} {}.",
+                entry.path, if entry.is_executable() { "" } else { "not " }, rev,
+                path.display(), if is_executable { "is" } else { "isn't" })?;
+        to_return.entry_disagreement += 1;
+    }
+    Ok(to_return)
+}
+
+
+/// Check that `directory` is exactly what's committed as `rev` in the
+/// repository at `repo`: every blob has to hash the same as its file (with
+/// CRLFs undone first where `.gitattributes` says git would, if `eol`),
+/// and there can't be any files the tree doesn't have.  `.git` and
+/// submodules are left out.
+#[allow(clippy::too_many_arguments)]
+pub fn compare_git(repo: &str, rev: &str, directory: &str,
+        mut writable: impl Write, num_vs: u8, eol: bool, filter: &Filter,
+        ignore_perm_errors_flag: bool) -> Result<BytesComparison, Error> {
+    if num_vs > 1 {
+        writeln!(writable, "Reading the tree of {} in {}", rev, repo)?;
+    }
+    let entries = git_tree(repo, rev)?;
+    let rules = if eol { attribute_rules(repo, &entries)? } else { Vec::new() };
+
+    let mut to_return = BytesComparison::default();
+    let mut tree_paths = HashSet::new();
+    let mut submodules = Vec::new();
+    for entry in &entries {
+        tree_paths.insert(entry.path.as_str());
+        if entry.is_submodule() {
+            if num_vs > 0 {
+                writeln!(writable, "Skipping submodule {}", entry.path)?;
+            }
+            submodules.push(entry.path.clone() + "/");
+            continue;
+        }
+        if !filter.wants_manifest_entry(directory, &entry.path, entry.size) {
+            if num_vs > 1 {
+                writeln!(writable, "Filtered out {}", entry.path)?;
+            }
+            continue;
+        }
+        if num_vs > 1 {
+            writeln!(writable, "Examining {}", entry.path)?;
+        }
+        to_return += verify_git_entry(entry, rev, directory, &mut writable,
+                &rules)?;
+    }
+
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let relative = relative_path_string(entry.path(), directory)?;
+        if relative.starts_with(".git/") || relative == ".git" ||
+                tree_paths.contains(relative.as_str()) ||
+                submodules.iter().any(|submodule| relative.starts_with(submodule)) {
+            continue;
+        }
+        let size = entry.metadata().map_or(0, |metadata| metadata.len() as usize);
+        writeln!(writable, "Disagreement ({} bytes): {} isn't in {}.", size,
+                entry.path().display(), rev)?;
+        to_return += missing_or_extra(size);
+    }
+
+    /* A file of the wrong size counts for the bigger of the two sizes */
+    let num_bytes = to_return.agreement + to_return.disagreement;
+
+    to_return.write_summary(&mut writable, num_bytes)?;
+    if to_return.entry_disagreement > 0 {
+        writeln!(writable, "Disagreed on {} empty, missing or differently executable files.",
+                to_return.entry_disagreement)?;
+    }
+    Ok(to_return)
+}
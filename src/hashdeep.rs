@@ -0,0 +1,328 @@
+use crate::algorithm::hashes_of_path_with;
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::relative_path_string;
+use crate::unhashable;
+use crate::BytesComparison;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::Write;
+
+
+const HEADER: &str = "%%%% HASHDEEP-1.0";
+
+
+/// What `write_hashdeep` puts in each row, besides the size and filename
+const ALGORITHMS: [Algorithm; 2] = [Algorithm::Md5, Algorithm::Sha256];
+
+
+/// One row of a hashdeep manifest: `size,md5,sha256,...,filename`.  Only
+/// the hashes confidence can compute are kept, in the order of the
+/// manifest's `Hashdeep::algorithms`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashdeepEntry {
+    pub size: usize,
+    pub hashes: Vec<String>,
+    pub path: String,
+}
+
+
+/// A hashdeep (or md5deep -d / hashdeep -l) manifest
+#[derive(Clone, Debug, Default)]
+pub struct Hashdeep {
+    pub algorithms: Vec<Algorithm>,
+    pub entries: Vec<HashdeepEntry>,
+}
+
+
+/// hashdeep writes whatever paths it was given, so take `./` and
+/// `directory` itself off the front to get something relative to it
+fn normalized(path: &str, directory: &str) -> String {
+    let directory = directory.trim_end_matches('/');
+    let path = path.strip_prefix(directory).and_then(|rest| rest.strip_prefix('/'))
+            .unwrap_or(path);
+    path.strip_prefix("./").unwrap_or(path).to_owned()
+}
+
+
+/// Read the hashdeep manifest `filename`.  Columns for hashes confidence
+/// doesn't know (tiger, whirlpool, ...) are skipped, but there has to be
+/// at least one it does.
+pub fn read_hashdeep(filename: &str) -> Result<Hashdeep, Error> {
+    let corrupt = |why: &str| {
+        Error::other(format!("{} isn't a hashdeep manifest: {}", filename, why))
+    };
+    let mut lines = BufReader::new(File::open(filename)?).lines();
+    if lines.next().transpose()?.as_deref().map(str::trim_end) != Some(HEADER) {
+        return Err(corrupt("no HASHDEEP-1.0 header"));
+    }
+    let columns_line = lines.next().transpose()?.unwrap_or_default();
+    let columns = columns_line.trim_end().strip_prefix("%%%% ")
+            .ok_or_else(|| corrupt("no list of columns"))?
+            .split(',').collect::<Vec<_>>();
+    if columns.first() != Some(&"size") || columns.last() != Some(&"filename") {
+        return Err(corrupt("columns don't start with size and end with filename"));
+    }
+
+    /* Which column each known algorithm is in */
+    let mut to_return = Hashdeep::default();
+    let mut hash_columns = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        if let Ok(algorithm) = Algorithm::from_name(column) {
+            to_return.algorithms.push(algorithm);
+            hash_columns.push(i);
+        }
+    }
+    if hash_columns.is_empty() {
+        return Err(corrupt("no md5, sha1 or sha256 column"));
+    }
+
+    for line in lines {
+        let line = line?;
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        /* Filenames can have commas in them, but they're last */
+        let pieces = line.splitn(columns.len(), ',').collect::<Vec<_>>();
+        if pieces.len() != columns.len() {
+            return Err(corrupt(&("short line: ".to_owned() + line)));
+        }
+        let size = pieces[0].parse::<usize>().map_err(|_| {
+            corrupt(&("can't interpret ".to_owned() + pieces[0] + " as a size"))
+        })?;
+        to_return.entries.push(HashdeepEntry{size,
+                hashes: hash_columns.iter()
+                        .map(|i| pieces[*i].to_ascii_lowercase()).collect(),
+                path: pieces[columns.len() - 1].to_owned()});
+    }
+    Ok(to_return)
+}
+
+
+/// Write a hashdeep manifest of `directory` (md5 and sha256, with paths
+/// relative to it, as `hashdeep -l` would) to `writable`.  Returns the
+/// number of files listed and the number left out because they couldn't
+/// be read.
+pub fn write_hashdeep(directory: &str, mut writable: impl Write,
+        filter: &Filter, ignore_perm_errors_flag: bool)
+                -> Result<(usize, usize), Error> {
+    writeln!(writable, "{}", HEADER)?;
+    writeln!(writable, "%%%% size,{},filename", ALGORITHMS.iter()
+            .map(|algorithm| algorithm.name()).collect::<Vec<_>>().join(","))?;
+    writeln!(writable, "## Invoked from: {}",
+            std::env::current_dir()?.display())?;
+    writeln!(writable, "## $ confidence export-hashdeep {}", directory)?;
+    writeln!(writable, "##")?;
+
+    let (mut num_listed, mut num_unreadable) = (0, 0);
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        let (hashes, num_bytes_hashed) = match hashes_of_path_with(
+                entry.path(), &ALGORITHMS) {
+            Ok(hashes_and_size) => hashes_and_size,
+            Err(error) => {
+
+                /* Not in the manifest, which is only for what was hashed */
+                eprintln!("Couldn't hash {}: {}", entry.path().display(), error);
+                num_unreadable += 1;
+                continue;
+            }
+        };
+        writeln!(writable, "{},{},{}", num_bytes_hashed, hashes.join(","),
+                relative_path_string(entry.path(), directory)?)?;
+        num_listed += 1;
+    }
+    Ok((num_listed, num_unreadable))
+}
+
+
+/// Where a file in the directory being audited stands against the
+/// manifest, in hashdeep's terms
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditResult {
+
+    /// Known: its hashes are in the manifest for this very path
+    Matched,
+
+    /// Known: its hashes are in the manifest, but for another path
+    Moved,
+
+    /// Some of its hashes are in the manifest, but not all for the same
+    /// file, which shouldn't happen without a collision or tampering
+    PartiallyMatched,
+
+    /// Unknown: none of its hashes are in the manifest
+    New,
+}
+
+
+/// Everything an audit found
+#[derive(Clone, Debug, Default)]
+pub struct Audit {
+
+    /// Every file examined, relative to the directory, and what it was
+    pub results: Vec<(String, AuditResult)>,
+
+    /// Paths in the manifest that nothing in the directory matched
+    pub not_found: Vec<String>,
+}
+
+
+impl Audit {
+    pub fn count(&self, result: AuditResult) -> usize {
+        self.results.iter().filter(|(_, other)| *other == result).count()
+    }
+}
+
+
+/// Audit `directory` against the hashdeep manifest `hashdeep_filename`,
+/// the way `hashdeep -a -vv` does: every file is matched, moved, partially
+/// matched or new, and known files nothing matched weren't found.  The
+/// audit passes if everything matched and nothing's missing.
+///
+/// Bytes in matched and moved files agree (moves are also entry
+/// disagreements, like in `manifest_diff`).  Bytes in everything else
+/// disagree, including files that weren't found or couldn't be read.  A
+/// new file where a known one used to be only counts once.
+pub fn audit(hashdeep_filename: &str, directory: &str, mut writable: impl Write,
+        num_vs: u8, filter: &Filter, ignore_perm_errors_flag: bool)
+                -> Result<(Audit, BytesComparison), Error> {
+    if num_vs > 1 {
+        writeln!(writable, "Reading {}", hashdeep_filename)?;
+    }
+    let manifest = read_hashdeep(hashdeep_filename)?;
+    let mut by_hashes: HashMap<(usize, Vec<String>), Vec<String>> = HashMap::new();
+    let mut by_hash: HashSet<(usize, &str)> = HashSet::new();
+    for entry in &manifest.entries {
+        by_hashes.entry((entry.size, entry.hashes.clone())).or_default()
+                .push(normalized(&entry.path, directory));
+        for (i, hash) in entry.hashes.iter().enumerate() {
+            by_hash.insert((i, hash));
+        }
+    }
+    let sizes = manifest.entries.iter()
+            .map(|entry| (normalized(&entry.path, directory), entry.size))
+            .collect::<HashMap<_, _>>();
+    let mut unused = sizes.keys().cloned().collect::<HashSet<_>>();
+
+    let mut to_return = (Audit::default(), BytesComparison::default());
+    let mut moved = Vec::new();
+    let mut num_unreadable = 0;
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        let relative = relative_path_string(entry.path(), directory)?;
+        let contents = match hashes_of_path_with(entry.path(),
+                &manifest.algorithms) {
+            Ok((hashes, num_bytes_hashed)) => (num_bytes_hashed, hashes),
+            Err(error) => {
+
+                /* It's there, so it wasn't also not found */
+                unused.remove(&relative);
+                num_unreadable += 1;
+                to_return.1 += unhashable(entry.path(), &error, &mut writable)?;
+                continue;
+            }
+        };
+        let (num_bytes_hashed, hashes) = (contents.0, &contents.1);
+        let result = match by_hashes.get(&contents) {
+            Some(paths) if paths.contains(&relative) => {
+                unused.remove(&relative);
+                AuditResult::Matched
+            },
+            Some(paths) => {
+                moved.push((relative.clone(), num_bytes_hashed, paths));
+                AuditResult::Moved
+            },
+            None => {
+                if hashes.iter().enumerate().any(|(i, hash)| by_hash.contains(&(i, hash.as_str()))) {
+                    AuditResult::PartiallyMatched
+                }
+                else {
+                    AuditResult::New
+                }
+            }
+        };
+
+        match result {
+            AuditResult::Matched => {
+                if num_vs > 0 {
+                    writeln!(writable, "Matched: {}", relative)?;
+                }
+                to_return.1 += BytesComparison{agreement: num_bytes_hashed,
+                        ..Default::default()};
+            },
+            AuditResult::Moved => {
+                to_return.1 += BytesComparison{agreement: num_bytes_hashed,
+                        entry_disagreement: 1, ..Default::default()};
+            },
+            AuditResult::PartiallyMatched | AuditResult::New => {
+
+                /* Whatever was here before isn't also missing */
+                let (size, changed) = if unused.remove(&relative) {
+                    (cmp::max(num_bytes_hashed, sizes[&relative]),
+                            " (changed since the manifest)")
+                }
+                else {
+                    (num_bytes_hashed, "")
+                };
+                writeln!(writable, "{} ({} bytes): {}{}",
+                        if result == AuditResult::New { "New" } else { "Partially matched" },
+                        size, relative, changed)?;
+                to_return.1 += BytesComparison{disagreement: size,
+                        ..Default::default()};
+            },
+        }
+        to_return.0.results.push((relative, result));
+    }
+
+    /* A moved file was moved from wherever its contents aren't anymore */
+    for (relative, num_bytes_moved, paths) in moved {
+        let from = paths.iter().find(|path| unused.contains(*path))
+                .unwrap_or(&paths[0]);
+        unused.remove(from);
+        writeln!(writable, "Moved ({} bytes): {} (was {})", num_bytes_moved,
+                relative, from)?;
+    }
+
+    for entry in &manifest.entries {
+        let path = normalized(&entry.path, directory);
+        if unused.remove(&path) {
+            writeln!(writable, "Known file not found ({} bytes): {}",
+                    entry.size, path)?;
+            to_return.0.not_found.push(path);
+            to_return.1 += BytesComparison{disagreement: entry.size,
+                    ..Default::default()};
+        }
+    }
+
+    let (audit, comparison) = &to_return;
+    writeln!(writable, "Input files examined: {}", audit.results.len())?;
+    writeln!(writable, "Known files expecting: {}", manifest.entries.len())?;
+    writeln!(writable, "Files matched: {}", audit.count(AuditResult::Matched))?;
+    writeln!(writable, "Files partially matched: {}",
+            audit.count(AuditResult::PartiallyMatched))?;
+    writeln!(writable, "Files moved: {}", audit.count(AuditResult::Moved))?;
+    writeln!(writable, "New files found: {}", audit.count(AuditResult::New))?;
+    writeln!(writable, "Known files not found: {}", audit.not_found.len())?;
+    if num_unreadable > 0 {
+        writeln!(writable, "Files that couldn't be read: {}", num_unreadable)?;
+    }
+    writeln!(writable, "Audit {}", if comparison.disagrees() { "failed" } else { "passed" })?;
+
+    let num_bytes = comparison.agreement + comparison.disagreement;
+    comparison.write_summary(&mut writable, num_bytes)?;
+    Ok(to_return)
+}
@@ -0,0 +1,388 @@
+use crate::cache::sqlite_error;
+use crate::cache::HashCache;
+use crate::filter::Filter;
+use crate::hash_of_path;
+use crate::manifest::read_manifest;
+use crate::manifest::EntryKind;
+use crate::percent_of;
+use crate::special;
+use crate::unhashable;
+use crate::verify_entry;
+use crate::BytesComparison;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+
+/// What a file looked like the last time it was verified
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub digest: String,
+    pub size: u64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+
+    /// Seconds since the epoch
+    pub last_verified: i64,
+}
+
+
+impl Record {
+    pub fn of(metadata: &fs::Metadata, digest: String, last_verified: i64)
+            -> Record {
+        Record{digest, size: metadata.len(), mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(), last_verified}
+    }
+
+
+    /// Whether the file looks like it hasn't been written to since
+    pub fn same_stat(&self, metadata: &fs::Metadata) -> bool {
+        self.size == metadata.len() && self.mtime == metadata.mtime() &&
+                self.mtime_nsec == metadata.mtime_nsec()
+    }
+}
+
+
+/// Every file's digest, size, mtime and when it was last verified, kept
+/// across runs in a SQLite file.  Paths are absolute so one history can
+/// cover several trees.
+pub struct History {
+    connection: Connection,
+}
+
+
+impl History {
+
+    /// Open (or create) the history in `filename`
+    pub fn open(filename: &str) -> Result<History, Error> {
+        let connection = Connection::open(filename).map_err(sqlite_error)?;
+        connection.execute_batch("
+                PRAGMA journal_mode = WAL;
+                PRAGMA synchronous = NORMAL;
+                CREATE TABLE IF NOT EXISTS files (
+                    path TEXT PRIMARY KEY,
+                    digest TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    mtime INTEGER NOT NULL,
+                    mtime_nsec INTEGER NOT NULL,
+                    last_verified INTEGER NOT NULL
+                );").map_err(sqlite_error)?;
+        Ok(History{connection})
+    }
+
+
+    pub fn get(&self, path: &str) -> Result<Option<Record>, Error> {
+        self.connection.query_row("
+                SELECT digest, size, mtime, mtime_nsec, last_verified
+                FROM files WHERE path = ?1",
+                params![path],
+                |row| Ok(Record{digest: row.get(0)?,
+                        size: row.get::<_, i64>(1)? as u64, mtime: row.get(2)?,
+                        mtime_nsec: row.get(3)?, last_verified: row.get(4)?}))
+                .optional().map_err(sqlite_error)
+    }
+
+
+    pub fn put(&self, path: &str, record: &Record) -> Result<(), Error> {
+        self.connection.execute("
+                INSERT OR REPLACE INTO files (path, digest, size, mtime,
+                        mtime_nsec, last_verified)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![path, record.digest, record.size as i64, record.mtime,
+                        record.mtime_nsec, record.last_verified])
+                .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+
+    pub fn forget(&self, path: &str) -> Result<(), Error> {
+        self.connection.execute("DELETE FROM files WHERE path = ?1",
+                params![path]).map_err(sqlite_error)?;
+        Ok(())
+    }
+
+
+    /// Every path recorded under the directory `top` (absolute) and its
+    /// record, sorted by path
+    pub fn records_under(&self, top: &str)
+            -> Result<Vec<(String, Record)>, Error> {
+        let prefix = top.trim_end_matches('/').to_owned() + "/";
+        let mut statement = self.connection.prepare("
+                SELECT path, digest, size, mtime, mtime_nsec, last_verified
+                FROM files WHERE substr(path, 1, ?2) = ?1 ORDER BY path")
+                .map_err(sqlite_error)?;
+        let rows = statement.query_map(params![prefix, prefix.len() as i64],
+                |row| Ok((row.get(0)?, Record{digest: row.get(1)?,
+                        size: row.get::<_, i64>(2)? as u64, mtime: row.get(3)?,
+                        mtime_nsec: row.get(4)?, last_verified: row.get(5)?})))
+                .map_err(sqlite_error)?;
+        rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_error)
+    }
+}
+
+
+pub fn now_seconds() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64).unwrap_or(0)
+}
+
+
+/// A rough, human sized version of `seconds`, e.g. `3d 4h` or `12m`
+pub fn describe_age(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let (days, hours) = (seconds / 86400, (seconds % 86400) / 3600);
+    let minutes = (seconds % 3600) / 60;
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    }
+    else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    }
+    else if minutes > 0 {
+        format!("{}m", minutes)
+    }
+    else {
+        format!("{}s", seconds)
+    }
+}
+
+
+/// Re-hash every file under `directory` and compare it with what
+/// `history` remembers from the last scrub, then remember this one.
+///
+/// A file whose size or mtime changed was edited, which is fine.  One
+/// whose contents changed without either changing is corrupt: that's a
+/// disagreement, and its old record is kept so the next scrub flags it
+/// too.  Files the history has that are gone are disagreements as well,
+/// unless `forget_missing`, in which case they're dropped from it.
+pub fn scrub(directory: &str, history: &History, mut writable: impl Write,
+        num_vs: u8, filter: &Filter, ignore_perm_errors_flag: bool,
+        forget_missing: bool) -> Result<BytesComparison, Error> {
+    let now = now_seconds();
+    let top = fs::canonicalize(directory)?;
+    let mut to_return = BytesComparison::default();
+    let (mut num_verified, mut num_new, mut num_edited, mut num_corrupt) =
+            (0, 0, 0, 0);
+    let mut num_unreadable = 0;
+    let mut oldest: Option<(i64, String)> = None;
+    let mut seen = HashSet::new();
+
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_file() || special::special_kind_of(path).is_some() {
+            continue;
+        }
+        let relative = path.strip_prefix(directory).map_err(Error::other)?;
+        let key = top.join(relative).to_string_lossy().into_owned();
+        seen.insert(key.clone());
+
+        /* Stat first, so a file that changes while it's being hashed
+         * looks edited next time. */
+        let hashed = fs::metadata(path).and_then(|metadata| {
+            hash_of_path(path).map(|(digest, num_bytes_hashed)| {
+                (metadata, digest, num_bytes_hashed)
+            })
+        });
+        let (metadata, digest, num_bytes_hashed) = match hashed {
+            Ok(hashed) => hashed,
+            Err(error) => {
+                num_unreadable += 1;
+                to_return += unhashable(path, &error, &mut writable)?;
+                continue;
+            }
+        };
+        let record = match history.get(&key)? {
+            Some(record) => record,
+            None => {
+                num_new += 1;
+                if num_vs > 0 {
+                    writeln!(writable, "New: {}", path.display())?;
+                }
+                history.put(&key, &Record::of(&metadata, digest, now))?;
+                continue;
+            }
+        };
+        let age = describe_age(now - record.last_verified);
+        if oldest.as_ref().is_none_or(|(last_verified, _)| {
+            record.last_verified < *last_verified
+        }) {
+            oldest = Some((record.last_verified, path.display().to_string()));
+        }
+
+        if !record.same_stat(&metadata) {
+            num_edited += 1;
+            if num_vs > 0 {
+                writeln!(writable, "Edited: {} (last verified {} ago)",
+                        path.display(), age)?;
+            }
+            history.put(&key, &Record::of(&metadata, digest, now))?;
+        }
+        else if record.digest == digest {
+            num_verified += 1;
+            if num_vs > 0 {
+                writeln!(writable, "Verified {} (last verified {} ago)",
+                        path.display(), age)?;
+            }
+            to_return += BytesComparison{agreement: num_bytes_hashed,
+                    ..Default::default()};
+            history.put(&key, &Record{last_verified: now, ..record})?;
+        }
+        else {
+            num_corrupt += 1;
+            writeln!(writable, "Disagreement ({} bytes): {} changed without its size or mtime changing (last verified {} ago).",
+                    num_bytes_hashed, path.display(), age)?;
+            to_return += BytesComparison{disagreement: num_bytes_hashed,
+                    ..Default::default()};
+        }
+    }
+
+    /* Whatever the history has that wasn't seen is gone, unless it was
+     * just filtered out */
+    let mut num_missing = 0;
+    for (key, record) in history.records_under(&top.to_string_lossy())? {
+        if seen.contains(&key) {
+            continue;
+        }
+        let relative = Path::new(&key).strip_prefix(&top)
+                .map_err(Error::other)?.to_string_lossy().into_owned();
+        if !filter.wants_manifest_entry(directory, &relative,
+                record.size as usize) {
+            continue;
+        }
+        if forget_missing {
+            if num_vs > 0 {
+                writeln!(writable, "Forgot {}", key)?;
+            }
+            history.forget(&key)?;
+            continue;
+        }
+        num_missing += 1;
+        writeln!(writable, "Disagreement ({} bytes): {} is gone (last verified {} ago).",
+                record.size, key, describe_age(now - record.last_verified))?;
+        to_return += BytesComparison{disagreement: record.size as usize,
+                ..Default::default()};
+    }
+
+    writeln!(writable, "Verified {} files, {} new, {} edited, {} corrupt, {} missing.",
+            num_verified, num_new, num_edited, num_corrupt, num_missing)?;
+    let num_bytes_checked = to_return.agreement + to_return.disagreement;
+    to_return.write_summary(&mut writable, num_bytes_checked)?;
+    if num_unreadable > 0 {
+        writeln!(writable, "{} files couldn't be read.", num_unreadable)?;
+    }
+    if let Some((last_verified, path_s)) = oldest {
+        writeln!(writable, "Oldest verification before this scrub: {} ago ({})",
+                describe_age(now - last_verified), path_s)?;
+    }
+
+    Ok(to_return)
+}
+
+
+/// Like `compare_hashes`, but only for as long as `time_budget` allows.
+/// Entries `history` says were verified least recently (or never) go
+/// first, and each one that agrees is remembered as verified now, so
+/// repeated runs work through the whole manifest on a rolling schedule.
+/// Disagreeing entries aren't, so they stay at the front of the line.
+///
+/// At least one entry is verified per run, and the budget is only looked
+/// at between entries, so a big file can run over it.  Afterwards, how
+/// much of the manifest has been verified within the last
+/// `coverage_days` is reported.
+#[allow(clippy::too_many_arguments)]
+pub fn compare_hashes_within(hashes_filename: &str, directory: &str,
+        num_vs: u8, mut writable: impl Write, filter: &Filter,
+        history: &History, time_budget: Duration, coverage_days: u64,
+        cache: &Option<HashCache>) -> Result<BytesComparison, Error> {
+    if crate::archive::is_archive(directory) {
+        return Err(Error::other(
+                "An archive or disk image can't be verified a bit at a time"));
+    }
+    let start = Instant::now();
+    let now = now_seconds();
+    let top = fs::canonicalize(directory)?;
+    if num_vs > 1 {
+        writeln!(writable, "Reading {}", hashes_filename)?;
+    }
+    let (entries, _) = read_manifest(hashes_filename)?;
+
+    /* Oldest verification first, never-verified before that */
+    let mut queue = Vec::new();
+    for entry in entries {
+        if let EntryKind::Directory(_) = entry.kind {
+            continue;
+        }
+        if !filter.wants_manifest_entry(directory, &entry.path, entry.size) {
+            continue;
+        }
+        let key = top.join(&entry.path).to_string_lossy().into_owned();
+        let last_verified = history.get(&key)?
+                .map(|record| record.last_verified);
+        queue.push((last_verified, key, entry));
+    }
+    queue.sort_by(|a, b| (a.0, &a.2.path).cmp(&(b.0, &b.2.path)));
+
+    let mut to_return = BytesComparison::default();
+    let mut num_verified = 0;
+    for (last_verified, key, entry) in &queue {
+        if num_verified > 0 && start.elapsed() >= time_budget {
+            break;
+        }
+        if num_vs > 1 {
+            let age = match last_verified {
+                Some(last_verified) => describe_age(now - last_verified) + " ago",
+                None => "never".to_owned(),
+            };
+            writeln!(writable, "Examining {} (last verified {})", entry.path,
+                    age)?;
+        }
+        num_verified += 1;
+        let comparison = verify_entry(entry, directory, &mut writable, cache)?;
+        to_return += comparison;
+        if comparison.disagrees() {
+            continue;
+        }
+
+        /* Nothing there to remember it by, so it doesn't count as verified */
+        let (_, digest) = entry.tag_and_value();
+        if let Ok(metadata) = fs::metadata(Path::new(directory).join(&entry.path)) {
+            history.put(key, &Record::of(&metadata, digest, now))?;
+        }
+    }
+
+    let num_bytes = queue.iter().map(|(_, _, entry)| entry.size).sum::<usize>();
+    let num_bytes_checked = queue.iter().take(num_verified)
+            .map(|(_, _, entry)| entry.size).sum::<usize>();
+    writeln!(writable, "Verified {} of {} files ({} of {} bytes) in {}.",
+            num_verified, queue.len(), num_bytes_checked, num_bytes,
+            describe_age(start.elapsed().as_secs() as i64))?;
+    to_return.write_summary(&mut writable, num_bytes_checked)?;
+
+    /* How much is covered now, this run included */
+    let since = now - coverage_days as i64 * 24 * 60 * 60;
+    let (mut num_covered, mut num_bytes_covered) = (0, 0);
+    for (_, key, entry) in &queue {
+        if let Some(record) = history.get(key)? {
+            if record.last_verified >= since {
+                num_covered += 1;
+                num_bytes_covered += entry.size;
+            }
+        }
+    }
+    writeln!(writable, "Verified within the last {} days: {}/{} files, {}/{} bytes ({}%)",
+            coverage_days, num_covered, queue.len(), num_bytes_covered,
+            num_bytes, percent_of(num_bytes_covered, num_bytes))?;
+
+    Ok(to_return)
+}
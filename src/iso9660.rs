@@ -0,0 +1,389 @@
+use crate::archive::ArchiveEntry;
+use crate::special::SpecialKind;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Error;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+
+/// Volume descriptors start this far in
+const SYSTEM_AREA_SIZE: u64 = 16 * 2048;
+
+/// Directory record flags
+const DIRECTORY: u8 = 0x02;
+const ASSOCIATED_FILE: u8 = 0x04;
+const MULTI_EXTENT: u8 = 0x80;
+
+/// How many `CE` (continuation area) entries are followed for one record
+const MAX_CONTINUATIONS: usize = 16;
+
+
+fn corrupt(why: &str) -> Error {
+    Error::other("Corrupt ISO9660 image: ".to_owned() + why)
+}
+
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, Error> {
+    bytes.get(offset..offset + 4)
+            .map(|le| u32::from_le_bytes([le[0], le[1], le[2], le[3]]))
+            .ok_or_else(|| corrupt("record is too short"))
+}
+
+
+/// One directory record
+struct Record {
+    extent: u64,
+    size: u64,
+    flags: u8,
+    name: Vec<u8>,
+    system_use: Vec<u8>,
+}
+
+
+impl Record {
+    fn parse(bytes: &[u8]) -> Result<Record, Error> {
+        if bytes.len() < 34 {
+            return Err(corrupt("record is too short"));
+        }
+        let name_length = bytes[32] as usize;
+        let name = bytes.get(33..33 + name_length)
+                .ok_or_else(|| corrupt("name runs past the record"))?.to_vec();
+
+        /* The name's padded to an even length */
+        let system_use_start = 33 + name_length + (1 - name_length % 2);
+        Ok(Record{extent: u32_at(bytes, 2)? as u64, size: u32_at(bytes, 10)? as u64,
+                flags: bytes[25], name,
+                system_use: bytes.get(system_use_start..).unwrap_or(&[]).to_vec()})
+    }
+
+
+    /// `.` and `..` are named with a single 0 and 1
+    fn is_dot_or_dotdot(&self) -> bool {
+        self.name == [0] || self.name == [1]
+    }
+}
+
+
+/// What the Rock Ridge entries of a record say
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    device: Option<(u32, u32)>,
+
+    /// Where a directory that was moved to keep the tree shallow really is
+    /// (`CL`)
+    child: Option<u64>,
+
+    /// This is the moved directory, which is listed where it belongs
+    /// instead (`RE`)
+    relocated: bool,
+
+    /// `NM` says this is `.` or `..`
+    current_or_parent: bool,
+}
+
+
+/// A device number as Rock Ridge's `PN` has it.  Single 32-bit numbers
+/// are encoded as Linux does.
+fn device_numbers(high: u32, low: u32) -> (u32, u32) {
+    if high == 0 {
+        ((low >> 8) & 0xfff, (low & 0xff) | ((low >> 12) & 0xfff00))
+    }
+    else {
+        (high, low)
+    }
+}
+
+
+/// Everything in an ISO9660 image, by its Rock Ridge names if it has them,
+/// otherwise by its Joliet names if it has those
+pub struct Image {
+    file: File,
+
+    /// How long the image file is, which nothing in it can run past
+    length: u64,
+    block_size: u64,
+
+    /// Bytes to skip at the start of every System Use area, if the image
+    /// has Rock Ridge entries
+    rock_ridge: Option<usize>,
+    joliet: bool,
+    visited: HashSet<u64>,
+    pub entries: Vec<ArchiveEntry>,
+
+    /// Byte offsets and lengths of each entry's extents
+    extents: Vec<Vec<(u64, u64)>>,
+}
+
+
+impl Image {
+
+    /// Read the directories of the image `filename`
+    pub fn open(filename: &str) -> Result<Image, Error> {
+        let file = File::open(filename)?;
+        let length = file.metadata()?.len();
+        let mut image = Image{file, length, block_size: 2048,
+                rock_ridge: None, joliet: false, visited: HashSet::new(),
+                entries: Vec::new(), extents: Vec::new()};
+
+        /* The primary volume descriptor, and a Joliet supplementary one if
+         * there is one */
+        let mut primary = None;
+        let mut joliet = None;
+        let mut offset = SYSTEM_AREA_SIZE;
+        loop {
+            let descriptor = image.read_at(offset, 2048)
+                    .map_err(|_| corrupt("no volume descriptor terminator"))?;
+            if &descriptor[1..6] != b"CD001" {
+                return Err(corrupt("bad volume descriptor"));
+            }
+            match descriptor[0] {
+                1 if primary.is_none() => primary = Some(descriptor),
+                2 if joliet.is_none() &&
+                        [&b"%/@"[..], b"%/C", b"%/E"].contains(&&descriptor[88..91]) => {
+                    joliet = Some(descriptor);
+                },
+                255 => break,
+                _ => {},
+            }
+            offset += 2048;
+        }
+        let primary = primary.ok_or_else(|| corrupt("no primary volume descriptor"))?;
+        image.block_size = u16::from_le_bytes([primary[128], primary[129]]) as u64;
+        if image.block_size == 0 {
+            return Err(corrupt("block size is 0"));
+        }
+
+        /* Rock Ridge images have an SP entry in the root's `.` */
+        let root = Record::parse(&primary[156..190])?;
+        let dot = image.read_at(root.extent * image.block_size,
+                image.block_size as usize)?;
+        let dot = Record::parse(dot.get(..dot[0] as usize)
+                .ok_or_else(|| corrupt("root directory has no ."))?)?;
+        if dot.system_use.len() >= 7 && dot.system_use.starts_with(b"SP") &&
+                dot.system_use[4..6] == [0xbe, 0xef] {
+            image.rock_ridge = Some(dot.system_use[6] as usize);
+        }
+
+        let root = match joliet {
+            Some(ref joliet) if image.rock_ridge.is_none() => {
+                image.joliet = true;
+                Record::parse(&joliet[156..190])?
+            },
+            _ => root,
+        };
+        image.read_directory(root.extent, root.size, "")?;
+        Ok(image)
+    }
+
+
+    /// `length` bytes from `offset` on, which has to be checked against the
+    /// image before anything's allocated, since both come from the image
+    fn read_at(&mut self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        if offset.checked_add(length as u64).is_none_or(|end| end > self.length) {
+            return Err(corrupt("something runs past the end of the image"));
+        }
+        let mut to_return = vec![0; length];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut to_return)
+                .map_err(|_| corrupt("something runs past the end of the image"))?;
+        Ok(to_return)
+    }
+
+
+    /// The records of the directory at block `extent`.  Records don't
+    /// cross blocks, so a 0 length means the rest of the block is padding.
+    fn records_of(&mut self, extent: u64, size: u64) -> Result<Vec<Record>, Error> {
+        let bytes = self.read_at(extent * self.block_size, size as usize)?;
+        let mut to_return = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let length = bytes[offset] as usize;
+            if length == 0 {
+                offset = (offset / self.block_size as usize + 1) *
+                        self.block_size as usize;
+                continue;
+            }
+            let record = bytes.get(offset..offset + length)
+                    .ok_or_else(|| corrupt("record runs past its directory"))?;
+            to_return.push(Record::parse(record)?);
+            offset += length;
+        }
+        Ok(to_return)
+    }
+
+
+    /// Follow the System Use entries in `area` (and any continuation areas
+    /// it points to)
+    fn rock_ridge_of(&mut self, area: &[u8]) -> Result<RockRidge, Error> {
+        let mut to_return = RockRidge::default();
+        let mut areas = vec![area.to_vec()];
+        let mut num_continuations = 0;
+        while let Some(area) = areas.pop() {
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let length = area[offset + 2] as usize;
+                let entry = match area.get(offset..offset + length) {
+                    Some(entry) if length >= 4 => entry,
+                    _ => break,
+                };
+                match &entry[..2] {
+                    b"NM" if entry.len() >= 5 => {
+                        if entry[4] & 0x06 != 0 {
+                            to_return.current_or_parent = true;
+                        }
+                        let name = to_return.name.get_or_insert_with(String::new);
+                        name.push_str(&String::from_utf8_lossy(&entry[5..]));
+                    },
+                    b"PX" => to_return.mode = Some(u32_at(entry, 4)?),
+                    b"PN" => {
+                        to_return.device = Some(device_numbers(u32_at(entry, 4)?,
+                                u32_at(entry, 12)?));
+                    },
+                    b"CL" => to_return.child = Some(u32_at(entry, 4)? as u64),
+                    b"RE" => to_return.relocated = true,
+                    b"CE" if num_continuations < MAX_CONTINUATIONS => {
+                        num_continuations += 1;
+                        let continuation = self.read_at(
+                                u32_at(entry, 4)? as u64 * self.block_size +
+                                        u32_at(entry, 12)? as u64,
+                                u32_at(entry, 20)? as usize)?;
+                        areas.push(continuation);
+                    },
+                    b"ST" => break,
+                    _ => {},
+                }
+                offset += length;
+            }
+        }
+        Ok(to_return)
+    }
+
+
+    /// The name of `record` without Rock Ridge: Joliet's is UCS-2, and
+    /// plain ones end in a `;1` version and maybe a `.`
+    fn name_of(&self, record: &Record) -> String {
+        let name = if self.joliet {
+            let units = record.name.chunks(2)
+                    .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]));
+            char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect::<String>()
+        }
+        else {
+            String::from_utf8_lossy(&record.name).into_owned()
+        };
+        let name = match name.rfind(';') {
+            Some(semicolon) => &name[..semicolon],
+            None => &name,
+        };
+        if !self.joliet && record.flags & DIRECTORY == 0 && name.len() > 1 {
+            name.strip_suffix('.').unwrap_or(name).to_owned()
+        }
+        else {
+            name.to_owned()
+        }
+    }
+
+
+    /// Add everything in the directory at block `extent` (and under it) to
+    /// `entries`, under `prefix`
+    fn read_directory(&mut self, extent: u64, size: u64, prefix: &str)
+            -> Result<(), Error> {
+        if !self.visited.insert(extent) {
+            return Ok(());
+        }
+
+        /* A file over 4GiB is split over records that all have the same
+         * name, all but the last flagged as multi-extent */
+        let mut extents = Vec::new();
+        for record in self.records_of(extent, size)? {
+            if record.is_dot_or_dotdot() || record.flags & ASSOCIATED_FILE != 0 {
+                continue;
+            }
+            let rock_ridge = match self.rock_ridge {
+                Some(skip) => {
+                    let area = record.system_use.get(skip..).unwrap_or(&[]).to_vec();
+                    self.rock_ridge_of(&area)?
+                },
+                None => RockRidge::default(),
+            };
+            if rock_ridge.current_or_parent || rock_ridge.relocated {
+                continue;
+            }
+            let name = match rock_ridge.name {
+                Some(ref name) => name.clone(),
+                None => self.name_of(&record),
+            };
+            let path = if prefix.is_empty() {
+                name
+            }
+            else {
+                prefix.to_owned() + "/" + &name
+            };
+
+            if let Some(child) = rock_ridge.child {
+                let dot = self.read_at(child * self.block_size,
+                        self.block_size as usize)?;
+                let dot = Record::parse(dot.get(..dot[0] as usize)
+                        .ok_or_else(|| corrupt("relocated directory has no ."))?)?;
+                self.read_directory(child, dot.size, &path)?;
+                continue;
+            }
+            if record.flags & DIRECTORY != 0 {
+                self.read_directory(record.extent, record.size, &path)?;
+                continue;
+            }
+
+            let special = match rock_ridge.mode.map(|mode| mode & 0o170000) {
+                None | Some(0o100000) => None,
+                Some(0o010000) => Some(SpecialKind::Fifo),
+                Some(0o140000) => Some(SpecialKind::Socket),
+                Some(0o020000) => {
+                    let (major, minor) = rock_ridge.device.unwrap_or((0, 0));
+                    Some(SpecialKind::CharDevice(major, minor))
+                },
+                Some(0o060000) => {
+                    let (major, minor) = rock_ridge.device.unwrap_or((0, 0));
+                    Some(SpecialKind::BlockDevice(major, minor))
+                },
+
+                /* Symlinks aren't kept, the same as when walking */
+                _ => continue,
+            };
+            extents.push((record.extent * self.block_size, record.size));
+            if record.flags & MULTI_EXTENT != 0 {
+                continue;
+            }
+            let extents = std::mem::take(&mut extents);
+            let size = if special.is_some() {
+                0
+            }
+            else {
+                extents.iter().map(|(_, length)| *length as usize).sum()
+            };
+            self.entries.push(ArchiveEntry::in_image(path, size, special,
+                    self.entries.len()));
+            self.extents.push(extents);
+        }
+        Ok(())
+    }
+
+
+    /// Write the contents of the `i`th entry to `writable`
+    pub fn copy_contents(&mut self, i: usize, writable: &mut impl Write)
+            -> Result<(), Error> {
+        for (offset, length) in self.extents[i].clone() {
+            self.file.seek(SeekFrom::Start(offset))?;
+            let num_bytes = std::io::copy(&mut (&mut self.file).take(length), writable)?;
+            if num_bytes < length {
+                return Err(corrupt("file runs past the end of the image"));
+            }
+        }
+        Ok(())
+    }
+}
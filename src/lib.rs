@@ -1,7 +1,5 @@
-use base64;
 use clap::ArgMatches;
 use same_file::Handle;
-use sha1;
 use std::cmp;
 use std::fs;
 use std::fs::File;
@@ -15,14 +13,93 @@ use std::io::SeekFrom;
 use std::io::Write;
 use std::ops::Add;
 use std::ops::AddAssign;
+use std::path::Component;
 use std::path::Path;
-use walkdir::WalkDir;
+use std::path::PathBuf;
 use indicatif::ProgressBar;
 
-
+pub mod algorithm;
+pub mod archive;
+pub mod bagit;
+pub mod cache;
+pub mod copy;
+pub mod diff;
+pub mod filter;
+pub mod fixup;
+pub mod git;
+pub mod hashdeep;
+pub mod history;
+pub mod iso9660;
+pub mod manifest;
+pub mod merkle;
+pub mod mtree;
+pub mod parity;
+pub mod repair;
+pub mod sidecar;
+pub mod special;
+pub mod squashfs;
+pub mod sums;
+pub mod tags;
+pub mod torrent;
+pub mod update;
+pub mod vote;
+
+use algorithm::Algorithm;
+use cache::HashCache;
+use filter::Filter;
+use fixup::Fixup;
+use manifest::EntryKind;
+use manifest::FileStat;
+use manifest::ManifestEntry;
+use merkle::TreeHasher;
+use parity::ParityWriter;
+use special::SpecialKind;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct BytesComparison {
     disagreement: usize,
     agreement: usize,
+
+    /// Disagreeing entries that have no bytes to count, like special files
+    entry_disagreement: usize,
+}
+
+
+impl BytesComparison {
+    pub fn agreement(&self) -> usize {
+        self.agreement
+    }
+
+    pub fn disagreement(&self) -> usize {
+        self.disagreement
+    }
+
+    pub fn entry_disagreement(&self) -> usize {
+        self.entry_disagreement
+    }
+
+    /// Whether anything at all disagreed, bytes or otherwise
+    pub fn disagrees(&self) -> bool {
+        self.disagreement > 0 || self.entry_disagreement > 0
+    }
+
+    /// Write out how many of `num_bytes` agreed and disagreed, the way most
+    /// modes finish up
+    pub fn write_summary(&self, writable: &mut impl Write, num_bytes: usize)
+            -> Result<(), Error> {
+        writeln!(writable, "Agreed on {}/{} bytes ({}% confidence)",
+                self.agreement, num_bytes, percent_of(self.agreement, num_bytes))?;
+        writeln!(writable, "Disagreed on {}/{} bytes ({}% worry)",
+                self.disagreement, num_bytes,
+                percent_of(self.disagreement, num_bytes))
+    }
+}
+
+
+/// `part` as a percentage of `whole`.  Nothing at all is 0%, not NaN%.
+pub fn percent_of(part: usize, whole: usize) -> f32 {
+    (part as f32 / whole.max(1) as f32) * 100.0
 }
 
 
@@ -33,6 +110,8 @@ impl Add for BytesComparison {
         Self {
             disagreement: self.disagreement + other.disagreement,
             agreement: self.agreement + other.agreement,
+            entry_disagreement: self.entry_disagreement +
+                    other.entry_disagreement,
         }
     }
 }
@@ -43,6 +122,8 @@ impl AddAssign for BytesComparison {
         *self = Self {
             disagreement: self.disagreement + other.disagreement,
             agreement: self.agreement + other.agreement,
+            entry_disagreement: self.entry_disagreement +
+                    other.entry_disagreement,
         };
     }
 }
@@ -51,11 +132,10 @@ impl AddAssign for BytesComparison {
 pub fn size_from_path(path: &Path) -> Result<usize, Error> {
     if !path.is_file() {
         if let Some(path_s) = path.to_str() {
-            Err(Error::new(ErrorKind::Other,
-                    path_s.to_owned() + " is not a regular file."))
+            Err(Error::other(path_s.to_owned() + " is not a regular file."))
         }
         else {
-            Err(Error::new(ErrorKind::Other, "Empty path"))
+            Err(Error::other("Empty path"))
         }
     }
     else {
@@ -65,38 +145,26 @@ pub fn size_from_path(path: &Path) -> Result<usize, Error> {
 }
 
 
-/// Returns the hash string and the number of bytes hashed
-pub fn hash_of_path(path: &Path) -> Result<(String, usize), Error> {
-    if !path.is_file() {
-        match path.to_str() {
-            Some(path_s) => {
-                return Err(Error::new(ErrorKind::Other,
-                        path_s.to_owned() + " is not a regular file."));
-            },
-            None => {
-                return Err(Error::new(ErrorKind::Other, "Empty path"));
-            }
-        }
+/// Write out that `path` couldn't be hashed and count it as disagreeing,
+/// by however big it looks, or as an entry if that's nothing
+pub fn unhashable(path: &Path, error: &Error, writable: &mut impl Write)
+        -> Result<BytesComparison, Error> {
+    let size = fs::metadata(path).map(|metadata| metadata.len() as usize)
+            .unwrap_or(0);
+    writeln!(writable, "Disagreement ({} bytes): Couldn't hash {}: {}", size,
+            path.display(), error)?;
+    if size == 0 {
+        Ok(BytesComparison{entry_disagreement: 1, ..Default::default()})
     }
-
-    let mut cur_hash = sha1::Sha1::new();
-    let mut file = File::open(&path)?;
-    let mut buffer: [u8; 32] = [0; 32];
-    let mut num_bytes_hashed: usize = 0;
-    let mut done = false;
-    while !done {
-        let num_bytes_read = file.read(&mut buffer[..])?;
-
-        let bytes_to_hash = &buffer[..num_bytes_read];
-
-        cur_hash.update(bytes_to_hash);
-        num_bytes_hashed += num_bytes_read;
-        if num_bytes_read < 32 {
-            done = true;
-        }
+    else {
+        Ok(BytesComparison{disagreement: size, ..Default::default()})
     }
+}
+
 
-    Ok((cur_hash.digest().to_string(), num_bytes_hashed))
+/// Returns the hash string and the number of bytes hashed
+pub fn hash_of_path(path: &Path) -> Result<(String, usize), Error> {
+    algorithm::hash_of_path_with(path, Algorithm::Sha1)
 }
 
 
@@ -105,51 +173,121 @@ pub fn hash_of_path(path: &Path) -> Result<(String, usize), Error> {
 /// Writes out hash for later comparison
 pub fn hash_path(path: &Path, filename_l: &str,
         writable: &mut impl Write, num_vs: u8,
-        progress_bar: &Option<ProgressBar>) -> Result<usize, Error> {
-    if num_vs > 1 {
-        eprintln!("Output hash of {}", path.display());
+        progress_bar: &Option<ProgressBar>, cache: &Option<HashCache>)
+                -> Result<usize, Error> {
+    match hash_path_entry(path, filename_l, writable, num_vs, progress_bar,
+            cache, false)? {
+        Some(entry) => Ok(entry.size),
+        None => Ok(0),
     }
+}
 
-    if !path.is_file() {
-        return Ok(0);
+
+/// Same as `hash_path`, but returns the manifest entry that was written
+/// out, if any.  Directories and broken symlinks don't get one.  Regular
+/// files only get mtime and inode columns with `stat_columns`, since
+/// manifests with them can't be read by confidence 1.0.
+pub fn hash_path_entry(path: &Path, filename_l: &str,
+        writable: &mut impl Write, num_vs: u8,
+        progress_bar: &Option<ProgressBar>, cache: &Option<HashCache>,
+        stat_columns: bool) -> Result<Option<ManifestEntry>, Error> {
+    if num_vs > 1 {
+        eprintln!("Output hash of {}", path.display());
     }
 
-    let possibly_error = hash_of_path(path);
+    /* Special files are recorded by type, never opened */
+    let (kind, size, stat) = match special::special_kind_of(path) {
+        Some(special_kind) => (EntryKind::Special(special_kind), 0, None),
+        None => {
+            if !path.is_file() {
+                return Ok(None);
+            }
 
-    match possibly_error {
-        Ok((cur_hash, num_bytes_hashed)) => {
-            output_progress(num_bytes_hashed as u64, &progress_bar);
+            /* Stat first, so a file that changes while it's being hashed
+             * looks changed next time. */
+            let stat = FileStat::of(&fs::metadata(path)?);
+            /* The manifest is going to `writable`, so a wrong cached hash
+             * can only be mentioned on stderr */
+            let (cur_hash, num_bytes_hashed, _) = cache::hash_of_path_cached(
+                    path, cache, &mut std::io::stderr())?;
+            output_progress(num_bytes_hashed as u64, progress_bar);
             if num_vs > 1 {
                 eprintln!("Successfully hashed {} bytes",
                         num_bytes_hashed);
             }
+            (EntryKind::File(cur_hash), num_bytes_hashed,
+                    Some(stat).filter(|_| stat_columns))
+        }
+    };
 
-            // TODO Maybe use serde or something so the path isn't just
-            // whatever'd be displayed?  (Weirdo unicode characters, etc.)
-            match path.strip_prefix(filename_l) {
-                Ok(main_part) => {
+    // TODO Maybe use serde or something so the path isn't just
+    // whatever'd be displayed?  (Weirdo unicode characters, etc.)
+    let entry = ManifestEntry{kind, path: relative_path_string(path, filename_l)?,
+            size, stat};
+
+    /* The path is base64'd so it has no spaces
+     * When it's read back into a Path, it'll have to be converted
+     * from a vector of u8's (this is unix specific!) to an OsStr */
+    writeln!(writable, "{}", entry.to_line())?;
+    Ok(Some(entry))
+}
 
-                    /* Get the path as a string, then base64 it so it has no spaces
-                     * When it's read back into a Path, it'll have to be converted
-                     * from a vector of u8's (this is unix specific!) to an OsStr */
-                    if let Some(path_s) = main_part.to_str() {
-                        writeln!(writable, "sha1: {} {} {}", cur_hash,
-                                base64::encode(path_s), num_bytes_hashed)?;
-                    }
-                    else {
-                        return Err(Error::new(ErrorKind::Other,
-                                "Could not cast path to a string"));
-                    }
-                },
-                Err(error) => {
-                    return Err(Error::new(ErrorKind::Other, error));
-                }
 
+/// `path` relative to `filename_l`, as a string
+pub fn relative_path_string(path: &Path, filename_l: &str)
+        -> Result<String, Error> {
+    match path.strip_prefix(filename_l) {
+        Ok(main_part) => {
+            if let Some(path_s) = main_part.to_str() {
+                Ok(path_s.to_owned())
+            }
+            else {
+                Err(Error::other("Could not cast path to a string"))
             }
-            Ok(num_bytes_hashed)
         },
-        Err(error) => Err(error)
+        Err(error) => Err(Error::other(error)),
+    }
+}
+
+
+/// `path` without any `.` or `..` in it, as far as that's possible
+/// without looking at the filesystem.  `None` if it's absolute or has more
+/// `..` than it has directories, so it would lead somewhere else.
+pub fn tidied(path: &Path) -> Option<PathBuf> {
+    let mut to_return = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !to_return.pop() {
+                    return None;
+                }
+            },
+            Component::Normal(name) => to_return.push(name),
+            Component::RootDir | Component::Prefix(_) => {
+                return None;
+            }
+        }
     }
+    Some(to_return)
+}
+
+
+/// Compare a special file on the left with whatever is at `path_r`.  No
+/// bytes are involved, so a mismatch counts as an `entry_disagreement`.
+pub fn compare_special(kind_l: SpecialKind, path_l: &Path, path_r: &Path,
+        writable: &mut impl Write, num_vs: u8)
+                -> Result<BytesComparison, Error> {
+    if special::special_kind_of(path_r) == Some(kind_l) {
+        if num_vs > 1 {
+            writeln!(writable, "Both are {}", kind_l)?;
+        }
+        return Ok(BytesComparison::default());
+    }
+
+    writeln!(writable, "'{}' is {}, but '{}' {}.", path_l.display(), kind_l,
+            path_r.display(), special::describe_path(path_r))?;
+    Ok(BytesComparison{entry_disagreement: 1, ..Default::default()})
 }
 
 
@@ -158,12 +296,13 @@ pub fn hash_path(path: &Path, filename_l: &str,
 /// it's in.  `filename_r` to be a directory that should have a copy
 /// of it.
 pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
-        writable: &mut impl Write, num_vs: u8, progress_bar: &Option<ProgressBar>)
-                -> Result<BytesComparison, Error> {
+        writable: &mut impl Write, num_vs: u8, progress_bar: &Option<ProgressBar>,
+        cache: &Option<HashCache>) -> Result<BytesComparison, Error> {
 
     /* Don't care about directories or symlinks */
-    if !path.is_file() {
-        return Ok(BytesComparison{disagreement: 0, agreement:0});
+    let special_l = special::special_kind_of(path);
+    if !path.is_file() && special_l.is_none() {
+        return Ok(BytesComparison::default());
     }
 
     match path.strip_prefix(filename_l) {
@@ -181,7 +320,12 @@ pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
                         "' doesn't contain '" + path.to_str().unwrap() +
                         "'.  Don't know what to do here.";
                 writeln!(writable, "{}", error_s)?;
-                return Err(Error::new(ErrorKind::Other, error_s));
+                return Err(Error::other(error_s));
+            }
+
+            if let Some(kind_l) = special_l {
+                return compare_special(kind_l, &path_l, &path_r, writable,
+                        num_vs);
             }
 
             if !path_r.is_file() {
@@ -190,8 +334,8 @@ pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
                         path_l.to_str().unwrap() + "' is.";
                 writeln!(writable, "{}", error_s)?;
                 let cur_size = size_from_path(&path_l)?;
-                output_progress(cur_size as u64, &progress_bar);
-                return Ok(BytesComparison{disagreement: cur_size, agreement: 0});
+                output_progress(cur_size as u64, progress_bar);
+                return Ok(BytesComparison{disagreement: cur_size, ..Default::default()});
             }
 
             /* Finally, path_l and path_r are files to compare. */
@@ -201,8 +345,8 @@ pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
             /* Be happy if they're literally the same file. */
             let num_bytes_l = metadata_l.len() as usize;
             if Handle::from_path(&path_l)? == Handle::from_path(&path_r)? {
-                output_progress(num_bytes_l as u64, &progress_bar);
-                return Ok(BytesComparison{agreement: num_bytes_l, disagreement: 0});
+                output_progress(num_bytes_l as u64, progress_bar);
+                return Ok(BytesComparison{agreement: num_bytes_l, ..Default::default()});
             }
 
             // TODO Don't just panic here.
@@ -216,11 +360,37 @@ pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
                 let error_s = "'".to_owned() + path_l.to_str().unwrap() +
                         "' and '" + path_r_s + "' aren't the same size.";
                 writeln!(writable, "{}", error_s)?;
-                output_progress(max_bytes_compared as u64, &progress_bar);
-                return Ok(BytesComparison{disagreement: max_bytes_compared, agreement: 0});
+                output_progress(max_bytes_compared as u64, progress_bar);
+                return Ok(BytesComparison{disagreement: max_bytes_compared, ..Default::default()});
+            }
+
+            /* If the cache knows both hashes, trust it (if told to) */
+            if let Some(ref cache) = cache {
+                if cache.mode() == cache::CacheMode::Trust {
+                    if let (Some(hash_l), Some(hash_r)) = (
+                            cache.lookup(&metadata_l, "sha1")?,
+                            cache.lookup(&metadata_r, "sha1")?) {
+                        output_progress(num_bytes_l as u64, progress_bar);
+                        if hash_l == hash_r {
+                            if num_vs > 1 {
+                                writeln!(writable, "Cached hashes of {} bytes agree",
+                                        num_bytes_l)?;
+                            }
+                            return Ok(BytesComparison{agreement: num_bytes_l,
+                                    ..Default::default()});
+                        }
+                        writeln!(writable, "'{}' and '{}' aren't equal.", path_l_s,
+                                path_r_s)?;
+                        return Ok(BytesComparison{disagreement: num_bytes_l,
+                                ..Default::default()});
+                    }
+                }
             }
 
-            /* Finally, compare their contents */
+            /* Finally, compare their contents, hashing them on the way if
+             * there's a cache to fill */
+            let mut hashes = cache.as_ref()
+                    .map(|_| (sha1::Sha1::new(), sha1::Sha1::new()));
             let mut file_l = File::open(&path_l)?;
             let mut file_r = File::open(&path_r)?;
             let mut buffer_l = [0; 32];
@@ -239,23 +409,27 @@ pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
                             "bytes from '" + path_l_s +
                             "' and '" + path_r_s + "'";
                     writeln!(writable, "{}", error_s)?;
-                    output_progress(max_bytes_compared as u64, &progress_bar);
-                    return Ok(BytesComparison{disagreement: max_bytes_compared, agreement: 0});
+                    output_progress(max_bytes_compared as u64, progress_bar);
+                    return Ok(BytesComparison{disagreement: max_bytes_compared, ..Default::default()});
                 }
 
-                if buffer_l != buffer_r {
+                if buffer_l[..num_bytes_read_l] != buffer_r[..num_bytes_read_r] {
                     let error_s = "'".to_owned() + path_l_s +
                             "' and '" + path_r_s +
                             "' aren't equal.";
                     writeln!(writable, "{}", error_s)?;
-                    output_progress(max_bytes_compared as u64, &progress_bar);
-                    return Ok(BytesComparison{disagreement: max_bytes_compared, agreement: 0});
+                    output_progress(max_bytes_compared as u64, progress_bar);
+                    return Ok(BytesComparison{disagreement: max_bytes_compared, ..Default::default()});
                 }
 
                 /* At this point, we've actually compared bytes */
-                num_bytes_examined += num_bytes_l;
+                num_bytes_examined += num_bytes_read_l;
+                if let Some((ref mut hash_l, ref mut hash_r)) = hashes {
+                    hash_l.update(&buffer_l[..num_bytes_read_l]);
+                    hash_r.update(&buffer_r[..num_bytes_read_r]);
+                }
 
-                if num_bytes_l < 32 {
+                if num_bytes_read_l < 32 {
                     done = true;
                 }
             }
@@ -264,13 +438,30 @@ pub fn compare_paths(path: &Path, filename_l: &str, filename_r: &str,
                 writeln!(writable, "Successfully compared {} bytes",
                         num_bytes_examined)?;
             }
-            output_progress(num_bytes_examined as u64, &progress_bar);
-            Ok(BytesComparison{agreement: num_bytes_examined, disagreement: 0})
+            output_progress(num_bytes_examined as u64, progress_bar);
+            if let (Some(cache), Some((hash_l, hash_r))) = (cache, hashes) {
+                let hash_l = hash_l.digest().to_string();
+                let hash_r = hash_r.digest().to_string();
+
+                /* They agree, but one might not be what it was when cached */
+                let num_bytes_wrong =
+                        cache.check(&path_l, &metadata_l, "sha1", &hash_l,
+                                writable)? +
+                        cache.check(&path_r, &metadata_r, "sha1", &hash_r,
+                                writable)?;
+                cache.store_all("sha1", &[(&metadata_l, &hash_l),
+                        (&metadata_r, &hash_r)])?;
+                if num_bytes_wrong > 0 {
+                    return Ok(BytesComparison{disagreement: num_bytes_examined,
+                            ..Default::default()});
+                }
+            }
+            Ok(BytesComparison{agreement: num_bytes_examined, ..Default::default()})
         },
 
         /* filename_l doesn't contain path*/
         Err(error) => {
-            return Err(Error::new(ErrorKind::Other, error));
+            Err(Error::other(error))
         }
     }
 }
@@ -284,7 +475,7 @@ pub fn last_line_of(mut open_file: &std::fs::File) -> Result<String, Error> {
     /* Seek to last line.  Iterate backwards from final byte to find
      * the penultimate \n, then read forward to get last line.
      * TODO Care about crlf or whatever. */
-    let original_position = open_file.seek(SeekFrom::Current(0))?;
+    let original_position = open_file.stream_position()?;
     let metadata = open_file.metadata()?;
     let mut last_line_byte_num: u64 = 0;
     let hashes_file_num_bytes = metadata.len();
@@ -296,8 +487,7 @@ pub fn last_line_of(mut open_file: &std::fs::File) -> Result<String, Error> {
         open_file.seek(SeekFrom::Start(byte_num))?;
         let num_bytes_read = open_file.read(&mut cur_byte)?;
         if num_bytes_read != 1 {
-            return Err(Error::new(ErrorKind::Other,
-                    "Couldn't read from file"));
+            return Err(Error::other("Couldn't read from file"));
         }
         if cur_byte[0] == 0x0a {
             last_line_byte_num = byte_num;
@@ -318,14 +508,13 @@ pub fn last_line_of(mut open_file: &std::fs::File) -> Result<String, Error> {
 pub fn bytes_from_last_line(last_line: &str) -> Result<usize, Error> {
     let pieces = last_line.split_whitespace().collect::<Vec<_>>();
     if pieces.len() != 3 || pieces[1] != "bytes" || pieces[2] != "hashed" {
-        return Err(Error::new(ErrorKind::Other,
-                "Corrupt file (doesn't end with 'XXX bytes hashed')"));
+        return Err(Error::other("Corrupt file (doesn't end with 'XXX bytes hashed')"));
     }
 
     let num_bytes_hashed = pieces[0].parse::<usize>();
     if num_bytes_hashed.is_err() {
         let err_s = "Can't interpret ".to_owned() + pieces[0] + " as an integer.";
-        return Err(Error::new(ErrorKind::Other, err_s));
+        return Err(Error::other(err_s));
     }
 
     Ok(num_bytes_hashed.unwrap())
@@ -346,13 +535,13 @@ pub fn path_string_from_b64(b64: &str) -> Result<String, Error> {
                 Err(_) => {
                     let err_s = "Couldn't convert bytes from unbased64'd {} t\
                             o a path".to_owned() + b64;
-                    return Err(Error::new(ErrorKind::Other, err_s));
+                    return Err(Error::other(err_s));
                 }
             }
         }
         Err(_) => {
             let err_s = "Couldn't unbase64 ".to_owned() + b64 + " to a path";
-            return Err(Error::new(ErrorKind::Other, err_s));
+            return Err(Error::other(err_s));
         }
     }
 
@@ -360,14 +549,97 @@ pub fn path_string_from_b64(b64: &str) -> Result<String, Error> {
 }
 
 
+/// Check one manifest entry against what's in `directory` now, writing
+/// out a line for any disagreement.
+pub fn verify_entry(entry: &ManifestEntry, directory: &str,
+        writable: &mut impl Write, cache: &Option<HashCache>)
+                -> Result<BytesComparison, Error> {
+    let old_path_s = &entry.path;
+    let num_bytes_hashed = entry.size;
+    let path = Path::new(directory).join(Path::new(old_path_s));
+
+    let sha1 = match entry.kind {
+        EntryKind::File(ref sha1) => sha1,
+        EntryKind::Directory(_) => {
+            return Ok(BytesComparison::default());
+        },
+
+        /* Special files only have to be the same type (and device) */
+        EntryKind::Special(kind) => {
+            if special::special_kind_of(&path) == Some(kind) {
+                return Ok(BytesComparison::default());
+            }
+            writeln!(writable, "Disagreement (0 bytes): {} is {} and {} {}.",
+                    old_path_s, kind, path.display(),
+                    special::describe_path(&path))?;
+            return Ok(BytesComparison{entry_disagreement: 1,
+                    ..Default::default()});
+        }
+    };
+
+    if !path.is_file() {
+        if num_bytes_hashed == 0 {
+            writeln!(writable, "Disagreement (0 bytes): {} is empty and {} {}.",
+                    old_path_s, path.display(),
+                    special::describe_path(&path))?;
+            return Ok(BytesComparison::default());
+        }
+        else {
+            writeln!(writable,
+                    "Disagreement ({} bytes): {} exists and {} {}.",
+                    num_bytes_hashed, old_path_s, path.display(),
+                    special::describe_path(&path))?;
+            return Ok(BytesComparison{disagreement: num_bytes_hashed,
+                    ..Default::default()});
+        }
+    }
+
+    /* Don't bother to hash if filesizes don't match
+     *
+     * The logic here has decided that nonexistent files and
+     * empty files are equal!
+     */
+    let cur_size = size_from_path(&path)?;
+    let max_bytes_compared = cmp::max(cur_size, num_bytes_hashed);
+    if cur_size != num_bytes_hashed {
+        writeln!(writable, "Disagreement ({} bytes): {} and {} are different sizes.",
+                max_bytes_compared, old_path_s, path.display())?;
+        return Ok(BytesComparison{disagreement: max_bytes_compared, ..Default::default()});
+    }
+
+    match cache::hash_of_path_cached(&path, cache, writable) {
+        Ok((hash_s, num_bytes_hashed, num_bytes_wrong)) => {
+            if *sha1 != hash_s {
+                writeln!(writable, "Disagreement ({} bytes): {} and {} have different hashes.",
+                        max_bytes_compared, old_path_s, path.display())?;
+                Ok(BytesComparison{disagreement: max_bytes_compared, ..Default::default()})
+            }
+            else if num_bytes_wrong > 0 {
+                Ok(BytesComparison{disagreement: num_bytes_wrong, ..Default::default()})
+            }
+            else {
+                Ok(BytesComparison{agreement: num_bytes_hashed, ..Default::default()})
+            }
+        },
+        Err(_) => {
+            writeln!(writable, "Disagreement ({} bytes): Couldn't hash {}",
+                    max_bytes_compared, path.display())?;
+            Ok(BytesComparison{disagreement: max_bytes_compared, ..Default::default()})
+        }
+    }
+}
+
+
+#[allow(clippy::too_many_arguments)]
 pub fn compare_hashes(hashes_filename: &str, directory: &str, num_vs: u8,
-        mut writable: impl Write, progress: bool) ->
-        Result<BytesComparison, Error> {
+        mut writable: impl Write, progress: bool, filter: &Filter,
+        cache: &Option<HashCache>, fixup: &mut Option<Fixup>)
+                -> Result<BytesComparison, Error> {
     if num_vs > 1 {
         writeln!(writable, "Reading {}", hashes_filename)?;
     }
     let hashes_path = Path::new(hashes_filename);
-    let hashes_file = File::open(&hashes_path)?;
+    let hashes_file = File::open(hashes_path)?;
     let num_bytes_hashed = bytes_from_last_line(&last_line_of(&hashes_file)?)?;
     let progress_bar = if progress {
         Some(ProgressBar::new(num_bytes_hashed as u64))
@@ -378,111 +650,80 @@ pub fn compare_hashes(hashes_filename: &str, directory: &str, num_vs: u8,
     if num_vs > 0 {
         writeln!(writable, "Num bytes previously hashed: {}", num_bytes_hashed)?;
     }
+    if archive::is_archive(directory) {
+        return archive::verify_archive(hashes_filename, directory, writable,
+                num_vs, &progress_bar, filter);
+    }
 
     /* Iterate line by line (except the final line) */
     let reader = BufReader::new(hashes_file);
-    let mut to_return = BytesComparison{agreement: 0, disagreement: 0};
+    let mut to_return = BytesComparison::default();
+    let mut num_bytes_filtered_out: usize = 0;
+    let mut manifest_paths = std::collections::HashSet::new();
     for line in reader.lines() {
         let line = line.unwrap();
-        let pieces = line.split_whitespace().collect::<Vec<_>>();
 
         /* Quit at last line */
-        if pieces.len() == 3 && pieces[1] == "bytes" && pieces[2] == "hashed" {
-            break;
-        }
-
-        if pieces.len() != 4 {
-            return Err(Error::new(ErrorKind::Other,
-                    "Corrupt file: found a line without 4 components"));
-        }
-
-        let sha1 = pieces[1];
+        let entry = match ManifestEntry::from_line(&line)? {
+            Some(entry) => entry,
+            None => break,
+        };
 
-        let num_bytes_hashed = pieces[3].parse::<usize>();
-        if num_bytes_hashed.is_err() {
-            let err_s = "Can't interpret ".to_owned() + pieces[3] +
-                    " as an integer.";
-            return Err(Error::new(ErrorKind::Other, err_s));
+        /* Directory hashes only restate what's checked file by file */
+        if let EntryKind::Directory(_) = entry.kind {
+            continue;
         }
-        let num_bytes_hashed = num_bytes_hashed.unwrap();
 
-        let old_path_s = path_string_from_b64(pieces[2])?;
-        let path = Path::new(directory).join(Path::new(&old_path_s));
+        let path = Path::new(directory).join(Path::new(&entry.path));
+        manifest_paths.insert(entry.path.clone());
 
         if num_vs > 1 {
             writeln!(writable, "Examining {}", path.display())?;
         }
 
-        output_progress(num_bytes_hashed as u64, &progress_bar);
+        output_progress(entry.size as u64, &progress_bar);
 
-        if !path.is_file() {
-            if num_bytes_hashed == 0 {
-                writeln!(writable, "Disagreement (0 bytes): {} is empty and {} doesn't exist.",
-                        old_path_s, path.display())?;
-                continue;
-            }
-            else {
-                writeln!(writable,
-                        "Disagreement ({} bytes): {} exists and {} doesn't exist.",
-                        num_bytes_hashed, old_path_s, path.display())?;
-                to_return += BytesComparison{disagreement: num_bytes_hashed,
-                        agreement: 0};
-                continue;
+        if !filter.wants_manifest_entry(directory, &entry.path, entry.size) {
+            if num_vs > 1 {
+                writeln!(writable, "Filtered out {}", path.display())?;
             }
-        }
-
-        /* Don't bother to hash if filesizes don't match
-         *
-         * The logic here has decided that nonexistent files and
-         * empty files are equal!
-         */
-        let cur_size = size_from_path(&path)?;
-        let max_bytes_compared = cmp::max(cur_size, num_bytes_hashed);
-        if cur_size != num_bytes_hashed {
-            writeln!(writable, "Disagreement ({} bytes): {} and {} are different sizes.",
-                    max_bytes_compared, old_path_s, path.display())?;
-            to_return += BytesComparison{disagreement: max_bytes_compared, agreement: 0};
+            num_bytes_filtered_out += entry.size;
             continue;
         }
 
-        match hash_of_path(&path) {
-            Ok(hash_and_size) => {
-                let hash_s = hash_and_size.0;
-                let num_bytes_hashed = hash_and_size.1;
-                if sha1 == hash_s {
-                    to_return += BytesComparison{disagreement: 0, agreement: num_bytes_hashed};
-                    continue;
+        let comparison = verify_entry(&entry, directory, &mut writable, cache)?;
+        to_return += comparison;
+        if let Some(ref mut fixup) = fixup {
+            if comparison.disagrees() {
+                if path.symlink_metadata().is_ok() {
+                    fixup.differing.push(entry.path.clone());
                 }
                 else {
-                    writeln!(writable, "Disagreement ({} bytes): {} and {} have different hashes.",
-                            max_bytes_compared, old_path_s, path.display())?;
-                    to_return += BytesComparison{disagreement: max_bytes_compared, agreement: 0};
-                    continue;
+                    fixup.missing.push(entry.path.clone());
                 }
-            },
-            Err(_) => {
-                writeln!(writable, "Disagreement ({} bytes): Couldn't hash {}",
-                        max_bytes_compared, path.display())?;
-                to_return += BytesComparison{disagreement: max_bytes_compared, agreement: 0};
-                continue;
             }
         }
     }
 
-    writeln!(writable,
-            "Agreed on {}/{} bytes ({}% confidence)",
-            to_return.agreement, num_bytes_hashed,
-            ((to_return.agreement as f32 / num_bytes_hashed as f32) * 100.0))?;
-    if to_return.disagreement > 0 {
-        writeln!(writable,
-            "Disagreed on {}/{} bytes ({}% worry)",
-            to_return.disagreement, num_bytes_hashed,
-            ((to_return.disagreement as f32 / num_bytes_hashed as f32) * 100.0))?;
-        writeln!(writable,
-                "The last writeln will be ignored for no reason I understand.")?;
+    /* Only worth looking for what the manifest doesn't have if it's going
+     * to be written out */
+    if let Some(ref mut fixup) = fixup {
+        for entry in filter.walk(directory).filter_map(|entry| entry.ok()) {
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let relative = relative_path_string(entry.path(), directory)?;
+            if !manifest_paths.contains(&relative) {
+                fixup.extra.push(relative);
+            }
+        }
     }
-    else {
-        writeln!(writable, "Disagreed on 0 bytes. (0% worry)")?;
+
+    let num_bytes_hashed = num_bytes_hashed.saturating_sub(num_bytes_filtered_out);
+    to_return.write_summary(&mut writable, num_bytes_hashed)?;
+    if to_return.entry_disagreement > 0 {
+        writeln!(writable, "Disagreed on {} special files.",
+                to_return.entry_disagreement)?;
     }
 
     Ok(to_return)
@@ -492,28 +733,112 @@ pub fn compare_hashes(hashes_filename: &str, directory: &str, num_vs: u8,
 /// Print a progress bar to stderr if `progress` is_some
 pub fn output_progress(numerator: u64, progress_bar: &Option<ProgressBar>) {
     if let Some(ref bar) = progress_bar {
-      bar.inc(numerator as u64);
+      bar.inc(numerator);
+    }
+}
+
+
+/// A lot of dancing around to return a regular io::Error instead of
+/// walkdir::Error. Maybe this can be avoided.  `None` means it's a
+/// permission error we were told to move past.
+pub fn walk_error(error: walkdir::Error, ignore_perm_errors_flag: bool)
+        -> Option<Error> {
+    match error.io_error() {
+        Some(io_error) => {
+            let kind = io_error.kind();
+            match kind {
+                ErrorKind::PermissionDenied => {
+                    if ignore_perm_errors_flag {
+                        return None;
+                    }
+                    Some(Error::new(kind, error))
+                },
+                _ => {
+                    Some(Error::new(kind, error))
+                }
+            }
+        },
+
+        /* Doesn't correspond to IO error, e.g. cycle following
+         * symbolic links */
+        None => {
+            Some(Error::other(error))
+        }
     }
 }
 
 
+/// Everything about a run besides what's being compared or hashed and
+/// where the output goes.  The defaults are what you get with no flags.
+#[derive(Default)]
+pub struct RuntimeOptions {
+    pub ignore_perm_errors_flag: bool,
+
+    /// The total size, if already known, for percentages and progress
+    pub num_bytes: Option<usize>,
+    pub num_vs: u8,
+    pub progress: bool,
+    pub find_file_sizes: bool,
+    pub filter: Filter,
+    pub tree_hashes: bool,
+    pub stat_columns: bool,
+    pub cache: Option<HashCache>,
+
+    /// Filled in with whatever disagreed, when asked for
+    pub fixup: Option<Fixup>,
+
+    /// Given the parity of every file as it's hashed, when asked for
+    pub parity: Option<ParityWriter>,
+}
+
+
 /// Return number of bytes 
+#[allow(clippy::too_many_arguments)]
 pub fn runtime_with_regular_args(ignore_perm_errors_flag: bool,
         num_bytes: Option<usize>, filename_l: &str, filename_r: Option<&str>,
-        hashes_filename: Option<&str>, mut writable: impl Write, num_vs: u8,
-        progress: bool, find_file_sizes: bool) ->
-        Result<i32, Error> {
+        hashes_filename: Option<&str>, writable: impl Write, num_vs: u8,
+        progress: bool, find_file_sizes: bool) -> Result<i32, Error> {
+    runtime_with_options(filename_l, filename_r, hashes_filename, writable,
+            &mut RuntimeOptions{ignore_perm_errors_flag, num_bytes, num_vs,
+                    progress, find_file_sizes, ..Default::default()})
+}
+
+
+/// `runtime_with_regular_args`, with everything else that can be asked for
+pub fn runtime_with_options(filename_l: &str, filename_r: Option<&str>,
+        hashes_filename: Option<&str>, mut writable: impl Write,
+        options: &mut RuntimeOptions) -> Result<i32, Error> {
+    let RuntimeOptions{ignore_perm_errors_flag, num_bytes, num_vs, progress,
+            find_file_sizes, ref filter, tree_hashes, stat_columns, ref cache,
+            ref mut fixup, ref mut parity} = *options;
     let comparing_paths = filename_r.is_some();
     let comparing_hashes = hashes_filename.is_some();
 
+    /* Archives are read as trees of their own instead of walked, and
+     * there's no copying into or out of them, or stat of what's in them */
+    let reading_archives = archive::is_archive(filename_l) ||
+            filename_r.is_some_and(archive::is_archive);
+    if reading_archives && fixup.is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                "There's no fixing up an archive or disk image, or copying out of one"));
+    }
+    if reading_archives && stat_columns {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                "What's in an archive or disk image has no inodes for stat columns"));
+    }
+    if reading_archives && parity.is_some() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                "There's no repairing an archive or disk image from parity"));
+    }
+
     if comparing_hashes {
         match compare_hashes(hashes_filename.unwrap(), filename_l, num_vs,
-                writable, progress) {
+                writable, progress, filter, cache, fixup) {
             Err(error) => {
-                return Err(Error::new(ErrorKind::Other, error));
+                return Err(Error::other(error));
             },
             Ok(bytes_comparison) => {
-                if bytes_comparison.disagreement > 0 {
+                if bytes_comparison.disagrees() {
                     return Ok(1);
                 }
                 else {
@@ -524,69 +849,100 @@ pub fn runtime_with_regular_args(ignore_perm_errors_flag: bool,
     }
 
     /* Otherwise, walk the tree now */
-    let mut bytes_compared = BytesComparison{disagreement: 0, agreement: 0};
+    let mut bytes_compared = BytesComparison::default();
     let mut bytes_examined: usize = 0;
-    let progress_bar = if progress && num_bytes.is_some() {
-        Some(ProgressBar::new(num_bytes.unwrap() as u64))
+    let progress_bar = match num_bytes {
+        Some(num_bytes) if progress => Some(ProgressBar::new(num_bytes as u64)),
+        // Somehow this doesn't work.
+        // _ if progress && find_file_sizes => Some(ProgressBar::new_spinner()),
+        _ => None,
+    };
+    let mut tree_hasher = if tree_hashes {
+        Some(TreeHasher::default())
     }
-    // Somehow this doesn't work.
-    // else if progress && find_file_sizes {
-    //     Some(ProgressBar::new_spinner())
-    // }
     else {
         None
     };
-    for entry in WalkDir::new(filename_l) {
+
+    if reading_archives {
+        if comparing_paths {
+            bytes_compared = archive::compare_with_archives(filename_l,
+                    filename_r.unwrap(), &mut writable, num_vs, &progress_bar,
+                    filter, ignore_perm_errors_flag, cache)?;
+        }
+        else if find_file_sizes {
+            bytes_examined = archive::size_of_archive(filename_l, filter)?;
+        }
+        else {
+            bytes_examined = archive::hash_archive(filename_l, &mut writable,
+                    num_vs, &progress_bar, filter, &mut tree_hasher)?;
+        }
+    }
+    let walk = if reading_archives { None } else { Some(filter.walk(filename_l)) };
+    for entry in walk.into_iter().flatten() {
         match entry {
             Ok(entry) => {
                 if comparing_paths {
-                    bytes_compared += compare_paths(entry.path(),
+                    let comparison = compare_paths(entry.path(),
                             filename_l, filename_r.unwrap(), &mut writable,
-                            num_vs, &progress_bar)?;
+                            num_vs, &progress_bar, cache)?;
+                    bytes_compared += comparison;
+                    if let Some(ref mut fixup) = fixup {
+                        if comparison.disagrees() {
+                            let relative = relative_path_string(entry.path(),
+                                    filename_l)?;
+                            if Path::new(filename_r.unwrap()).join(&relative)
+                                    .symlink_metadata().is_ok() {
+                                fixup.differing.push(relative);
+                            }
+                            else {
+                                fixup.missing.push(relative);
+                            }
+                        }
+                    }
                 }
 
                 else if find_file_sizes {
                     let before = bytes_examined;
-                    match size_from_path(entry.path()) {
-                        Ok(num_bytes) => {
-                            bytes_examined += num_bytes;
-                            output_progress((bytes_examined - before) as u64,
-                                    &progress_bar);
-                        },
-                        Err(_) => {}
+                    if let Ok(num_bytes) = size_from_path(entry.path()) {
+                        bytes_examined += num_bytes;
+                        output_progress((bytes_examined - before) as u64,
+                                &progress_bar);
                     }
                 }
 
                 /* This is the generated hashes case */
                 else {
-                    bytes_examined += hash_path(entry.path(), filename_l,
-                            &mut writable, num_vs, &progress_bar)?;
+                    let manifest_entry = hash_path_entry(entry.path(),
+                            filename_l, &mut writable, num_vs, &progress_bar,
+                            cache, stat_columns)?;
+                    if let Some(ref manifest_entry) = manifest_entry {
+                        bytes_examined += manifest_entry.size;
+                        if let (Some(ref mut parity), EntryKind::File(_)) =
+                                (&mut *parity, &manifest_entry.kind) {
+                            parity.add(entry.path(), &manifest_entry.path)?;
+                        }
+                    }
+
+                    if let Some(ref mut tree_hasher) = tree_hasher {
+                        if let Some(ref manifest_entry) = manifest_entry {
+                            tree_hasher.add_entry(manifest_entry);
+                        }
+                        else if entry.file_type().is_dir() {
+                            tree_hasher.add_directory(&relative_path_string(
+                                    entry.path(), filename_l)?);
+                        }
+                    }
                 }
             },
 
-            /* A lot of dancing around to return a regular io::Error instead of
-             * walkdir::Error. Maybe this can be avoided. */
             Err(error) => {
-                match error.io_error() {
-                    Some(io_error) => {
-                        let kind = io_error.kind();
-                        match kind {
-                            ErrorKind::PermissionDenied => {
-                                if ignore_perm_errors_flag {
-                                    continue;
-                                }
-                                return Err(Error::new(kind, error));
-                            },
-                            _ => {
-                                return Err(Error::new(kind, error));
-                            }
-                        }
+                match walk_error(error, ignore_perm_errors_flag) {
+                    Some(error) => {
+                        return Err(error);
                     },
-
-                    /* Doesn't correspond to IO error, e.g. cycle following
-                     * symbolic links */
                     None => {
-                        return Err(Error::new(ErrorKind::Other, error));
+                        continue;
                     }
                 }
             }
@@ -596,17 +952,28 @@ pub fn runtime_with_regular_args(ignore_perm_errors_flag: bool,
     // TODO This should only be written out when comparing to another
     // directory or a list of hashes
     if comparing_paths {
+        if let Some(ref mut fixup) = fixup {
+            for extra in repair::extras_of(filename_l, filename_r.unwrap(),
+                    filter, ignore_perm_errors_flag)? {
+                fixup.extra.push(relative_path_string(&extra,
+                        filename_r.unwrap())?);
+            }
+        }
+        if bytes_compared.entry_disagreement > 0 {
+            writeln!(writable, "{} special files disagree.",
+                    bytes_compared.entry_disagreement)?;
+        }
         match num_bytes {
             Some(num_bytes) => {
                 writeln!(writable, "{} of {} bytes agree.  ({}% confidence)",
                         bytes_compared.agreement, num_bytes,
-                        (bytes_compared.agreement as f32 / num_bytes as f32) * 100.0)?;
+                        percent_of(bytes_compared.agreement, num_bytes))?;
                 writeln!(writable,
                         "The last writeln will be ignored for no reason I understand.")?;
                 if bytes_compared.disagreement > 0 {
                     writeln!(writable, "{} of {} bytes disagree.  ({}% worry)",
                             bytes_compared.disagreement, num_bytes,
-                            (bytes_compared.disagreement as f32 / num_bytes as f32) * 100.0)?;
+                            percent_of(bytes_compared.disagreement, num_bytes))?;
                 }
                 else {
                     writeln!(writable, "0 bytes disagree.  (0% worry)")?;
@@ -630,11 +997,21 @@ pub fn runtime_with_regular_args(ignore_perm_errors_flag: bool,
         return Ok(0);
     }
     else {
+        if let Some(tree_hasher) = tree_hasher {
+            let directory_entries = tree_hasher.finish();
+            for directory_entry in &directory_entries {
+                writeln!(writable, "{}", directory_entry.to_line())?;
+            }
+            if let Some(EntryKind::Directory(ref root_hash)) =
+                    directory_entries.last().map(|entry| &entry.kind) {
+                writeln!(writable, "Tree fingerprint: {}", root_hash)?;
+            }
+        }
         writeln!(writable, "{} bytes hashed", bytes_examined)?;
         return Ok(0);
     }
 
-    if bytes_compared.disagreement > 0 {
+    if bytes_compared.disagrees() {
         Ok(1)
     }
     else {
@@ -643,8 +1020,606 @@ pub fn runtime_with_regular_args(ignore_perm_errors_flag: bool,
 }
 
 
+/// Open `--output-filename`, or stdout without one.  Gives the exit code
+/// to return if the file can't be created.
+fn output_from_matches(matches: &ArgMatches) -> Result<Box<dyn Write>, i32> {
+    match matches.value_of("output") {
+        Some(filename) => {
+            match File::create(filename) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(_error) => {
+                    println!("Couldn't open '{}' for writing.", filename);
+                    Err(2)
+                }
+            }
+        },
+        None => Ok(Box::new(std::io::stdout())),
+    }
+}
+
+
+/// `filter_from_matches`, printing the error and giving the exit code to
+/// return if the flags don't make sense
+fn filter_or_exit(matches: &ArgMatches) -> Result<Filter, i32> {
+    filter_from_matches(matches).map_err(|error| {
+        println!("{}", error);
+        1
+    })
+}
+
+
+/// Build a `Filter` out of `--include`, `--exclude`, `--min-size` and
+/// friends.  `.confidenceignore` files are honored unless told otherwise.
+pub fn filter_from_matches(matches: &ArgMatches) -> Result<Filter, Error> {
+    let mut filter = Filter::default();
+    let includes = matches.values_of("include")
+            .map(|values| values.collect::<Vec<_>>()).unwrap_or_default();
+    let excludes = matches.values_of("exclude")
+            .map(|values| values.collect::<Vec<_>>()).unwrap_or_default();
+    filter.includes = filter::glob_set(&includes)?;
+    filter.excludes = filter::glob_set(&excludes)?;
+    if let Some(size_s) = matches.value_of("min-size") {
+        filter.min_size = Some(filter::parse_size(size_s)?);
+    }
+    if let Some(size_s) = matches.value_of("max-size") {
+        filter.max_size = Some(filter::parse_size(size_s)?);
+    }
+    if let Some(time_s) = matches.value_of("newer-than") {
+        filter.newer_than = Some(filter::parse_time(time_s)?);
+    }
+    if let Some(time_s) = matches.value_of("older-than") {
+        filter.older_than = Some(filter::parse_time(time_s)?);
+    }
+    if let Some(depth_s) = matches.value_of("max-depth") {
+        match depth_s.parse::<usize>() {
+            Ok(max_depth) => {
+                filter.max_depth = Some(max_depth);
+            },
+            Err(_) => {
+                let err_s = "Couldn't interpret '".to_owned() + depth_s +
+                        "' as a depth.";
+                return Err(Error::other(err_s));
+            }
+        }
+    }
+    filter.one_file_system = matches.is_present("one-file-system");
+    if !matches.is_present("no-ignore-files") {
+        filter.ignore_filenames.push(".confidenceignore".to_owned());
+        if matches.is_present("gitignore") {
+            filter.ignore_filenames.push(".gitignore".to_owned());
+        }
+    }
+    Ok(filter)
+}
+
+
+/// Open the cache named by `--cache`, if any, trusting it unless told
+/// `--verify-cache`.
+pub fn cache_from_matches(matches: &ArgMatches)
+        -> Result<Option<HashCache>, Error> {
+    match matches.value_of("cache") {
+        Some(filename) => {
+            let mode = if matches.is_present("verify-cache") {
+                cache::CacheMode::Verify
+            }
+            else {
+                cache::CacheMode::Trust
+            };
+            Ok(Some(HashCache::open(filename, mode)?))
+        },
+        None => Ok(None),
+    }
+}
+
+
+/// Turn what one of the other modes returned into an exit code, the way
+/// `actual_runtime` does for the main mode.
+pub fn exit_code_of(result: Result<BytesComparison, Error>) -> i32 {
+    match result {
+        Ok(bytes_comparison) => {
+            if bytes_comparison.disagrees() {
+                1
+            }
+            else {
+                0
+            }
+        },
+        Err(error) => {
+            println!("Unexpected error: \"{}\"", error);
+            1
+        }
+    }
+}
+
+
+/// The `update` subcommand
+pub fn update_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let rehash_fraction = match matches.value_of("rehash-fraction") {
+        Some(fraction_s) => {
+            match fraction_s.parse::<f64>() {
+                Ok(fraction) if (0.0..=1.0).contains(&fraction) => fraction,
+                _ => {
+                    println!("Couldn't interpret '{}' as a fraction between 0 and 1.",
+                            fraction_s);
+                    return 1;
+                }
+            }
+        },
+        None => 0.0,
+    };
+    let cache = match cache_from_matches(matches) {
+        Ok(cache) => cache,
+        Err(error) => {
+            println!("Couldn't open the hash cache: {}", error);
+            return 1;
+        }
+    };
+    let output_file = match output_from_matches(matches) {
+        Ok(output_file) => output_file,
+        Err(code) => return code,
+    };
+
+    exit_code_of(update::update_manifest(
+            matches.value_of("old-manifest").unwrap(),
+            matches.value_of("directory").unwrap(), output_file, num_vs,
+            matches.is_present("progress"), &filter,
+            matches.is_present("ignore-permission-errors"), rehash_fraction,
+            matches.is_present("stat-columns"),
+            &cache))
+}
+
+
+/// The `tag` and `check-tags` subcommands
+pub fn tags_runtime(matches: &ArgMatches, num_vs: u8, check: bool) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let namespace = match matches.value_of("namespace") {
+        Some("confidence") => tags::CONFIDENCE,
+        _ => tags::SHATAG,
+    };
+    let directory = matches.value_of("directory").unwrap();
+    let ignore_perm_errors_flag = matches.is_present("ignore-permission-errors");
+    if check {
+        exit_code_of(tags::check_tags(directory, std::io::stdout(), num_vs,
+                &filter, ignore_perm_errors_flag, namespace))
+    }
+    else {
+        exit_code_of(tags::tag_tree(directory, std::io::stdout(), num_vs,
+                &filter, ignore_perm_errors_flag, namespace))
+    }
+}
+
+
+/// The `scrub` subcommand
+pub fn scrub_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let history = match history::History::open(
+            matches.value_of("history").unwrap()) {
+        Ok(history) => history,
+        Err(error) => {
+            println!("Couldn't open the scrub history: {}", error);
+            return 1;
+        }
+    };
+    exit_code_of(history::scrub(matches.value_of("directory").unwrap(),
+            &history, std::io::stdout(), num_vs, &filter,
+            matches.is_present("ignore-permission-errors"),
+            matches.is_present("forget-missing")))
+}
+
+
+/// The `copy` subcommand
+pub fn copy_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let manifest = match matches.value_of("output") {
+        Some(filename) => {
+            match File::create(filename) {
+                Ok(file) => {
+                    Box::new(file) as Box<dyn Write>
+                },
+                Err(_error) => {
+                    println!("Couldn't open '{}' for writing.", filename);
+                    return 2;
+                }
+            }
+        },
+        None => Box::new(std::io::sink()) as Box<dyn Write>,
+    };
+
+    exit_code_of(copy::copy_tree(matches.value_of("source").unwrap(),
+            matches.value_of("destination").unwrap(), manifest,
+            std::io::stdout(), num_vs, matches.is_present("progress"), &filter,
+            matches.is_present("ignore-permission-errors"),
+            matches.is_present("bypass-page-cache")))
+}
+
+
+/// The `vote` subcommand
+pub fn vote_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let cache = match cache_from_matches(matches) {
+        Ok(cache) => cache,
+        Err(error) => {
+            println!("Couldn't open the hash cache: {}", error);
+            return 1;
+        }
+    };
+    let names = matches.values_of("replicas").unwrap().collect::<Vec<_>>();
+    let mut replicas = Vec::new();
+    for name in &names {
+        match vote::entries_of(name, &filter,
+                matches.is_present("ignore-permission-errors"), &cache) {
+            Ok(entries) => replicas.push(entries),
+            Err(error) => {
+                println!("Couldn't read '{}': {}", name, error);
+                return 1;
+            }
+        }
+    }
+
+    exit_code_of(vote::vote(&replicas, &names, std::io::stdout(), num_vs)
+            .map(|comparisons| comparisons.into_iter()
+                    .fold(BytesComparison::default(), Add::add)))
+}
+
+
+/// `--algorithm`, if given
+fn algorithm_from_matches(matches: &ArgMatches) -> Result<Option<Algorithm>, Error> {
+    matches.value_of("algorithm").map(Algorithm::from_name).transpose()
+}
+
+
+/// The `check-sums` subcommand.  Without a directory, paths are relative
+/// to wherever the checksum file is, which is where vendors put them.
+pub fn check_sums_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let algorithm = match algorithm_from_matches(matches) {
+        Ok(algorithm) => algorithm,
+        Err(error) => {
+            println!("{}", error);
+            return 1;
+        }
+    };
+    let sums_filename = matches.value_of("sum-file").unwrap();
+    let directory = match matches.value_of("directory") {
+        Some(directory) => directory.to_owned(),
+        None => {
+            match Path::new(sums_filename).parent().and_then(|parent| parent.to_str()) {
+                Some(parent) if !parent.is_empty() => parent.to_owned(),
+                _ => ".".to_owned(),
+            }
+        }
+    };
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    exit_code_of(sums::verify_sums(sums_filename, &directory,
+            std::io::stdout(), num_vs, &filter, algorithm))
+}
+
+
+/// The `export-sums` subcommand
+pub fn export_sums_runtime(matches: &ArgMatches) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let source = matches.value_of("source").unwrap();
+
+    /* Manifests only have sha1s, so that's the only sensible default */
+    let algorithm = match algorithm_from_matches(matches) {
+        Ok(Some(algorithm)) => algorithm,
+        Ok(None) if Path::new(source).is_dir() => Algorithm::Sha256,
+        Ok(None) => Algorithm::Sha1,
+        Err(error) => {
+            println!("{}", error);
+            return 1;
+        }
+    };
+    let style = if matches.is_present("tag") {
+        sums::SumStyle::Bsd
+    }
+    else {
+        sums::SumStyle::Gnu
+    };
+    let writable = match output_from_matches(matches) {
+        Ok(output_file) => output_file,
+        Err(code) => return code,
+    };
+
+    match sums::write_sums(source, writable, algorithm, style, &filter,
+            matches.is_present("ignore-permission-errors")) {
+        Ok(_) => 0,
+        Err(error) => {
+            println!("Unexpected error: \"{}\"", error);
+            1
+        }
+    }
+}
+
+
+/// The `bag` subcommand
+pub fn bag_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let algorithms = match matches.values_of("algorithm") {
+        Some(names) => {
+            match names.map(Algorithm::from_name).collect::<Result<Vec<_>, _>>() {
+                Ok(algorithms) => algorithms,
+                Err(error) => {
+                    println!("{}", error);
+                    return 1;
+                }
+            }
+        },
+        None => vec![Algorithm::Sha256],
+    };
+    exit_code_of(bagit::create_bag(matches.value_of("source").unwrap(),
+            matches.value_of("destination"), std::io::stdout(), num_vs,
+            &algorithms, &filter, matches.is_present("ignore-permission-errors")))
+}
+
+
+/// The `mtree` subcommand
+pub fn mtree_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let directory = matches.value_of("directory").unwrap();
+    let ignore_perm_errors_flag = matches.is_present("ignore-permission-errors");
+    if let Some(spec_filename) = matches.value_of("verify") {
+        return exit_code_of(mtree::verify_mtree(spec_filename, directory,
+                std::io::stdout(), num_vs, &filter, ignore_perm_errors_flag));
+    }
+
+    let writable = match output_from_matches(matches) {
+        Ok(output_file) => output_file,
+        Err(code) => return code,
+    };
+    match mtree::write_mtree(directory, writable, &filter,
+            ignore_perm_errors_flag) {
+        Ok(_) => 0,
+        Err(error) => {
+            println!("Unexpected error: \"{}\"", error);
+            1
+        }
+    }
+}
+
+
+/// The `check-sidecars` subcommand
+pub fn check_sidecars_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    exit_code_of(sidecar::verify_sidecars(matches.value_of("directory").unwrap(),
+            std::io::stdout(), num_vs, &filter,
+            matches.is_present("ignore-permission-errors")))
+}
+
+
+/// The `compare-git` subcommand
+pub fn compare_git_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    exit_code_of(git::compare_git(matches.value_of("repo").unwrap(),
+            matches.value_of("rev").unwrap(),
+            matches.value_of("directory").unwrap(), std::io::stdout(), num_vs,
+            matches.is_present("eol"), &filter,
+            matches.is_present("ignore-permission-errors")))
+}
+
+
+/// The `hash` subcommand
+pub fn hash_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let tar_filename = matches.value_of("tar").unwrap();
+    let readable = if tar_filename == "-" {
+        Box::new(std::io::stdin()) as Box<dyn Read>
+    }
+    else {
+        match File::open(tar_filename) {
+            Ok(file) => Box::new(file) as Box<dyn Read>,
+            Err(_error) => {
+                println!("Couldn't open '{}' for reading.", tar_filename);
+                return 2;
+            }
+        }
+    };
+    let writable = match output_from_matches(matches) {
+        Ok(output_file) => output_file,
+        Err(code) => return code,
+    };
+    match archive::hash_tar_stream(readable, writable, num_vs, &filter) {
+        Ok(_) => 0,
+        Err(error) => {
+            println!("Unexpected error: \"{}\"", error);
+            1
+        }
+    }
+}
+
+
+/// The `audit` subcommand
+pub fn audit_runtime(matches: &ArgMatches, num_vs: u8) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    exit_code_of(hashdeep::audit(matches.value_of("hashdeep-file").unwrap(),
+            matches.value_of("directory").unwrap(), std::io::stdout(), num_vs,
+            &filter, matches.is_present("ignore-permission-errors"))
+            .map(|(_, comparison)| comparison))
+}
+
+
+/// The `export-hashdeep` subcommand
+pub fn export_hashdeep_runtime(matches: &ArgMatches) -> i32 {
+    let filter = match filter_or_exit(matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+    let writable = match output_from_matches(matches) {
+        Ok(output_file) => output_file,
+        Err(code) => return code,
+    };
+
+    match hashdeep::write_hashdeep(matches.value_of("directory").unwrap(),
+            writable, &filter, matches.is_present("ignore-permission-errors")) {
+        Ok((_, 0)) => 0,
+        Ok((_, num_unreadable)) => {
+            eprintln!("Left out {} files that couldn't be read.", num_unreadable);
+            1
+        },
+        Err(error) => {
+            println!("Unexpected error: \"{}\"", error);
+            1
+        }
+    }
+}
+
+
+/// The global flags that build a `Filter` (see `filter_from_matches`)
+const FILTER_FLAGS: [&str; 10] = ["include", "exclude", "min-size", "max-size",
+        "newer-than", "older-than", "max-depth", "one-file-system", "gitignore",
+        "no-ignore-files"];
+
+
+/// The first global flag given to `subcommand` that it wouldn't do
+/// anything with, since clap lets them all through to every subcommand
+fn unused_flag(subcommand: &str, matches: &ArgMatches) -> Option<&'static str> {
+    let (filter, ignore_perm_errors, progress, cache) = match subcommand {
+        "update" => (true, true, true, true),
+        "vote" => (true, true, false, true),
+        "copy" => (true, true, true, false),
+        "check-sums" | "hash" => (true, false, false, false),
+        "tag" | "check-tags" | "scrub" | "check-sidecars" | "export-sums" |
+                "bag" | "mtree" | "compare-git" | "audit" |
+                "export-hashdeep" => (true, true, false, false),
+        _ => (false, false, false, false),
+    };
+    let mut unused = Vec::new();
+    if !filter {
+        unused.extend_from_slice(&FILTER_FLAGS);
+    }
+    if !ignore_perm_errors {
+        unused.push("ignore-permission-errors");
+    }
+    if !progress {
+        unused.push("progress");
+    }
+    if !cache {
+        unused.extend_from_slice(&["cache", "verify-cache"]);
+    }
+    unused.into_iter().find(|flag| matches.is_present(flag))
+}
+
 
 pub fn actual_runtime(matches: ArgMatches) -> i32 {
+    let num_vs = matches.occurrences_of("verbosity") as u8;
+
+    if let (subcommand, Some(sub_matches)) = matches.subcommand() {
+        if let Some(flag) = unused_flag(subcommand, sub_matches) {
+            println!("--{} doesn't do anything for {}.", flag, subcommand);
+            return 1;
+        }
+    }
+
+    /* Modes other than comparing and hashing directories */
+    if let Some(sub_matches) = matches.subcommand_matches("manifest-diff") {
+        return exit_code_of(diff::manifest_diff(
+                sub_matches.value_of("old-manifest").unwrap(),
+                sub_matches.value_of("new-manifest").unwrap(),
+                std::io::stdout(), num_vs));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("update") {
+        return update_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("tag") {
+        return tags_runtime(sub_matches, num_vs, false);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("check-tags") {
+        return tags_runtime(sub_matches, num_vs, true);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("scrub") {
+        return scrub_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("vote") {
+        return vote_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("parity-repair") {
+        return exit_code_of(parity::repair_from_parity(
+                sub_matches.value_of("parity-file").unwrap(),
+                sub_matches.value_of("directory").unwrap(), std::io::stdout(),
+                num_vs, sub_matches.is_present("force")));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("copy") {
+        return copy_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("check-sums") {
+        return check_sums_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("check-sidecars") {
+        return check_sidecars_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("export-sums") {
+        return export_sums_runtime(sub_matches);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("bag") {
+        return bag_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("check-bag") {
+        return exit_code_of(bagit::validate_bag(
+                sub_matches.value_of("bag").unwrap(), std::io::stdout(),
+                num_vs));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("mtree") {
+        return mtree_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("verify") {
+        return exit_code_of(torrent::verify_torrent(
+                sub_matches.value_of("torrent").unwrap(),
+                sub_matches.value_of("directory").unwrap(), std::io::stdout(),
+                num_vs).map(|files| files.into_iter()
+                        .map(|(_, comparison)| comparison)
+                        .fold(BytesComparison::default(), Add::add)));
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("compare-git") {
+        return compare_git_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("hash") {
+        return hash_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("audit") {
+        return audit_runtime(sub_matches, num_vs);
+    }
+    if let Some(sub_matches) = matches.subcommand_matches("export-hashdeep") {
+        return export_hashdeep_runtime(sub_matches);
+    }
 
     /* Parse and validate arguments */
     let ignore_perm_errors_flag =
@@ -667,31 +1642,135 @@ pub fn actual_runtime(matches: ArgMatches) -> i32 {
         }
     }
 
+    let filter = match filter_or_exit(&matches) {
+        Ok(filter) => filter,
+        Err(code) => return code,
+    };
+
+    let cache = match cache_from_matches(&matches) {
+        Ok(cache) => cache,
+        Err(error) => {
+            println!("Couldn't open the hash cache: {}", error);
+            return 1;
+        }
+    };
+
     let find_file_sizes = matches.is_present("find-size");
-    let filename_l = matches.value_of("directory_one").unwrap();
-    let filename_r = matches.value_of("directory_two");
-    let num_vs = matches.occurrences_of("verbosity") as u8;
+    let filename_l = matches.value_of("directory-one").unwrap();
+    let filename_r = matches.value_of("directory-two");
     let input_filename = matches.value_of("input");
-    let output_file = match matches.value_of("output") {
-        Some(filename) => {
-            match File::create(filename) {
-                Ok(file) => {
-                    Box::new(file) as Box<dyn Write>
-                },
+    let output_file = match output_from_matches(&matches) {
+        Ok(output_file) => output_file,
+        Err(code) => return code,
+    };
+
+    /* Verifying a manifest a bit at a time needs a history */
+    if let Some(budget_s) = matches.value_of("time-budget") {
+        let time_budget = match filter::parse_duration(budget_s) {
+            Ok(time_budget) => time_budget,
+            Err(error) => {
+                println!("{}", error);
+                return 1;
+            }
+        };
+        let coverage_days = match matches.value_of("coverage-days")
+                .unwrap_or("30").parse::<u64>() {
+            Ok(coverage_days) => coverage_days,
+            Err(_) => {
+                println!("Couldn't interpret '{}' as a number of days.",
+                        matches.value_of("coverage-days").unwrap());
+                return 1;
+            }
+        };
+        let history = match history::History::open(
+                matches.value_of("history").unwrap()) {
+            Ok(history) => history,
+            Err(error) => {
+                println!("Couldn't open the scrub history: {}", error);
+                return 1;
+            }
+        };
+        return exit_code_of(history::compare_hashes_within(
+                input_filename.unwrap(), filename_l, num_vs, output_file,
+                &filter, &history, time_budget, coverage_days, &cache));
+    }
+
+    if matches.is_present("repair") {
+        return exit_code_of(repair::repair_tree(filename_l, filename_r.unwrap(),
+                std::io::stdout(), num_vs, &filter, ignore_perm_errors_flag,
+                matches.is_present("execute"),
+                matches.is_present("delete-extra")));
+    }
+
+    let fixup = matches.value_of("fixup")
+            .map(|_| match filename_r {
+                Some(filename_r) => Fixup::new(Some(filename_l), filename_r),
+                None => Fixup::new(None, filename_l),
+            });
+
+    /* Parity is worked out file by file as they're hashed */
+    let redundancy_s = matches.value_of("redundancy").unwrap_or("10");
+    let redundancy_percent = match redundancy_s.parse::<usize>() {
+        Ok(percent) if (1..=100).contains(&percent) => percent,
+        _ => {
+            println!("Couldn't interpret '{}' as a percentage from 1 to 100.",
+                    redundancy_s);
+            return 1;
+        }
+    };
+    let parity = match matches.value_of("parity") {
+        Some(parity_filename) => {
+            let parity_file = match File::create(parity_filename) {
+                Ok(file) => Box::new(std::io::BufWriter::new(file)) as Box<dyn Write>,
                 Err(_error) => {
-                    println!("Couldn't open '{}' for writing.", filename);
+                    println!("Couldn't open '{}' for writing.", parity_filename);
+                    return 2;
+                }
+            };
+            match ParityWriter::new(parity_file, redundancy_percent) {
+                Ok(parity) => Some(parity),
+                Err(error) => {
+                    println!("Couldn't write '{}': {}", parity_filename, error);
                     return 2;
                 }
             }
         },
-        None => Box::new(std::io::stdout()) as Box<dyn Write>,
+        None => None,
     };
+    let mut options = RuntimeOptions{ignore_perm_errors_flag, num_bytes,
+            num_vs, progress, find_file_sizes, filter,
+            tree_hashes: matches.is_present("tree-hashes"),
+            stat_columns: matches.is_present("stat-columns"), cache, fixup,
+            parity};
 
     /* Run them through the meat of the program */
-    match runtime_with_regular_args(ignore_perm_errors_flag, num_bytes,
-            filename_l, filename_r, input_filename, output_file, num_vs,
-            progress, find_file_sizes) {
+    match runtime_with_options(filename_l, filename_r, input_filename,
+            output_file, &mut options) {
         Ok(retval) => {
+            if let Some(ref fixup) = options.fixup {
+                let format = match matches.value_of("fixup-format") {
+                    Some("rsync") => fixup::FixupFormat::Rsync,
+                    _ => fixup::FixupFormat::Script,
+                };
+                let fixup_filename = matches.value_of("fixup").unwrap();
+                if let Err(error) = fixup.write(fixup_filename, format) {
+                    println!("Couldn't write '{}': {}", fixup_filename, error);
+                    return 2;
+                }
+            }
+            if let Some(parity) = options.parity {
+                let parity_filename = matches.value_of("parity").unwrap();
+                match parity.finish() {
+                    Ok(num_bytes) => {
+                        eprintln!("Wrote {}% parity for {} bytes to {}",
+                                redundancy_percent, num_bytes, parity_filename);
+                    },
+                    Err(error) => {
+                        println!("Couldn't write '{}': {}", parity_filename, error);
+                        return 2;
+                    }
+                }
+            }
             retval
         },
         Err(error) => {
@@ -704,6 +1783,9 @@ pub fn actual_runtime(matches: ArgMatches) -> i32 {
                         None => println!("File named \"{}\" couldn't be found.", filename_l),
                     }
                 },
+                ErrorKind::InvalidInput => {
+                    println!("{}.", outer_error_string);
+                },
                 ErrorKind::PermissionDenied => {
                     match error.into_inner() {
                         Some(inner_error) => {
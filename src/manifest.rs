@@ -0,0 +1,175 @@
+use crate::path_string_from_b64;
+use crate::special::SpecialKind;
+use std::fs::File;
+use std::fs::Metadata;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+
+
+/// What a line of a manifest describes, along with whatever stands in for
+/// its hash.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntryKind {
+    /// A regular file and its sha1
+    File(String),
+
+    /// A FIFO, socket or device.  These have no contents to hash.
+    Special(SpecialKind),
+
+    /// A directory and the hash of its children (see `merkle`)
+    Directory(String),
+}
+
+
+/// What a regular file looked like when it was hashed.  If none of this
+/// has changed, the file very probably hasn't either.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStat {
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub inode: u64,
+}
+
+
+impl FileStat {
+    pub fn of(metadata: &Metadata) -> FileStat {
+        FileStat{mtime: metadata.mtime(), mtime_nsec: metadata.mtime_nsec(),
+                inode: metadata.ino()}
+    }
+
+
+    /// The two columns this adds to a manifest line:
+    /// `<mtime seconds>.<nanoseconds> <inode>`
+    pub fn to_columns(&self) -> String {
+        format!("{}.{:09} {}", self.mtime, self.mtime_nsec, self.inode)
+    }
+
+
+    /// Inverse of `to_columns`
+    pub fn from_columns(mtime_s: &str, inode_s: &str) -> Result<FileStat, Error> {
+        if let Some((seconds, nanoseconds)) = mtime_s.split_once('.') {
+            if let (Ok(mtime), Ok(mtime_nsec), Ok(inode)) = (seconds.parse::<i64>(),
+                    nanoseconds.parse::<i64>(), inode_s.parse::<u64>()) {
+                return Ok(FileStat{mtime, mtime_nsec, inode});
+            }
+        }
+        let err_s = "Can't interpret ".to_owned() + mtime_s + " " + inode_s +
+                " as an mtime and inode.";
+        Err(Error::other(err_s))
+    }
+}
+
+
+/// One line of a manifest, as written by `hash_path`:
+///
+/// `sha1: <hash> <base64 of path> <size> <mtime> <inode>`
+///
+/// Special files and directories have their own tags in place of `sha1:`
+/// and no mtime or inode (older manifests don't have them for anything).
+/// Paths are relative to the directory that was hashed, and the final
+/// line of a manifest is `<total> bytes hashed`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub path: String,
+    pub size: usize,
+    pub stat: Option<FileStat>,
+}
+
+
+impl ManifestEntry {
+
+    /// Parse one line of a manifest.  `Ok(None)` means this was the final
+    /// `XXX bytes hashed` line.
+    pub fn from_line(line: &str) -> Result<Option<ManifestEntry>, Error> {
+        let pieces = line.split_whitespace().collect::<Vec<_>>();
+        if pieces.len() == 3 && pieces[1] == "bytes" && pieces[2] == "hashed" {
+            return Ok(None);
+        }
+
+        /* The whole tree's hash, which is also its `.` directory line */
+        if pieces.len() == 3 && pieces[0] == "Tree" && pieces[1] == "fingerprint:" {
+            return Ok(None);
+        }
+
+        if pieces.len() != 4 && pieces.len() != 6 {
+            return Err(Error::other("Corrupt file: found a line without 4 or 6 components"));
+        }
+        let stat = if pieces.len() == 6 {
+            Some(FileStat::from_columns(pieces[4], pieces[5])?)
+        }
+        else {
+            None
+        };
+
+        let size = match pieces[3].parse::<usize>() {
+            Ok(size) => size,
+            Err(_) => {
+                let err_s = "Can't interpret ".to_owned() + pieces[3] +
+                        " as an integer.";
+                return Err(Error::other(err_s));
+            }
+        };
+
+        let kind = match pieces[0] {
+            "sha1:" => EntryKind::File(pieces[1].to_owned()),
+            "dir:" => EntryKind::Directory(pieces[1].to_owned()),
+            tag => {
+                match SpecialKind::from_tag_and_value(tag, pieces[1])? {
+                    Some(kind) => EntryKind::Special(kind),
+                    None => {
+                        let err_s = "Corrupt file: unknown entry type ".to_owned() +
+                                tag;
+                        return Err(Error::other(err_s));
+                    }
+                }
+            }
+        };
+
+        Ok(Some(ManifestEntry{kind, path: path_string_from_b64(pieces[2])?,
+                size, stat}))
+    }
+
+
+    /// The first two columns of this entry's line, e.g. `sha1:` and the
+    /// hash
+    pub fn tag_and_value(&self) -> (&'static str, String) {
+        match self.kind {
+            EntryKind::File(ref hash) => ("sha1:", hash.clone()),
+            EntryKind::Special(kind) => (kind.tag(), kind.value()),
+            EntryKind::Directory(ref hash) => ("dir:", hash.clone()),
+        }
+    }
+
+
+    /// The line (sans newline) that `from_line` would read back as this
+    pub fn to_line(&self) -> String {
+        let (tag, value) = self.tag_and_value();
+        let line = format!("{} {} {} {}", tag, value,
+                base64::encode(&self.path), self.size);
+        match self.stat {
+            Some(stat) => line + " " + &stat.to_columns(),
+            None => line,
+        }
+    }
+}
+
+
+/// All the entries in the manifest `filename` and the number of bytes it
+/// says were hashed.
+pub fn read_manifest(filename: &str)
+        -> Result<(Vec<ManifestEntry>, usize), Error> {
+    let file = File::open(filename)?;
+    let num_bytes_hashed = crate::bytes_from_last_line(
+            &crate::last_line_of(&file)?)?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        match ManifestEntry::from_line(&line?)? {
+            Some(entry) => entries.push(entry),
+            None => break,
+        }
+    }
+    Ok((entries, num_bytes_hashed))
+}
@@ -0,0 +1,137 @@
+use crate::manifest::EntryKind;
+use crate::manifest::ManifestEntry;
+use std::collections::BTreeMap;
+
+
+/// What the top of a tree is called in a manifest, since an empty path
+/// wouldn't survive being written out.
+pub const ROOT: &str = ".";
+
+
+/// Builds a hash for every directory out of the hashes of its children,
+/// bottom up, so that the hash of the top directory fingerprints the
+/// whole tree.  Two directories have the same hash exactly when they have
+/// the same names, types and contents all the way down.
+///
+/// A directory's hash is the sha1 of one `<tag> <hash> <base64 of name>`
+/// line per child, sorted by name, where the tag and hash are what that
+/// child's manifest line has.
+///
+/// Only `manifest-diff` makes use of directory hashes, to skip subtrees
+/// that agree.  Verifying a tree against a manifest still checks every
+/// file, and skips the `dir:` lines.
+#[derive(Debug, Default)]
+pub struct TreeHasher {
+
+    /// Each directory (relative to the top, "" for the top) and the
+    /// children seen in it so far
+    children: BTreeMap<String, Vec<Child>>,
+}
+
+
+#[derive(Debug)]
+struct Child {
+    name: String,
+    tag: String,
+    hash: String,
+    size: usize,
+}
+
+
+impl TreeHasher {
+
+    /// Note a directory, relative to the top of the tree, so it's hashed
+    /// even if it turns out to be empty.
+    pub fn add_directory(&mut self, relative: &str) {
+        self.children.entry(relative.to_owned()).or_default();
+    }
+
+
+    /// Note a file (or special file) from a manifest
+    pub fn add_entry(&mut self, entry: &ManifestEntry) {
+        let (tag, hash) = entry.tag_and_value();
+        let (parent, name) = split_parent(&entry.path);
+        self.children.entry(parent.to_owned()).or_default().push(Child{
+            name: name.to_owned(),
+            tag: tag.to_owned(),
+            hash,
+            size: entry.size,
+        });
+    }
+
+
+    /// Manifest entries for every directory, deepest first, so the top of
+    /// the tree (named `ROOT`) is last.  Its size is the total size of
+    /// everything in it.
+    pub fn finish(mut self) -> Vec<ManifestEntry> {
+
+        /* Every parent of a directory is a directory too */
+        let directories = self.children.keys().cloned().collect::<Vec<_>>();
+        for directory in directories {
+            let mut cur = directory.as_str();
+            while !cur.is_empty() {
+                cur = split_parent(cur).0;
+                self.children.entry(cur.to_owned()).or_default();
+            }
+        }
+
+        let mut directories = self.children.keys().cloned().collect::<Vec<_>>();
+        directories.sort_by_key(|directory| {
+            std::cmp::Reverse(depth_of(directory))
+        });
+
+        let mut to_return = Vec::new();
+        for directory in directories {
+            let mut children = self.children.remove(&directory)
+                    .unwrap_or_default();
+            children.sort_by(|a, b| a.name.cmp(&b.name));
+
+            let mut cur_hash = sha1::Sha1::new();
+            let mut size = 0;
+            for child in &children {
+                cur_hash.update(format!("{} {} {}\n", child.tag, child.hash,
+                        base64::encode(&child.name)).as_bytes());
+                size += child.size;
+            }
+            let hash = cur_hash.digest().to_string();
+
+            if directory.is_empty() {
+                to_return.push(ManifestEntry{kind: EntryKind::Directory(hash),
+                        path: ROOT.to_owned(), size, stat: None});
+            }
+            else {
+                let (parent, name) = split_parent(&directory);
+                self.children.entry(parent.to_owned()).or_default()
+                        .push(Child{
+                    name: name.to_owned(),
+                    tag: "dir:".to_owned(),
+                    hash: hash.clone(),
+                    size,
+                });
+                to_return.push(ManifestEntry{kind: EntryKind::Directory(hash),
+                        path: directory, size, stat: None});
+            }
+        }
+
+        to_return
+    }
+}
+
+
+/// `("a/b", "c")` for `"a/b/c"` and `("", "a")` for `"a"`
+pub fn split_parent(relative: &str) -> (&str, &str) {
+    match relative.rsplit_once('/') {
+        Some((parent, name)) => (parent, name),
+        None => ("", relative),
+    }
+}
+
+
+fn depth_of(relative: &str) -> usize {
+    if relative.is_empty() {
+        0
+    }
+    else {
+        relative.matches('/').count() + 1
+    }
+}
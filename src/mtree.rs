@@ -0,0 +1,492 @@
+use crate::algorithm::hashes_of_path_with;
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::relative_path_string;
+use crate::BytesComparison;
+use std::cmp;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+
+/// The top of the tree, as an mtree spec names it
+pub const ROOT: &str = ".";
+
+
+/// One entry of an mtree spec: a path (relative to the top of the tree,
+/// or `ROOT`) and its keywords, in order, with any `/set` defaults
+/// filled in.  Keywords like `optional` have no value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MtreeEntry {
+    pub path: String,
+    pub keywords: Vec<(String, String)>,
+}
+
+
+impl MtreeEntry {
+    pub fn get(&self, keyword: &str) -> Option<&str> {
+        self.keywords.iter().find(|(other, _)| other == keyword)
+                .map(|(_, value)| value.as_str())
+    }
+
+
+    /// The line (sans newline) for this entry in a full path spec
+    pub fn to_line(&self) -> String {
+        let path = if self.path == ROOT {
+            ROOT.to_owned()
+        }
+        else {
+            "./".to_owned() + &encoded(&self.path)
+        };
+        self.keywords.iter().fold(path, |line, (keyword, value)| {
+            if value.is_empty() {
+                line + " " + keyword
+            }
+            else {
+                line + " " + keyword + "=" + value
+            }
+        })
+    }
+}
+
+
+/// Names in specs are encoded with vis(3): anything that isn't printable
+/// ASCII, and anything that means something in a spec, becomes `\ooo`
+fn encoded(name: &str) -> String {
+    let mut to_return = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_graphic() && !b"\\#=*?[".contains(&byte) {
+            to_return.push(byte as char);
+        }
+        else {
+            to_return.push_str(&format!("\\{:03o}", byte));
+        }
+    }
+    to_return
+}
+
+
+/// Inverse of `encoded`, which also understands the C style escapes some
+/// mtree implementations write
+fn decoded(name: &str) -> Result<String, Error> {
+    let bytes = name.as_bytes();
+    let mut to_return = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            to_return.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let octal = &bytes[i + 1..cmp::min(i + 4, bytes.len())];
+        if octal.len() == 3 && octal.iter().all(|byte| (b'0'..=b'7').contains(byte)) {
+            to_return.push(octal.iter().fold(0u8, |sum, byte| {
+                sum.wrapping_mul(8) + (byte - b'0')
+            }));
+            i += 4;
+            continue;
+        }
+        to_return.push(match bytes[i + 1] {
+            b's' => b' ',
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            other => other,
+        });
+        i += 2;
+    }
+    String::from_utf8(to_return).map_err(|_| {
+        Error::other("Can't decode the mtree name ".to_owned() + name)
+    })
+}
+
+
+/// `ROOT` for the top of the tree, else `path` without `./` in front
+fn normalized(path: &str) -> String {
+    let path = path.trim_end_matches('/');
+    if path == ROOT || path.is_empty() {
+        return ROOT.to_owned();
+    }
+    path.strip_prefix("./").unwrap_or(path).to_owned()
+}
+
+
+/// `a/b` for `b` in `a`, but just `b` at the top
+fn joined(directory: &str, name: &str) -> String {
+    if directory == ROOT {
+        name.to_owned()
+    }
+    else {
+        directory.to_owned() + "/" + name
+    }
+}
+
+
+/// Read the mtree spec in `contents`, in either the full path form
+/// (`./a/b type=file ...`) or the classic form, where an entry without a
+/// slash in its name is in the current directory, a directory entry
+/// becomes the current directory and `..` goes back up.
+pub fn parse_mtree(contents: &str) -> Result<Vec<MtreeEntry>, Error> {
+    let mut to_return = Vec::new();
+    let mut defaults: Vec<(String, String)> = Vec::new();
+    let mut current = ROOT.to_owned();
+    let mut line = String::new();
+    for physical_line in contents.lines() {
+
+        /* A backslash at the end continues the line */
+        if let Some(start) = physical_line.strip_suffix('\\') {
+            line.push_str(start);
+            line.push(' ');
+            continue;
+        }
+        line.push_str(physical_line);
+        let logical_line = std::mem::take(&mut line);
+        let mut words = logical_line.split_whitespace();
+        let first = match words.next() {
+            Some(first) if !first.starts_with('#') => first,
+            _ => continue,
+        };
+        let keywords = words.map(|word| match word.split_once('=') {
+            Some((keyword, value)) => (keyword.to_owned(), value.to_owned()),
+            None => (word.to_owned(), String::new()),
+        }).collect::<Vec<_>>();
+
+        match first {
+            "/set" => {
+                for (keyword, value) in keywords {
+                    defaults.retain(|(other, _)| *other != keyword);
+                    defaults.push((keyword, value));
+                }
+            },
+            "/unset" => {
+                for (keyword, _) in keywords {
+                    if keyword == "all" {
+                        defaults.clear();
+                    }
+                    defaults.retain(|(other, _)| *other != keyword);
+                }
+            },
+            ".." => {
+                current = match current.rsplit_once('/') {
+                    Some((parent, _)) => parent.to_owned(),
+                    None => ROOT.to_owned(),
+                };
+            },
+            name => {
+                let name = decoded(name)?;
+                let is_full_path = name.contains('/');
+                let path = if is_full_path || name == ROOT {
+                    normalized(&name)
+                }
+                else {
+                    joined(&current, &name)
+                };
+                let mut all_keywords = defaults.iter()
+                        .filter(|(keyword, _)| !keywords.iter().any(|(other, _)| other == keyword))
+                        .cloned().collect::<Vec<_>>();
+                all_keywords.extend(keywords);
+                let entry = MtreeEntry{path, keywords: all_keywords};
+                if !is_full_path && entry.get("type") == Some("dir") {
+                    current = entry.path.clone();
+                }
+                to_return.push(entry);
+            }
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// The mtree `type` of what `metadata` describes
+fn type_of(metadata: &fs::Metadata) -> &'static str {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        "link"
+    }
+    else if file_type.is_dir() {
+        "dir"
+    }
+    else if file_type.is_fifo() {
+        "fifo"
+    }
+    else if file_type.is_socket() {
+        "socket"
+    }
+    else if file_type.is_char_device() {
+        "char"
+    }
+    else if file_type.is_block_device() {
+        "block"
+    }
+    else {
+        "file"
+    }
+}
+
+
+/// The digest keywords confidence can check, and what they're hashes of
+fn digest_algorithm(keyword: &str) -> Option<Algorithm> {
+    match keyword {
+        "md5" | "md5digest" => Some(Algorithm::Md5),
+        "sha1" | "sha1digest" => Some(Algorithm::Sha1),
+        "sha256" | "sha256digest" => Some(Algorithm::Sha256),
+        "sha512" | "sha512digest" => Some(Algorithm::Sha512),
+        _ => None,
+    }
+}
+
+
+/// The spec entry for what's at `path` now, named `relative`
+fn entry_of(path: &Path, relative: &str) -> Result<MtreeEntry, Error> {
+    let metadata = path.symlink_metadata()?;
+    let mut keywords = vec![
+        ("type".to_owned(), type_of(&metadata).to_owned()),
+        ("mode".to_owned(), format!("{:04o}", metadata.mode() & 0o7777)),
+        ("uid".to_owned(), metadata.uid().to_string()),
+        ("gid".to_owned(), metadata.gid().to_string()),
+    ];
+    if metadata.is_file() {
+        let (mut hashes, num_bytes_hashed) = hashes_of_path_with(path,
+                &[Algorithm::Sha256])?;
+        keywords.push(("size".to_owned(), num_bytes_hashed.to_string()));
+        keywords.push(("sha256digest".to_owned(), hashes.remove(0)));
+    }
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        let target = target.to_str().ok_or_else(|| {
+            Error::other("Could not cast path to a string")
+        })?;
+        keywords.push(("link".to_owned(), encoded(target)));
+    }
+    Ok(MtreeEntry{path: if relative.is_empty() { ROOT.to_owned() } else { relative.to_owned() },
+            keywords})
+}
+
+
+/// Write a full path mtree spec of `directory` to `writable`: type, mode,
+/// uid and gid of everything, size and sha256digest of files and link of
+/// symlinks.  Returns the number of entries.
+pub fn write_mtree(directory: &str, mut writable: impl Write, filter: &Filter,
+        ignore_perm_errors_flag: bool) -> Result<usize, Error> {
+    writeln!(writable, "#mtree v2.0")?;
+    writeln!(writable, "# confidence mtree spec of {}", directory)?;
+    let mut num_entries = 0;
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let relative = relative_path_string(entry.path(), directory)?;
+        writeln!(writable, "{}", entry_of(entry.path(), &relative)?.to_line())?;
+        num_entries += 1;
+    }
+    Ok(num_entries)
+}
+
+
+/// Check what's at `path` against `entry`, writing out a line for each
+/// keyword that doesn't match.  A file that can't be read has contents
+/// that don't match.  Returns whether the contents (size and digests) matched, whether everything else did, and the most bytes
+/// either could have.
+fn check_entry(entry: &MtreeEntry, path: &Path, metadata: &fs::Metadata,
+        writable: &mut impl Write) -> Result<(bool, bool, usize), Error> {
+    let mut size = metadata.len() as usize;
+    let (mut contents_agree, mut rest_agrees) = (true, true);
+
+    let digests = entry.keywords.iter()
+            .filter_map(|(keyword, value)| {
+                digest_algorithm(keyword).map(|algorithm| (keyword, algorithm, value))
+            }).collect::<Vec<_>>();
+    let actual_digests = if metadata.is_file() && !digests.is_empty() {
+        let algorithms = digests.iter().map(|(_, algorithm, _)| *algorithm)
+                .collect::<Vec<_>>();
+        match hashes_of_path_with(path, &algorithms) {
+            Ok((hashes, _)) => Some(hashes),
+            Err(error) => {
+                writeln!(writable, "Disagreement ({} bytes): Couldn't hash {}: {}",
+                        size, path.display(), error)?;
+                contents_agree = false;
+                None
+            }
+        }
+    }
+    else {
+        Some(Vec::new())
+    };
+
+    for (keyword, expected) in &entry.keywords {
+        let found = match keyword.as_str() {
+            "type" => type_of(metadata).to_owned(),
+            "uid" => metadata.uid().to_string(),
+            "gid" => metadata.gid().to_string(),
+            "nlink" => metadata.nlink().to_string(),
+            "link" => {
+                match fs::read_link(path) {
+                    Ok(target) => encoded(&target.to_string_lossy()),
+                    Err(_) => "nothing".to_owned(),
+                }
+            },
+            "mode" => {
+                match u32::from_str_radix(expected, 8) {
+                    Ok(mode) if mode == metadata.mode() & 0o7777 => continue,
+                    _ => format!("{:04o}", metadata.mode() & 0o7777),
+                }
+            },
+            "size" => {
+                if !metadata.is_file() {
+                    continue;
+                }
+                if let Ok(expected) = expected.parse::<usize>() {
+                    size = cmp::max(size, expected);
+                }
+                metadata.len().to_string()
+            },
+            "time" => {
+                let (seconds, nanoseconds) = expected.split_once('.')
+                        .unwrap_or((expected, "0"));
+                if seconds.parse::<i64>().ok() == Some(metadata.mtime()) &&
+                        (nanoseconds.parse::<i64>().ok() == Some(metadata.mtime_nsec()) ||
+                                nanoseconds.trim_start_matches('0').is_empty()) {
+                    continue;
+                }
+                format!("{}.{:09}", metadata.mtime(), metadata.mtime_nsec())
+            },
+            other => {
+                match (digests.iter().position(|(keyword, _, _)| *keyword == other),
+                        &actual_digests) {
+                    (Some(i), Some(actual_digests)) => {
+                        actual_digests.get(i).cloned().unwrap_or_else(|| "nothing".to_owned())
+                    },
+
+                    /* Already said it couldn't be hashed */
+                    (Some(_), None) => continue,
+
+                    /* uname, flags, optional and whatever else */
+                    (None, _) => continue,
+                }
+            }
+        };
+        if found.eq_ignore_ascii_case(expected) {
+            continue;
+        }
+        let is_contents = keyword == "size" || digest_algorithm(keyword).is_some();
+        if is_contents {
+            contents_agree = false;
+        }
+        else {
+            rest_agrees = false;
+        }
+        writeln!(writable, "Disagreement ({} bytes): {} {} expected {}, found {}",
+                if is_contents { size } else { 0 }, entry.path, keyword,
+                expected, found)?;
+    }
+    Ok((contents_agree, rest_agrees, size))
+}
+
+
+/// Check `directory` against the mtree spec `spec_filename`, writing out
+/// every keyword that doesn't match, every entry that's missing (unless
+/// it's `optional`) and everything that's extra (except under `ignore`
+/// directories).
+///
+/// Bytes of files whose size and digests match agree, and of files whose
+/// don't (or that are missing or extra) disagree.  Entries with any other
+/// keyword wrong count as entry disagreements.
+pub fn verify_mtree(spec_filename: &str, directory: &str,
+        mut writable: impl Write, num_vs: u8, filter: &Filter,
+        ignore_perm_errors_flag: bool) -> Result<BytesComparison, Error> {
+    if num_vs > 1 {
+        writeln!(writable, "Reading {}", spec_filename)?;
+    }
+    let spec = parse_mtree(&fs::read_to_string(spec_filename)?)?;
+    let mut to_return = BytesComparison::default();
+    let mut num_entries_disagreeing = 0;
+
+    for entry in &spec {
+        let path = if entry.path == ROOT {
+            Path::new(directory).to_owned()
+        }
+        else {
+            Path::new(directory).join(&entry.path)
+        };
+        let metadata = match path.symlink_metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                if entry.get("optional").is_some() {
+                    continue;
+                }
+                let size = entry.get("size").and_then(|size| size.parse::<usize>().ok())
+                        .unwrap_or(0);
+                writeln!(writable, "Disagreement ({} bytes): {} is missing.",
+                        size, entry.path)?;
+                to_return += if size == 0 {
+                    BytesComparison{entry_disagreement: 1, ..Default::default()}
+                }
+                else {
+                    BytesComparison{disagreement: size, ..Default::default()}
+                };
+                continue;
+            }
+        };
+        if entry.get("nochange").is_some() {
+            continue;
+        }
+        if num_vs > 1 {
+            writeln!(writable, "Examining {}", path.display())?;
+        }
+
+        let (contents_agree, rest_agrees, size) = check_entry(entry, &path,
+                &metadata, &mut writable)?;
+        let size = if metadata.is_file() { size } else { 0 };
+        to_return += if contents_agree {
+            BytesComparison{agreement: size, ..Default::default()}
+        }
+        else {
+            BytesComparison{disagreement: size, ..Default::default()}
+        };
+        if !rest_agrees {
+            num_entries_disagreeing += 1;
+            to_return += BytesComparison{entry_disagreement: 1,
+                    ..Default::default()};
+        }
+    }
+
+    /* Everything in the tree has to be in the spec */
+    let in_spec = spec.iter().map(|entry| entry.path.as_str())
+            .collect::<HashSet<_>>();
+    let ignored = spec.iter().filter(|entry| entry.get("ignore").is_some())
+            .map(|entry| entry.path.as_str()).collect::<Vec<_>>();
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let relative = relative_path_string(entry.path(), directory)?;
+        let relative = if relative.is_empty() { ROOT.to_owned() } else { relative };
+        if in_spec.contains(relative.as_str()) || ignored.iter().any(|top| {
+            *top == ROOT || Path::new(&relative).starts_with(top)
+        }) {
+            continue;
+        }
+        let size = if entry.file_type().is_file() {
+            entry.metadata().map(|metadata| metadata.len() as usize).unwrap_or(0)
+        }
+        else {
+            0
+        };
+        writeln!(writable, "Disagreement ({} bytes): {} is extra.", size,
+                relative)?;
+        to_return += if size == 0 {
+            BytesComparison{entry_disagreement: 1, ..Default::default()}
+        }
+        else {
+            BytesComparison{disagreement: size, ..Default::default()}
+        };
+    }
+
+    let num_bytes = to_return.agreement + to_return.disagreement;
+    to_return.write_summary(&mut writable, num_bytes)?;
+    if num_entries_disagreeing > 0 {
+        writeln!(writable, "Disagreed on the type, mode, owner, links or time of {} entries.",
+                num_entries_disagreeing)?;
+    }
+    Ok(to_return)
+}
@@ -0,0 +1,449 @@
+use crate::filter::Filter;
+use crate::manifest::FileStat;
+use crate::path_string_from_b64;
+use crate::relative_path_string;
+use crate::special;
+use crate::BytesComparison;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+
+/// How many bytes each checksummed (and recoverable) block covers
+pub const BLOCK_SIZE: usize = 4096;
+
+/// Most data blocks sharing one set of parity blocks.  Reed-Solomon over
+/// GF(2^8) can't have more than 256 blocks in all.
+const MAX_DATA_BLOCKS: usize = 128;
+
+const HEADER: &str = "confidence parity 1";
+
+
+/// A run of a file's blocks and the parity blocks that can rebuild them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stripe {
+    pub num_data: usize,
+
+    /// Each parity block and its sha1
+    pub parity: Vec<(String, Vec<u8>)>,
+}
+
+
+/// Everything a parity file has about one file
+///
+/// In the parity file, that's a `file <base64 of path> <size> <mtime>
+/// <inode> <block size>` line, a `block <sha1>` line per block, then a
+/// `stripe <data blocks> <parity blocks>` line per stripe, each followed
+/// by its `parity <sha1> <base64 of block>` lines.  The last block is
+/// padded with zeros before it's hashed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileParity {
+    pub path: String,
+    pub size: u64,
+    pub stat: FileStat,
+    pub block_size: usize,
+    pub block_hashes: Vec<String>,
+    pub stripes: Vec<Stripe>,
+}
+
+
+fn sha1_of(bytes: &[u8]) -> String {
+    let mut hasher = sha1::Sha1::new();
+    hasher.update(bytes);
+    hasher.digest().to_string()
+}
+
+
+fn reed_solomon_error(error: reed_solomon_erasure::Error) -> Error {
+    Error::other(format!("{:?}", error))
+}
+
+
+/// Block `index` of `file`, padded with zeros to `block_size`.  Whatever
+/// is past `size` doesn't count.
+fn read_block(file: &mut File, index: usize, block_size: usize, size: u64)
+        -> Result<Vec<u8>, Error> {
+    let mut block = vec![0; block_size];
+    let offset = (index * block_size) as u64;
+    if offset >= size {
+        return Ok(block);
+    }
+    let wanted = std::cmp::min(block_size as u64, size - offset) as usize;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut num_bytes_read = 0;
+    while num_bytes_read < wanted {
+        let num_bytes = file.read(&mut block[num_bytes_read..wanted])?;
+        if num_bytes == 0 {
+            break;
+        }
+        num_bytes_read += num_bytes;
+    }
+    Ok(block)
+}
+
+
+/// Work out checksums and parity blocks for the file at `path`, with
+/// `redundancy_percent` parity blocks per hundred data blocks (at least
+/// one per stripe)
+pub fn file_parity(path: &Path, relative: &str, redundancy_percent: usize)
+        -> Result<FileParity, Error> {
+
+    /* Stat first, so a file that changes while it's being read looks
+     * changed later */
+    let stat = FileStat::of(&fs::metadata(path)?);
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let num_blocks = size.div_ceil(BLOCK_SIZE as u64) as usize;
+    let mut block_hashes = Vec::new();
+    let mut stripes = Vec::new();
+    let mut first = 0;
+    while first < num_blocks {
+        let num_data = std::cmp::min(MAX_DATA_BLOCKS, num_blocks - first);
+        let num_parity = std::cmp::max(1,
+                (num_data * redundancy_percent).div_ceil(100));
+        let mut shards = Vec::new();
+        for index in first..first + num_data {
+            let block = read_block(&mut file, index, BLOCK_SIZE, size)?;
+            block_hashes.push(sha1_of(&block));
+            shards.push(block);
+        }
+        shards.resize(num_data + num_parity, vec![0; BLOCK_SIZE]);
+        ReedSolomon::new(num_data, num_parity).map_err(reed_solomon_error)?
+                .encode(&mut shards).map_err(reed_solomon_error)?;
+        let parity = shards.split_off(num_data).into_iter()
+                .map(|block| (sha1_of(&block), block)).collect();
+        stripes.push(Stripe{num_data, parity});
+        first += num_data;
+    }
+    Ok(FileParity{path: relative.to_owned(), size, stat, block_size: BLOCK_SIZE,
+            block_hashes, stripes})
+}
+
+
+/// A parity file being written a file at a time, so it can be done
+/// alongside hashing instead of walking the tree again
+pub struct ParityWriter {
+    writable: Box<dyn Write>,
+    redundancy_percent: usize,
+    num_bytes: usize,
+}
+
+
+impl ParityWriter {
+
+    /// Start a parity file in `writable` with `redundancy_percent` parity
+    /// blocks per hundred blocks of data
+    pub fn new(mut writable: Box<dyn Write>, redundancy_percent: usize)
+            -> Result<ParityWriter, Error> {
+        writeln!(writable, "{}", HEADER)?;
+        Ok(ParityWriter{writable, redundancy_percent, num_bytes: 0})
+    }
+
+
+    /// Write out the parity of the regular file at `path`, which is at
+    /// `relative` in the tree
+    pub fn add(&mut self, path: &Path, relative: &str) -> Result<(), Error> {
+        let parity = file_parity(path, relative, self.redundancy_percent)?;
+        let writable = &mut self.writable;
+        writeln!(writable, "file {} {} {} {}", base64::encode(&parity.path),
+                parity.size, parity.stat.to_columns(), parity.block_size)?;
+        for block_hash in &parity.block_hashes {
+            writeln!(writable, "block {}", block_hash)?;
+        }
+        for stripe in &parity.stripes {
+            writeln!(writable, "stripe {} {}", stripe.num_data,
+                    stripe.parity.len())?;
+            for (parity_hash, block) in &stripe.parity {
+                writeln!(writable, "parity {} {}", parity_hash,
+                        base64::encode(block))?;
+            }
+        }
+        self.num_bytes += parity.size as usize;
+        Ok(())
+    }
+
+
+    /// Flush what's been written.  Returns the number of bytes covered.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        self.writable.flush()?;
+        Ok(self.num_bytes)
+    }
+}
+
+
+/// Write parity for every regular file under `directory` to `writable`.
+/// Returns the number of bytes covered.
+pub fn write_parity(directory: &str, writable: impl Write + 'static,
+        filter: &Filter, ignore_perm_errors_flag: bool,
+        redundancy_percent: usize) -> Result<usize, Error> {
+    let mut parity_writer = ParityWriter::new(Box::new(writable),
+            redundancy_percent)?;
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let path = entry.path();
+        if !path.is_file() || special::special_kind_of(path).is_some() {
+            continue;
+        }
+        parity_writer.add(path, &relative_path_string(path, directory)?)?;
+    }
+    parity_writer.finish()
+}
+
+
+fn corrupt(line: &str) -> Error {
+    Error::other("Corrupt parity file at: ".to_owned() + line)
+}
+
+
+/// Make sure everything `read_parity` read about a file fits together, so
+/// repairing it can't index past the end of anything
+fn check_file_parity(parity: &FileParity) -> Result<(), Error> {
+    let corrupt = |why: &str| Error::other(format!(
+            "Corrupt parity file: {} for {}", why, parity.path));
+    let num_blocks = parity.size.div_ceil(parity.block_size as u64);
+    if parity.block_hashes.len() as u64 != num_blocks {
+        return Err(corrupt("the wrong number of blocks"));
+    }
+    if parity.stripes.iter().map(|stripe| stripe.num_data).sum::<usize>() !=
+            parity.block_hashes.len() {
+        return Err(corrupt("stripes that don't cover the blocks"));
+    }
+    for stripe in &parity.stripes {
+        if stripe.num_data == 0 || stripe.parity.is_empty() ||
+                stripe.num_data + stripe.parity.len() > 256 {
+            return Err(corrupt("a stripe of the wrong shape"));
+        }
+        if stripe.parity.iter().any(|(_, block)| block.len() != parity.block_size) {
+            return Err(corrupt("a parity block of the wrong size"));
+        }
+    }
+    Ok(())
+}
+
+
+/// Everything in the parity file `filename`.  A file whose blocks,
+/// stripes and parity blocks don't add up makes the whole thing corrupt.
+pub fn read_parity(filename: &str) -> Result<Vec<FileParity>, Error> {
+    let mut lines = BufReader::new(File::open(filename)?).lines();
+    match lines.next() {
+        Some(Ok(ref line)) if line == HEADER => {},
+        _ => {
+            let err_s = "'".to_owned() + filename + "' isn't a parity file.";
+            return Err(Error::other(err_s));
+        }
+    }
+
+    let mut to_return: Vec<FileParity> = Vec::new();
+    for line in lines {
+        let line = line?;
+        let pieces = line.split(' ').collect::<Vec<_>>();
+        match (pieces[0], to_return.last_mut()) {
+            ("file", _) if pieces.len() == 6 => {
+                let size = pieces[2].parse::<u64>().map_err(|_| corrupt(&line))?;
+                let block_size = pieces[5].parse::<usize>().ok()
+                        .filter(|block_size| *block_size > 0)
+                        .ok_or_else(|| corrupt(&line))?;
+                if let Some(file_parity) = to_return.last() {
+                    check_file_parity(file_parity)?;
+                }
+                to_return.push(FileParity{path: path_string_from_b64(pieces[1])?,
+                        size, stat: FileStat::from_columns(pieces[3], pieces[4])?,
+                        block_size, block_hashes: Vec::new(),
+                        stripes: Vec::new()});
+            },
+            ("block", Some(file_parity)) if pieces.len() == 2 => {
+                file_parity.block_hashes.push(pieces[1].to_owned());
+            },
+            ("stripe", Some(file_parity)) if pieces.len() == 3 => {
+                let num_data = pieces[1].parse::<usize>()
+                        .map_err(|_| corrupt(&line))?;
+                file_parity.stripes.push(Stripe{num_data, parity: Vec::new()});
+            },
+            ("parity", Some(file_parity)) if pieces.len() == 3 => {
+                let stripe = file_parity.stripes.last_mut()
+                        .ok_or_else(|| corrupt(&line))?;
+                let block = base64::decode(pieces[2]).map_err(|_| corrupt(&line))?;
+                stripe.parity.push((pieces[1].to_owned(), block));
+            },
+            _ => {
+                return Err(corrupt(&line));
+            }
+        }
+    }
+    if let Some(file_parity) = to_return.last() {
+        check_file_parity(file_parity)?;
+    }
+    Ok(to_return)
+}
+
+
+/// Check one file against its parity, rebuilding damaged blocks in place
+/// when there's enough parity left to do it.  Returns how many blocks
+/// were rebuilt, or `None` if some couldn't be.
+fn repair_file(parity: &FileParity, path: &Path, mut writable: impl Write)
+        -> Result<Option<usize>, Error> {
+    let exists = path.is_file();
+    let mut file = if exists {
+        Some(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+    else {
+        None
+    };
+    let mut repaired = Vec::new();
+    let mut first = 0;
+    for stripe in &parity.stripes {
+        let mut shards = Vec::new();
+        for index in first..first + stripe.num_data {
+            let block = match file {
+                Some(ref mut file) => Some(read_block(file, index,
+                        parity.block_size, parity.size)?),
+                None => None,
+            };
+            shards.push(block.filter(|block| {
+                sha1_of(block) == parity.block_hashes[index]
+            }));
+        }
+        let damaged = (0..stripe.num_data).filter(|i| shards[*i].is_none())
+                .collect::<Vec<_>>();
+        if damaged.is_empty() {
+            first += stripe.num_data;
+            continue;
+        }
+        for (parity_hash, block) in &stripe.parity {
+            shards.push(Some(block.clone()).filter(|block| {
+                sha1_of(block) == *parity_hash
+            }));
+        }
+        let num_usable = stripe.parity.iter().zip(&shards[stripe.num_data..])
+                .filter(|(_, shard)| shard.is_some()).count();
+        if damaged.len() > num_usable {
+            writeln!(writable, "Can't repair {}: {} damaged blocks share only {} usable parity blocks.",
+                    path.display(), damaged.len(), num_usable)?;
+            return Ok(None);
+        }
+        ReedSolomon::new(stripe.num_data, stripe.parity.len())
+                .map_err(reed_solomon_error)?
+                .reconstruct_data(&mut shards).map_err(reed_solomon_error)?;
+        for i in damaged {
+            repaired.push((first + i, shards[i].take().unwrap()));
+        }
+        first += stripe.num_data;
+    }
+
+    let wrong_size = exists && fs::metadata(path)?.len() != parity.size;
+    if repaired.is_empty() && !wrong_size && exists {
+        return Ok(Some(0));
+    }
+    let mut file = match file {
+        Some(file) => file,
+        None => {
+
+            /* Whatever it was in may well be gone too */
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            File::create(path)?
+        },
+    };
+    for (index, block) in &repaired {
+        let offset = (index * parity.block_size) as u64;
+        let length = std::cmp::min(parity.block_size as u64, parity.size - offset);
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&block[..length as usize])?;
+    }
+    file.set_len(parity.size)?;
+    file.sync_all()?;
+
+    /* It's back to how it was, so its mtime should be too */
+    file.set_modified(UNIX_EPOCH + Duration::new(parity.stat.mtime as u64,
+            parity.stat.mtime_nsec as u32))?;
+    Ok(Some(repaired.len()))
+}
+
+
+/// Whether every block of `path` matches its checksum
+fn verify_file(parity: &FileParity, path: &Path) -> Result<bool, Error> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() != parity.size {
+        return Ok(false);
+    }
+    for (index, block_hash) in parity.block_hashes.iter().enumerate() {
+        if sha1_of(&read_block(&mut file, index, parity.block_size,
+                parity.size)?) != *block_hash {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+
+/// Use the per-block checksums in the parity file `parity_filename` to
+/// find damaged blocks under `directory`, rebuild them in place from the
+/// parity blocks, and check the result.
+///
+/// Files modified since the parity file was made are left alone (they'd
+/// look entirely damaged) unless `force`.
+pub fn repair_from_parity(parity_filename: &str, directory: &str,
+        mut writable: impl Write, num_vs: u8, force: bool)
+                -> Result<BytesComparison, Error> {
+    let mut to_return = BytesComparison::default();
+    let (mut num_repaired, mut num_skipped) = (0, 0);
+    for parity in read_parity(parity_filename)? {
+        let path = Path::new(directory).join(&parity.path);
+        if let Ok(metadata) = fs::metadata(&path) {
+            let stat = FileStat::of(&metadata);
+            if !force && (stat.mtime, stat.mtime_nsec) !=
+                    (parity.stat.mtime, parity.stat.mtime_nsec) {
+                num_skipped += 1;
+                writeln!(writable, "Skipped {}, which was modified after the parity file was made.",
+                        path.display())?;
+                continue;
+            }
+        }
+        if num_vs > 1 {
+            writeln!(writable, "Examining {}", path.display())?;
+        }
+
+        let size = parity.size as usize;
+        match repair_file(&parity, &path, &mut writable)? {
+            Some(num_blocks) if verify_file(&parity, &path)? => {
+                if num_blocks > 0 || num_vs > 0 {
+                    writeln!(writable, "Repaired {} blocks of {}", num_blocks,
+                            path.display())?;
+                }
+                if num_blocks > 0 {
+                    num_repaired += 1;
+                }
+                to_return += BytesComparison{agreement: size,
+                        ..Default::default()};
+            },
+            Some(_) => {
+                writeln!(writable, "Disagreement ({} bytes): {} still doesn't match its checksums after repair.",
+                        size, path.display())?;
+                to_return += BytesComparison{disagreement: size,
+                        ..Default::default()};
+            },
+            None => {
+                to_return += BytesComparison{disagreement: size,
+                        ..Default::default()};
+            }
+        }
+    }
+
+    let num_bytes = to_return.agreement + to_return.disagreement;
+    writeln!(writable, "Repaired {} files, skipped {} modified ones.",
+            num_repaired, num_skipped)?;
+    to_return.write_summary(&mut writable, num_bytes)?;
+    Ok(to_return)
+}
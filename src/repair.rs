@@ -0,0 +1,264 @@
+use crate::compare_paths;
+use crate::copy::copy_to_temporary;
+use crate::copy::make_way;
+use crate::copy::sync_parent;
+use crate::copy::temporary_for;
+use crate::filter::Filter;
+use crate::hash_of_path;
+use crate::relative_path_string;
+use crate::special;
+use crate::BytesComparison;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+
+/// Why something on the right needs fixing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Problem {
+    Missing,
+    Differs,
+}
+
+
+impl Problem {
+    fn describe(&self) -> &'static str {
+        match self {
+            Problem::Missing => "missing",
+            Problem::Differs => "differs",
+        }
+    }
+}
+
+
+/// Copy `path_l` over `path_r` by way of a temporary file next to it,
+/// which only replaces `path_r` once it's been read back and agrees.
+/// Returns whether it did.
+fn repair_file(path_l: &Path, path_r: &Path) -> Result<bool, Error> {
+    let temporary = temporary_for(path_r, ".confidence-repair")?;
+    let (hash_l, _) = copy_to_temporary(path_l, &temporary, &None)?;
+    let (hash_r, _) = hash_of_path(&temporary)?;
+    if hash_l != hash_r {
+        fs::remove_file(&temporary)?;
+        return Ok(false);
+    }
+    make_way(path_r, |file_type| !file_type.is_dir())?;
+    fs::rename(&temporary, path_r)?;
+    sync_parent(path_r)?;
+    Ok(true)
+}
+
+
+/// A directory at `path_r` where `path_l` isn't one is extra, so it's only
+/// deleted with `delete_extra`, and said so.  Returns whether the way is
+/// clear (or would be, if not `execute`) for `path_l` to be copied.
+fn clear_directory(path_l: &Path, path_r: &Path, writable: &mut impl Write,
+        execute: bool, delete_extra: bool, num_deleted: &mut usize)
+                -> Result<bool, Error> {
+    if !path_r.symlink_metadata().is_ok_and(|metadata| metadata.is_dir()) {
+        return Ok(true);
+    }
+    if !delete_extra {
+        writeln!(writable, "Extra {}, which is in the way of {}",
+                path_r.display(), path_l.display())?;
+        return Ok(false);
+    }
+    writeln!(writable, "{} {}", if execute { "Deleted" } else { "Would delete" },
+            path_r.display())?;
+    if execute {
+        fs::remove_dir_all(path_r)?;
+        *num_deleted += 1;
+    }
+    Ok(true)
+}
+
+
+/// Whatever is under `destination` but not `source`.  Once a directory is
+/// extra, so is everything in it, so only the directory is listed.
+pub fn extras_of(source: &str, destination: &str, filter: &Filter,
+        ignore_perm_errors_flag: bool) -> Result<Vec<PathBuf>, Error> {
+    let mut extras: Vec<PathBuf> = Vec::new();
+    for entry in filter.walk_entries(destination, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let path_r = entry.path();
+        if extras.iter().any(|extra| path_r.starts_with(extra)) {
+            continue;
+        }
+        let relative = relative_path_string(path_r, destination)?;
+        if relative.is_empty() ||
+                Path::new(source).join(&relative).symlink_metadata().is_ok() {
+            continue;
+        }
+        extras.push(path_r.to_owned());
+    }
+    Ok(extras)
+}
+
+
+/// Make `destination` a copy of `source`: copy over whatever's missing
+/// or different and, with `delete_extra`, delete whatever's only in
+/// `destination`.  A directory where `source` has a file or symlink is
+/// extra too.  Every action is written out, and nothing is actually
+/// done unless `execute`.  Each copy is read back before it replaces
+/// anything.  Symlinks are recreated, not followed, the way `copy` does.
+///
+/// The result counts bytes that already agreed or were repaired as
+/// agreeing, and everything that still disagrees (so everything, if not
+/// `execute`) as disagreeing.
+#[allow(clippy::too_many_arguments)]
+pub fn repair_tree(source: &str, destination: &str, mut writable: impl Write,
+        num_vs: u8, filter: &Filter, ignore_perm_errors_flag: bool,
+        execute: bool, delete_extra: bool) -> Result<BytesComparison, Error> {
+    let said = |done: &'static str, would: &'static str| {
+        if execute { done } else { would }
+    };
+    let mut to_return = BytesComparison::default();
+    let (mut num_copied, mut num_bytes_copied, mut num_linked,
+            mut num_deleted) = (0, 0, 0, 0);
+
+    for entry in filter.walk_entries(source, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let path_l = entry.path();
+        let relative = relative_path_string(path_l, source)?;
+        let path_r = Path::new(destination).join(&relative);
+
+        if entry.file_type().is_dir() {
+
+            /* `destination` itself may well be a symlink to a directory,
+             * but nothing under it should be */
+            let is_dir = if relative.is_empty() {
+                path_r.is_dir()
+            }
+            else {
+                path_r.symlink_metadata().is_ok_and(|metadata| metadata.is_dir())
+            };
+            if !is_dir {
+                writeln!(writable, "{} {}",
+                        said("Made directory", "Would make directory"),
+                        path_r.display())?;
+                if execute {
+                    make_way(&path_r, |_| false)?;
+                    fs::create_dir_all(&path_r)?;
+                    sync_parent(&path_r)?;
+                }
+            }
+            continue;
+        }
+
+        if entry.file_type().is_symlink() {
+            let target = fs::read_link(path_l)?;
+            if fs::read_link(&path_r).is_ok_and(|target_r| target_r == target) {
+                continue;
+            }
+            if !clear_directory(path_l, &path_r, &mut writable, execute,
+                    delete_extra, &mut num_deleted)? {
+                to_return += BytesComparison{entry_disagreement: 1,
+                        ..Default::default()};
+                continue;
+            }
+            writeln!(writable, "{} {} -> {}", said("Linked", "Would link"),
+                    path_r.display(), target.display())?;
+            if execute {
+                make_way(&path_r, |_| false)?;
+                std::os::unix::fs::symlink(&target, &path_r)?;
+                sync_parent(&path_r)?;
+                num_linked += 1;
+            }
+            else {
+                to_return += BytesComparison{entry_disagreement: 1,
+                        ..Default::default()};
+            }
+            continue;
+        }
+
+        if let Some(kind) = special::special_kind_of(path_l) {
+            if special::special_kind_of(&path_r) != Some(kind) {
+                writeln!(writable, "Can't repair {}, which is {}.",
+                        path_r.display(), kind)?;
+                to_return += BytesComparison{entry_disagreement: 1,
+                        ..Default::default()};
+            }
+            continue;
+        }
+        if !path_l.is_file() {
+            continue;
+        }
+
+        /* Only what disagrees needs fixing.  A symlink isn't a copy, even
+         * if it leads to one. */
+        let size = fs::metadata(path_l)?.len() as usize;
+        let comparison = if path_r.symlink_metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            BytesComparison{disagreement: size, ..Default::default()}
+        }
+        else {
+            compare_paths(path_l, source, destination, &mut std::io::sink(), 0,
+                    &None, &None)?
+        };
+        if !comparison.disagrees() {
+            to_return += comparison;
+            continue;
+        }
+        if !clear_directory(path_l, &path_r, &mut writable, execute,
+                delete_extra, &mut num_deleted)? {
+            to_return += comparison;
+            continue;
+        }
+        let problem = if path_r.symlink_metadata().is_ok() {
+            Problem::Differs
+        }
+        else {
+            Problem::Missing
+        };
+        writeln!(writable, "{} ({}, {} bytes) {} -> {}",
+                said("Copied", "Would copy"), problem.describe(), size,
+                path_l.display(), path_r.display())?;
+        if !execute {
+            to_return += comparison;
+            continue;
+        }
+        if repair_file(path_l, &path_r)? {
+            num_copied += 1;
+            num_bytes_copied += size;
+            to_return += BytesComparison{agreement: size, ..Default::default()};
+        }
+        else {
+            writeln!(writable, "Disagreement ({} bytes): the copy of {} didn't match it, so {} was left alone.",
+                    size, path_l.display(), path_r.display())?;
+            to_return += comparison;
+        }
+    }
+
+    let extras = extras_of(source, destination, filter,
+            ignore_perm_errors_flag)?;
+    for extra in &extras {
+        if !delete_extra {
+            writeln!(writable, "Extra {}", extra.display())?;
+            continue;
+        }
+        writeln!(writable, "{} {}", said("Deleted", "Would delete"),
+                extra.display())?;
+        if execute {
+            if extra.is_dir() && !extra.symlink_metadata()?.file_type().is_symlink() {
+                fs::remove_dir_all(extra)?;
+            }
+            else {
+                fs::remove_file(extra)?;
+            }
+            num_deleted += 1;
+        }
+    }
+
+    if execute {
+        writeln!(writable, "Copied {} files ({} bytes), linked {} and deleted {} extras.",
+                num_copied, num_bytes_copied, num_linked, num_deleted)?;
+    }
+    else if num_vs > 0 || to_return.disagrees() || !extras.is_empty() {
+        writeln!(writable, "Dry run: nothing was changed.  Use --execute to make these changes.")?;
+    }
+    writeln!(writable, "{} bytes agree.", to_return.agreement)?;
+    writeln!(writable, "{} bytes disagree.", to_return.disagreement)?;
+    Ok(to_return)
+}
@@ -0,0 +1,166 @@
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::relative_path_string;
+use crate::tidied;
+use crate::sums::verify_sum_entry;
+use crate::sums::SumEntry;
+use crate::BytesComparison;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::path::Path;
+
+
+/// What kind of checksum file `filename` is, if any: `.sfv` files list
+/// CRC32s, and `.md5`, `.sha256`, `MD5SUMS`, etc. are like `sha256sum`
+/// writes.  The algorithm is what its lines were made with.
+fn sidecar_kind(filename: &str) -> Option<Algorithm> {
+    let lowercase = filename.to_ascii_lowercase();
+    let (_, extension) = lowercase.rsplit_once('.').unwrap_or(("", ""));
+    match (lowercase.as_str(), extension) {
+        (_, "sfv") => Some(Algorithm::Crc32),
+        (_, "md5") | ("md5sums", _) => Some(Algorithm::Md5),
+        (_, "sha1") | ("sha1sums", _) => Some(Algorithm::Sha1),
+        (_, "sha256") | ("sha256sums", _) => Some(Algorithm::Sha256),
+        (_, "sha512") | ("sha512sums", _) => Some(Algorithm::Sha512),
+        (_, "b2") | ("b2sums", _) => Some(Algorithm::Blake2b),
+        _ => None,
+    }
+}
+
+
+/// One line of an SFV file: `<filename> <crc32>`, where the filename can
+/// have spaces in it, and `\` between directories since SFV comes from
+/// Windows.  `Ok(None)` means a blank line or `;` comment.
+fn sfv_entry(line: &str) -> Result<Option<SumEntry>, Error> {
+    let line = line.trim_end();
+    if line.trim_start().is_empty() || line.starts_with(';') {
+        return Ok(None);
+    }
+    match line.rsplit_once(|c: char| c.is_whitespace()) {
+        Some((path, crc)) if crc.len() == 8 &&
+                crc.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(SumEntry{algorithm: Algorithm::Crc32,
+                    digest: crc.to_ascii_lowercase(),
+                    path: path.trim_end().replace('\\', "/")}))
+        },
+        _ => Err(Error::other("Corrupt SFV line: ".to_owned() + line)),
+    }
+}
+
+
+/// Every entry of the checksum file `sidecar`, whose lines were made with
+/// `algorithm`
+fn read_sidecar(sidecar: &Path, algorithm: Algorithm)
+        -> Result<Vec<SumEntry>, Error> {
+    let mut to_return = Vec::new();
+    for line in fs::read_to_string(sidecar)?.lines() {
+        let entry = if algorithm == Algorithm::Crc32 {
+            sfv_entry(line)?
+        }
+        else {
+            SumEntry::from_line(line, Some(algorithm))?
+        };
+        to_return.extend(entry);
+    }
+    Ok(to_return)
+}
+
+
+/// Find every checksum file under `directory` (see `sidecar_kind`) and
+/// check each file it lists, relative to the checksum file's own
+/// directory, rolling it all into one result.  Files that no checksum file
+/// lists are written out at the end, but don't count either way.
+///
+/// A checksum file that can't be read or parsed, and an entry that would
+/// lead outside `directory`, are written out and count as disagreeing
+/// entries; everything else is still checked.
+pub fn verify_sidecars(directory: &str, mut writable: impl Write, num_vs: u8,
+        filter: &Filter, ignore_perm_errors_flag: bool)
+                -> Result<BytesComparison, Error> {
+    let mut sidecars = Vec::new();
+    let mut files = Vec::new();
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = relative_path_string(entry.path(), directory)?;
+        match sidecar_kind(&entry.file_name().to_string_lossy()) {
+            Some(algorithm) => sidecars.push((relative, algorithm)),
+            None => {
+                let size = entry.metadata().map_or(0, |metadata| metadata.len());
+                files.push((relative, size));
+            },
+        }
+    }
+
+    let mut to_return = BytesComparison::default();
+    let mut covered = BTreeSet::new();
+    let (mut num_unreadable, mut num_outside) = (0, 0);
+    for (sidecar, algorithm) in &sidecars {
+        if num_vs > 0 {
+            writeln!(writable, "Checking the files listed in {}", sidecar)?;
+        }
+        let entries = match read_sidecar(&Path::new(directory).join(sidecar),
+                *algorithm) {
+            Ok(entries) => entries,
+            Err(error) => {
+                writeln!(writable, "Disagreement (0 bytes): Couldn't read {}: {}",
+                        sidecar, error)?;
+                num_unreadable += 1;
+                continue;
+            }
+        };
+        let sidecar_directory = Path::new(sidecar).parent()
+                .unwrap_or_else(|| Path::new("")).to_owned();
+        for entry in entries {
+            let relative = match tidied(&sidecar_directory.join(&entry.path)) {
+                Some(relative) => relative,
+                None => {
+                    writeln!(writable, "Disagreement (0 bytes): {} lists {}, which isn't under {}.",
+                            sidecar, entry.path, directory)?;
+                    num_outside += 1;
+                    continue;
+                }
+            };
+            to_return += verify_sum_entry(&entry,
+                    &Path::new(directory).join(&relative), &mut writable,
+                    num_vs)?;
+            covered.insert(relative);
+        }
+    }
+
+    let uncovered = files.iter()
+            .filter(|(relative, _)| !covered.contains(Path::new(relative)))
+            .collect::<Vec<_>>();
+    for (relative, size) in &uncovered {
+        writeln!(writable, "Not in any checksum file ({} bytes): {}", size,
+                relative)?;
+    }
+
+    let num_bytes = to_return.agreement + to_return.disagreement;
+    writeln!(writable, "Checked {} checksum files.", sidecars.len())?;
+    to_return.write_summary(&mut writable, num_bytes)?;
+    if to_return.entry_disagreement > 0 {
+        writeln!(writable, "{} listed files are missing.",
+                to_return.entry_disagreement)?;
+    }
+    if num_unreadable > 0 {
+        writeln!(writable, "{} checksum files couldn't be read.",
+                num_unreadable)?;
+    }
+    if num_outside > 0 {
+        writeln!(writable, "{} listed files aren't under {}.", num_outside,
+                directory)?;
+    }
+    if !uncovered.is_empty() {
+        writeln!(writable, "{} files ({} bytes) aren't in any checksum file.",
+                uncovered.len(),
+                uncovered.iter().map(|(_, size)| size).sum::<u64>())?;
+    }
+    to_return += BytesComparison{entry_disagreement: num_unreadable + num_outside,
+            ..Default::default()};
+    Ok(to_return)
+}
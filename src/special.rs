@@ -0,0 +1,139 @@
+use std::fs;
+use std::io::Error;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+
+/// A file that's neither regular, a directory nor a symlink.  These have
+/// no contents worth hashing, so they're recorded by type (and device
+/// numbers for devices).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    CharDevice(u32, u32),
+    BlockDevice(u32, u32),
+}
+
+
+impl SpecialKind {
+
+    /// The first column of a manifest line describing this kind of file
+    pub fn tag(&self) -> &'static str {
+        match self {
+            SpecialKind::Fifo => "fifo:",
+            SpecialKind::Socket => "socket:",
+            SpecialKind::CharDevice(_, _) => "chardev:",
+            SpecialKind::BlockDevice(_, _) => "blockdev:",
+        }
+    }
+
+
+    /// The second column of a manifest line, where a regular file would
+    /// have its hash.  `major,minor` for devices, `-` otherwise.
+    pub fn value(&self) -> String {
+        match self {
+            SpecialKind::CharDevice(major, minor) |
+                    SpecialKind::BlockDevice(major, minor) => {
+                format!("{},{}", major, minor)
+            },
+            _ => "-".to_owned(),
+        }
+    }
+
+
+    /// Inverse of `tag` and `value`.  `Ok(None)` if `tag` isn't one of
+    /// ours.
+    pub fn from_tag_and_value(tag: &str, value: &str)
+            -> Result<Option<SpecialKind>, Error> {
+        match tag {
+            "fifo:" => Ok(Some(SpecialKind::Fifo)),
+            "socket:" => Ok(Some(SpecialKind::Socket)),
+            "chardev:" | "blockdev:" => {
+                let (major, minor) = device_numbers_from(value)?;
+                if tag == "chardev:" {
+                    Ok(Some(SpecialKind::CharDevice(major, minor)))
+                }
+                else {
+                    Ok(Some(SpecialKind::BlockDevice(major, minor)))
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+
+impl std::fmt::Display for SpecialKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SpecialKind::Fifo => write!(f, "a FIFO"),
+            SpecialKind::Socket => write!(f, "a socket"),
+            SpecialKind::CharDevice(major, minor) => {
+                write!(f, "character device {},{}", major, minor)
+            },
+            SpecialKind::BlockDevice(major, minor) => {
+                write!(f, "block device {},{}", major, minor)
+            },
+        }
+    }
+}
+
+
+fn device_numbers_from(value: &str) -> Result<(u32, u32), Error> {
+    let pieces = value.split(',').collect::<Vec<_>>();
+    if pieces.len() == 2 {
+        if let (Ok(major), Ok(minor)) = (pieces[0].parse::<u32>(),
+                pieces[1].parse::<u32>()) {
+            return Ok((major, minor));
+        }
+    }
+    let err_s = "Can't interpret ".to_owned() + value +
+            " as major,minor device numbers.";
+    Err(Error::other(err_s))
+}
+
+
+/// What kind of special file `path` is, if any.  Symlinks are followed,
+/// same as `Path::is_file`.  Only metadata is examined; the file is never
+/// opened, so this is safe to call on FIFOs.
+pub fn special_kind_of(path: &Path) -> Option<SpecialKind> {
+    let metadata = fs::metadata(path).ok()?;
+    let file_type = metadata.file_type();
+    let rdev = metadata.rdev();
+    if file_type.is_fifo() {
+        Some(SpecialKind::Fifo)
+    }
+    else if file_type.is_socket() {
+        Some(SpecialKind::Socket)
+    }
+    else if file_type.is_char_device() {
+        Some(SpecialKind::CharDevice(libc::major(rdev), libc::minor(rdev)))
+    }
+    else if file_type.is_block_device() {
+        Some(SpecialKind::BlockDevice(libc::major(rdev), libc::minor(rdev)))
+    }
+    else {
+        None
+    }
+}
+
+
+/// How to describe whatever is at `path` when it isn't what we expected
+pub fn describe_path(path: &Path) -> String {
+    match special_kind_of(path) {
+        Some(kind) => format!("is {}", kind),
+        None => {
+            if path.is_file() {
+                "is a regular file".to_owned()
+            }
+            else if path.is_dir() {
+                "is a directory".to_owned()
+            }
+            else {
+                "doesn't exist".to_owned()
+            }
+        }
+    }
+}
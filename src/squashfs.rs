@@ -0,0 +1,423 @@
+use crate::archive::ArchiveEntry;
+use crate::special::SpecialKind;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Error;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+
+const MAGIC: u32 = 0x7371_7368;
+
+/// Metadata blocks hold at most this much once decompressed
+const METADATA_SIZE: usize = 8192;
+
+/// Set in a metadata block's header when it's stored uncompressed
+const UNCOMPRESSED_METADATA: u16 = 1 << 15;
+
+/// Set in a data block's size when it's stored uncompressed
+const UNCOMPRESSED_BLOCK: u32 = 1 << 24;
+
+/// A file whose end isn't in a fragment has this fragment index
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+
+/// Fragment table entries in each of its metadata blocks
+const FRAGMENTS_PER_BLOCK: u32 = (METADATA_SIZE / 16) as u32;
+
+
+fn corrupt(why: &str) -> Error {
+    Error::other("Corrupt SquashFS image: ".to_owned() + why)
+}
+
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2],
+            bytes[offset + 3]])
+}
+
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u32_at(bytes, offset) as u64 | (u32_at(bytes, offset + 4) as u64) << 32
+}
+
+
+/// Ways blocks can be compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compressor {
+    Gzip,
+    Lzma,
+    Xz,
+    Zstd,
+}
+
+
+impl Compressor {
+    fn of(id: u16) -> Result<Compressor, Error> {
+        match id {
+            1 => Ok(Compressor::Gzip),
+            2 => Ok(Compressor::Lzma),
+            4 => Ok(Compressor::Xz),
+            6 => Ok(Compressor::Zstd),
+            3 => Err(Error::other("Can't read SquashFS images compressed with LZO")),
+            5 => Err(Error::other("Can't read SquashFS images compressed with LZ4")),
+            _ => Err(corrupt("unknown compressor")),
+        }
+    }
+
+
+    /// Decompress `compressed`, which should come to at most `limit` bytes
+    fn decompress(&self, compressed: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+        let mut to_return = Vec::new();
+        let reader: Box<dyn Read + '_> = match self {
+            Compressor::Gzip => Box::new(flate2::read::ZlibDecoder::new(compressed)),
+            Compressor::Lzma => {
+                let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)?;
+                Box::new(xz2::read::XzDecoder::new_stream(compressed, stream))
+            },
+            Compressor::Xz => Box::new(xz2::read::XzDecoder::new(compressed)),
+            Compressor::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(compressed)?),
+        };
+        reader.take(limit as u64 + 1).read_to_end(&mut to_return)?;
+        if to_return.len() > limit {
+            return Err(corrupt("block decompresses to too much"));
+        }
+        Ok(to_return)
+    }
+}
+
+
+/// Where a regular file's contents are
+struct Contents {
+    size: u64,
+    blocks_start: u64,
+    block_sizes: Vec<u32>,
+    fragment: u32,
+    fragment_offset: u32,
+}
+
+
+/// Where metadata is read from next: the metadata block's position in the
+/// image and how far into it once it's decompressed
+#[derive(Clone, Copy)]
+struct Cursor {
+    block: u64,
+    offset: usize,
+}
+
+
+/// Everything in a SquashFS (version 4) image
+pub struct Image {
+    file: File,
+
+    /// How long the image file is, which nothing in it can run past
+    length: u64,
+    compressor: Compressor,
+    block_size: u64,
+    inode_table: u64,
+    directory_table: u64,
+    fragment_table: u64,
+    num_fragments: u32,
+
+    /// Decompressed metadata blocks, and where the next one starts
+    metadata: HashMap<u64, (Vec<u8>, u64)>,
+
+    /// The last fragment block read, since files' ends tend to share one
+    fragment_block: Option<(u32, Vec<u8>)>,
+    visited: HashSet<u64>,
+    pub entries: Vec<ArchiveEntry>,
+
+    /// Where each entry's contents are (`None` for special files)
+    contents: Vec<Option<Contents>>,
+}
+
+
+impl Image {
+
+    /// Read the directories of the image `filename`
+    pub fn open(filename: &str) -> Result<Image, Error> {
+        let mut file = File::open(filename)?;
+        let mut superblock = [0; 96];
+        file.read_exact(&mut superblock)
+                .map_err(|_| corrupt("too short for a superblock"))?;
+        if u32_at(&superblock, 0) != MAGIC {
+            return Err(corrupt("no magic number"));
+        }
+        if u16_at(&superblock, 28) != 4 {
+            return Err(Error::other("Can only read version 4 SquashFS images"));
+        }
+        let block_size = u32_at(&superblock, 12);
+        if !block_size.is_power_of_two() || !(4096..=1 << 20).contains(&block_size) {
+            return Err(corrupt("bad block size"));
+        }
+        let length = file.metadata()?.len();
+        let mut image = Image{file, length, compressor: Compressor::of(u16_at(&superblock, 20))?,
+                block_size: block_size as u64,
+                inode_table: u64_at(&superblock, 64),
+                directory_table: u64_at(&superblock, 72),
+                fragment_table: u64_at(&superblock, 80),
+                num_fragments: u32_at(&superblock, 16),
+                metadata: HashMap::new(), fragment_block: None,
+                visited: HashSet::new(), entries: Vec::new(),
+                contents: Vec::new()};
+
+        /* Inode references are a metadata block's offset into the inode
+         * table, shifted up 16, plus the offset into it */
+        let root = u64_at(&superblock, 32);
+        image.read_directory(root, "")?;
+        Ok(image)
+    }
+
+
+    /// `length` bytes from `offset` on, which has to be checked against the
+    /// image before anything's allocated, since both come from the image
+    fn read_at(&mut self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        if offset.checked_add(length as u64).is_none_or(|end| end > self.length) {
+            return Err(corrupt("something runs past the end of the image"));
+        }
+        let mut to_return = vec![0; length];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut to_return)
+                .map_err(|_| corrupt("something runs past the end of the image"))?;
+        Ok(to_return)
+    }
+
+
+    /// `length` bytes of metadata from `cursor` on, moving it along
+    fn read_metadata(&mut self, cursor: &mut Cursor, length: usize)
+            -> Result<Vec<u8>, Error> {
+
+        /* `length` comes from the image, so memory is only taken as the
+         * metadata turns out to be there */
+        let mut to_return = Vec::with_capacity(length.min(METADATA_SIZE));
+        while to_return.len() < length {
+            if !self.metadata.contains_key(&cursor.block) {
+                let header = self.read_at(cursor.block, 2)?;
+                let header = u16_at(&header, 0);
+                let stored_size = (header & !UNCOMPRESSED_METADATA) as usize;
+                let stored = self.read_at(cursor.block + 2, stored_size)?;
+                let block = if header & UNCOMPRESSED_METADATA != 0 {
+                    stored
+                }
+                else {
+                    self.compressor.decompress(&stored, METADATA_SIZE)?
+                };
+                self.metadata.insert(cursor.block,
+                        (block, cursor.block + 2 + stored_size as u64));
+            }
+            let (ref block, next) = self.metadata[&cursor.block];
+            if cursor.offset >= block.len() {
+                if block.is_empty() {
+                    return Err(corrupt("empty metadata block"));
+                }
+                cursor.offset -= block.len();
+                cursor.block = next;
+                continue;
+            }
+            let num_bytes = (length - to_return.len()).min(block.len() - cursor.offset);
+            to_return.extend_from_slice(&block[cursor.offset..cursor.offset + num_bytes]);
+            cursor.offset += num_bytes;
+        }
+        Ok(to_return)
+    }
+
+
+    fn inode_cursor(&self, inode: u64) -> Cursor {
+        Cursor{block: self.inode_table + (inode >> 16), offset: (inode & 0xffff) as usize}
+    }
+
+
+    /// The block sizes after a file inode's fixed part.  Sparse files can
+    /// be far bigger than the image, but their lists of blocks can't be
+    /// bigger than the most metadata the image could hold: a full
+    /// metadata block for every 3 bytes (header and all).
+    fn block_sizes(&mut self, cursor: &mut Cursor, size: u64, fragment: u32)
+            -> Result<Vec<u32>, Error> {
+        let mut num_blocks = size / self.block_size;
+        if fragment == NO_FRAGMENT && !size.is_multiple_of(self.block_size) {
+            num_blocks += 1;
+        }
+        if num_blocks.saturating_mul(4) > self.length / 3 * METADATA_SIZE as u64 {
+            return Err(corrupt("file is bigger than the image could describe"));
+        }
+        let bytes = self.read_metadata(cursor, num_blocks as usize * 4)?;
+        Ok(bytes.chunks(4).map(|le| u32_at(le, 0)).collect())
+    }
+
+
+    /// Add everything in the directory whose inode is `inode` (and under
+    /// it) to `entries`, under `prefix`
+    fn read_directory(&mut self, inode: u64, prefix: &str) -> Result<(), Error> {
+        if !self.visited.insert(inode) {
+            return Ok(());
+        }
+        let mut cursor = self.inode_cursor(inode);
+        let header = self.read_metadata(&mut cursor, 16)?;
+        let (block, offset, size) = match u16_at(&header, 0) {
+            1 => {
+                let fields = self.read_metadata(&mut cursor, 16)?;
+                (u32_at(&fields, 0), u16_at(&fields, 10), u16_at(&fields, 8) as u32)
+            },
+            8 => {
+                let fields = self.read_metadata(&mut cursor, 24)?;
+                (u32_at(&fields, 8), u16_at(&fields, 18), u32_at(&fields, 4))
+            },
+            _ => return Err(corrupt("directory inode isn't a directory")),
+        };
+
+        /* The listing's size counts `.` and `..`, which aren't in it */
+        let mut cursor = Cursor{block: self.directory_table + block as u64,
+                offset: offset as usize};
+        let mut remaining = (size as usize).saturating_sub(3);
+        while remaining > 0 {
+            let header = self.read_metadata(&mut cursor, 12)?;
+            remaining = remaining.saturating_sub(12);
+            let count = u32_at(&header, 0) as usize + 1;
+            let start = u32_at(&header, 4) as u64;
+            for _ in 0..count {
+                let fields = self.read_metadata(&mut cursor, 8)?;
+                let name_size = u16_at(&fields, 6) as usize + 1;
+                let name = self.read_metadata(&mut cursor, name_size)?;
+                remaining = remaining.saturating_sub(8 + name_size);
+                let name = String::from_utf8_lossy(&name);
+                let path = if prefix.is_empty() {
+                    name.into_owned()
+                }
+                else {
+                    prefix.to_owned() + "/" + &name
+                };
+                self.read_inode(start << 16 | u16_at(&fields, 0) as u64, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+
+    /// Add whatever the inode `inode` is to `entries` as `path`
+    fn read_inode(&mut self, inode: u64, path: &str) -> Result<(), Error> {
+        let mut cursor = self.inode_cursor(inode);
+        let header = self.read_metadata(&mut cursor, 16)?;
+        let device = |rdev: u32| {
+            ((rdev >> 8) & 0xfff, (rdev & 0xff) | ((rdev >> 12) & 0xfff00))
+        };
+        let (special, contents) = match u16_at(&header, 0) {
+            1 | 8 => return self.read_directory(inode, path),
+            2 => {
+                let fields = self.read_metadata(&mut cursor, 16)?;
+                let size = u32_at(&fields, 12) as u64;
+                let fragment = u32_at(&fields, 4);
+                let block_sizes = self.block_sizes(&mut cursor, size, fragment)?;
+                (None, Some(Contents{size, blocks_start: u32_at(&fields, 0) as u64,
+                        block_sizes, fragment, fragment_offset: u32_at(&fields, 8)}))
+            },
+            9 => {
+                let fields = self.read_metadata(&mut cursor, 40)?;
+                let size = u64_at(&fields, 8);
+                let fragment = u32_at(&fields, 28);
+                let block_sizes = self.block_sizes(&mut cursor, size, fragment)?;
+                (None, Some(Contents{size, blocks_start: u64_at(&fields, 0),
+                        block_sizes, fragment, fragment_offset: u32_at(&fields, 32)}))
+            },
+            4 | 11 => {
+                let fields = self.read_metadata(&mut cursor, 8)?;
+                let (major, minor) = device(u32_at(&fields, 4));
+                (Some(SpecialKind::BlockDevice(major, minor)), None)
+            },
+            5 | 12 => {
+                let fields = self.read_metadata(&mut cursor, 8)?;
+                let (major, minor) = device(u32_at(&fields, 4));
+                (Some(SpecialKind::CharDevice(major, minor)), None)
+            },
+            6 | 13 => (Some(SpecialKind::Fifo), None),
+            7 | 14 => (Some(SpecialKind::Socket), None),
+
+            /* Symlinks aren't kept, the same as when walking */
+            3 | 10 => return Ok(()),
+            _ => return Err(corrupt("unknown inode type")),
+        };
+        let size = contents.as_ref().map_or(0, |contents| contents.size as usize);
+        self.entries.push(ArchiveEntry::in_image(path.to_owned(), size, special,
+                self.entries.len()));
+        self.contents.push(contents);
+        Ok(())
+    }
+
+
+    /// The decompressed fragment block `fragment`
+    fn fragment_block(&mut self, fragment: u32) -> Result<Vec<u8>, Error> {
+        if let Some((last, ref block)) = self.fragment_block {
+            if last == fragment {
+                return Ok(block.clone());
+            }
+        }
+        if fragment >= self.num_fragments {
+            return Err(corrupt("fragment index is out of range"));
+        }
+        let lookup = self.read_at(self.fragment_table +
+                (fragment / FRAGMENTS_PER_BLOCK) as u64 * 8, 8)?;
+        let mut cursor = Cursor{block: u64_at(&lookup, 0),
+                offset: (fragment % FRAGMENTS_PER_BLOCK) as usize * 16};
+        let entry = self.read_metadata(&mut cursor, 16)?;
+        let block = self.data_block(u64_at(&entry, 0), u32_at(&entry, 8))?;
+        self.fragment_block = Some((fragment, block.clone()));
+        Ok(block)
+    }
+
+
+    /// The decompressed data block at `offset`, whose stored size is `size`
+    fn data_block(&mut self, offset: u64, size: u32) -> Result<Vec<u8>, Error> {
+        let stored = self.read_at(offset, (size & !UNCOMPRESSED_BLOCK) as usize)?;
+        if size & UNCOMPRESSED_BLOCK != 0 {
+            Ok(stored)
+        }
+        else {
+            self.compressor.decompress(&stored, self.block_size as usize)
+        }
+    }
+
+
+    /// Write the contents of the `i`th entry to `writable`.  Blocks stored
+    /// with a size of 0 are sparse.
+    pub fn copy_contents(&mut self, i: usize, writable: &mut impl Write)
+            -> Result<(), Error> {
+        let (size, mut offset, block_sizes, fragment, fragment_offset) =
+                match self.contents[i] {
+            Some(ref contents) => (contents.size, contents.blocks_start,
+                    contents.block_sizes.clone(), contents.fragment,
+                    contents.fragment_offset as usize),
+            None => return Ok(()),
+        };
+        let mut remaining = size;
+        for block_size in block_sizes {
+            let num_bytes = remaining.min(self.block_size);
+            if block_size == 0 {
+                writable.write_all(&vec![0; num_bytes as usize])?;
+            }
+            else {
+                let block = self.data_block(offset, block_size)?;
+                let block = block.get(..num_bytes as usize)
+                        .ok_or_else(|| corrupt("data block is too short"))?;
+                writable.write_all(block)?;
+                offset += (block_size & !UNCOMPRESSED_BLOCK) as u64;
+            }
+            remaining -= num_bytes;
+        }
+        if remaining > 0 {
+            if fragment == NO_FRAGMENT {
+                return Err(corrupt("file is shorter than its size"));
+            }
+            let block = self.fragment_block(fragment)?;
+            let tail = block.get(fragment_offset..fragment_offset + remaining as usize)
+                    .ok_or_else(|| corrupt("fragment is too short"))?;
+            writable.write_all(tail)?;
+        }
+        Ok(())
+    }
+}
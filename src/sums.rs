@@ -0,0 +1,281 @@
+use crate::algorithm::hash_of_path_with;
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::manifest::read_manifest;
+use crate::manifest::EntryKind;
+use crate::relative_path_string;
+use crate::special;
+use crate::tidied;
+use crate::unhashable;
+use crate::BytesComparison;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::Write;
+use std::path::Path;
+
+
+/// How a checksum file lays out its lines
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SumStyle {
+
+    /// `<hex>  <path>`, as `sha256sum` writes by default
+    Gnu,
+
+    /// `SHA256 (<path>) = <hex>`, as BSD tools and `sha256sum --tag` write
+    Bsd,
+}
+
+
+/// One line of a checksum file, e.g. `SHA256SUMS` or `MD5SUMS`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SumEntry {
+    pub algorithm: Algorithm,
+    pub digest: String,
+    pub path: String,
+}
+
+
+/// Escape a path the way coreutils does when it has a backslash or a line
+/// break in it.  Returns whether that was necessary, since such lines then
+/// start with a backslash.
+fn escaped(path: &str) -> (bool, String) {
+    if !path.contains(&['\\', '\n', '\r'][..]) {
+        return (false, path.to_owned());
+    }
+    (true, path.replace('\\', "\\\\").replace('\n', "\\n")
+            .replace('\r', "\\r"))
+}
+
+
+/// Inverse of `escaped`
+fn unescaped(path: &str) -> Result<String, Error> {
+    let mut to_return = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            to_return.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => to_return.push('\\'),
+            Some('n') => to_return.push('\n'),
+            Some('r') => to_return.push('\r'),
+            _ => {
+                let err_s = "Can't unescape the filename ".to_owned() + path;
+                return Err(Error::other(err_s));
+            }
+        }
+    }
+    Ok(to_return)
+}
+
+
+fn is_hex_digest(string: &str, algorithm: Algorithm) -> bool {
+    string.len() == algorithm.hex_len() &&
+            string.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+
+impl SumEntry {
+
+    /// Parse one line in either style.  `algorithm` is what plain lines
+    /// were made with; if `None`, it's guessed from the length of the
+    /// digest.  `Ok(None)` means a blank line or comment.
+    pub fn from_line(line: &str, algorithm: Option<Algorithm>)
+            -> Result<Option<SumEntry>, Error> {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let corrupt = || {
+            Error::other("Corrupt checksum line: ".to_owned() + line)
+        };
+        let (is_escaped, rest) = match line.strip_prefix('\\') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let path_of = |path: &str| {
+            if is_escaped { unescaped(path) } else { Ok(path.to_owned()) }
+        };
+
+        /* BSD style */
+        if let Some((tag, rest)) = rest.split_once(" (") {
+            if let (Some(algorithm), Some((path, digest))) =
+                    (Algorithm::from_bsd_tag(tag), rest.rsplit_once(") = ")) {
+                if !is_hex_digest(digest, algorithm) {
+                    return Err(corrupt());
+                }
+                return Ok(Some(SumEntry{algorithm,
+                        digest: digest.to_ascii_lowercase(),
+                        path: path_of(path)?}));
+            }
+        }
+
+        /* GNU style, with a space or (for "binary mode") a star before the
+         * path */
+        let (digest, path) = rest.split_once(' ').ok_or_else(corrupt)?;
+        let path = path.strip_prefix(' ').or_else(|| path.strip_prefix('*'))
+                .ok_or_else(corrupt)?;
+        let algorithm = match algorithm {
+            Some(algorithm) => algorithm,
+            None => Algorithm::from_hex_len(digest.len()).ok_or_else(corrupt)?,
+        };
+        if !is_hex_digest(digest, algorithm) || path.is_empty() {
+            return Err(corrupt());
+        }
+        Ok(Some(SumEntry{algorithm, digest: digest.to_ascii_lowercase(),
+                path: path_of(path)?}))
+    }
+
+
+    /// The line (sans newline) `from_line` would read back as this
+    pub fn to_line(&self, style: SumStyle) -> String {
+        let (is_escaped, path) = escaped(&self.path);
+        let prefix = if is_escaped { "\\" } else { "" };
+        match style {
+            SumStyle::Gnu => format!("{}{}  {}", prefix, self.digest, path),
+            SumStyle::Bsd => format!("{}{} ({}) = {}", prefix,
+                    self.algorithm.bsd_tag(), path, self.digest),
+        }
+    }
+}
+
+
+/// Every entry of the checksum file `filename`
+pub fn read_sums(filename: &str, algorithm: Option<Algorithm>)
+        -> Result<Vec<SumEntry>, Error> {
+    let mut to_return = Vec::new();
+    for line in BufReader::new(fs::File::open(filename)?).lines() {
+        if let Some(entry) = SumEntry::from_line(&line?, algorithm)? {
+            to_return.push(entry);
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// Check the file at `path` against `entry`, writing out a line if it
+/// disagrees.  One that can't be read disagrees too.
+pub fn verify_sum_entry(entry: &SumEntry, path: &Path, writable: &mut impl Write,
+        num_vs: u8) -> Result<BytesComparison, Error> {
+    if num_vs > 1 {
+        writeln!(writable, "Examining {}", path.display())?;
+    }
+    if !path.is_file() {
+        writeln!(writable, "Disagreement (0 bytes): {} is listed and {} {}.",
+                entry.path, path.display(), special::describe_path(path))?;
+        return Ok(BytesComparison{entry_disagreement: 1, ..Default::default()});
+    }
+
+    let (digest, num_bytes_hashed) = match hash_of_path_with(path,
+            entry.algorithm) {
+        Ok(digest_and_size) => digest_and_size,
+        Err(error) => {
+            return unhashable(path, &error, writable);
+        }
+    };
+    if digest == entry.digest {
+        if num_vs > 0 {
+            writeln!(writable, "{}: OK", entry.path)?;
+        }
+        Ok(BytesComparison{agreement: num_bytes_hashed, ..Default::default()})
+    }
+    else {
+        writeln!(writable, "Disagreement ({} bytes): {} doesn't have the {} listed for {}.",
+                num_bytes_hashed, path.display(), entry.algorithm.name(),
+                entry.path)?;
+        Ok(BytesComparison{disagreement: num_bytes_hashed, ..Default::default()})
+    }
+}
+
+
+/// Check every file listed in the checksum file `sums_filename` against
+/// what's in `directory` now.  Checksum files don't record sizes, so the
+/// totals are of what was actually hashed, and a missing file only counts
+/// as an entry disagreement.  Files `filter` doesn't want aren't checked,
+/// and paths that lead out of `directory` are entry disagreements too.
+pub fn verify_sums(sums_filename: &str, directory: &str,
+        mut writable: impl Write, num_vs: u8, filter: &Filter,
+        algorithm: Option<Algorithm>) -> Result<BytesComparison, Error> {
+    if num_vs > 1 {
+        writeln!(writable, "Reading {}", sums_filename)?;
+    }
+    let mut to_return = BytesComparison::default();
+    let mut num_outside = 0;
+    for entry in read_sums(sums_filename, algorithm)? {
+        let relative = match tidied(Path::new(&entry.path)) {
+            Some(relative) => relative,
+            None => {
+                writeln!(writable, "Disagreement (0 bytes): {} lists {}, which isn't under {}.",
+                        sums_filename, entry.path, directory)?;
+                num_outside += 1;
+                continue;
+            }
+        };
+        if !filter.wants_listed_entry(directory, &relative.to_string_lossy(),
+                None) {
+            if num_vs > 1 {
+                writeln!(writable, "Filtered out {}", entry.path)?;
+            }
+            continue;
+        }
+        to_return += verify_sum_entry(&entry, &Path::new(directory).join(&relative),
+                &mut writable, num_vs)?;
+    }
+
+    let num_bytes = to_return.agreement + to_return.disagreement;
+    to_return.write_summary(&mut writable, num_bytes)?;
+    if to_return.entry_disagreement > 0 {
+        writeln!(writable, "{} listed files are missing.",
+                to_return.entry_disagreement)?;
+    }
+    if num_outside > 0 {
+        writeln!(writable, "{} listed files aren't under {}.", num_outside,
+                directory)?;
+    }
+    to_return += BytesComparison{entry_disagreement: num_outside,
+            ..Default::default()};
+    Ok(to_return)
+}
+
+
+/// Write a checksum file for `source` to `writable`.  A directory is
+/// hashed with `algorithm`, through `filter`; anything else is taken to be
+/// a manifest written by `hash_path`, which only has sha1s to offer.
+/// Returns the number of files listed.
+pub fn write_sums(source: &str, mut writable: impl Write, algorithm: Algorithm,
+        style: SumStyle, filter: &Filter, ignore_perm_errors_flag: bool)
+                -> Result<usize, Error> {
+    let mut num_listed = 0;
+    if !Path::new(source).is_dir() {
+        if algorithm != Algorithm::Sha1 {
+            let err_s = source.to_owned() + " only has sha1 hashes.  Give a directory to get " +
+                    algorithm.name() + ".";
+            return Err(Error::other(err_s));
+        }
+        let (entries, _) = read_manifest(source)?;
+        for entry in entries {
+            if let EntryKind::File(digest) = entry.kind {
+                writeln!(writable, "{}", SumEntry{algorithm, digest,
+                        path: entry.path}.to_line(style))?;
+                num_listed += 1;
+            }
+        }
+        return Ok(num_listed);
+    }
+
+    for entry in filter.walk_entries(source, ignore_perm_errors_flag) {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        let (digest, _) = hash_of_path_with(entry.path(), algorithm)?;
+        writeln!(writable, "{}", SumEntry{algorithm, digest,
+                path: relative_path_string(entry.path(), source)?}
+                .to_line(style))?;
+        num_listed += 1;
+    }
+    Ok(num_listed)
+}
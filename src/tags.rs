@@ -0,0 +1,285 @@
+use crate::algorithm;
+use crate::algorithm::Algorithm;
+use crate::filter::Filter;
+use crate::special;
+use crate::unhashable;
+use crate::BytesComparison;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+
+/// Where shatag (and cshatag) keep their tags
+pub const SHATAG: &str = "user.shatag";
+
+/// Where confidence keeps its own tags, for anyone who'd rather not share
+pub const CONFIDENCE: &str = "user.confidence";
+
+
+/// A file's hash as stored in its extended attributes, next to the mtime
+/// the file had when it was hashed:
+///
+/// `<namespace>.sha256` holds the hex digest and `<namespace>.ts` holds
+/// `<mtime seconds>.<nanoseconds>`, the way shatag does it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    pub digest: String,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+
+impl Tag {
+    fn ts(&self) -> String {
+        format!("{}.{:09}", self.mtime, self.mtime_nsec)
+    }
+
+
+    /// Whether this tag was made when the file had `metadata`'s mtime
+    pub fn is_current(&self, metadata: &fs::Metadata) -> bool {
+        self.mtime == metadata.mtime() && self.mtime_nsec == metadata.mtime_nsec()
+    }
+}
+
+
+/// Inverse of `Tag::ts`.  Older shatag wrote a float's worth of digits,
+/// so fewer than nine after the point are fine.
+fn parse_ts(ts: &str) -> Option<(i64, i64)> {
+    let (seconds, fraction) = match ts.trim().split_once('.') {
+        Some((seconds, fraction)) => (seconds, fraction),
+        None => (ts.trim(), ""),
+    };
+    if fraction.len() > 9 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let nanoseconds = format!("{:0<9}", fraction).parse::<i64>().ok()?;
+    Some((seconds.parse::<i64>().ok()?, nanoseconds))
+}
+
+
+/// The tag `path` has under `namespace`, if it has a readable one
+pub fn read_tag(path: &Path, namespace: &str, algorithm: Algorithm)
+        -> Result<Option<Tag>, Error> {
+    let digest = xattr::get(path, format!("{}.{}", namespace, algorithm.name()))?;
+    let ts = xattr::get(path, format!("{}.ts", namespace))?;
+    if let (Some(digest), Some(ts)) = (digest, ts) {
+        if let (Ok(digest), Ok(ts)) = (String::from_utf8(digest),
+                String::from_utf8(ts)) {
+            if let Some((mtime, mtime_nsec)) = parse_ts(&ts) {
+                return Ok(Some(Tag{digest: digest.trim().to_owned(), mtime,
+                        mtime_nsec}));
+            }
+        }
+    }
+    Ok(None)
+}
+
+
+pub fn write_tag(path: &Path, namespace: &str, algorithm: Algorithm, tag: &Tag)
+        -> Result<(), Error> {
+    xattr::set(path, format!("{}.{}", namespace, algorithm.name()),
+            tag.digest.as_bytes())?;
+    xattr::set(path, format!("{}.ts", namespace), tag.ts().as_bytes())
+}
+
+
+/// Every regular file under `directory` the filter lets through
+fn tagged_files(directory: &str, filter: &Filter, ignore_perm_errors_flag: bool)
+        -> Result<Vec<std::path::PathBuf>, Error> {
+    let mut to_return = Vec::new();
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let path = entry?.into_path();
+        if path.is_file() && special::special_kind_of(&path).is_none() {
+            to_return.push(path);
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// Hash every file under `directory` and store the hash in its extended
+/// attributes under `namespace`.
+///
+/// A file whose tag has its current mtime but the wrong hash changed
+/// without being written to.  That's reported as a disagreement and the
+/// tag is left alone, so later checks still flag it.  So is a file that
+/// can't be read or whose extended attributes can't be read or written,
+/// and the rest are still tagged.
+pub fn tag_tree(directory: &str, mut writable: impl Write, num_vs: u8,
+        filter: &Filter, ignore_perm_errors_flag: bool, namespace: &str)
+                -> Result<BytesComparison, Error> {
+    let mut to_return = BytesComparison::default();
+    let (mut num_tagged, mut num_current, mut num_failed) = (0, 0, 0);
+    let mut num_unreadable = 0;
+    for path in tagged_files(directory, filter, ignore_perm_errors_flag)? {
+
+        /* Stat first, so a file that changes while it's being hashed
+         * looks changed next time. */
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                num_unreadable += 1;
+                to_return += unhashable(&path, &error, &mut writable)?;
+                continue;
+            }
+        };
+        let tag = match read_tag(&path, namespace, Algorithm::Sha256) {
+            Ok(tag) => tag,
+            Err(error) => {
+                writeln!(writable, "Disagreement ({} bytes): Couldn't read the tag of {}: {}",
+                        metadata.len(), path.display(), error)?;
+                num_failed += 1;
+                to_return += BytesComparison{disagreement: metadata.len() as usize,
+                        ..Default::default()};
+                continue;
+            }
+        };
+        let (digest, num_bytes_hashed) = match algorithm::hash_of_path_with(
+                &path, Algorithm::Sha256) {
+            Ok(digest_and_size) => digest_and_size,
+            Err(error) => {
+                num_unreadable += 1;
+                to_return += unhashable(&path, &error, &mut writable)?;
+                continue;
+            }
+        };
+        if let Some(tag) = tag.filter(|tag| tag.is_current(&metadata)) {
+            if tag.digest == digest {
+                num_current += 1;
+                to_return += BytesComparison{agreement: num_bytes_hashed,
+                        ..Default::default()};
+            }
+            else {
+                writeln!(writable, "Disagreement ({} bytes): {} changed without its mtime changing.",
+                        num_bytes_hashed, path.display())?;
+                to_return += BytesComparison{disagreement: num_bytes_hashed,
+                        ..Default::default()};
+            }
+            continue;
+        }
+
+        if let Err(error) = write_tag(&path, namespace, Algorithm::Sha256,
+                &Tag{digest, mtime: metadata.mtime(),
+                        mtime_nsec: metadata.mtime_nsec()}) {
+            writeln!(writable, "Disagreement ({} bytes): Couldn't tag {}: {}",
+                    num_bytes_hashed, path.display(), error)?;
+            num_failed += 1;
+            to_return += BytesComparison{disagreement: num_bytes_hashed,
+                    ..Default::default()};
+            continue;
+        }
+        num_tagged += 1;
+        to_return += BytesComparison{agreement: num_bytes_hashed,
+                ..Default::default()};
+        if num_vs > 0 {
+            writeln!(writable, "Tagged {}", path.display())?;
+        }
+    }
+
+    writeln!(writable, "Tagged {} files, {} were already up to date.",
+            num_tagged, num_current)?;
+    if num_failed > 0 {
+        writeln!(writable, "{} files' tags couldn't be read or written.",
+                num_failed)?;
+    }
+    if num_unreadable > 0 {
+        writeln!(writable, "{} files couldn't be read.", num_unreadable)?;
+    }
+    if to_return.disagreement > 0 {
+        writeln!(writable, "{} bytes disagree.", to_return.disagreement)?;
+    }
+    Ok(to_return)
+}
+
+
+/// Re-hash every file under `directory` and compare with the hash stored
+/// in its extended attributes under `namespace`.  No manifest needed.
+///
+/// Files modified since they were tagged, and files with no tag at all,
+/// are reported but don't count either way.  Files that can't be read,
+/// or whose tags can't be, count as disagreements.
+pub fn check_tags(directory: &str, mut writable: impl Write, num_vs: u8,
+        filter: &Filter, ignore_perm_errors_flag: bool, namespace: &str)
+                -> Result<BytesComparison, Error> {
+    let mut to_return = BytesComparison::default();
+    let (mut num_untagged, mut num_outdated, mut num_unreadable) = (0, 0, 0);
+    let mut num_unhashable = 0;
+    for path in tagged_files(directory, filter, ignore_perm_errors_flag)? {
+        if num_vs > 1 {
+            writeln!(writable, "Examining {}", path.display())?;
+        }
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(error) => {
+                num_unhashable += 1;
+                to_return += unhashable(&path, &error, &mut writable)?;
+                continue;
+            }
+        };
+        let tag = match read_tag(&path, namespace, Algorithm::Sha256) {
+            Ok(Some(tag)) => tag,
+            Ok(None) => {
+                num_untagged += 1;
+                if num_vs > 0 {
+                    writeln!(writable, "No tag: {}", path.display())?;
+                }
+                continue;
+            },
+            Err(error) => {
+                writeln!(writable, "Disagreement ({} bytes): Couldn't read the tag of {}: {}",
+                        metadata.len(), path.display(), error)?;
+                num_unreadable += 1;
+                to_return += BytesComparison{disagreement: metadata.len() as usize,
+                        ..Default::default()};
+                continue;
+            }
+        };
+        if !tag.is_current(&metadata) {
+            num_outdated += 1;
+            if num_vs > 0 {
+                writeln!(writable, "Outdated tag: {} was modified after it was tagged.",
+                        path.display())?;
+            }
+            continue;
+        }
+
+        let (digest, num_bytes_hashed) = match algorithm::hash_of_path_with(
+                &path, Algorithm::Sha256) {
+            Ok(digest_and_size) => digest_and_size,
+            Err(error) => {
+                num_unhashable += 1;
+                to_return += unhashable(&path, &error, &mut writable)?;
+                continue;
+            }
+        };
+        if digest == tag.digest {
+            to_return += BytesComparison{agreement: num_bytes_hashed,
+                    ..Default::default()};
+        }
+        else {
+            writeln!(writable, "Disagreement ({} bytes): {} has a different hash than its tag.",
+                    num_bytes_hashed, path.display())?;
+            to_return += BytesComparison{disagreement: num_bytes_hashed,
+                    ..Default::default()};
+        }
+    }
+
+    let num_bytes_checked = to_return.agreement + to_return.disagreement;
+    to_return.write_summary(&mut writable, num_bytes_checked)?;
+    if num_untagged > 0 {
+        writeln!(writable, "{} files have no tag.", num_untagged)?;
+    }
+    if num_outdated > 0 {
+        writeln!(writable, "{} files were modified after they were tagged.",
+                num_outdated)?;
+    }
+    if num_unreadable > 0 {
+        writeln!(writable, "{} files' tags couldn't be read.", num_unreadable)?;
+    }
+    if num_unhashable > 0 {
+        writeln!(writable, "{} files couldn't be read.", num_unhashable)?;
+    }
+    Ok(to_return)
+}
@@ -0,0 +1,595 @@
+use crate::BytesComparison;
+use sha2::Digest;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::File;
+use std::io::Error;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+
+/// v2 torrents hash files in blocks of this many bytes
+const BLOCK_SIZE: usize = 16 * 1024;
+
+
+/// How deep lists and dictionaries can nest, which is far deeper than any
+/// real torrent's directories go
+const MAX_DEPTH: usize = 512;
+
+
+/// A decoded bencoded value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+
+fn corrupt(why: &str) -> Error {
+    Error::other("Corrupt torrent: ".to_owned() + why)
+}
+
+
+impl Bencode {
+
+    /// Decode the value at the start of `bytes`.  Returns it and how many
+    /// bytes it took up.
+    pub fn decode(bytes: &[u8]) -> Result<(Bencode, usize), Error> {
+        Bencode::decode_within(bytes, MAX_DEPTH)
+    }
+
+
+    /// Same as `decode`, but lists and dictionaries can only go `depth`
+    /// deep, so a crafted file can't use up the stack
+    fn decode_within(bytes: &[u8], depth: usize) -> Result<(Bencode, usize), Error> {
+        if depth == 0 {
+            return Err(corrupt("nested too deeply"));
+        }
+        let digits_until = |start: usize, end: u8| -> Result<(i64, usize), Error> {
+            let length = bytes[start..].iter().position(|byte| *byte == end)
+                    .ok_or_else(|| corrupt("unterminated number"))?;
+            let number = std::str::from_utf8(&bytes[start..start + length]).ok()
+                    .and_then(|number_s| number_s.parse::<i64>().ok())
+                    .ok_or_else(|| corrupt("bad number"))?;
+            Ok((number, start + length + 1))
+        };
+        match bytes.first() {
+            Some(b'i') => {
+                let (number, end) = digits_until(1, b'e')?;
+                Ok((Bencode::Int(number), end))
+            },
+            Some(b'0'..=b'9') => {
+                let (length, start) = digits_until(0, b':')?;
+                let length = usize::try_from(length)
+                        .map_err(|_| corrupt("bad length"))?;
+                let end = start.checked_add(length)
+                        .filter(|end| *end <= bytes.len())
+                        .ok_or_else(|| corrupt("string runs past the end"))?;
+                Ok((Bencode::Bytes(bytes[start..end].to_vec()), end))
+            },
+            Some(b'l') => {
+                let mut to_return = Vec::new();
+                let mut offset = 1;
+                while bytes.get(offset) != Some(&b'e') {
+                    let (value, length) = Bencode::decode_within(&bytes[offset..],
+                            depth - 1)?;
+                    to_return.push(value);
+                    offset += length;
+                }
+                Ok((Bencode::List(to_return), offset + 1))
+            },
+            Some(b'd') => {
+                let mut to_return = BTreeMap::new();
+                let mut offset = 1;
+                while bytes.get(offset) != Some(&b'e') {
+                    let (key, key_length) = Bencode::decode_within(&bytes[offset..],
+                            depth - 1)?;
+                    let key = match key {
+                        Bencode::Bytes(key) => key,
+                        _ => return Err(corrupt("dictionary key isn't a string")),
+                    };
+                    let (value, value_length) = Bencode::decode_within(
+                            &bytes[offset + key_length..], depth - 1)?;
+                    to_return.insert(key, value);
+                    offset += key_length + value_length;
+                }
+                Ok((Bencode::Dict(to_return), offset + 1))
+            },
+            _ => Err(corrupt("unexpected byte or end")),
+        }
+    }
+
+
+    pub fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dict(dict) => dict.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Bencode::Int(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+}
+
+
+/// One file a torrent describes.  Padding files (in hybrid torrents)
+/// aren't on disk, they're just zeros.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TorrentFile {
+
+    /// Relative to the top of what was downloaded
+    pub path: Vec<String>,
+    pub length: usize,
+    pub is_padding: bool,
+
+    /// v2 only: the root of the file's merkle tree
+    pub pieces_root: Option<Vec<u8>>,
+}
+
+
+impl TorrentFile {
+    pub fn path_string(&self) -> String {
+        self.path.join("/")
+    }
+}
+
+
+/// What's needed out of a torrent's `info` dictionary to check the files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Torrent {
+    pub name: String,
+    pub piece_length: usize,
+
+    /// Whether there's one file called `name` rather than a directory
+    pub is_single_file: bool,
+    pub files: Vec<TorrentFile>,
+
+    /// v1: sha1 of each piece, in order across every file
+    pub pieces: Option<Vec<u8>>,
+
+    /// v2: sha256 of each piece of each file bigger than a piece, by
+    /// pieces root
+    pub piece_layers: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+
+/// A path component, which a torrent mustn't be able to escape the
+/// download directory with
+fn component(component: &Bencode) -> Result<String, Error> {
+    match component.as_str() {
+        Some(component) if !component.is_empty() && component != "." &&
+                component != ".." && !component.contains('/') => {
+            Ok(component.to_owned())
+        },
+        _ => Err(corrupt("bad path component")),
+    }
+}
+
+
+/// A file's `length`, which mustn't be negative
+fn length_of(file: &Bencode, missing: &str) -> Result<usize, Error> {
+    let length = file.get("length").and_then(Bencode::as_int)
+            .ok_or_else(|| corrupt(missing))?;
+    usize::try_from(length).map_err(|_| corrupt("bad length"))
+}
+
+
+/// The files under a v2 `file tree` dictionary, depth first, in order
+fn files_of_tree(tree: &Bencode, prefix: &mut Vec<String>,
+        files: &mut Vec<TorrentFile>) -> Result<(), Error> {
+    let dict = match tree {
+        Bencode::Dict(dict) => dict,
+        _ => return Err(corrupt("file tree isn't a dictionary")),
+    };
+    for (name, subtree) in dict {
+        if name.is_empty() {
+            let length = length_of(subtree, "file without a length")?;
+            files.push(TorrentFile{path: prefix.clone(), length,
+                    is_padding: false,
+                    pieces_root: subtree.get("pieces root").and_then(Bencode::as_bytes)
+                            .map(|root| root.to_vec())});
+            continue;
+        }
+        prefix.push(component(&Bencode::Bytes(name.clone()))?);
+        files_of_tree(subtree, prefix, files)?;
+        prefix.pop();
+    }
+    Ok(())
+}
+
+
+impl Torrent {
+
+    /// Read a .torrent file.  v1 piece hashes are used if there are any
+    /// (hybrid torrents have both), otherwise v2's.
+    pub fn read(filename: &str) -> Result<Torrent, Error> {
+        let (metainfo, _) = Bencode::decode(&fs::read(filename)?)?;
+        let info = metainfo.get("info").ok_or_else(|| corrupt("no info"))?;
+        let name = component(info.get("name").ok_or_else(|| corrupt("no name"))?)?;
+        let piece_length = info.get("piece length").and_then(Bencode::as_int)
+                .filter(|length| *length > 0)
+                .ok_or_else(|| corrupt("no piece length"))? as usize;
+        let pieces = info.get("pieces").and_then(Bencode::as_bytes)
+                .map(|pieces| pieces.to_vec());
+
+        let mut files = Vec::new();
+        let is_single_file;
+        if pieces.is_some() {
+            is_single_file = info.get("files").is_none();
+            if is_single_file {
+                let length = length_of(info, "no length")?;
+                files.push(TorrentFile{path: Vec::new(), length,
+                        is_padding: false, pieces_root: None});
+            }
+            for file in match info.get("files") {
+                Some(Bencode::List(files)) => &files[..],
+                _ => &[],
+            } {
+                let path = match file.get("path") {
+                    Some(Bencode::List(path)) if !path.is_empty() => {
+                        path.iter().map(component).collect::<Result<Vec<_>, _>>()?
+                    },
+                    _ => return Err(corrupt("file without a path")),
+                };
+                let length = length_of(file, "file without a length")?;
+                let is_padding = file.get("attr").and_then(Bencode::as_str)
+                        .is_some_and(|attr| attr.contains('p'));
+                files.push(TorrentFile{path, length, is_padding,
+                        pieces_root: None});
+            }
+        }
+        else {
+            let tree = info.get("file tree")
+                    .ok_or_else(|| corrupt("neither pieces nor a file tree"))?;
+            files_of_tree(tree, &mut Vec::new(), &mut files)?;
+
+            /* A single file is named after the torrent */
+            is_single_file = files.len() == 1 && files[0].path == [name.clone()];
+            if is_single_file {
+                files[0].path.clear();
+            }
+        }
+
+        /* The lengths get added up later */
+        files.iter().try_fold(0usize, |sum, file| sum.checked_add(file.length))
+                .ok_or_else(|| corrupt("bad length"))?;
+
+        let mut piece_layers = BTreeMap::new();
+        if let Some(Bencode::Dict(layers)) = metainfo.get("piece layers") {
+            for (root, layer) in layers {
+                if let Some(layer) = layer.as_bytes() {
+                    piece_layers.insert(root.clone(), layer.to_vec());
+                }
+            }
+        }
+        Ok(Torrent{name, piece_length, is_single_file, files, pieces, piece_layers})
+    }
+
+
+    /// Where `file` is, given the directory the torrent was downloaded
+    /// into.  Clients put multi-file torrents in a directory named after
+    /// the torrent, but `directory` can also be that directory itself (or
+    /// the file itself, for a single file torrent).
+    pub fn path_of(&self, directory: &str, file: &TorrentFile) -> PathBuf {
+        let directory = Path::new(directory);
+        if self.is_single_file {
+            if directory.is_file() {
+                return directory.to_owned();
+            }
+            return directory.join(&self.name);
+        }
+        let top = directory.join(&self.name);
+        let top = if top.is_dir() { top } else { directory.to_owned() };
+        file.path.iter().fold(top, |path, component| path.join(component))
+    }
+}
+
+
+/// Read as much of `buffer` from `file` as there is
+fn read_fully(file: &mut File, buffer: &mut [u8]) -> Result<usize, Error> {
+    let mut num_bytes_read = 0;
+    while num_bytes_read < buffer.len() {
+        match file.read(&mut buffer[num_bytes_read..])? {
+            0 => break,
+            num_bytes => num_bytes_read += num_bytes,
+        }
+    }
+    Ok(num_bytes_read)
+}
+
+
+/// How each file did
+#[derive(Clone, Debug, Default)]
+struct FileResult {
+    comparison: BytesComparison,
+    num_bad_pieces: usize,
+    is_missing: bool,
+}
+
+
+/// Check every v1 piece.  Pieces run across file boundaries, so each
+/// piece's bytes agree or disagree in whichever files they came from.
+/// Missing or short files are read as zeros, but pieces that needed them
+/// never agree.
+fn verify_v1(torrent: &Torrent, pieces: &[u8], directory: &str,
+        results: &mut [FileResult]) -> Result<(), Error> {
+    let num_bytes = torrent.files.iter().map(|file| file.length).sum::<usize>();
+    if pieces.len() != num_bytes.div_ceil(torrent.piece_length) * 20 {
+        return Err(corrupt("the number of pieces doesn't match the lengths of the files"));
+    }
+
+    let mut hasher = sha1::Sha1::new();
+    let (mut piece_index, mut piece_fill, mut piece_incomplete) = (0, 0, false);
+    let mut contributions: Vec<(usize, usize)> = Vec::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut finish_piece = |hasher: &mut sha1::Sha1,
+            contributions: &mut Vec<(usize, usize)>, piece_incomplete: bool,
+            results: &mut [FileResult]| {
+        let expected = &pieces[piece_index * 20..piece_index * 20 + 20];
+        let agrees = !piece_incomplete && hasher.digest().bytes() == expected;
+        for (i, num_bytes) in contributions.drain(..) {
+            results[i].comparison += if agrees {
+                BytesComparison{agreement: num_bytes, ..Default::default()}
+            }
+            else {
+                BytesComparison{disagreement: num_bytes, ..Default::default()}
+            };
+            if !agrees {
+                results[i].num_bad_pieces += 1;
+            }
+        }
+        hasher.reset();
+        piece_index += 1;
+    };
+
+    for (i, file) in torrent.files.iter().enumerate() {
+        let mut opened = if file.is_padding {
+            None
+        }
+        else {
+            match File::open(torrent.path_of(directory, file)) {
+                Ok(opened) => Some(opened),
+                Err(_) => {
+                    results[i].is_missing = true;
+                    None
+                }
+            }
+        };
+        let mut remaining = file.length;
+        while remaining > 0 {
+            let wanted = cmp::min(cmp::min(remaining, buffer.len()),
+                    torrent.piece_length - piece_fill);
+            let num_bytes_read = match opened {
+                Some(ref mut opened) => read_fully(opened, &mut buffer[..wanted])?,
+                None => 0,
+            };
+            if num_bytes_read < wanted {
+                buffer[num_bytes_read..wanted].iter_mut().for_each(|byte| *byte = 0);
+                if !file.is_padding {
+                    piece_incomplete = true;
+                }
+            }
+            hasher.update(&buffer[..wanted]);
+            if !file.is_padding {
+                match contributions.last_mut() {
+                    Some((j, num_bytes)) if *j == i => *num_bytes += wanted,
+                    _ => contributions.push((i, wanted)),
+                }
+            }
+            piece_fill += wanted;
+            remaining -= wanted;
+            if piece_fill == torrent.piece_length {
+                finish_piece(&mut hasher, &mut contributions, piece_incomplete,
+                        results);
+                piece_fill = 0;
+                piece_incomplete = false;
+            }
+        }
+    }
+    if piece_fill > 0 {
+        finish_piece(&mut hasher, &mut contributions, piece_incomplete, results);
+    }
+    Ok(())
+}
+
+
+/// The root of a merkle tree of sha256s with `num_leaves` leaves (a power
+/// of two), the ones past the end of `layer` being zeros
+fn merkle_root(mut layer: Vec<[u8; 32]>, num_leaves: usize) -> [u8; 32] {
+    layer.resize(num_leaves, [0; 32]);
+    while layer.len() > 1 {
+        layer = layer.chunks(2).map(|pair| {
+            let mut hasher = sha2::Sha256::new();
+            hasher.update(pair[0]);
+            hasher.update(pair[1]);
+            hasher.finalize().into()
+        }).collect();
+    }
+    layer[0]
+}
+
+
+/// Piece layers aren't in the info dictionary, so the info hash doesn't
+/// vouch for them: `layer` is only any use if it hashes up to
+/// `pieces_root`.  Past the end of the file, pieces are of all-zero
+/// leaves.
+fn check_piece_layer(layer: &[u8], pieces_root: &[u8], num_pieces: usize,
+        blocks_per_piece: usize) -> Result<(), Error> {
+    if layer.len() != num_pieces * 32 {
+        return Err(corrupt("piece layer is the wrong length"));
+    }
+    let mut hashes = layer.chunks(32)
+            .map(|hash| <[u8; 32]>::try_from(hash).unwrap())
+            .collect::<Vec<_>>();
+    let num_leaves = num_pieces.next_power_of_two();
+    hashes.resize(num_leaves, merkle_root(Vec::new(), blocks_per_piece));
+    if merkle_root(hashes, num_leaves)[..] != pieces_root[..] {
+        return Err(corrupt("piece layer doesn't match its pieces root"));
+    }
+    Ok(())
+}
+
+
+/// Check one file of a v2 torrent, piece by piece if it's bigger than a
+/// piece (against the piece layers) or as a whole (against its pieces
+/// root) if not
+fn verify_v2_file(torrent: &Torrent, file: &TorrentFile, path: &Path,
+        result: &mut FileResult) -> Result<(), Error> {
+    if file.length == 0 {
+        return Ok(());
+    }
+    let pieces_root = file.pieces_root.as_ref()
+            .ok_or_else(|| corrupt("file without a pieces root"))?;
+    let mut opened = match File::open(path) {
+        Ok(opened) => opened,
+        Err(_) => {
+            result.is_missing = true;
+            result.comparison += BytesComparison{disagreement: file.length,
+                    ..Default::default()};
+            return Ok(());
+        }
+    };
+    let blocks_per_piece = cmp::max(torrent.piece_length / BLOCK_SIZE, 1);
+    let layer = if file.length > torrent.piece_length {
+        let layer = torrent.piece_layers.get(pieces_root)
+                .ok_or_else(|| corrupt("no piece layer for a file bigger than a piece"))?;
+        check_piece_layer(layer, pieces_root, file.length.div_ceil(torrent.piece_length),
+                blocks_per_piece)?;
+        Some(layer)
+    }
+    else {
+        None
+    };
+
+    let mut buffer = vec![0; BLOCK_SIZE];
+    let mut remaining = file.length;
+    let mut piece_index = 0;
+    while remaining > 0 {
+        let piece_size = cmp::min(remaining, torrent.piece_length);
+        let mut leaves = Vec::new();
+        let mut is_short = false;
+        let mut num_bytes_left = piece_size;
+        while num_bytes_left > 0 {
+            let wanted = cmp::min(num_bytes_left, BLOCK_SIZE);
+            if read_fully(&mut opened, &mut buffer[..wanted])? < wanted {
+                is_short = true;
+            }
+            leaves.push(sha2::Sha256::digest(&buffer[..wanted]).into());
+            num_bytes_left -= wanted;
+        }
+
+        let agrees = !is_short && match layer {
+            Some(layer) => {
+                layer.get(piece_index * 32..piece_index * 32 + 32) ==
+                        Some(&merkle_root(leaves, blocks_per_piece)[..])
+            },
+            None => {
+                let num_leaves = leaves.len().next_power_of_two();
+                merkle_root(leaves, num_leaves)[..] == pieces_root[..]
+            }
+        };
+        result.comparison += if agrees {
+            BytesComparison{agreement: piece_size, ..Default::default()}
+        }
+        else {
+            result.num_bad_pieces += 1;
+            BytesComparison{disagreement: piece_size, ..Default::default()}
+        };
+        remaining -= piece_size;
+        piece_index += 1;
+    }
+    Ok(())
+}
+
+
+/// Check the files of the torrent `torrent_filename` under `directory`
+/// (see `Torrent::path_of`) against its piece hashes, all offline.
+/// Writes out every file that disagrees (and, if `num_vs > 0`, every
+/// one that agrees) and the totals.
+///
+/// Returns each file's path and what agreed and disagreed in it.
+pub fn verify_torrent(torrent_filename: &str, directory: &str,
+        mut writable: impl Write, num_vs: u8)
+                -> Result<Vec<(String, BytesComparison)>, Error> {
+    let torrent = Torrent::read(torrent_filename)?;
+    if num_vs > 1 {
+        writeln!(writable, "{} is v{} with {} files and {} byte pieces",
+                torrent_filename, if torrent.pieces.is_some() { 1 } else { 2 },
+                torrent.files.len(), torrent.piece_length)?;
+    }
+
+    let mut results = vec![FileResult::default(); torrent.files.len()];
+    match torrent.pieces {
+        Some(ref pieces) => verify_v1(&torrent, pieces, directory, &mut results)?,
+        None => {
+            for (file, result) in torrent.files.iter().zip(results.iter_mut()) {
+                verify_v2_file(&torrent, file, &torrent.path_of(directory, file),
+                        result)?;
+            }
+        }
+    }
+
+    let mut to_return = Vec::new();
+    let mut total = BytesComparison::default();
+    for (file, result) in torrent.files.iter().zip(results) {
+        if file.is_padding {
+            continue;
+        }
+        let path = torrent.path_of(directory, file);
+        let mut comparison = result.comparison;
+
+        /* Only `length` bytes are read, so anything past them is only
+         * noticed here */
+        let size = fs::metadata(&path).map(|metadata| metadata.len() as usize);
+        if result.is_missing {
+            writeln!(writable, "Disagreement ({} bytes): {} is missing.",
+                    file.length, path.display())?;
+        }
+        else if let Some(size) = size.ok().filter(|size| *size != file.length) {
+            comparison = BytesComparison{disagreement: cmp::max(size, file.length),
+                    ..Default::default()};
+            writeln!(writable, "Disagreement ({} bytes): {} is {} bytes, not {}.",
+                    comparison.disagreement, path.display(), size, file.length)?;
+        }
+        else if comparison.disagrees() {
+            writeln!(writable, "Disagreement ({} bytes): {} doesn't match {} pieces.",
+                    comparison.disagreement, path.display(),
+                    result.num_bad_pieces)?;
+        }
+        else if num_vs > 0 {
+            writeln!(writable, "{}: agreed on {}/{} bytes", path.display(),
+                    comparison.agreement, file.length)?;
+        }
+        total += comparison;
+        to_return.push((if torrent.is_single_file { torrent.name.clone() } else { file.path_string() },
+                comparison));
+    }
+
+    let num_bytes = total.agreement + total.disagreement;
+    total.write_summary(&mut writable, num_bytes)?;
+    Ok(to_return)
+}
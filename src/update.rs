@@ -0,0 +1,198 @@
+use crate::cache::HashCache;
+use crate::filter::Filter;
+use crate::hash_of_path;
+use crate::hash_path_entry;
+use crate::manifest::read_manifest;
+use crate::manifest::EntryKind;
+use crate::manifest::FileStat;
+use crate::manifest::ManifestEntry;
+use crate::merkle::TreeHasher;
+use crate::output_progress;
+use crate::relative_path_string;
+use crate::special;
+use crate::BytesComparison;
+use indicatif::ProgressBar;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::io::Write;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+
+/// Write out a fresh manifest of `directory` based on `old_manifest`,
+/// only hashing files whose size, mtime or inode changed since then.
+///
+/// So that bit rot isn't missed forever, a random `rehash_fraction` of
+/// the unchanged files are hashed anyway.  One whose contents changed
+/// even though nothing else did is reported as a disagreement, and keeps
+/// its old hash in the new manifest so later verifications still flag it.
+///
+/// Files can only be seen to be unchanged if the old manifest has mtime
+/// and inode columns, so the new one gets them if the old one had them or
+/// `stat_columns` is set; `hash_path` only writes them when asked to.
+/// Without them every file is hashed again, which is said on stderr.
+///
+/// What changed is reported on stderr, since the new manifest may well be
+/// going to stdout.  Tree hashes are recomputed if the old manifest had
+/// them.
+#[allow(clippy::too_many_arguments)]
+pub fn update_manifest(old_manifest: &str, directory: &str,
+        mut writable: impl Write, num_vs: u8, progress: bool, filter: &Filter,
+        ignore_perm_errors_flag: bool, rehash_fraction: f64, stat_columns: bool,
+        cache: &Option<HashCache>) -> Result<BytesComparison, Error> {
+    let (old_entries, num_bytes_before) = read_manifest(old_manifest)?;
+    let had_stat_columns = old_entries.iter().any(|entry| entry.stat.is_some());
+    if !had_stat_columns && old_entries.iter().any(|entry| {
+        matches!(entry.kind, EntryKind::File(_))
+    }) {
+        eprintln!("{} has no mtime or inode columns, so every file will be hashed again{}.",
+                old_manifest, if stat_columns { "" }
+                else { " (use --stat-columns so the next update won't have to)" });
+    }
+    let stat_columns = stat_columns || had_stat_columns;
+    let mut tree_hasher = if old_entries.iter().any(|entry| {
+        matches!(entry.kind, EntryKind::Directory(_))
+    }) {
+        Some(TreeHasher::default())
+    }
+    else {
+        None
+    };
+    let mut old_by_path = old_entries.into_iter()
+            .filter(|entry| !matches!(entry.kind, EntryKind::Directory(_)))
+            .map(|entry| (entry.path.clone(), entry))
+            .collect::<HashMap<_, _>>();
+    let progress_bar = if progress {
+        Some(ProgressBar::new(num_bytes_before as u64))
+    }
+    else {
+        None
+    };
+
+    let mut random = XorShift::seeded();
+    let mut to_return = BytesComparison::default();
+    let mut num_bytes = 0;
+    let (mut num_reused, mut num_changed, mut num_added,
+            mut num_rehashed) = (0, 0, 0, 0);
+    for entry in filter.walk_entries(directory, ignore_perm_errors_flag) {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = relative_path_string(path, directory)?;
+        let old_entry = old_by_path.remove(&relative);
+
+        /* Reuse the old hash if nothing about the file changed */
+        let mut manifest_entry = None;
+        if path.is_file() && special::special_kind_of(path).is_none() {
+            let metadata = fs::metadata(path)?;
+            let stat = FileStat::of(&metadata);
+            let size = metadata.len() as usize;
+            if let Some(old_entry) = old_entry.as_ref().filter(|old_entry| {
+                matches!(old_entry.kind, EntryKind::File(_)) &&
+                        old_entry.stat == Some(stat) && old_entry.size == size
+            }) {
+                if random.next_f64() < rehash_fraction {
+                    num_rehashed += 1;
+                    let (cur_hash, num_bytes_hashed) = hash_of_path(path)?;
+                    if old_entry.kind == EntryKind::File(cur_hash) {
+                        to_return += BytesComparison{agreement: num_bytes_hashed,
+                                ..Default::default()};
+                    }
+                    else {
+                        eprintln!("Disagreement ({} bytes): {} changed without its size, mtime or inode changing.",
+                                size, path.display());
+                        to_return += BytesComparison{disagreement: size,
+                                ..Default::default()};
+                    }
+                }
+                else if num_vs > 1 {
+                    eprintln!("Unchanged {}", path.display());
+                }
+                num_reused += 1;
+                output_progress(size as u64, &progress_bar);
+                writeln!(writable, "{}", old_entry.to_line())?;
+                manifest_entry = Some(old_entry.clone());
+            }
+        }
+
+        if manifest_entry.is_none() {
+            manifest_entry = hash_path_entry(path, directory, &mut writable,
+                    num_vs, &progress_bar, cache, stat_columns)?;
+            if let Some(EntryKind::File(_)) = manifest_entry.as_ref()
+                    .map(|manifest_entry| &manifest_entry.kind) {
+                if old_entry.is_some() {
+                    num_changed += 1;
+                    if num_vs > 0 {
+                        eprintln!("Changed {}", path.display());
+                    }
+                }
+                else {
+                    num_added += 1;
+                    if num_vs > 0 {
+                        eprintln!("Added {}", path.display());
+                    }
+                }
+            }
+        }
+
+        if let Some(ref manifest_entry) = manifest_entry {
+            num_bytes += manifest_entry.size;
+        }
+        if let Some(ref mut tree_hasher) = tree_hasher {
+            if let Some(ref manifest_entry) = manifest_entry {
+                tree_hasher.add_entry(manifest_entry);
+            }
+            else if entry.file_type().is_dir() {
+                tree_hasher.add_directory(&relative);
+            }
+        }
+    }
+
+    let mut removed = old_by_path.into_values().collect::<Vec<ManifestEntry>>();
+    removed.sort_by(|a, b| a.path.cmp(&b.path));
+    for old_entry in &removed {
+        if num_vs > 0 {
+            eprintln!("Removed {}", old_entry.path);
+        }
+    }
+
+    if let Some(tree_hasher) = tree_hasher {
+        for directory_entry in tree_hasher.finish() {
+            writeln!(writable, "{}", directory_entry.to_line())?;
+        }
+    }
+    writeln!(writable, "{} bytes hashed", num_bytes)?;
+
+    eprintln!("Reused {} hashes, rehashed {} changed files, added {}, removed {}.",
+            num_reused, num_changed, num_added, removed.len());
+    if num_rehashed > 0 {
+        eprintln!("Spot checked {} unchanged files: {} bytes agreed, {} bytes disagreed.",
+                num_rehashed, to_return.agreement, to_return.disagreement);
+    }
+
+    Ok(to_return)
+}
+
+
+/// Just enough randomness to pick files to spot check
+struct XorShift {
+    state: u64,
+}
+
+
+impl XorShift {
+    fn seeded() -> XorShift {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64).unwrap_or(0);
+        XorShift{state: (nanos ^ ((std::process::id() as u64) << 32)) | 1}
+    }
+
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,153 @@
+use crate::cache::HashCache;
+use crate::filter::Filter;
+use crate::hash_path_entry;
+use crate::manifest::read_manifest;
+use crate::manifest::EntryKind;
+use crate::manifest::ManifestEntry;
+use crate::percent_of;
+use crate::BytesComparison;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::Error;
+use std::io::Write;
+use std::path::Path;
+
+
+/// Every entry of a replica, which is either a directory (hashed now,
+/// through `filter`) or a manifest written by `hash_path` earlier
+pub fn entries_of(replica: &str, filter: &Filter, ignore_perm_errors_flag: bool,
+        cache: &Option<HashCache>) -> Result<Vec<ManifestEntry>, Error> {
+    if !Path::new(replica).is_dir() {
+        let (entries, _) = read_manifest(replica)?;
+        return Ok(entries.into_iter().filter(|entry| {
+            !matches!(entry.kind, EntryKind::Directory(_))
+        }).collect());
+    }
+
+    let mut to_return = Vec::new();
+    for entry in filter.walk_entries(replica, ignore_perm_errors_flag) {
+        if let Some(manifest_entry) = hash_path_entry(entry?.path(),
+                replica, &mut std::io::sink(), 0, &None, cache, false)? {
+            to_return.push(manifest_entry);
+        }
+    }
+    Ok(to_return)
+}
+
+
+/// What one replica has at a path: its tag, hash and size, or nothing
+type Contents = Option<(&'static str, String, usize)>;
+
+
+/// Compare three or more replicas path by path.  Wherever they don't all
+/// agree, replicas are grouped by what they have there, and if more than
+/// half of them agree, the rest are named as the likely corrupt ones.
+/// Without a majority, every replica disagrees.  Two replicas can never
+/// outvote each other, so fewer than three is an error.
+///
+/// Returns what agreed and disagreed in each replica, in order.
+pub fn vote(replicas: &[Vec<ManifestEntry>], names: &[&str],
+        mut writable: impl Write, num_vs: u8) -> Result<Vec<BytesComparison>, Error> {
+    let num_replicas = replicas.len();
+    if num_replicas < 3 {
+        return Err(Error::other(format!(
+                "Voting takes at least three replicas, not {}", num_replicas)));
+    }
+    let mut by_path: BTreeMap<&str, Vec<Contents>> = BTreeMap::new();
+    for (i, entries) in replicas.iter().enumerate() {
+        for entry in entries {
+            let (tag, value) = entry.tag_and_value();
+            by_path.entry(entry.path.as_str())
+                    .or_insert_with(|| vec![None; num_replicas])[i] =
+                    Some((tag, value, entry.size));
+        }
+    }
+
+    let mut to_return = vec![BytesComparison::default(); num_replicas];
+    let mut num_unanimous = 0;
+    for (path, contents) in &by_path {
+
+        /* Group replicas by what they have, biggest group first */
+        let mut groups: Vec<(&Contents, Vec<usize>)> = Vec::new();
+        for (i, content) in contents.iter().enumerate() {
+            match groups.iter_mut().find(|(other, _)| *other == content) {
+                Some((_, members)) => members.push(i),
+                None => groups.push((content, vec![i])),
+            }
+        }
+        groups.sort_by_key(|(_, members)| cmp::Reverse(members.len()));
+        let size_of = |content: &Contents| {
+            content.as_ref().map_or(0, |(_, _, size)| *size)
+        };
+
+        if groups.len() == 1 {
+            num_unanimous += 1;
+            for comparison in to_return.iter_mut() {
+                *comparison += BytesComparison{agreement: size_of(&contents[0]),
+                        ..Default::default()};
+            }
+            continue;
+        }
+
+        let (majority, members) = &groups[0];
+        if members.len() * 2 <= num_replicas {
+            let groups_s = groups.iter().map(|(_, members)| {
+                members.iter().map(|i| names[*i]).collect::<Vec<_>>().join(", ")
+            }).collect::<Vec<_>>().join(" | ");
+            writeln!(writable, "No majority for {}: {}", path, groups_s)?;
+            for (i, comparison) in to_return.iter_mut().enumerate() {
+                let size = size_of(&contents[i]);
+                *comparison += if size == 0 {
+                    BytesComparison{entry_disagreement: 1, ..Default::default()}
+                }
+                else {
+                    BytesComparison{disagreement: size, ..Default::default()}
+                };
+            }
+            continue;
+        }
+
+        if num_vs > 1 {
+            writeln!(writable, "{} of {} replicas agree on {}", members.len(),
+                    num_replicas, path)?;
+        }
+        for (i, comparison) in to_return.iter_mut().enumerate() {
+            if members.contains(&i) {
+                *comparison += BytesComparison{agreement: size_of(majority),
+                        ..Default::default()};
+                continue;
+            }
+            let size = cmp::max(size_of(majority), size_of(&contents[i]));
+            match (majority, &contents[i]) {
+                (Some(_), None) => {
+                    writeln!(writable, "Missing ({} bytes): {} in {} ({} of {} replicas have it)",
+                            size, path, names[i], members.len(), num_replicas)?;
+                },
+                (None, Some(_)) => {
+                    writeln!(writable, "Extra ({} bytes): {} in {} ({} of {} replicas don't have it)",
+                            size, path, names[i], members.len(), num_replicas)?;
+                },
+                _ => {
+                    writeln!(writable, "Likely corrupt ({} bytes): {} in {} ({} of {} replicas agree on something else)",
+                            size, path, names[i], members.len(), num_replicas)?;
+                }
+            }
+            *comparison += if size == 0 {
+                BytesComparison{entry_disagreement: 1, ..Default::default()}
+            }
+            else {
+                BytesComparison{disagreement: size, ..Default::default()}
+            };
+        }
+    }
+
+    writeln!(writable, "{} of {} paths agree in every replica.", num_unanimous,
+            by_path.len())?;
+    for (name, comparison) in names.iter().zip(&to_return) {
+        let num_bytes = comparison.agreement + comparison.disagreement;
+        writeln!(writable, "{}: agreed on {}/{} bytes ({}% confidence)", name,
+                comparison.agreement, num_bytes,
+                percent_of(comparison.agreement, num_bytes))?;
+    }
+    Ok(to_return)
+}
@@ -0,0 +1,239 @@
+#!/usr/bin/env python3
+"""Make the disk images the integration tests read.
+
+The ISO9660 images (Rock Ridge, Joliet and plain) are written by bsdtar and
+gzipped to keep them small.  The SquashFS image is written here, so it can
+have a bit of everything (compressed, uncompressed, sparse and fragmented
+blocks, an extended file inode, a FIFO, a device and a symlink) without
+needing root or squashfs-tools.
+
+Run from anywhere; the images are written next to this script.
+"""
+
+import gzip
+import os
+import shutil
+import struct
+import subprocess
+import tempfile
+import zlib
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+# Path -> contents.  The tests make the same tree to compare with.
+FILES = {
+    "a": b"abc",
+    "empty": b"",
+    "dir/blob": b"0123456789" * 500,
+    "dir/zeros": b"\0" * 8292,
+    "dir/raw": bytes(range(256)) * 16 + b"0123456789",
+    "dir/deeper/Mixed Case name.txt": b"hello",
+}
+
+# Only Rock Ridge (which relocates deep directories) and SquashFS images
+# have these, and the FIFO
+DEEP_FILES = {
+    "d1/d2/d3/d4/d5/d6/d7/d8/d9/deep": b"deep",
+}
+
+
+def make_tree(top, files, fifo):
+    for path, contents in files.items():
+        os.makedirs(os.path.dirname(os.path.join(top, path)), exist_ok=True)
+        with open(os.path.join(top, path), "wb") as f:
+            f.write(contents)
+    if fifo:
+        os.mkfifo(os.path.join(top, "fifo"))
+
+
+def make_isos():
+    for name, options in [("rockridge", "!pad"),
+            ("joliet", "!pad,!rockridge"),
+            ("plain", "!pad,!rockridge,!joliet")]:
+        with tempfile.TemporaryDirectory() as top:
+            if name == "rockridge":
+                make_tree(top, {**FILES, **DEEP_FILES}, True)
+            else:
+                make_tree(top, FILES, False)
+            iso = os.path.join(HERE, "tree-" + name + ".iso")
+            subprocess.run(["bsdtar", "--format", "iso9660", "--options",
+                    "iso9660:" + options, "-cf", iso, "-C", top, "."],
+                    check=True)
+            with open(iso, "rb") as f, \
+                    gzip.GzipFile(iso + ".gz", "wb", mtime=0) as g:
+                shutil.copyfileobj(f, g)
+            os.remove(iso)
+
+
+BLOCK_SIZE = 4096
+NO_FRAGMENT = 0xFFFFFFFF
+UNCOMPRESSED_BLOCK = 1 << 24
+UNCOMPRESSED_METADATA = 1 << 15
+NONE = 0xFFFFFFFFFFFFFFFF
+
+
+def metadata_block(data, compress=True):
+    """One metadata block (at most 8 KiB of data) with its header"""
+    assert len(data) <= 8192
+    compressed = zlib.compress(data, 9)
+    if compress and len(compressed) < len(data):
+        return struct.pack("<H", len(compressed)) + compressed
+    return struct.pack("<H", len(data) | UNCOMPRESSED_METADATA) + data
+
+
+def make_squashfs():
+    files = {**FILES, **DEEP_FILES}
+    image = bytearray(96)
+    fragment = bytearray()
+    inodes = bytearray()
+    directories = bytearray()
+
+    def inode_header(kind, mode, number):
+        return struct.pack("<HHHHII", kind, mode, 0, 0, 0, number)
+
+    # Data blocks.  dir/raw's first block is stored uncompressed and it has
+    # no fragment, dir/zeros is all sparse blocks and gets an extended
+    # inode, and the ends of everything else share a fragment.
+    def add_file(path, contents):
+        blocks_start = len(image)
+        sizes = []
+        use_fragment = path != "dir/raw"
+        num_blocks = len(contents) // BLOCK_SIZE
+        if not use_fragment and len(contents) % BLOCK_SIZE:
+            num_blocks += 1
+        for i in range(num_blocks):
+            block = contents[i * BLOCK_SIZE:(i + 1) * BLOCK_SIZE]
+            if block.count(0) == len(block):
+                sizes.append(0)
+            elif path == "dir/raw" and i == 0:
+                image.extend(block)
+                sizes.append(len(block) | UNCOMPRESSED_BLOCK)
+            else:
+                compressed = zlib.compress(block, 9)
+                image.extend(compressed)
+                sizes.append(len(compressed))
+        tail = contents[num_blocks * BLOCK_SIZE:]
+        if tail:
+            fragment_offset = len(fragment)
+            fragment.extend(tail)
+            return blocks_start, sizes, 0, fragment_offset
+        return blocks_start, sizes, NO_FRAGMENT, 0
+
+    numbers = {}
+
+    def number_of(path):
+        if path not in numbers:
+            numbers[path] = len(numbers) + 1
+        return numbers[path]
+
+    # Everything, as a tree of dicts, with leaves of ("file", contents),
+    # ("fifo",), ("chr", major, minor) or ("symlink", target)
+    tree = {}
+    for path, contents in files.items():
+        *parents, name = path.split("/")
+        cur = tree
+        for parent in parents:
+            cur = cur.setdefault(parent, {})
+        cur[name] = ("file", contents)
+    tree["fifo"] = ("fifo",)
+    tree["null"] = ("chr", 1, 3)
+    tree["link"] = ("symlink", b"a")
+
+    placed = {}
+    for path, contents in files.items():
+        placed[path] = add_file(path, contents)
+    fragment_start = len(image)
+    compressed = zlib.compress(bytes(fragment), 9)
+    image.extend(compressed)
+    fragment_entry = struct.pack("<QII", fragment_start, len(compressed), 0)
+
+    def add_inode(data):
+        offset = len(inodes)
+        inodes.extend(data)
+        return offset
+
+    # Children first, so a directory knows where they are
+    def add_directory(path, children):
+        entries = []
+        for name in sorted(children):
+            child = children[name]
+            child_path = path + "/" + name if path else name
+            number = number_of(child_path)
+            if isinstance(child, dict):
+                offset = add_directory(child_path, child)
+                kind = 1
+            elif child[0] == "file":
+                blocks_start, sizes, fragment_index, fragment_offset = \
+                        placed[child_path]
+                contents = child[1]
+                if child_path == "dir/zeros":
+                    kind = 2
+                    offset = add_inode(inode_header(9, 0o644, number) +
+                            struct.pack("<QQQIIII", blocks_start,
+                                    len(contents), 2 * BLOCK_SIZE, 1,
+                                    fragment_index, fragment_offset,
+                                    0xFFFFFFFF) +
+                            struct.pack("<%dI" % len(sizes), *sizes))
+                else:
+                    kind = 2
+                    offset = add_inode(inode_header(2, 0o644, number) +
+                            struct.pack("<IIII", blocks_start,
+                                    fragment_index, fragment_offset,
+                                    len(contents)) +
+                            struct.pack("<%dI" % len(sizes), *sizes))
+            elif child[0] == "fifo":
+                kind = 6
+                offset = add_inode(inode_header(6, 0o644, number) +
+                        struct.pack("<I", 1))
+            elif child[0] == "chr":
+                kind = 5
+                rdev = (child[1] << 8) | child[2]
+                offset = add_inode(inode_header(5, 0o666, number) +
+                        struct.pack("<II", 1, rdev))
+            else:
+                kind = 3
+                offset = add_inode(inode_header(3, 0o777, number) +
+                        struct.pack("<II", 1, len(child[1])) + child[1])
+            entries.append((name.encode(), offset, number, kind))
+
+        listing = bytearray()
+        if entries:
+            base = min(number for _, _, number, _ in entries)
+            listing.extend(struct.pack("<III", len(entries) - 1, 0, base))
+            for name, offset, number, kind in entries:
+                listing.extend(struct.pack("<HhHH", offset, number - base,
+                        kind, len(name) - 1) + name)
+        listing_offset = len(directories)
+        directories.extend(listing)
+        return add_inode(inode_header(1, 0o755, number_of(path)) +
+                struct.pack("<IIHHI", 0, 2, len(listing) + 3,
+                        listing_offset, len(numbers) + 2))
+
+    root_offset = add_directory("", tree)
+
+    inode_table_start = len(image)
+    image.extend(metadata_block(bytes(inodes)))
+    directory_table_start = len(image)
+    image.extend(metadata_block(bytes(directories), compress=False))
+    fragment_block = len(image)
+    image.extend(metadata_block(fragment_entry))
+    fragment_table_start = len(image)
+    image.extend(struct.pack("<Q", fragment_block))
+    id_block = len(image)
+    image.extend(metadata_block(struct.pack("<I", 0)))
+    id_table_start = len(image)
+    image.extend(struct.pack("<Q", id_block))
+    bytes_used = len(image)
+
+    image[:96] = struct.pack("<IIIIIHHHHHHQQQQQQQQ", 0x73717368,
+            len(numbers), 0, BLOCK_SIZE, 1, 1, 12, 0x0200, 1, 4, 0,
+            root_offset, bytes_used, id_table_start, NONE, inode_table_start,
+            directory_table_start, fragment_table_start, NONE)
+    image.extend(b"\0" * (-len(image) % 4096))
+    with open(os.path.join(HERE, "tree.sqfs"), "wb") as f:
+        f.write(image)
+
+
+if __name__ == "__main__":
+    make_isos()
+    make_squashfs()
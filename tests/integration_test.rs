@@ -1,4 +1,7 @@
+use confidence::filter::Filter;
+use confidence::runtime_with_options;
 use confidence::runtime_with_regular_args;
+use confidence::RuntimeOptions;
 
 #[test]
 fn test_dir_0() {
@@ -9,30 +12,1683 @@ fn test_dir_0() {
 
     // TODO Remove sentinel files before test.  They're only there because
     // empty directories can't be versioned.
-    assert_eq!(std::str::from_utf8(&stdout).unwrap(), r###"Compare tests/test_dir_0/a/b/c/r to tests/test_dir_1/a/b/c/r
+    assert_eq!(std::str::from_utf8(&stdout).unwrap(), r###"Compare tests/test_dir_0/a/b/c/d/e/f/g/h/i/j/sentinel to tests/test_dir_1/a/b/c/d/e/f/g/h/i/j/sentinel
+Successfully compared 0 bytes
+Compare tests/test_dir_0/a/b/c/r to tests/test_dir_1/a/b/c/r
 Successfully compared 2 bytes
 Compare tests/test_dir_0/a/b/c/s to tests/test_dir_1/a/b/c/s
 Successfully compared 2 bytes
-Compare tests/test_dir_0/a/b/c/d/e/f/g/h/i/j/sentinel to tests/test_dir_1/a/b/c/d/e/f/g/h/i/j/sentinel
+Compare tests/test_dir_0/k/l/m/n/sentinel to tests/test_dir_1/k/l/m/n/sentinel
 Successfully compared 0 bytes
-Compare tests/test_dir_0/k/t to tests/test_dir_1/k/t
-Successfully compared 2 bytes
 Compare tests/test_dir_0/k/l/m/u to tests/test_dir_1/k/l/m/u
 Successfully compared 2 bytes
+Compare tests/test_dir_0/k/l/m/v to tests/test_dir_1/k/l/m/v
+Successfully compared 2 bytes
 Compare tests/test_dir_0/k/l/m/w to tests/test_dir_1/k/l/m/w
 Successfully compared 2 bytes
-Compare tests/test_dir_0/k/l/m/v to tests/test_dir_1/k/l/m/v
+Compare tests/test_dir_0/k/t to tests/test_dir_1/k/t
 Successfully compared 2 bytes
-Compare tests/test_dir_0/k/l/m/n/sentinel to tests/test_dir_1/k/l/m/n/sentinel
-Successfully compared 0 bytes
-Compare tests/test_dir_0/y to tests/test_dir_1/y
+Compare tests/test_dir_0/o/p/q/z to tests/test_dir_1/o/p/q/z
 Successfully compared 2 bytes
 Compare tests/test_dir_0/o/x to tests/test_dir_1/o/x
 Successfully compared 3 bytes
-Compare tests/test_dir_0/o/p/q/z to tests/test_dir_1/o/p/q/z
+Compare tests/test_dir_0/y to tests/test_dir_1/y
 Successfully compared 2 bytes
 19 of 19 bytes agree.  (100% confidence)
 The last writeln will be ignored for no reason I understand.
 0 bytes disagree.  (0% worry)
 "###);
 }
+
+
+/// The mtime and inode columns of a manifest line for `path`
+fn stat_columns(path: &std::path::Path) -> String {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::metadata(path).unwrap();
+    format!("{}.{:09} {}", metadata.mtime(), metadata.mtime_nsec(),
+            metadata.ino())
+}
+
+
+fn make_fifo(path: &std::path::Path) {
+    let path_c = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path_c.as_ptr(), 0o644) }, 0);
+}
+
+
+#[test]
+fn special_files() {
+    let dir_l = tempfile::tempdir().unwrap();
+    let dir_r = tempfile::tempdir().unwrap();
+    std::fs::write(dir_l.path().join("f"), "ab").unwrap();
+    std::fs::write(dir_r.path().join("f"), "ab").unwrap();
+    make_fifo(&dir_l.path().join("p"));
+    std::fs::write(dir_r.path().join("p"), "").unwrap();
+    let filename_l = dir_l.path().to_str().unwrap();
+    let filename_r = dir_r.path().to_str().unwrap();
+
+    /* The FIFO is recorded, never opened (which would hang) */
+    let mut manifest = Vec::new();
+    let result = runtime_with_options(filename_l, None, None, &mut manifest,
+            &mut RuntimeOptions{stat_columns: true, ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+    assert_eq!(std::str::from_utf8(&manifest).unwrap(), format!(
+            "sha1: da23614e02469a0d7c7bd1bdab5c9c474b1904dc {} 2 {}\nfifo: - {} 0\n2 bytes hashed\n",
+            base64::encode("f"), stat_columns(&dir_l.path().join("f")),
+            base64::encode("p")));
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(manifest_file.path(), &manifest).unwrap();
+    let manifest_filename = manifest_file.path().to_str().unwrap();
+
+    let mut stdout = Vec::new();
+    let result = runtime_with_regular_args(false, None, filename_l, None,
+            Some(manifest_filename), &mut stdout, 0, false, false);
+    assert_eq!(result.unwrap(), 0);
+
+    let mut stdout = Vec::new();
+    let result = runtime_with_regular_args(false, None, filename_r, None,
+            Some(manifest_filename), &mut stdout, 0, false, false);
+    assert_eq!(result.unwrap(), 1);
+    assert!(std::str::from_utf8(&stdout).unwrap().contains(
+            &("is a FIFO and ".to_owned() + filename_r + "/p is a regular file.")));
+
+    let mut stdout = Vec::new();
+    let result = runtime_with_regular_args(false, None, filename_l,
+            Some(filename_r), None, &mut stdout, 0, false, false);
+    assert_eq!(result.unwrap(), 1);
+    assert!(std::str::from_utf8(&stdout).unwrap().contains(
+            "1 special files disagree."));
+}
+
+
+#[test]
+fn filtered_walks() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("build")).unwrap();
+    std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join("a.txt"), "ab").unwrap();
+    std::fs::write(dir.path().join("b.log"), "ab").unwrap();
+    std::fs::write(dir.path().join("build/d.txt"), "ab").unwrap();
+    std::fs::write(dir.path().join("sub/c.txt"), "abcdef").unwrap();
+    let filename = dir.path().to_str().unwrap();
+
+    let mut filter = Filter::default();
+    filter.excludes = confidence::filter::glob_set(&["*.log", "build"]).unwrap();
+    filter.min_size = Some(3);
+    let mut manifest = Vec::new();
+    let result = runtime_with_options(filename, None, None, &mut manifest,
+            &mut RuntimeOptions{filter: filter.clone(), ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+    assert_eq!(std::str::from_utf8(&manifest).unwrap(), format!(
+            "sha1: 1f8ac10f23c5b5bc1167bda84b833e5c057a77d2 {} 6\n6 bytes hashed\n",
+            base64::encode("sub/c.txt")));
+
+    /* Everything else is ignored when verifying with the same filter */
+    let mut full_manifest = Vec::new();
+    runtime_with_regular_args(false, None, filename, None, None,
+            &mut full_manifest, 0, false, false).unwrap();
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(manifest_file.path(), &full_manifest).unwrap();
+    std::fs::write(dir.path().join("build/d.txt"), "xy").unwrap();
+    std::fs::remove_file(dir.path().join("b.log")).unwrap();
+
+    let mut stdout = Vec::new();
+    let result = runtime_with_options(filename, None,
+            manifest_file.path().to_str(), &mut stdout,
+            &mut RuntimeOptions{filter: filter.clone(), ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+    assert!(std::str::from_utf8(&stdout).unwrap().starts_with(
+            "Agreed on 6/6 bytes (100% confidence)"));
+
+    let mut stdout = Vec::new();
+    let result = runtime_with_regular_args(false, None, filename, None,
+            manifest_file.path().to_str(), &mut stdout, 0, false, false);
+    assert_eq!(result.unwrap(), 1);
+
+    /* A total smaller than what's filtered out isn't a reason to panic */
+    let full_manifest = String::from_utf8(full_manifest).unwrap()
+            .replace("\n12 bytes hashed\n", "\n0 bytes hashed\n");
+    std::fs::write(manifest_file.path(), &full_manifest).unwrap();
+    let mut stdout = Vec::new();
+    let result = runtime_with_options(filename, None,
+            manifest_file.path().to_str(), &mut stdout,
+            &mut RuntimeOptions{filter: filter.clone(), ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+    assert!(std::str::from_utf8(&stdout).unwrap().starts_with(
+            "Agreed on 6/0 bytes (600% confidence)"));
+
+    /* Too big to represent is an error, not a panic */
+    assert!(confidence::filter::parse_size("99999999999T").is_err());
+    assert!(confidence::filter::parse_duration("999999999999999999w").is_err());
+    assert!(confidence::filter::parse_time("30000000000000w").is_err());
+    assert!(confidence::filter::parse_time("@99999999999999999999").is_err());
+    assert!(confidence::filter::parse_time("99999999999-01-01").is_err());
+}
+
+
+#[test]
+fn ignore_files() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("target/debug")).unwrap();
+    std::fs::create_dir_all(dir.path().join("sub")).unwrap();
+    std::fs::write(dir.path().join(".confidenceignore"),
+            "target/\n*.tmp\n").unwrap();
+    std::fs::write(dir.path().join("sub/.confidenceignore"),
+            "secret\n!keep.tmp\n").unwrap();
+    std::fs::write(dir.path().join("target/debug/out"), "ab").unwrap();
+    std::fs::write(dir.path().join("scratch.tmp"), "ab").unwrap();
+    std::fs::write(dir.path().join("sub/keep.tmp"), "ab").unwrap();
+    std::fs::write(dir.path().join("sub/secret"), "ab").unwrap();
+    let filename = dir.path().to_str().unwrap();
+
+    let mut filter = Filter::default();
+    filter.ignore_filenames.push(".confidenceignore".to_owned());
+    let mut manifest = Vec::new();
+    runtime_with_options(filename, None, None, &mut manifest,
+            &mut RuntimeOptions{filter: filter.clone(), ..Default::default()}).unwrap();
+    let paths = std::str::from_utf8(&manifest).unwrap().lines()
+            .filter(|line| line.starts_with("sha1:"))
+            .filter_map(|line| line.split_whitespace().nth(2))
+            .filter_map(|b64| confidence::path_string_from_b64(b64).ok())
+            .collect::<Vec<_>>();
+    assert_eq!(paths, vec![".confidenceignore", "sub/.confidenceignore",
+            "sub/keep.tmp"]);
+
+    /* Ignored files can change without the verification noticing */
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(manifest_file.path(), &manifest).unwrap();
+    std::fs::write(dir.path().join("target/debug/out"), "xyz").unwrap();
+    let mut stdout = Vec::new();
+    let result = runtime_with_options(filename, None,
+            manifest_file.path().to_str(), &mut stdout,
+            &mut RuntimeOptions{filter: filter.clone(), ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+}
+
+
+fn tree_hash_lines(filename: &str) -> Vec<String> {
+    let mut manifest = Vec::new();
+    let result = runtime_with_options(filename, None, None, &mut manifest,
+            &mut RuntimeOptions{tree_hashes: true, ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+    std::str::from_utf8(&manifest).unwrap().lines()
+            .filter(|line| line.starts_with("dir:"))
+            .map(|line| line.to_owned())
+            .collect()
+}
+
+
+#[test]
+fn tree_hashes() {
+    let lines_0 = tree_hash_lines("tests/test_dir_0");
+    let lines_1 = tree_hash_lines("tests/test_dir_1");
+    let lines_2 = tree_hash_lines("tests/test_dir_2");
+
+    /* Deepest first, with the whole tree's fingerprint last */
+    let root = lines_0.last().unwrap();
+    assert!(root.ends_with(&format!(" {} 19", base64::encode("."))));
+    assert_eq!(lines_0, lines_1);
+
+    /* test_dir_2 lacks the sentinels and has a different y, but o/ agrees */
+    assert_ne!(lines_0.last(), lines_2.last());
+    let o_line = format!(" {} 5", base64::encode("o"));
+    assert_eq!(lines_0.iter().find(|line| line.ends_with(&o_line)),
+            lines_2.iter().find(|line| line.ends_with(&o_line)));
+
+    /* The fingerprint is in the manifest, which still verifies */
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    let result = runtime_with_options("tests/test_dir_0", None, None,
+            std::fs::File::create(manifest_file.path()).unwrap(),
+            &mut RuntimeOptions{tree_hashes: true, ..Default::default()});
+    assert_eq!(result.unwrap(), 0);
+    let root_hash = root.split_whitespace().nth(1).unwrap();
+    assert!(std::fs::read_to_string(manifest_file.path()).unwrap().ends_with(
+            &format!("\nTree fingerprint: {}\n19 bytes hashed\n", root_hash)));
+    let result = runtime_with_regular_args(false, None, "tests/test_dir_1",
+            None, manifest_file.path().to_str(), &mut Vec::new(), 0, false,
+            false);
+    assert_eq!(result.unwrap(), 0);
+}
+
+
+fn write_manifest(filename: &str, tree_hashes: bool, stat_columns: bool)
+        -> tempfile::NamedTempFile {
+    let mut manifest = Vec::new();
+    runtime_with_options(filename, None, None, &mut manifest,
+            &mut RuntimeOptions{tree_hashes, stat_columns, ..Default::default()}).unwrap();
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(manifest_file.path(), &manifest).unwrap();
+    manifest_file
+}
+
+
+#[test]
+fn manifest_diff() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("same")).unwrap();
+    std::fs::create_dir_all(dir.path().join("changing")).unwrap();
+    std::fs::write(dir.path().join("same/a"), "aaaa").unwrap();
+    std::fs::write(dir.path().join("changing/b"), "bbb").unwrap();
+    std::fs::write(dir.path().join("changing/c"), "cc").unwrap();
+    std::fs::write(dir.path().join("changing/gone"), "g").unwrap();
+    let filename = dir.path().to_str().unwrap();
+    let old_manifest = write_manifest(filename, true, false);
+
+    std::fs::rename(dir.path().join("changing/b"),
+            dir.path().join("changing/b2")).unwrap();
+    std::fs::write(dir.path().join("changing/c"), "ccc").unwrap();
+    std::fs::remove_file(dir.path().join("changing/gone")).unwrap();
+    std::fs::write(dir.path().join("new"), "nnnnn").unwrap();
+    let new_manifest = write_manifest(filename, true, false);
+
+    let mut stdout = Vec::new();
+    let result = confidence::diff::manifest_diff(
+            old_manifest.path().to_str().unwrap(),
+            new_manifest.path().to_str().unwrap(), &mut stdout, 0).unwrap();
+    assert!(result.disagrees());
+    assert_eq!(std::str::from_utf8(&stdout).unwrap(), r###"Removed (1 bytes): changing/gone
+Added (5 bytes): new
+Modified (2 -> 3 bytes): changing/c
+Moved (3 bytes): changing/b -> changing/b2
+1 removed (1 bytes)
+1 added (5 bytes)
+1 modified (3 bytes)
+1 moved (3 bytes)
+4 bytes unchanged
+"###);
+
+    let mut stdout = Vec::new();
+    let result = confidence::diff::manifest_diff(
+            new_manifest.path().to_str().unwrap(),
+            new_manifest.path().to_str().unwrap(), &mut stdout, 0).unwrap();
+    assert!(!result.disagrees());
+}
+
+
+#[test]
+fn update_manifest() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("rotting"), "abcd").unwrap();
+    std::fs::write(dir.path().join("edited"), "abcd").unwrap();
+    let filename = dir.path().to_str().unwrap();
+    let old_manifest = write_manifest(filename, false, true);
+
+    /* Flip contents in place without the size or mtime changing */
+    let rotting = std::fs::OpenOptions::new().write(true)
+            .open(dir.path().join("rotting")).unwrap();
+    let mtime = rotting.metadata().unwrap().modified().unwrap();
+    std::fs::write(dir.path().join("rotting"), "abce").unwrap();
+    rotting.set_modified(mtime).unwrap();
+    std::fs::write(dir.path().join("edited"), "abcde").unwrap();
+
+    /* Without spot checks, the rot goes unnoticed and the old hash stays */
+    let mut new_manifest = Vec::new();
+    let result = confidence::update::update_manifest(
+            old_manifest.path().to_str().unwrap(), filename,
+            &mut new_manifest, 0, false, &Filter::default(), false,
+            0.0, false, &None).unwrap();
+    assert!(!result.disagrees());
+    let new_manifest = std::str::from_utf8(&new_manifest).unwrap();
+    let old_lines = std::fs::read_to_string(old_manifest.path()).unwrap();
+    let rotting_line = old_lines.lines()
+            .find(|line| line.contains(&base64::encode("rotting"))).unwrap();
+    assert!(new_manifest.contains(rotting_line));
+    assert!(new_manifest.ends_with("9 bytes hashed\n"));
+
+    /* Spot checking everything catches it */
+    let result = confidence::update::update_manifest(
+            old_manifest.path().to_str().unwrap(), filename,
+            &mut Vec::new(), 0, false, &Filter::default(), false,
+            1.0, false, &None).unwrap();
+    assert_eq!(result.disagreement(), 4);
+}
+
+
+#[test]
+fn hash_cache() {
+    use confidence::cache::{CacheMode, HashCache};
+
+    let dir = tempfile::tempdir().unwrap();
+    let cache_file = tempfile::NamedTempFile::new().unwrap();
+    let cache_filename = cache_file.path().to_str().unwrap();
+    let data_dir = dir.path().join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let path = data_dir.join("a");
+    std::fs::write(&path, "abcd").unwrap();
+    let filename = data_dir.to_str().unwrap();
+    let manifest_with = |mode| {
+        let cache = Some(HashCache::open(cache_filename, mode).unwrap());
+        let mut manifest = Vec::new();
+        runtime_with_options(filename, None, None, &mut manifest,
+                &mut RuntimeOptions{cache, ..Default::default()}).unwrap();
+        String::from_utf8(manifest).unwrap()
+    };
+    let real_hash = "81fe8bfe87576c3ecb22426f8e57847382917acf";
+    assert!(manifest_with(CacheMode::Trust).starts_with(
+            &format!("sha1: {} ", real_hash)));
+
+    /* A trusted cache is believed, even when it's wrong */
+    let bogus_hash = "0000000000000000000000000000000000000000";
+    HashCache::open(cache_filename, CacheMode::Trust).unwrap()
+            .store(&std::fs::metadata(&path).unwrap(), "sha1", bogus_hash)
+            .unwrap();
+    assert!(manifest_with(CacheMode::Trust).starts_with(
+            &format!("sha1: {} ", bogus_hash)));
+
+    /* ...but not a verified one, which also fixes it */
+    assert!(manifest_with(CacheMode::Verify).starts_with(
+            &format!("sha1: {} ", real_hash)));
+    assert!(manifest_with(CacheMode::Trust).starts_with(
+            &format!("sha1: {} ", real_hash)));
+
+    /* Writing to the file invalidates its entry */
+    HashCache::open(cache_filename, CacheMode::Trust).unwrap()
+            .store(&std::fs::metadata(&path).unwrap(), "sha1", bogus_hash)
+            .unwrap();
+    std::fs::write(&path, "abcd").unwrap();
+    assert!(manifest_with(CacheMode::Trust).starts_with(
+            &format!("sha1: {} ", real_hash)));
+
+    /* Two-tree comparisons use it too */
+    let copy_dir = dir.path().join("copy");
+    std::fs::create_dir_all(&copy_dir).unwrap();
+    std::fs::write(copy_dir.join("a"), "abcd").unwrap();
+    let mut options = RuntimeOptions{cache: Some(HashCache::open(cache_filename,
+            CacheMode::Trust).unwrap()), ..Default::default()};
+    let result = runtime_with_options(filename, copy_dir.to_str(), None,
+            &mut Vec::new(), &mut options);
+    assert_eq!(result.unwrap(), 0);
+    assert_eq!(options.cache.as_ref().unwrap().lookup(&std::fs::metadata(copy_dir.join("a")).unwrap(),
+            "sha1").unwrap().as_deref(), Some(real_hash));
+
+    /* A verified cache that's wrong is a disagreement, even when the file
+     * still matches */
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(manifest_file.path(), manifest_with(CacheMode::Trust))
+            .unwrap();
+    let mut options = RuntimeOptions{cache: Some(HashCache::open(cache_filename,
+            CacheMode::Verify).unwrap()), ..Default::default()};
+    for compare_to in [(None, manifest_file.path().to_str()),
+            (copy_dir.to_str(), None)] {
+        options.cache.as_ref().unwrap().store(&std::fs::metadata(&path).unwrap(), "sha1",
+                bogus_hash).unwrap();
+        let mut stdout = Vec::new();
+        let result = runtime_with_options(filename, compare_to.0, compare_to.1,
+                &mut stdout, &mut options);
+        assert_eq!(result.unwrap(), 1);
+        assert!(std::str::from_utf8(&stdout).unwrap().contains(
+                "Disagreement (4 bytes): The cached hash of "));
+    }
+}
+
+
+#[test]
+fn xattr_tags() {
+    use confidence::tags;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a");
+    std::fs::write(&path, "abcd").unwrap();
+    std::fs::write(dir.path().join("b"), "efg").unwrap();
+    let filename = dir.path().to_str().unwrap();
+
+    let result = tags::tag_tree(filename, &mut Vec::new(), 0,
+            &Filter::default(), false, tags::SHATAG).unwrap();
+    assert_eq!(result.agreement(), 7);
+    assert_eq!(xattr::get(&path, "user.shatag.sha256").unwrap().unwrap(),
+            b"88d4266fd4e6338d13b845fcf289579d209c897823b9217da3e161936f031589");
+    let mtime = std::fs::metadata(&path).unwrap().modified().unwrap()
+            .duration_since(std::time::UNIX_EPOCH).unwrap();
+    assert_eq!(xattr::get(&path, "user.shatag.ts").unwrap().unwrap(),
+            format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos()).as_bytes());
+
+    let mut stdout = Vec::new();
+    let result = tags::check_tags(filename, &mut stdout, 0, &Filter::default(),
+            false, tags::SHATAG).unwrap();
+    assert!(!result.disagrees());
+    assert!(std::str::from_utf8(&stdout).unwrap()
+            .starts_with("Agreed on 7/7 bytes (100% confidence)"));
+
+    /* Rot (same mtime) is a disagreement; an edit only outdates the tag */
+    let rotting = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    std::fs::write(&path, "abce").unwrap();
+    rotting.set_modified(std::time::UNIX_EPOCH + mtime).unwrap();
+    std::fs::write(dir.path().join("b"), "efgh").unwrap();
+    let mut stdout = Vec::new();
+    let result = tags::check_tags(filename, &mut stdout, 0, &Filter::default(),
+            false, tags::SHATAG).unwrap();
+    assert_eq!(result.disagreement(), 4);
+    assert_eq!(result.agreement(), 0);
+    let stdout = String::from_utf8(stdout).unwrap();
+    assert!(stdout.contains("has a different hash than its tag."));
+    assert!(stdout.contains("1 files were modified after they were tagged."));
+
+    /* The other namespace knows nothing yet */
+    let mut stdout = Vec::new();
+    tags::check_tags(filename, &mut stdout, 0, &Filter::default(), false,
+            tags::CONFIDENCE).unwrap();
+    assert!(std::str::from_utf8(&stdout).unwrap()
+            .contains("2 files have no tag."));
+
+    /* Nothing to check isn't NaN% */
+    let empty = tempfile::tempdir().unwrap();
+    let mut stdout = Vec::new();
+    tags::check_tags(empty.path().to_str().unwrap(), &mut stdout, 0,
+            &Filter::default(), false, tags::SHATAG).unwrap();
+    assert!(std::str::from_utf8(&stdout).unwrap()
+            .starts_with("Agreed on 0/0 bytes (0% confidence)"));
+}
+
+
+#[test]
+fn scrub_history() {
+    use confidence::history::{scrub, History};
+
+    let dir = tempfile::tempdir().unwrap();
+    let history_file = tempfile::NamedTempFile::new().unwrap();
+    let history = History::open(history_file.path().to_str().unwrap()).unwrap();
+    let data_dir = dir.path().join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(data_dir.join("rotting"), "abcd").unwrap();
+    std::fs::write(data_dir.join("edited"), "abcd").unwrap();
+    std::fs::write(data_dir.join("gone"), "abc").unwrap();
+    let filename = data_dir.to_str().unwrap();
+
+    let mut stdout = Vec::new();
+    let result = scrub(filename, &history, &mut stdout, 0, &Filter::default(),
+            false, false).unwrap();
+    assert!(!result.disagrees());
+    assert_eq!(std::str::from_utf8(&stdout).unwrap(),
+            "Verified 0 files, 3 new, 0 edited, 0 corrupt, 0 missing.\n\
+            Agreed on 0/0 bytes (0% confidence)\n\
+            Disagreed on 0/0 bytes (0% worry)\n");
+
+    let rotting = std::fs::OpenOptions::new().write(true)
+            .open(data_dir.join("rotting")).unwrap();
+    let mtime = rotting.metadata().unwrap().modified().unwrap();
+    std::fs::write(data_dir.join("rotting"), "abce").unwrap();
+    rotting.set_modified(mtime).unwrap();
+    std::fs::write(data_dir.join("edited"), "abcde").unwrap();
+    std::fs::remove_file(data_dir.join("gone")).unwrap();
+
+    let mut stdout = Vec::new();
+    let result = scrub(filename, &history, &mut stdout, 0, &Filter::default(),
+            false, false).unwrap();
+    assert_eq!(result.disagreement(), 7);
+    let stdout = String::from_utf8(stdout).unwrap();
+    assert!(stdout.contains(&format!("Disagreement (4 bytes): {} changed without its size or mtime changing (last verified 0s ago).",
+            data_dir.join("rotting").display())));
+    assert!(stdout.contains("gone is gone (last verified 0s ago)."));
+    assert!(stdout.contains("Verified 0 files, 0 new, 1 edited, 1 corrupt, 1 missing."));
+
+    /* Corruption keeps being flagged; the missing file can be forgotten */
+    let result = scrub(filename, &history, &mut Vec::new(), 0,
+            &Filter::default(), false, true).unwrap();
+    assert_eq!(result.disagreement(), 4);
+    let result = scrub(filename, &history, &mut Vec::new(), 0,
+            &Filter::default(), false, false).unwrap();
+    assert_eq!(result.disagreement(), 4);
+    assert_eq!(result.agreement(), 5);
+}
+
+
+#[test]
+fn time_budget() {
+    use confidence::history::{compare_hashes_within, History};
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("a"), "aa").unwrap();
+    std::fs::write(dir.path().join("b"), "bbb").unwrap();
+    std::fs::write(dir.path().join("c"), "cccc").unwrap();
+    let filename = dir.path().to_str().unwrap();
+    let manifest = write_manifest(filename, false, false);
+    let history_file = tempfile::NamedTempFile::new().unwrap();
+    let history = History::open(history_file.path().to_str().unwrap()).unwrap();
+    let run = || {
+        let mut stdout = Vec::new();
+        let result = compare_hashes_within(manifest.path().to_str().unwrap(),
+                filename, 0, &mut stdout, &Filter::default(), &history,
+                std::time::Duration::from_secs(0), 30, &None).unwrap();
+        (result, String::from_utf8(stdout).unwrap())
+    };
+
+    /* No time at all still gets one file done per run, oldest first */
+    let (result, stdout) = run();
+    assert_eq!(result.agreement(), 2);
+    assert!(stdout.starts_with("Verified 1 of 3 files (2 of 9 bytes)"));
+    assert!(stdout.ends_with("Verified within the last 30 days: 1/3 files, 2/9 bytes (22.222223%)\n"));
+    assert_eq!(run().0.agreement(), 3);
+
+    /* A disagreement stays at the front of the line */
+    std::fs::write(dir.path().join("c"), "cccd").unwrap();
+    assert_eq!(run().0.disagreement(), 4);
+    assert_eq!(run().0.disagreement(), 4);
+    std::fs::write(dir.path().join("c"), "cccc").unwrap();
+    let (result, stdout) = run();
+    assert_eq!(result.agreement(), 4);
+    assert!(stdout.contains("3/3 files, 9/9 bytes (100%)"));
+}
+
+
+#[test]
+fn copy_and_verify() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    std::fs::create_dir_all(source.join("sub/empty")).unwrap();
+    std::fs::write(source.join("a"), "abcd").unwrap();
+    std::fs::write(source.join("sub/b"), vec![7; 100000]).unwrap();
+    std::os::unix::fs::symlink("../a", source.join("sub/link")).unwrap();
+    let source_s = source.to_str().unwrap();
+    let destination = dir.path().join("destination");
+
+    let mut manifest = Vec::new();
+    let mut stdout = Vec::new();
+    let result = confidence::copy::copy_tree(source_s,
+            destination.to_str().unwrap(), &mut manifest, &mut stdout, 0,
+            false, &Filter::default(), false, true).unwrap();
+    assert!(!result.disagrees());
+    assert_eq!(result.agreement(), 100004);
+    assert!(std::str::from_utf8(&stdout).unwrap()
+            .starts_with("Copied 2 files (100004 bytes)."));
+    assert_eq!(std::fs::read(destination.join("sub/b")).unwrap(),
+            vec![7; 100000]);
+    assert_eq!(std::fs::read_link(destination.join("sub/link")).unwrap(),
+            std::path::Path::new("../a"));
+    assert!(destination.join("sub/empty").is_dir());
+    assert_eq!(std::fs::metadata(destination.join("a")).unwrap().modified()
+            .unwrap(), std::fs::metadata(source.join("a")).unwrap()
+            .modified().unwrap());
+
+    /* The manifest is just what hashing the source would have made */
+    assert_eq!(String::from_utf8(manifest).unwrap(),
+            String::from_utf8(std::fs::read(write_manifest(source_s, false, false)
+                    .path()).unwrap()).unwrap());
+
+    /* Copying again replaces whatever's in the way, and never writes
+     * through a symlink */
+    let outside = dir.path().join("outside");
+    std::fs::write(&outside, "keep").unwrap();
+    std::fs::remove_file(destination.join("a")).unwrap();
+    std::os::unix::fs::symlink(&outside, destination.join("a")).unwrap();
+    std::fs::remove_file(destination.join("sub/link")).unwrap();
+    std::fs::create_dir_all(destination.join("sub/link/in")).unwrap();
+    let result = confidence::copy::copy_tree(source_s,
+            destination.to_str().unwrap(), &mut Vec::new(), &mut Vec::new(), 0,
+            false, &Filter::default(), false, false).unwrap();
+    assert!(!result.disagrees());
+    assert_eq!(std::fs::read(&outside).unwrap(), b"keep");
+    assert!(destination.join("a").symlink_metadata().unwrap().is_file());
+    assert_eq!(std::fs::read_link(destination.join("sub/link")).unwrap(),
+            std::path::Path::new("../a"));
+
+    /* Nor into a file that's hard linked with something else */
+    std::fs::remove_file(destination.join("a")).unwrap();
+    std::fs::hard_link(&outside, destination.join("a")).unwrap();
+    confidence::copy::copy_tree(source_s, destination.to_str().unwrap(),
+            &mut Vec::new(), &mut Vec::new(), 0, false, &Filter::default(),
+            false, false).unwrap();
+    assert_eq!(std::fs::read(&outside).unwrap(), b"keep");
+    assert_eq!(std::fs::read(destination.join("a")).unwrap(), b"abcd");
+    assert!(!destination.join("a.confidence-copy").exists());
+
+    /* Special files can't be copied, so the copy isn't complete */
+    make_fifo(&source.join("fifo"));
+    let mut stdout = Vec::new();
+    let result = confidence::copy::copy_tree(source_s,
+            destination.to_str().unwrap(), &mut Vec::new(), &mut stdout, 0,
+            false, &Filter::default(), false, false).unwrap();
+    assert_eq!((result.entry_disagreement(), result.disagrees()), (1, true));
+    assert!(String::from_utf8(stdout).unwrap().contains(", which is a FIFO."));
+}
+
+
+#[test]
+fn repair() {
+    use confidence::repair::repair_tree;
+
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    let replica = dir.path().join("replica");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(replica.join("extra_dir")).unwrap();
+    std::fs::write(source.join("same"), "same").unwrap();
+    std::fs::write(replica.join("same"), "same").unwrap();
+    std::fs::write(source.join("differs"), "good").unwrap();
+    std::fs::write(replica.join("differs"), "badd").unwrap();
+    std::fs::write(source.join("sub/missing"), "abc").unwrap();
+    std::fs::write(replica.join("extra_dir/extra"), "x").unwrap();
+    let (source_s, replica_s) = (source.to_str().unwrap(),
+            replica.to_str().unwrap());
+
+    /* A dry run only says what it would do */
+    let mut stdout = Vec::new();
+    let result = repair_tree(source_s, replica_s, &mut stdout, 0,
+            &Filter::default(), false, false, true).unwrap();
+    assert_eq!(result.disagreement(), 7);
+    assert_eq!(String::from_utf8(stdout).unwrap(), format!(r###"Would copy (differs, 4 bytes) {0}/differs -> {1}/differs
+Would make directory {1}/sub
+Would copy (missing, 3 bytes) {0}/sub/missing -> {1}/sub/missing
+Would delete {1}/extra_dir
+Dry run: nothing was changed.  Use --execute to make these changes.
+4 bytes agree.
+7 bytes disagree.
+"###, source_s, replica_s));
+    assert_eq!(std::fs::read(replica.join("differs")).unwrap(), b"badd");
+
+    let result = repair_tree(source_s, replica_s, &mut Vec::new(), 0,
+            &Filter::default(), false, true, true).unwrap();
+    assert!(!result.disagrees());
+    assert_eq!(result.agreement(), 11);
+    assert!(!replica.join("extra_dir").exists());
+    let result = runtime_with_regular_args(false, None, source_s,
+            Some(replica_s), None, &mut Vec::new(), 0, false, false);
+    assert_eq!(result.unwrap(), 0);
+
+    /* Symlinks are recreated as symlinks, and one on the right is replaced
+     * even if it leads to the right contents */
+    std::os::unix::fs::symlink("same", source.join("link")).unwrap();
+    std::fs::write(replica.join("link"), "same").unwrap();
+    std::fs::remove_file(replica.join("same")).unwrap();
+    std::os::unix::fs::symlink(source.join("same"), replica.join("same"))
+            .unwrap();
+    let mut stdout = Vec::new();
+    let result = repair_tree(source_s, replica_s, &mut stdout, 0,
+            &Filter::default(), false, true, true).unwrap();
+    assert!(!result.disagrees());
+    assert!(String::from_utf8(stdout).unwrap().contains(
+            "Copied 1 files (4 bytes), linked 1 and deleted 0 extras."));
+    assert_eq!(std::fs::read_link(replica.join("link")).unwrap(),
+            std::path::Path::new("same"));
+    assert!(replica.join("same").symlink_metadata().unwrap().is_file());
+    assert_eq!(std::fs::read(source.join("same")).unwrap(), b"same");
+
+    /* A directory where a file should be is extra, so it stays without
+     * --delete-extra */
+    std::fs::remove_file(replica.join("differs")).unwrap();
+    std::fs::create_dir_all(replica.join("differs/keep")).unwrap();
+    let mut stdout = Vec::new();
+    let result = repair_tree(source_s, replica_s, &mut stdout, 0,
+            &Filter::default(), false, true, false).unwrap();
+    assert_eq!(result.disagreement(), 4);
+    assert!(String::from_utf8(stdout).unwrap().contains(&format!(
+            "Extra {}/differs, which is in the way of {}/differs\n", replica_s,
+            source_s)));
+    assert!(replica.join("differs/keep").is_dir());
+    let mut stdout = Vec::new();
+    let result = repair_tree(source_s, replica_s, &mut stdout, 0,
+            &Filter::default(), false, true, true).unwrap();
+    assert!(!result.disagrees());
+    assert!(String::from_utf8(stdout).unwrap().contains(&format!(
+            "Deleted {}/differs\n", replica_s)));
+    assert_eq!(std::fs::read(replica.join("differs")).unwrap(), b"good");
+}
+
+
+#[test]
+fn majority_vote() {
+    use confidence::vote::{entries_of, vote};
+
+    let dir = tempfile::tempdir().unwrap();
+    let names = ["one", "two", "three"];
+    for name in &names {
+        std::fs::create_dir_all(dir.path().join(name)).unwrap();
+        std::fs::write(dir.path().join(name).join("same"), "same").unwrap();
+        std::fs::write(dir.path().join(name).join("photo"), "good").unwrap();
+    }
+    std::fs::write(dir.path().join("two/photo"), "rot!").unwrap();
+    std::fs::remove_file(dir.path().join("three/same")).unwrap();
+    std::fs::write(dir.path().join("one/split"), "a").unwrap();
+    std::fs::write(dir.path().join("two/split"), "b").unwrap();
+
+    /* The third replica is a manifest, the others directories */
+    let manifest = write_manifest(dir.path().join("three").to_str().unwrap(),
+            true, false);
+    let replicas = vec![
+        entries_of(dir.path().join("one").to_str().unwrap(),
+                &Filter::default(), false, &None).unwrap(),
+        entries_of(dir.path().join("two").to_str().unwrap(),
+                &Filter::default(), false, &None).unwrap(),
+        entries_of(manifest.path().to_str().unwrap(), &Filter::default(),
+                false, &None).unwrap(),
+    ];
+    let mut stdout = Vec::new();
+    let comparisons = vote(&replicas, &names, &mut stdout, 0).unwrap();
+    assert_eq!(String::from_utf8(stdout).unwrap(), r###"Likely corrupt (4 bytes): photo in two (2 of 3 replicas agree on something else)
+Missing (4 bytes): same in three (2 of 3 replicas have it)
+No majority for split: one | two | three
+0 of 3 paths agree in every replica.
+one: agreed on 8/9 bytes (88.88889% confidence)
+two: agreed on 4/9 bytes (44.444447% confidence)
+three: agreed on 4/8 bytes (50% confidence)
+"###);
+    assert_eq!(comparisons[2].entry_disagreement(), 1);
+
+    /* Two replicas can't outvote each other */
+    assert!(vote(&replicas[..2], &names[..2], &mut Vec::new(), 0).is_err());
+}
+
+
+#[test]
+fn parity_repair() {
+    use confidence::parity::{repair_from_parity, write_parity, ParityWriter,
+            BLOCK_SIZE};
+
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let path = data_dir.join("big");
+    let contents = (0..5 * BLOCK_SIZE + 100).map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+    std::fs::write(&path, &contents).unwrap();
+    std::fs::write(data_dir.join("small"), "abcd").unwrap();
+    let filename = data_dir.to_str().unwrap();
+    let parity_file = tempfile::NamedTempFile::new().unwrap();
+    let parity_filename = parity_file.path().to_str().unwrap();
+    write_parity(filename, std::fs::File::create(parity_filename).unwrap(),
+            &Filter::default(), false, 20).unwrap();
+
+    /* Working it out while hashing gives the same parity file */
+    let alongside = dir.path().join("alongside.parity");
+    let mut options = RuntimeOptions{parity: Some(ParityWriter::new(Box::new(
+            std::fs::File::create(&alongside).unwrap()), 20).unwrap()),
+            ..Default::default()};
+    assert_eq!(runtime_with_options(filename, None, None, &mut Vec::new(),
+            &mut options).unwrap(), 0);
+    assert_eq!(options.parity.unwrap().finish().unwrap(), contents.len() + 4);
+    assert_eq!(std::fs::read(&alongside).unwrap(),
+            std::fs::read(parity_filename).unwrap());
+
+    /* Two damaged blocks, two parity blocks, and the mtime restored */
+    let damage = |offsets: &[u64]| {
+        use std::io::{Seek, SeekFrom, Write};
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path)
+                .unwrap();
+        let mtime = file.metadata().unwrap().modified().unwrap();
+        for offset in offsets {
+            file.seek(SeekFrom::Start(*offset)).unwrap();
+            file.write_all(b"rot").unwrap();
+        }
+        file.set_modified(mtime).unwrap();
+    };
+    damage(&[10, 3 * BLOCK_SIZE as u64 + 5]);
+    let mut stdout = Vec::new();
+    let result = repair_from_parity(parity_filename, filename, &mut stdout, 0,
+            false).unwrap();
+    assert!(!result.disagrees());
+    assert_eq!(result.agreement(), contents.len() + 4);
+    assert!(String::from_utf8(stdout).unwrap().starts_with(&format!(
+            "Repaired 2 blocks of {}\n", path.display())));
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+
+    /* Three is too many */
+    damage(&[10, 2 * BLOCK_SIZE as u64, 5 * BLOCK_SIZE as u64]);
+    let mut stdout = Vec::new();
+    let result = repair_from_parity(parity_filename, filename, &mut stdout, 0,
+            false).unwrap();
+    assert_eq!(result.disagreement(), contents.len());
+    assert!(String::from_utf8(stdout).unwrap()
+            .contains("3 damaged blocks share only 2 usable parity blocks."));
+
+    /* An edited file is left alone */
+    std::fs::write(data_dir.join("small"), "abce").unwrap();
+    let mut stdout = Vec::new();
+    repair_from_parity(parity_filename, filename, &mut stdout, 0, false)
+            .unwrap();
+    assert!(String::from_utf8(stdout).unwrap()
+            .contains("small, which was modified after the parity file was made."));
+    assert_eq!(std::fs::read(data_dir.join("small")).unwrap(), b"abce");
+
+    /* A file is rebuilt even when its directory's gone too */
+    let other_dir = dir.path().join("other");
+    std::fs::create_dir_all(other_dir.join("sub")).unwrap();
+    std::fs::write(other_dir.join("sub/tiny"), "tiny").unwrap();
+    let other_parity = dir.path().join("other.parity");
+    write_parity(other_dir.to_str().unwrap(),
+            std::fs::File::create(&other_parity).unwrap(), &Filter::default(),
+            false, 20).unwrap();
+    std::fs::remove_dir_all(other_dir.join("sub")).unwrap();
+    let result = repair_from_parity(other_parity.to_str().unwrap(),
+            other_dir.to_str().unwrap(), &mut Vec::new(), 0, false).unwrap();
+    assert!(!result.disagrees());
+    assert_eq!(std::fs::read(other_dir.join("sub/tiny")).unwrap(), b"tiny");
+
+    /* A truncated or edited parity file is corrupt, not a panic */
+    let lines = std::fs::read_to_string(parity_filename).unwrap();
+    let edit_last = |prefix: &str, edit: &dyn Fn(&str) -> Option<String>| {
+        let mut lines = lines.lines().map(str::to_owned).collect::<Vec<_>>();
+        let i = lines.iter().rposition(|line| line.starts_with(prefix)).unwrap();
+        match edit(&lines[i]) {
+            Some(line) => lines[i] = line,
+            None => {
+                lines.remove(i);
+            }
+        }
+        lines.join("\n") + "\n"
+    };
+    for bad in [edit_last("block ", &|_| None),
+            edit_last("parity ", &|_| None),
+            edit_last("file ", &|line| Some(line.replace(" 4096", " 0"))),
+            edit_last("parity ", &|line| {
+                Some(line.rsplit_once(' ').unwrap().0.to_owned() + " AAAA")
+            })] {
+        std::fs::write(parity_filename, bad).unwrap();
+        assert!(repair_from_parity(parity_filename, filename, &mut Vec::new(),
+                0, true).is_err());
+    }
+}
+
+
+#[test]
+fn fixup_output() {
+    use confidence::fixup::{Fixup, FixupFormat};
+
+    let dir = tempfile::tempdir().unwrap();
+    let (source, replica) = (dir.path().join("source"), dir.path().join("replica"));
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(&replica).unwrap();
+    std::fs::write(source.join("it's"), "good").unwrap();
+    std::fs::write(replica.join("it's"), "badd").unwrap();
+    std::fs::write(source.join("sub/missing"), "abc").unwrap();
+    std::fs::write(replica.join("extra"), "x").unwrap();
+    let (source_s, replica_s) = (source.to_str().unwrap(),
+            replica.to_str().unwrap());
+
+    let mut options = RuntimeOptions{
+            fixup: Some(Fixup::new(Some(source_s), replica_s)),
+            ..Default::default()};
+    let result = runtime_with_options(source_s, Some(replica_s), None,
+            &mut Vec::new(), &mut options);
+    assert_eq!(result.unwrap(), 1);
+    let fixup = options.fixup.unwrap();
+    let mut script = Vec::new();
+    fixup.write_script(&mut script).unwrap();
+    assert_eq!(String::from_utf8(script).unwrap(), format!(r###"#!/bin/sh
+# Written by confidence.  Review before running.
+set -e
+SRC='{}'
+DST='{}'
+
+# Missing (1)
+mkdir -p -- "$DST"/'sub'
+cp --preserve=all -- "$SRC"/'sub/missing' "$DST"/'sub/missing'
+
+# Differing (1)
+cp --preserve=all -- "$SRC"/'it'\''s' "$DST"/'it'\''s'
+
+# Extra (1).  Uncomment to delete.
+# rm -r -- "$DST"/'extra'
+"###, source_s, replica_s));
+
+    let prefix = dir.path().join("fixup");
+    fixup.write(prefix.to_str().unwrap(), FixupFormat::Rsync).unwrap();
+    assert_eq!(std::fs::read(dir.path().join("fixup.missing")).unwrap(),
+            b"sub/missing\0");
+    assert_eq!(std::fs::read(dir.path().join("fixup.extra")).unwrap(),
+            b"extra\0");
+
+    /* Against a manifest, the good copy has to come from somewhere else */
+    let manifest = write_manifest(source_s, false, false);
+    let mut options = RuntimeOptions{fixup: Some(Fixup::new(None, replica_s)),
+            ..Default::default()};
+    runtime_with_options(replica_s, None, manifest.path().to_str(),
+            &mut Vec::new(), &mut options).unwrap();
+    let fixup = options.fixup.unwrap();
+    assert_eq!((fixup.missing, fixup.differing, fixup.extra),
+            (vec!["sub/missing".to_owned()], vec!["it's".to_owned()],
+                    vec!["extra".to_owned()]));
+
+    /* Nothing in a path gets run, SRC or no SRC */
+    let mut fixup = Fixup::new(None, "m$(touch PWNED)\"`touch PWNED`");
+    fixup.missing.push("a".to_owned());
+    fixup.extra.push("x\ntouch PWNED".to_owned());
+    let script = dir.path().join("fixup.sh");
+    fixup.write(script.to_str().unwrap(), FixupFormat::Script).unwrap();
+    let status = std::process::Command::new("sh").arg(&script)
+            .current_dir(dir.path()).env_remove("SRC")
+            .stderr(std::process::Stdio::null()).status().unwrap();
+    assert!(!status.success());
+    assert!(!dir.path().join("PWNED").exists());
+}
+
+
+#[test]
+fn sum_files() {
+    use confidence::algorithm::Algorithm;
+    use confidence::sums::{read_sums, verify_sums, write_sums, SumStyle};
+
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree");
+    std::fs::create_dir_all(tree.join("d")).unwrap();
+    std::fs::write(tree.join("a"), "abc").unwrap();
+    std::fs::write(tree.join("back\\slash"), "x").unwrap();
+    std::fs::write(tree.join("d/h"), "hello").unwrap();
+    let tree_s = tree.to_str().unwrap();
+
+    /* As sha256sum, md5sum --tag and b2sum -b would write them */
+    let sums = dir.path().join("SUMS");
+    std::fs::write(&sums, concat!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  a\n",
+            "\\2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881  back\\\\slash\n",
+            "MD5 (d/h) = 5d41402abc4b2a76b9719d911017c592\n",
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d17d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923 *a\n")).unwrap();
+    let sums_s = sums.to_str().unwrap();
+    let entries = read_sums(sums_s, None).unwrap();
+    assert_eq!(entries.iter().map(|entry| (entry.algorithm, entry.path.as_str()))
+            .collect::<Vec<_>>(), vec![(Algorithm::Sha256, "a"),
+                    (Algorithm::Sha256, "back\\slash"), (Algorithm::Md5, "d/h"),
+                    (Algorithm::Blake2b, "a")]);
+
+    let comparison = verify_sums(sums_s, tree_s, &mut Vec::new(), 0,
+            &Filter::default(), None).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagrees()), (12, false));
+
+    std::fs::write(tree.join("a"), "abd").unwrap();
+    std::fs::remove_file(tree.join("d/h")).unwrap();
+    let mut output = Vec::new();
+    let comparison = verify_sums(sums_s, tree_s, &mut output, 0,
+            &Filter::default(), None).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (1, 6, 1));
+    assert!(String::from_utf8(output).unwrap()
+            .contains("Disagreed on 6/7 bytes"));
+
+    /* Filters apply, going by what's there now */
+    let mut filter = Filter::default();
+    filter.excludes = confidence::filter::glob_set(&["a"]).unwrap();
+    let comparison = verify_sums(sums_s, tree_s, &mut Vec::new(), 0, &filter,
+            None).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (1, 0, 1));
+
+    /* With only a missing file left, there's nothing to divide by */
+    filter.excludes = confidence::filter::glob_set(&["a", "back*"]).unwrap();
+    let mut output = Vec::new();
+    assert!(verify_sums(sums_s, tree_s, &mut output, 0, &filter, None).unwrap()
+            .disagrees());
+    assert!(String::from_utf8(output).unwrap()
+            .contains("Agreed on 0/0 bytes (0% confidence)\nDisagreed on 0/0 bytes (0% worry)\n"));
+    let mut filter = Filter::default();
+    filter.min_size = Some(2);
+    let comparison = verify_sums(sums_s, tree_s, &mut Vec::new(), 0, &filter,
+            None).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (0, 6, 1));
+
+    /* Nothing outside the directory is hashed */
+    let outside = dir.path().join("OUTSIDE");
+    std::fs::write(&outside, "abc").unwrap();
+    std::fs::write(&sums, format!("{0}  ../OUTSIDE\n{0}  {1}\n{0}  d/../a\n",
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            outside.display())).unwrap();
+    let mut output = Vec::new();
+    let comparison = verify_sums(sums_s, tree_s, &mut output, 0,
+            &Filter::default(), None).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (0, 3, 2));
+    assert!(String::from_utf8(output).unwrap()
+            .contains("lists ../OUTSIDE, which isn't under"));
+
+    /* Exported lines read back as what they were made from */
+    let mut exported = Vec::new();
+    assert_eq!(write_sums(tree_s, &mut exported, Algorithm::Md5, SumStyle::Bsd,
+            &Filter::default(), false).unwrap(), 2);
+    let exported = String::from_utf8(exported).unwrap();
+    assert!(exported.contains("\\MD5 (back\\\\slash) = 9dd4e461268c8034f5c8564e155c67a6\n"));
+    std::fs::write(&sums, &exported).unwrap();
+    assert!(!verify_sums(sums_s, tree_s, &mut Vec::new(), 0,
+            &Filter::default(), None).unwrap().disagrees());
+
+    /* Manifests can only be exported as sha1 */
+    let manifest = write_manifest(tree_s, false, false);
+    let manifest_s = manifest.path().to_str().unwrap();
+    let mut exported = Vec::new();
+    write_sums(manifest_s, &mut exported, Algorithm::Sha1, SumStyle::Gnu,
+            &Filter::default(), false).unwrap();
+    assert!(String::from_utf8(exported).unwrap()
+            .contains("\\11f6ad8ec52a2984abaafd7c3b516503785c2072  back\\\\slash\n"));
+    assert!(write_sums(manifest_s, &mut Vec::new(), Algorithm::Sha256,
+            SumStyle::Gnu, &Filter::default(), false).is_err());
+}
+
+
+#[test]
+fn sidecar_files() {
+    use confidence::sidecar::verify_sidecars;
+
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path();
+    std::fs::create_dir_all(tree.join("d/e")).unwrap();
+    std::fs::write(tree.join("a"), "abc").unwrap();
+    std::fs::write(tree.join("with space"), "hello").unwrap();
+    std::fs::write(tree.join("d/h"), "hello").unwrap();
+    std::fs::write(tree.join("d/e/stray"), "xyz").unwrap();
+
+    /* Each listed file is relative to its own checksum file */
+    std::fs::write(tree.join("all.sfv"), concat!(
+            "; Generated by some SFV tool\n",
+            "a 352441C2\n",
+            "with space 3610A686\n")).unwrap();
+    std::fs::write(tree.join("d/d.md5"),
+            "5d41402abc4b2a76b9719d911017c592  h\n").unwrap();
+    let tree_s = tree.to_str().unwrap();
+    let mut output = Vec::new();
+    let comparison = verify_sidecars(tree_s, &mut output, 0,
+            &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagrees()), (13, false));
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Not in any checksum file (3 bytes): d/e/stray"));
+    assert!(output.contains("1 files (3 bytes) aren't in any checksum file."));
+
+    std::fs::write(tree.join("a"), "abd").unwrap();
+    std::fs::remove_file(tree.join("d/h")).unwrap();
+    let comparison = verify_sidecars(tree_s, &mut Vec::new(), 0,
+            &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (5, 3, 1));
+
+    /* SFV paths can have backslashes.  A checksum file that can't be read,
+     * or an entry outside the tree, disagrees without stopping the rest. */
+    std::fs::write(tree.join("d/e.sfv"), "e\\stray EB8EBA67\n").unwrap();
+    std::fs::write(tree.join("junk.sha256"), "not a checksum\n").unwrap();
+    std::fs::write(tree.join("d/up.md5"),
+            "900150983cd24fb0d6963f7d28e17f72  ../../a\n").unwrap();
+    let mut output = Vec::new();
+    let comparison = verify_sidecars(tree_s, &mut output, 0,
+            &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (8, 3, 3));
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Couldn't read junk.sha256: "));
+    assert!(output.contains("d/up.md5 lists ../../a, which isn't under"));
+    assert!(!output.contains("Not in any checksum file"));
+}
+
+
+#[test]
+fn git_trees() {
+    use confidence::git::{blob_hash_of, compare_git};
+    use std::process::Command;
+
+    /* As git hash-object would say */
+    assert_eq!(blob_hash_of(b"hello\n"), "ce013625030ba8dba906f756967f9e9ca394464a");
+
+    let dir = tempfile::tempdir().unwrap();
+    let repo = dir.path().join("repo");
+    std::fs::create_dir_all(repo.join("sub")).unwrap();
+    std::fs::write(repo.join(".gitattributes"), "*.txt eol=crlf\n").unwrap();
+    std::fs::write(repo.join("a.txt"), "a\nb\n").unwrap();
+    std::fs::write(repo.join("sub/h"), "hello\n").unwrap();
+    let big = "x".repeat(64 * 1024 - 1) + "\ny\n";
+    std::fs::write(repo.join("big.txt"), &big).unwrap();
+    let git = |args: &[&str]| {
+        assert!(Command::new("git").arg("-C").arg(&repo)
+                .args(["-c", "user.name=a", "-c", "user.email=a@b"])
+                .args(args).output().unwrap().status.success());
+    };
+    git(&["init", "-q"]);
+    git(&["add", "-A"]);
+    git(&["commit", "-qm", "Initial"]);
+    let repo_s = repo.to_str().unwrap();
+
+    /* A checkout, with the CRLFs git would put in */
+    let checkout = dir.path().join("checkout");
+    std::fs::create_dir_all(checkout.join("sub")).unwrap();
+    std::fs::write(checkout.join(".gitattributes"), "*.txt eol=crlf\n").unwrap();
+    std::fs::write(checkout.join("a.txt"), "a\r\nb\r\n").unwrap();
+    std::fs::write(checkout.join("sub/h"), "hello\n").unwrap();
+
+    /* With a CRLF split between one buffer and the next */
+    std::fs::write(checkout.join("big.txt"), big.replace('\n', "\r\n")).unwrap();
+    let checkout_s = checkout.to_str().unwrap();
+    let comparison = compare_git(repo_s, "HEAD", checkout_s, &mut Vec::new(),
+            0, true, &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagrees()), (65563, false));
+    assert!(compare_git(repo_s, "HEAD", checkout_s, &mut Vec::new(), 0, false,
+            &Filter::default(), false).unwrap().disagrees());
+
+    /* Missing and extra files */
+    std::fs::remove_file(checkout.join("sub/h")).unwrap();
+    std::fs::write(checkout.join("extra"), "xyz").unwrap();
+    let mut output = Vec::new();
+    let comparison = compare_git(repo_s, "HEAD", checkout_s, &mut output, 0,
+            true, &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement()), (65557, 9));
+    assert!(String::from_utf8(output).unwrap().contains("isn't in HEAD."));
+
+    /* A file that's grown counts for its new size, worry and all */
+    std::fs::write(checkout.join("a.txt"), "a\r\n".repeat(1000)).unwrap();
+    let mut output = Vec::new();
+    let comparison = compare_git(repo_s, "HEAD", checkout_s, &mut output, 0,
+            true, &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagreement()),
+            (65553, 2009));
+    assert!(String::from_utf8(output).unwrap()
+            .contains("Disagreed on 2009/67562 bytes"));
+    assert!(compare_git(repo_s, "no-such-rev", checkout_s, &mut Vec::new(), 0,
+            true, &Filter::default(), false).is_err());
+}
+
+
+#[test]
+fn archive_trees() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree");
+    std::fs::create_dir_all(tree.join("d")).unwrap();
+    std::fs::write(tree.join("a"), "abc").unwrap();
+    std::fs::write(tree.join("d/h"), "hello").unwrap();
+    let tree_s = tree.to_str().unwrap();
+
+    /* As `tar czf tree.tgz tree` would make it */
+    let tgz = dir.path().join("tree.tgz");
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            std::fs::File::create(&tgz).unwrap(), flate2::Compression::default()));
+    builder.append_dir_all("tree", &tree).unwrap();
+    builder.into_inner().unwrap().finish().unwrap();
+    let tgz_s = tgz.to_str().unwrap();
+
+    /* A zip of what's in the tree, with "d/h" changed */
+    let zip_path = dir.path().join("tree.zip");
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+    for (name, contents) in &[("a", "abc"), ("d/h", "jello")] {
+        zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap();
+    let zip_s = zip_path.to_str().unwrap();
+
+    /* Hashing an archive gives what hashing the tree does, sans stats,
+     * with the paths that are in the archive */
+    let mut manifest = Vec::new();
+    assert_eq!(runtime_with_regular_args(false, None, tgz_s, None, None,
+            &mut manifest, 0, false, false).unwrap(), 0);
+    assert_eq!(std::str::from_utf8(&manifest).unwrap(), format!(
+            "sha1: a9993e364706816aba3e25717850c26c9cd0d89d {} 3\nsha1: aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d {} 5\n8 bytes hashed\n",
+            base64::encode("tree/a"), base64::encode("tree/d/h")));
+
+    for (filename_l, filename_r) in &[(tgz_s, tree_s), (tree_s, tgz_s)] {
+        assert_eq!(runtime_with_regular_args(false, Some(8), filename_l,
+                Some(filename_r), None, &mut Vec::new(), 0, false, false).unwrap(), 0);
+    }
+
+    /* The zip's crc32 alone is enough to tell "d/h" differs */
+    let mut output = Vec::new();
+    assert_eq!(runtime_with_regular_args(false, Some(8), zip_s, Some(tree_s),
+            None, &mut output, 0, false, false).unwrap(), 1);
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("3 of 8 bytes agree."));
+    assert!(output.contains(&format!("'{}/d/h' and '{}/d/h' aren't equal.",
+            zip_s, tree_s)));
+
+    /* A manifest of the tree lines up with what's under the top directory */
+    let manifest = write_manifest(tree_s, false, false);
+    let manifest_s = manifest.path().to_str();
+    assert_eq!(runtime_with_regular_args(false, None, tgz_s, None, manifest_s,
+            &mut Vec::new(), 0, false, false).unwrap(), 0);
+    let mut output = Vec::new();
+    assert_eq!(runtime_with_regular_args(false, None, zip_s, None, manifest_s,
+            &mut output, 0, false, false).unwrap(), 1);
+    assert_eq!(String::from_utf8(output).unwrap(), format!(
+            "Disagreement (5 bytes): d/h and {}/d/h have different hashes.\n\
+            Agreed on 3/8 bytes (37.5% confidence)\n\
+            Disagreed on 5/8 bytes (62.5% worry)\n", zip_s));
+
+    /* The tree's side goes through the cache, which is believed if trusted */
+    use confidence::cache::{CacheMode, HashCache};
+    let cache_file = dir.path().join("cache");
+    let mut options = RuntimeOptions{cache: Some(HashCache::open(
+            cache_file.to_str().unwrap(), CacheMode::Trust).unwrap()),
+            ..Default::default()};
+    assert_eq!(runtime_with_options(tgz_s, Some(tree_s), None, &mut Vec::new(),
+            &mut options).unwrap(), 0);
+    let cache = options.cache.as_ref().unwrap();
+    let metadata = std::fs::metadata(tree.join("a")).unwrap();
+    assert_eq!(cache.lookup(&metadata, "sha1").unwrap().as_deref(),
+            Some("a9993e364706816aba3e25717850c26c9cd0d89d"));
+    std::fs::write(dir.path().join("jello"), "jello").unwrap();
+    let (jello_sha1, _) = confidence::hash_of_path(&dir.path().join("jello"))
+            .unwrap();
+    cache.store(&std::fs::metadata(tree.join("d/h")).unwrap(), "sha1",
+            &jello_sha1).unwrap();
+    assert_eq!(runtime_with_options(zip_s, Some(tree_s), None, &mut Vec::new(),
+            &mut options).unwrap(), 0);
+
+    /* There's no copying into or out of one, or stat of what's in one */
+    let fixup = confidence::fixup::Fixup::new(Some(zip_s), tree_s);
+    assert!(runtime_with_options(zip_s, Some(tree_s), None, &mut Vec::new(),
+            &mut RuntimeOptions{fixup: Some(fixup), ..Default::default()})
+            .is_err());
+    assert!(runtime_with_options(tgz_s, None, None, &mut Vec::new(),
+            &mut RuntimeOptions{stat_columns: true, ..Default::default()})
+            .is_err());
+}
+
+
+#[test]
+fn tar_streams() {
+    use confidence::archive::hash_tar_stream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree");
+    std::fs::create_dir_all(tree.join("d")).unwrap();
+    std::fs::write(tree.join("a"), "abc").unwrap();
+    std::fs::write(tree.join("d/h"), "hello").unwrap();
+    let tree_s = tree.to_str().unwrap();
+
+    /* As `tar -C tree -cf - .` would stream it */
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_dir_all(".", &tree).unwrap();
+    let stream = builder.into_inner().unwrap();
+    let mut manifest = Vec::new();
+    assert_eq!(hash_tar_stream(&stream[..], &mut manifest, 0,
+            &Filter::default()).unwrap(), 8);
+    let manifest_s = std::str::from_utf8(&manifest).unwrap();
+    assert!(manifest_s.contains(&format!(
+            "sha1: aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d {} 5\n",
+            base64::encode("d/h"))));
+    assert!(manifest_s.ends_with("\n8 bytes hashed\n"));
+
+    /* Compressed, it's the same */
+    let mut gzipped = flate2::write::GzEncoder::new(Vec::new(),
+            flate2::Compression::default());
+    std::io::Write::write_all(&mut gzipped, &stream).unwrap();
+    let mut gzipped_manifest = Vec::new();
+    hash_tar_stream(&gzipped.finish().unwrap()[..], &mut gzipped_manifest, 0,
+            &Filter::default()).unwrap();
+    assert_eq!(gzipped_manifest, manifest);
+
+    /* The restored tree agrees with the stream */
+    let manifest_file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(manifest_file.path(), &manifest).unwrap();
+    let manifest_s = manifest_file.path().to_str().unwrap();
+    assert_eq!(runtime_with_regular_args(false, None, tree_s, None,
+            Some(manifest_s), &mut Vec::new(), 0, false, false).unwrap(), 0);
+    std::fs::write(tree.join("a"), "abd").unwrap();
+    assert_eq!(runtime_with_regular_args(false, None, tree_s, None,
+            Some(manifest_s), &mut Vec::new(), 0, false, false).unwrap(), 1);
+    assert!(hash_tar_stream(&b"not a tar"[..], &mut Vec::new(), 0,
+            &Filter::default()).is_err());
+}
+
+
+#[test]
+fn disk_images() {
+    let dir = tempfile::tempdir().unwrap();
+
+    /* What tests/images/make_images.py put in the images */
+    let tree = dir.path().join("src");
+    std::fs::create_dir_all(tree.join("dir/deeper")).unwrap();
+    std::fs::create_dir_all(tree.join("d1/d2/d3/d4/d5/d6/d7/d8/d9")).unwrap();
+    let raw = (0..=255u8).cycle().take(4096).chain(b"0123456789".iter().copied())
+            .collect::<Vec<_>>();
+    for (path, contents) in &[("a", b"abc".to_vec()), ("empty", Vec::new()),
+            ("dir/blob", b"0123456789".repeat(500)), ("dir/zeros", vec![0; 8292]),
+            ("dir/raw", raw), ("dir/deeper/Mixed Case name.txt", b"hello".to_vec()),
+            ("d1/d2/d3/d4/d5/d6/d7/d8/d9/deep", b"deep".to_vec())] {
+        std::fs::write(tree.join(path), contents).unwrap();
+    }
+    make_fifo(&tree.join("fifo"));
+    let tree_s = tree.to_str().unwrap();
+
+    let mut images = Vec::new();
+    for name in &["rockridge", "joliet", "plain"] {
+        let image = dir.path().join(format!("tree-{}.iso", name));
+        let mut gzipped = flate2::read::GzDecoder::new(std::fs::File::open(
+                format!("tests/images/tree-{}.iso.gz", name)).unwrap());
+        std::io::copy(&mut gzipped, &mut std::fs::File::create(&image).unwrap())
+                .unwrap();
+        images.push(image.to_str().unwrap().to_owned());
+    }
+    let (rock_ridge, joliet, plain) = (&images[0], &images[1], &images[2]);
+    let squashfs = "tests/images/tree.sqfs";
+
+    /* Rock Ridge has everything, deep directories and FIFOs included */
+    for (filename_l, filename_r) in &[(rock_ridge.as_str(), tree_s),
+            (tree_s, rock_ridge.as_str()), (tree_s, squashfs),
+            (rock_ridge.as_str(), squashfs)] {
+        assert_eq!(runtime_with_regular_args(false, Some(17410), filename_l,
+                Some(filename_r), None, &mut Vec::new(), 0, false, false).unwrap(), 0);
+    }
+
+    /* Joliet only has the regular files that aren't too deep */
+    assert_eq!(runtime_with_regular_args(false, Some(17406), joliet,
+            Some(tree_s), None, &mut Vec::new(), 0, false, false).unwrap(), 0);
+
+    /* The SquashFS image also has a device the tree doesn't */
+    let mut output = Vec::new();
+    assert_eq!(runtime_with_regular_args(false, Some(17410), squashfs,
+            Some(tree_s), None, &mut output, 0, false, false).unwrap(), 1);
+    assert!(String::from_utf8(output).unwrap().contains(&format!(
+            "'{}/null' is character device 1,3, but '{}/null' isn't.", squashfs,
+            tree_s)));
+    let mut manifest = Vec::new();
+    assert_eq!(runtime_with_regular_args(false, None, squashfs, None, None,
+            &mut manifest, 0, false, false).unwrap(), 0);
+    let manifest_s = std::str::from_utf8(&manifest).unwrap();
+    assert!(manifest_s.contains(&format!(
+            "sha1: 42a4ceb1616c2d2ad615d2ea4aac95dec5dada8a {} 8292\n",
+            base64::encode("dir/zeros"))));
+    assert!(manifest_s.contains(&format!("chardev: 1,3 {} 0\n",
+            base64::encode("null"))));
+    assert!(manifest_s.ends_with("\n17410 bytes hashed\n"));
+
+    /* Without either, names are as ISO9660 has them */
+    let mut manifest = Vec::new();
+    assert_eq!(runtime_with_regular_args(false, None, plain, None, None,
+            &mut manifest, 0, false, false).unwrap(), 0);
+    assert!(std::str::from_utf8(&manifest).unwrap().contains(&format!(
+            "sha1: aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d {} 5\n",
+            base64::encode("DIR/DEEPER/MIXED_CA.TXT"))));
+
+    /* Corrupt block sizes are errors, not panics or divisions by zero */
+    let open_corrupted = |original: &str, offset: usize, bytes: &[u8]| {
+        let mut contents = std::fs::read(original).unwrap();
+        contents[offset..offset + bytes.len()].copy_from_slice(bytes);
+        let extension = std::path::Path::new(original).extension().unwrap();
+        let corrupted = dir.path().join("corrupted").with_extension(extension);
+        std::fs::write(&corrupted, contents).unwrap();
+        runtime_with_regular_args(false, None, corrupted.to_str().unwrap(),
+                None, None, &mut Vec::new(), 0, false, false)
+    };
+    for block_size in &[0u32, 3000, 1 << 21] {
+        assert!(open_corrupted(squashfs, 12, &block_size.to_le_bytes()).is_err());
+    }
+    assert!(open_corrupted(plain, 16 * 2048 + 128, &16u16.to_le_bytes()).is_err());
+    /* A root directory far bigger than the image */
+    assert!(open_corrupted(plain, 16 * 2048 + 156 + 10, &0xffff_fff0u32.to_le_bytes()).is_err());
+}
+
+
+#[test]
+fn hashdeep_audit() {
+    use confidence::hashdeep::{audit, read_hashdeep, write_hashdeep, AuditResult};
+
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree");
+    std::fs::create_dir_all(&tree).unwrap();
+    std::fs::write(tree.join("a"), "abc").unwrap();
+    std::fs::write(tree.join("h2"), "hello").unwrap();
+    std::fs::write(tree.join("tampered"), "xyz").unwrap();
+    std::fs::write(tree.join("n"), "new").unwrap();
+    let tree_s = tree.to_str().unwrap();
+
+    let manifest = dir.path().join("known.hashdeep");
+    std::fs::write(&manifest, concat!(
+            "%%%% HASHDEEP-1.0\n",
+            "%%%% size,md5,sha256,filename\n",
+            "## Invoked from: /evidence\n",
+            "##\n",
+            "3,900150983cd24fb0d6963f7d28e17f72,ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad,./a\n",
+            "5,5d41402abc4b2a76b9719d911017c592,2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824,sub/h\n",
+            "3,d16fb36f0911f878998c136191af705e,0000000000000000000000000000000000000000000000000000000000000000,tampered\n",
+            "1,9dd4e461268c8034f5c8564e155c67a6,2d711642b726b04401627ca9fbac32f5c8530fb1903cc4db02258717921a4881,gone, with a comma\n")).unwrap();
+    let manifest_s = manifest.to_str().unwrap();
+    assert_eq!(read_hashdeep(manifest_s).unwrap().entries[3].path,
+            "gone, with a comma");
+
+    let mut output = Vec::new();
+    let (results, comparison) = audit(manifest_s, tree_s, &mut output, 0,
+            &Filter::default(), false).unwrap();
+    let mut by_path = results.results.clone();
+    by_path.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(by_path, vec![("a".to_owned(), AuditResult::Matched),
+            ("h2".to_owned(), AuditResult::Moved),
+            ("n".to_owned(), AuditResult::New),
+            ("tampered".to_owned(), AuditResult::PartiallyMatched)]);
+    assert_eq!(results.not_found, vec!["gone, with a comma".to_owned()]);
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (8, 7, 1));
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Moved (5 bytes): h2 (was sub/h)\n"));
+    assert!(output.contains("Partially matched (3 bytes): tampered (changed since the manifest)\n"));
+    assert!(output.contains("Audit failed\n"));
+
+    /* What's written reads back, and audits clean */
+    let mut written = Vec::new();
+    assert_eq!(write_hashdeep(tree_s, &mut written, &Filter::default(), false)
+            .unwrap(), (4, 0));
+    std::fs::write(&manifest, &written).unwrap();
+    assert_eq!(read_hashdeep(manifest_s).unwrap().entries.len(), 4);
+    let (_, comparison) = audit(manifest_s, tree_s, &mut Vec::new(), 0,
+            &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagrees()), (14, false));
+}
+
+
+#[test]
+fn bagit_bags() {
+    use confidence::algorithm::Algorithm;
+    use confidence::bagit::{create_bag, validate_bag};
+
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::write(source.join("a"), "abc").unwrap();
+    std::fs::write(source.join("sub/50%\n"), "hello").unwrap();
+    let source_s = source.to_str().unwrap();
+
+    /* Copied into a new bag */
+    let bag = dir.path().join("bag");
+    let bag_s = bag.to_str().unwrap();
+    let comparison = create_bag(source_s, Some(bag_s), &mut Vec::new(), 0,
+            &[Algorithm::Sha256, Algorithm::Md5], &Filter::default(), false)
+            .unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagrees()), (8, false));
+    assert_eq!(std::fs::read_to_string(bag.join("manifest-sha256.txt")).unwrap(),
+            concat!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  data/a\n",
+                    "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824  data/sub/50%25%0A\n"));
+    assert!(std::fs::read_to_string(bag.join("bag-info.txt")).unwrap()
+            .contains("Payload-Oxum: 8.2\n"));
+    assert!(bag.join("tagmanifest-md5.txt").is_file());
+    let comparison = validate_bag(bag_s, &mut Vec::new(), 0).unwrap();
+    assert!(!comparison.disagrees());
+
+    /* Fixity and completeness both count */
+    std::fs::write(bag.join("data/a"), "abd").unwrap();
+    std::fs::write(bag.join("data/unlisted"), "zz").unwrap();
+    let mut output = Vec::new();
+    let comparison = validate_bag(bag_s, &mut output, 0).unwrap();
+    assert_eq!((comparison.disagreement(), comparison.entry_disagreement()),
+            (5, 1));
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Disagreement (3 bytes): data/a has the wrong md5 and sha256.\n"));
+    assert!(output.contains("Disagreement (2 bytes): data/unlisted isn't in every payload manifest.\n"));
+    assert!(output.contains("the Payload-Oxum is 8.2, but the payload is 10.3."));
+
+    /* Nothing outside the bag is looked at */
+    let mut manifest = std::fs::OpenOptions::new().append(true)
+            .open(bag.join("manifest-md5.txt")).unwrap();
+    std::io::Write::write_all(&mut manifest,
+            b"900150983cd24fb0d6963f7d28e17f72  ../source/a\n\
+            900150983cd24fb0d6963f7d28e17f72  /etc/hostname\n").unwrap();
+    let mut output = Vec::new();
+    let comparison = validate_bag(bag_s, &mut output, 0).unwrap();
+    assert_eq!(comparison.entry_disagreement(), 3);
+    assert!(String::from_utf8(output).unwrap()
+            .contains("manifest-md5.txt lists ../source/a, which isn't in the bag.\n"));
+
+    /* Not in place over what an interrupted bagging left behind */
+    std::fs::create_dir(source.join(".confidence-bagging")).unwrap();
+    assert!(create_bag(source_s, None, &mut Vec::new(), 0,
+            &[Algorithm::Sha256], &Filter::default(), false).is_err());
+    assert!(source.join("a").is_file());
+    std::fs::remove_dir(source.join(".confidence-bagging")).unwrap();
+
+    /* In place */
+    let comparison = create_bag(source_s, None, &mut Vec::new(), 0,
+            &[Algorithm::Sha256], &Filter::default(), false).unwrap();
+    assert_eq!(comparison.agreement(), 8);
+    assert!(source.join("data/sub/50%\n").is_file());
+    assert!(!source.join("a").exists());
+    assert!(!validate_bag(source_s, &mut Vec::new(), 0).unwrap().disagrees());
+    std::fs::remove_file(source.join("data/a")).unwrap();
+    assert_eq!(validate_bag(source_s, &mut Vec::new(), 0).unwrap()
+            .entry_disagreement(), 2);
+}
+
+
+#[test]
+fn mtree_specs() {
+    use confidence::mtree::{parse_mtree, verify_mtree, write_mtree};
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree");
+    std::fs::create_dir_all(tree.join("sub")).unwrap();
+    std::fs::write(tree.join("a"), "abc").unwrap();
+    std::fs::set_permissions(tree.join("a"),
+            std::fs::Permissions::from_mode(0o600)).unwrap();
+    std::fs::write(tree.join("sub/h h"), "hello").unwrap();
+    std::os::unix::fs::symlink("a", tree.join("l")).unwrap();
+    let tree_s = tree.to_str().unwrap();
+
+    let mut spec = Vec::new();
+    assert_eq!(write_mtree(tree_s, &mut spec, &Filter::default(), false).unwrap(), 5);
+    let spec = String::from_utf8(spec).unwrap();
+    assert!(spec.contains("\n./a type=file mode=0600 "));
+    assert!(spec.contains(" size=3 sha256digest=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n"));
+    assert!(spec.contains("\n./l type=link mode=0777 "));
+    assert!(spec.contains("\n./sub/h\\040h type=file "));
+    let spec_filename = dir.path().join("spec");
+    std::fs::write(&spec_filename, &spec).unwrap();
+    let spec_s = spec_filename.to_str().unwrap();
+    let comparison = verify_mtree(spec_s, tree_s, &mut Vec::new(), 0,
+            &Filter::default(), false).unwrap();
+    assert_eq!((comparison.agreement(), comparison.disagrees()), (8, false));
+
+    /* Each keyword is reported on its own */
+    std::fs::set_permissions(tree.join("a"),
+            std::fs::Permissions::from_mode(0o644)).unwrap();
+    std::fs::write(tree.join("sub/h h"), "jello").unwrap();
+    std::fs::remove_file(tree.join("l")).unwrap();
+    std::os::unix::fs::symlink("sub", tree.join("l")).unwrap();
+    std::fs::write(tree.join("new"), "new").unwrap();
+    let mut output = Vec::new();
+    let comparison = verify_mtree(spec_s, tree_s, &mut output, 0,
+            &Filter::default(), false).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("Disagreement (0 bytes): a mode expected 0600, found 0644\n"));
+    assert!(output.contains("Disagreement (0 bytes): l link expected a, found sub\n"));
+    assert!(output.contains("Disagreement (5 bytes): sub/h h sha256digest expected "));
+    assert!(output.contains("Disagreement (3 bytes): new is extra.\n"));
+    assert_eq!((comparison.agreement(), comparison.disagreement(),
+            comparison.entry_disagreement()), (3, 8, 2));
+
+    /* The classic form, with /set defaults and .. */
+    let entries = parse_mtree(concat!("#mtree\n",
+            "/set type=file mode=0644\n",
+            ". type=dir mode=0755\n",
+            "    sub type=dir\n",
+            "        h\\040h size=5 \\\n",
+            "            optional\n",
+            "    ..\n",
+            "    a mode=0600\n")).unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.path.as_str()).collect::<Vec<_>>(),
+            vec![".", "sub", "sub/h h", "a"]);
+    assert_eq!(entries[2].to_line(), "./sub/h\\040h type=file mode=0644 size=5 optional");
+    assert_eq!(entries[3].get("mode"), Some("0600"));
+}
+
+
+#[test]
+fn torrent_pieces() {
+    use confidence::torrent::verify_torrent;
+    use sha2::Digest;
+
+    let dir = tempfile::tempdir().unwrap();
+    let content = dir.path().join("set");
+    std::fs::create_dir_all(content.join("sub")).unwrap();
+    let a = (0..40 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let b = b"hello".to_vec();
+    std::fs::write(content.join("a"), &a).unwrap();
+    std::fs::write(content.join("sub/b"), &b).unwrap();
+    let piece_length = 16 * 1024;
+    let bytes_s = |bytes: &[u8]| {
+        let mut to_return = format!("{}:", bytes.len()).into_bytes();
+        to_return.extend_from_slice(bytes);
+        to_return
+    };
+
+    /* v1: pieces run across both files, so the last one covers both */
+    let all = [&a[..], &b[..]].concat();
+    let pieces = all.chunks(piece_length)
+            .flat_map(|piece| sha1::Sha1::from(piece).digest().bytes().to_vec())
+            .collect::<Vec<_>>();
+    let v1 = [&b"d4:infod5:filesld6:lengthi40960e4:pathl1:aeed6:lengthi5e4:pathl3:sub1:beee4:name3:set12:piece lengthi16384e6:pieces"[..],
+            &bytes_s(&pieces), b"ee"].concat();
+    let torrent = dir.path().join("v1.torrent");
+    std::fs::write(&torrent, &v1).unwrap();
+    let torrent_s = torrent.to_str().unwrap();
+    let dir_s = dir.path().to_str().unwrap();
+    let results = verify_torrent(torrent_s, dir_s, &mut Vec::new(), 0).unwrap();
+    assert_eq!(results.iter().map(|(path, comparison)| (path.as_str(),
+            comparison.agreement())).collect::<Vec<_>>(),
+            vec![("a", 40960), ("sub/b", 5)]);
+
+    let mut changed = b.clone();
+    changed[0] = b'j';
+    std::fs::write(content.join("sub/b"), &changed).unwrap();
+    let mut output = Vec::new();
+    let results = verify_torrent(torrent_s, content.to_str().unwrap(),
+            &mut output, 0).unwrap();
+    assert_eq!((results[0].1.agreement(), results[0].1.disagreement()),
+            (32768, 8192));
+    assert_eq!(results[1].1.disagreement(), 5);
+    assert!(String::from_utf8(output).unwrap()
+            .contains("Disagreed on 8197/40965 bytes"));
+
+    /* Whatever's past the end of a file disagrees, and takes the file
+     * with it */
+    std::fs::write(content.join("sub/b"), b"hellogarbage").unwrap();
+    let mut output = Vec::new();
+    let results = verify_torrent(torrent_s, dir_s, &mut output, 0).unwrap();
+    assert_eq!((results[1].1.agreement(), results[1].1.disagreement()), (0, 12));
+    assert!(String::from_utf8(output).unwrap().contains("sub/b is 12 bytes, not 5."));
+    std::fs::write(content.join("sub/b"), &changed).unwrap();
+
+    /* v2: one block per piece, so each piece hash is just the block's
+     * sha256, and the small file's root is too */
+    let layer = a.chunks(piece_length)
+            .flat_map(|block| sha2::Sha256::digest(block).to_vec())
+            .collect::<Vec<_>>();
+    let pair = |l: &[u8], r: &[u8]| sha2::Sha256::digest([l, r].concat()).to_vec();
+    let root_a = pair(&pair(&layer[..32], &layer[32..64]), &pair(&layer[64..], &[0; 32]));
+    let root_b = sha2::Sha256::digest(&b).to_vec();
+    let v2 = [&b"d4:infod9:file treed1:ad0:d6:lengthi40960e11:pieces root"[..],
+            &bytes_s(&root_a), b"ee3:subd1:bd0:d6:lengthi5e11:pieces root",
+            &bytes_s(&root_b), b"eeee12:meta versioni2e4:name3:set12:piece lengthi16384ee12:piece layersd",
+            &bytes_s(&root_a), &bytes_s(&layer), b"ee"].concat();
+    std::fs::write(&torrent, &v2).unwrap();
+    let results = verify_torrent(torrent_s, dir_s, &mut Vec::new(), 0).unwrap();
+    assert_eq!((results[0].1.agreement(), results[0].1.disagrees()), (40960, false));
+    assert_eq!(results[1].1.disagreement(), 5);
+
+    /* The piece layers aren't covered by the info hash, so they have to
+     * add up to the pieces root */
+    let mut bad_layer = layer.clone();
+    bad_layer[0] ^= 1;
+    let bad = [&v2[..v2.len() - layer.len() - 2], &bad_layer, b"ee"].concat();
+    std::fs::write(&torrent, &bad).unwrap();
+    assert!(verify_torrent(torrent_s, dir_s, &mut Vec::new(), 0).is_err());
+    std::fs::write(&torrent, &v2).unwrap();
+    std::fs::remove_file(content.join("a")).unwrap();
+    let mut output = Vec::new();
+    verify_torrent(torrent_s, dir_s, &mut output, 0).unwrap();
+    assert!(String::from_utf8(output).unwrap().contains("set/a is missing."));
+
+    /* Negative lengths don't wrap around */
+    let header = "d4:infod5:filesld6:lengthi40960e4:pathl1:aeed6:lengthi5e4:pathl3:sub1:beee4:name3:set12:piece lengthi16384e6:pieces";
+    let huge = "lengthi9223372036854775807e";
+    for bad_header in [header.replace("lengthi5e", "lengthi-5e"),
+            header.replace("lengthi40960e", huge).replace("lengthi5e", huge),
+            header.replace("3:set", "-3:set")] {
+        let bad = [bad_header.as_bytes(), &bytes_s(&pieces), b"ee"].concat();
+        std::fs::write(&torrent, &bad).unwrap();
+        assert!(verify_torrent(torrent_s, dir_s, &mut Vec::new(), 0).is_err());
+    }
+
+    /* Nor does nesting blow the stack */
+    std::fs::write(&torrent, vec![b'l'; 2 * 1024 * 1024]).unwrap();
+    assert!(verify_torrent(torrent_s, dir_s, &mut Vec::new(), 0).is_err());
+}